@@ -0,0 +1,59 @@
+//! The sample restarter application, using `#[shellflip::main]` to do the wiring.
+//!
+//! Restart it by sending SIGUSR1, or with `shellflip-admin --socket /tmp/restarter-main.sock
+//! restart`. The listener is passed to the new process, so clients keep connecting to the same
+//! address, and connections to the old process are served until they close.
+use shellflip::app::Context;
+use shellflip::listeners::listener_or_bind;
+use shellflip::ShutdownSignal;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+
+#[shellflip::main(socket = "/tmp/restarter-main.sock")]
+async fn main(mut ctx: Context) -> anyhow::Result<()> {
+    env_logger::init();
+    let listener = listener_or_bind("echo", "127.0.0.1:8080")?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    println!(
+        "Instance no. {} listening on {}",
+        ctx.generation,
+        listener.local_addr()?
+    );
+    ctx.ready();
+
+    loop {
+        let (sock, addr) = listener.accept().await?;
+        log::info!("Received connection from {}", addr);
+        ctx.shutdown
+            .spawn(ctx.shutdown.scope(|signal| echo(sock, signal)));
+    }
+}
+
+async fn echo(mut sock: TcpStream, mut shutdown_signal: ShutdownSignal) {
+    let mut buf = [0u8; 1024];
+    let out = format!("Hello, this is process {}\n", std::process::id());
+    let _ = sock.write_all(out.as_bytes()).await;
+
+    loop {
+        select! {
+            r = sock.read(&mut buf) => match r {
+                Ok(0) => return,
+                Ok(n) => {
+                    if let Err(e) = sock.write_all(&buf[..n]).await {
+                        log::error!("write failed: {}", e);
+                        return;
+                    }
+                }
+                Err(e) => {
+                    log::error!("read failed: {}", e);
+                    return;
+                }
+            },
+            _ = shutdown_signal.on_shutdown() => {
+                log::info!("shutdown requested but client {:?} is still active", sock.peer_addr());
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! A UDP echo service that is restarted without dropping queries, using `#[shellflip::main]`.
+//!
+//! Restart it by sending SIGUSR1, or with `shellflip-admin --service restarter-udp restart`, while
+//! sending it datagrams, e.g. with `nc -u 127.0.0.1 5353`. The socket is passed to the new process,
+//! and every query the old process has received is answered, after a short delay that stands in
+//! for the work of e.g. resolving a DNS query, before it exits.
+use shellflip::app::Context;
+use shellflip::drain::udp::UdpRequests;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+#[shellflip::main(service = "restarter-udp")]
+async fn main(mut ctx: Context) -> anyhow::Result<()> {
+    env_logger::init();
+    let socket = match shellflip::files::take_inherited("echo") {
+        Some(fd) => std::net::UdpSocket::from(fd),
+        None => std::net::UdpSocket::bind("127.0.0.1:5353")?,
+    };
+    shellflip::files::register("echo", &socket)?;
+    socket.set_nonblocking(true)?;
+    let socket = Arc::new(UdpSocket::from_std(socket)?);
+    println!(
+        "Instance no. {} listening on {}",
+        ctx.generation,
+        socket.local_addr()?
+    );
+
+    let requests = UdpRequests::new(socket, ctx.shutdown.handle());
+    // Spawned, so that it outlives `main` and receives the queries still queued once the restart
+    // has completed.
+    let serving = ctx.shutdown.spawn(serve(requests));
+    ctx.ready();
+    serving.await??;
+    Ok(())
+}
+
+async fn serve(mut requests: UdpRequests) -> std::io::Result<()> {
+    let mut buf = [0u8; 1500];
+    while let Some(request) = requests.recv(&mut buf).await {
+        let request = request?;
+        let reply = format!(
+            "process {}: {}",
+            std::process::id(),
+            String::from_utf8_lossy(&buf[..request.len])
+        );
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let peer = request.peer;
+            if let Err(e) = request.respond(reply.as_bytes()).await {
+                log::error!("reply to {} failed: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
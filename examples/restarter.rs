@@ -43,8 +43,7 @@ impl LifecycleHandler for AppData {
     async fn send_to_new_process(&mut self, mut write_pipe: PipeWriter) -> std::io::Result<()> {
         if self.restart_generation > 4 {
             log::info!("Four restarts is more than anybody needs, surely?");
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(std::io::Error::other(
                 "The operation completed successfully",
             ));
         }
@@ -80,13 +79,17 @@ async fn main() -> Result<(), Error> {
         Some(Commands::Restart) => {
             let res = restart_conf.request_restart().await;
             match res {
-                Ok(id) => {
-                    log::info!("Restart succeeded, child pid is {}", id);
+                Ok(outcome) => {
+                    log::info!(
+                        "Restart {} succeeded, child pid is {}",
+                        outcome.restart_id,
+                        outcome.pid
+                    );
                     return Ok(());
                 }
                 Err(e) => {
                     log::error!("Restart failed: {}", e);
-                    return Err(e);
+                    return Err(e.into());
                 }
             }
         }
@@ -41,10 +41,7 @@ impl LifecycleHandler for AppData {
     async fn send_to_new_process(&mut self, mut write_pipe: PipeWriter) -> std::io::Result<()> {
         if self.restart_generation > 4 {
             log::info!("四次重启已经足够多了，对吧？");
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "操作成功完成",
-            ));
+            return Err(std::io::Error::other("操作成功完成"));
         }
         write_pipe.write_u32(self.restart_generation).await?;
         Ok(())
@@ -78,13 +75,13 @@ async fn main() -> Result<(), Error> {
         Some(Commands::Restart) => {
             let res = restart_conf.request_restart().await;
             match res {
-                Ok(id) => {
-                    log::info!("重启成功，子进程ID是{}", id);
+                Ok(outcome) => {
+                    log::info!("重启{}成功，子进程ID是{}", outcome.restart_id, outcome.pid);
                     return Ok(());
                 }
                 Err(e) => {
                     log::error!("重启失败: {}", e);
-                    return Err(e);
+                    return Err(e.into());
                 }
             }
         }
@@ -169,4 +166,4 @@ async fn echo(mut sock: TcpStream, shutdown_handle: Arc<ShutdownHandle>) {
             }
         }
     }
-}
\ No newline at end of file
+}
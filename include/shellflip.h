@@ -0,0 +1,36 @@
+/* C bindings for the shellflip admin client, built with the `ffi` feature. See src/ffi.rs. */
+#ifndef SHELLFLIP_H
+#define SHELLFLIP_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define SHELLFLIP_OK 0
+#define SHELLFLIP_ERR_INVALID_ARGUMENT -1
+#define SHELLFLIP_ERR_CONNECT -2
+#define SHELLFLIP_ERR_REJECTED -3
+#define SHELLFLIP_ERR_UNSUPPORTED -4
+#define SHELLFLIP_ERR_OTHER -5
+
+/* Restart the process listening on socket_path. pid_out may be NULL. */
+int shellflip_request_restart(const char *socket_path, uint32_t *pid_out);
+
+/* Query the restart status as JSON. Free *json_out with shellflip_free_string. */
+int shellflip_status(const char *socket_path, char **json_out);
+
+/* Ask the process listening on socket_path to shut down. */
+int shellflip_shutdown(const char *socket_path);
+
+/* The message of the last error on this thread, or NULL. Owned by the library. */
+const char *shellflip_last_error(void);
+
+void shellflip_free_string(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,261 @@
+//! Procedural macros for shellflip. Use them through the `macros` feature of the `shellflip` crate,
+//! which re-exports them.
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::punctuated::Punctuated;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Fields, ItemFn, LitInt, LitStr, MetaNameValue, Path,
+    Token,
+};
+
+/// Runs an async main function with graceful restarts, see `shellflip::app`.
+///
+/// Takes the optional arguments `socket`, the default path of the restart coordination socket, or
+/// `service`, the name to find it by, see `shellflip::discovery`, along with `instance`, an
+/// expression naming the instance of the service, and `config`, a function returning the
+/// `RestartConfig` to start from.
+#[proc_macro_attribute]
+pub fn main(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args =
+        parse_macro_input!(args with Punctuated::<MetaNameValue, Token![,]>::parse_terminated);
+    let mut func = parse_macro_input!(item as ItemFn);
+
+    let mut configure = None;
+    let mut instance = None;
+    let mut config = quote!(::shellflip::RestartConfig::default());
+    for arg in args {
+        let value = arg.value;
+        if arg.path.is_ident("socket") || arg.path.is_ident("service") {
+            if configure.is_some() {
+                return syn::Error::new_spanned(
+                    arg.path,
+                    "expected `socket` or `service`, not both",
+                )
+                .to_compile_error()
+                .into();
+            }
+            configure = Some((arg.path, value));
+        } else if arg.path.is_ident("instance") {
+            instance = Some(value);
+        } else if arg.path.is_ident("config") {
+            config = quote!(#value());
+        } else {
+            return syn::Error::new_spanned(
+                arg.path,
+                "expected `socket`, `service`, `instance` or `config`",
+            )
+            .to_compile_error()
+            .into();
+        }
+    }
+    if func.sig.asyncness.is_none() {
+        return syn::Error::new_spanned(func.sig.fn_token, "the main function must be async")
+            .to_compile_error()
+            .into();
+    }
+
+    let configure = match (configure, instance) {
+        (Some((path, service)), Some(instance)) if path.is_ident("service") => quote! {
+            ::shellflip::app::configure_instance(
+                #config,
+                #service,
+                &::std::string::ToString::to_string(&(#instance)),
+            )
+        },
+        (_, Some(instance)) => {
+            return syn::Error::new_spanned(instance, "`instance` needs `service`")
+                .to_compile_error()
+                .into();
+        }
+        (Some((path, service)), None) if path.is_ident("service") => {
+            quote!(::shellflip::app::configure_service(#config, #service))
+        }
+        (Some((_, socket)), None) => {
+            quote!(::shellflip::app::configure(#config, ::core::option::Option::Some(#socket)))
+        }
+        (None, None) => {
+            quote!(::shellflip::app::configure(#config, ::core::option::Option::None))
+        }
+    };
+    let attrs = std::mem::take(&mut func.attrs);
+    let vis = &func.vis;
+    let ident = func.sig.ident.clone();
+    let output = func.sig.output.clone();
+    func.sig.ident = format_ident!("__shellflip_{}", ident);
+    let inner = &func.sig.ident;
+
+    quote! {
+        #(#attrs)*
+        #vis fn #ident() #output {
+            #func
+            let config = #configure;
+            ::shellflip::app::block_on(::shellflip::app::run(config, #inner))
+        }
+    }
+    .into()
+}
+
+/// Implements `shellflip::handover::Handover` and `HandoverField` for a struct with named fields.
+/// See `shellflip::handover` for the attributes.
+#[proc_macro_derive(Handover, attributes(handover))]
+pub fn derive_handover(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match handover_impl(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// The `handover` attribute of a field.
+#[derive(Default)]
+struct FieldAttrs {
+    tag: Option<LitInt>,
+    since: Option<LitInt>,
+    /// `Some(None)` for `Default::default`.
+    default: Option<Option<Path>>,
+    skip: bool,
+}
+
+fn field_attrs(field: &syn::Field) -> syn::Result<FieldAttrs> {
+    let mut attrs = FieldAttrs::default();
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("handover")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("tag") {
+                attrs.tag = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("since") {
+                attrs.since = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("default") {
+                attrs.default = Some(match meta.input.peek(Token![=]) {
+                    true => Some(meta.value()?.parse::<LitStr>()?.parse()?),
+                    false => None,
+                });
+            } else if meta.path.is_ident("skip") {
+                attrs.skip = true;
+            } else {
+                return Err(meta.error("expected `tag`, `since`, `default` or `skip`"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(attrs)
+}
+
+fn handover_impl(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut version = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("handover")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("version") {
+                version = Some(meta.value()?.parse::<LitInt>()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `version`"))
+            }
+        })?;
+    }
+    let version = version.map_or(quote!(1), |v| quote!(#v));
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "Handover can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "Handover can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut puts = Vec::new();
+    let mut gets = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let attrs = field_attrs(field)?;
+        if attrs.skip {
+            gets.push(quote!(#ident: ::core::default::Default::default()));
+            continue;
+        }
+        let Some(tag) = attrs.tag else {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "missing `#[handover(tag = N)]` or `#[handover(skip)]`",
+            ));
+        };
+        let ty = &field.ty;
+        let name = ident.to_string();
+        let default = match &attrs.default {
+            Some(Some(path)) => quote!(#path()),
+            _ => quote!(::core::default::Default::default()),
+        };
+        let missing = match (&attrs.default, &attrs.since) {
+            (Some(_), _) => quote!(#default),
+            (None, Some(since)) => quote! {
+                if record.version() < #since {
+                    #default
+                } else {
+                    return ::core::result::Result::Err(
+                        ::shellflip::handover::missing_field(#tag, #name),
+                    );
+                }
+            },
+            (None, None) => quote! {
+                return ::core::result::Result::Err(
+                    ::shellflip::handover::missing_field(#tag, #name),
+                )
+            },
+        };
+        puts.push(quote! {
+            ::shellflip::handover::HandoverField::put(&self.#ident, &mut record, #tag);
+        });
+        gets.push(quote! {
+            #ident: match <#ty as ::shellflip::handover::HandoverField>::get(record, #tag)? {
+                ::core::option::Option::Some(value) => value,
+                ::core::option::Option::None => { #missing }
+            }
+        });
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::shellflip::handover::Handover for #ident #ty_generics #where_clause {
+            const VERSION: u16 = #version;
+
+            fn to_record(&self) -> ::shellflip::handover::HandoverRecord {
+                let mut record = ::shellflip::handover::HandoverRecord::new(Self::VERSION);
+                #(#puts)*
+                record
+            }
+
+            fn from_record(
+                record: &::shellflip::handover::HandoverRecord,
+            ) -> ::std::io::Result<Self> {
+                ::core::result::Result::Ok(#ident { #(#gets),* })
+            }
+        }
+
+        impl #impl_generics ::shellflip::handover::HandoverField
+            for #ident #ty_generics #where_clause
+        {
+            fn put(&self, record: &mut ::shellflip::handover::HandoverRecord, tag: u16) {
+                record.put_record(tag, &::shellflip::handover::Handover::to_record(self));
+            }
+
+            fn get(
+                record: &::shellflip::handover::HandoverRecord,
+                tag: u16,
+            ) -> ::std::io::Result<::core::option::Option<Self>> {
+                record
+                    .get_record(tag)?
+                    .map(|r| <Self as ::shellflip::handover::Handover>::from_record(&r))
+                    .transpose()
+            }
+        }
+    })
+}
@@ -0,0 +1,445 @@
+//! A client for the restart coordination socket of any process that uses shellflip, for managing
+//! it without going through its own command line. The `shellflip-admin` binary, built with the
+//! `admin-cli` feature, wraps this client.
+//!
+//! `list_instances` finds the running instances of a service, see `discovery`, and
+//! `rolling_restart` restarts them one after another, e.g. from a deploy script.
+//!
+//! ```no_run
+//! # async fn example() -> shellflip::RestartResult<()> {
+//! let client = shellflip::admin::AdminClient::new("/run/myservice/restart.sock");
+//! println!("{:?}", client.status().await?);
+//! let outcome = client.restart(Default::default()).await?;
+//! println!("restarted as pid {}", outcome.pid);
+//! # Ok(())
+//! # }
+//! ```
+use crate::discovery;
+use crate::restart_coordination_socket::RestartCoordinationSocket;
+use crate::{
+    AdminCommand, ChildOutputHook, Error, RestartConfig, RestartEvent, RestartId, RestartOptions,
+    RestartOutcome, RestartResult, StatusReport,
+};
+use futures::Stream;
+use std::fmt;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+
+/// How long `list_instances` waits for each instance to report its status.
+pub const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running instance of a service, found by `list_instances`.
+#[derive(Debug)]
+pub struct InstanceStatus {
+    /// See `discovery::InstanceSocket::instance`.
+    pub instance: Option<String>,
+    pub client: AdminClient,
+    /// The status of the instance, or why it couldn't be queried.
+    pub status: RestartResult<StatusReport>,
+}
+
+/// Find the running instances of `service`, see `discovery::instances`, and query their status.
+/// Sockets that nothing listens on any more, left over from processes that are gone, are skipped.
+/// The instances are queried at the same time, and those that don't answer within
+/// `STATUS_TIMEOUT` are listed with a `TimedOut` error.
+pub async fn list_instances(service: &str) -> RestartResult<Vec<InstanceStatus>> {
+    query_instances(discovery::instances(service)?).await
+}
+
+async fn query_instances(
+    sockets: Vec<discovery::InstanceSocket>,
+) -> RestartResult<Vec<InstanceStatus>> {
+    let mut clients = Vec::new();
+    for socket in sockets {
+        clients.push((socket.instance, AdminClient::checked(socket.path)?));
+    }
+    let statuses = futures::future::join_all(clients.iter().map(|(_, client)| async move {
+        match timeout(STATUS_TIMEOUT, client.status()).await {
+            Ok(status) => status,
+            Err(_) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no status within {STATUS_TIMEOUT:?}"),
+            ))),
+        }
+    }))
+    .await;
+    let mut running = Vec::new();
+    for ((instance, client), status) in clients.into_iter().zip(statuses) {
+        if let Err(Error::Connect { source, .. }) = &status {
+            if source.kind() == io::ErrorKind::ConnectionRefused {
+                continue;
+            }
+        }
+        running.push(InstanceStatus {
+            instance,
+            client,
+            status,
+        });
+    }
+    Ok(running)
+}
+
+/// Settings for `rolling_restart`.
+#[derive(Clone, Debug, Default)]
+pub struct RollingRestartConfig {
+    /// The options of the restart of each instance.
+    pub options: RestartOptions,
+    /// How long to wait between the restarts of two instances, e.g. to let the restarted one warm
+    /// up.
+    pub pause: Duration,
+    /// Carry on with the remaining instances when an instance fails to restart, instead of
+    /// leaving them running as they are.
+    pub keep_going: bool,
+}
+
+/// The outcome of the restart of one instance in `rolling_restart`.
+#[derive(Debug)]
+pub struct InstanceRestart {
+    /// See `discovery::InstanceSocket::instance`.
+    pub instance: Option<String>,
+    pub client: AdminClient,
+    /// `None` if the instance was not restarted, as an earlier one failed.
+    pub result: Option<RestartResult<RestartOutcome>>,
+}
+
+/// The outcomes of `rolling_restart`, in the order the instances were restarted.
+#[derive(Debug, Default)]
+pub struct RollingRestartReport {
+    pub instances: Vec<InstanceRestart>,
+}
+
+impl RollingRestartReport {
+    /// The instances that failed to restart.
+    pub fn failed(&self) -> impl Iterator<Item = &InstanceRestart> {
+        self.instances
+            .iter()
+            .filter(|i| matches!(i.result, Some(Err(_))))
+    }
+
+    /// Whether every instance was restarted.
+    pub fn is_success(&self) -> bool {
+        self.instances
+            .iter()
+            .all(|i| matches!(i.result, Some(Ok(_))))
+    }
+}
+
+/// Restart the running instances of `service`, see `list_instances`, one after another, so that
+/// the others keep serving while each restarts. Unless `RollingRestartConfig::keep_going` is set,
+/// the instances after the first one that fails are left as they are.
+pub async fn rolling_restart(
+    service: &str,
+    config: &RollingRestartConfig,
+) -> RestartResult<RollingRestartReport> {
+    let instances = list_instances(service).await?;
+    let instances = instances.into_iter().map(|i| (i.instance, i.client));
+    Ok(restart_instances(instances, config).await)
+}
+
+async fn restart_instances(
+    instances: impl IntoIterator<Item = (Option<String>, AdminClient)>,
+    config: &RollingRestartConfig,
+) -> RollingRestartReport {
+    let mut report = RollingRestartReport::default();
+    let mut failed = false;
+    for (instance, client) in instances {
+        let result = if failed && !config.keep_going {
+            None
+        } else {
+            if !report.instances.is_empty() && !config.pause.is_zero() {
+                tokio::time::sleep(config.pause).await;
+            }
+            let result = client.restart(config.options.clone()).await;
+            failed |= result.is_err();
+            Some(result)
+        };
+        report.instances.push(InstanceRestart {
+            instance,
+            client,
+            result,
+        });
+    }
+    report
+}
+
+/// Talks to a running process over its restart coordination socket.
+#[derive(Clone)]
+pub struct AdminClient {
+    socket_path: PathBuf,
+    child_output_hook: Option<ChildOutputHook>,
+}
+
+impl fmt::Debug for AdminClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminClient")
+            .field("socket_path", &self.socket_path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AdminClient {
+    pub fn new(socket_path: impl Into<PathBuf>) -> Self {
+        AdminClient {
+            socket_path: socket_path.into(),
+            child_output_hook: None,
+        }
+    }
+
+    /// Pass the output of the new process relayed during restarts with
+    /// `RestartOptions::relay_output` to `f`, see `RestartConfig::child_output_hook`.
+    pub fn on_child_output(mut self, f: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.child_output_hook = Some(Arc::new(f));
+        self
+    }
+
+    /// Talks to the process serving `service` at its default socket path, see `discovery`. Fails if
+    /// the name is invalid, see `discovery::check_name`, or if the directory of the socket could
+    /// have been tampered with, see `discovery::check_socket_dir`.
+    pub fn for_service(service: &str) -> io::Result<Self> {
+        Self::checked(discovery::socket_path(service)?)
+    }
+
+    /// Talks to the process serving `instance` of `service`, see `discovery::instance_socket_path`.
+    /// Fails as `for_service` does.
+    pub fn for_instance(service: &str, instance: &str) -> io::Result<Self> {
+        Self::checked(discovery::instance_socket_path(service, instance)?)
+    }
+
+    fn checked(socket_path: PathBuf) -> io::Result<Self> {
+        discovery::check_socket_dir(&socket_path)?;
+        Ok(Self::new(socket_path))
+    }
+
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Restart the running process. Running processes that predate restart options are restarted
+    /// without them.
+    pub async fn restart(&self, options: RestartOptions) -> RestartResult<RestartOutcome> {
+        self.config().request_restart_with(options).await
+    }
+
+    /// Restart the running process, passing `fds` to the new process under their names, see
+    /// `RestartConfig::request_restart_with_fds`.
+    pub async fn restart_with_fds(
+        &self,
+        options: RestartOptions,
+        fds: Vec<(String, OwnedFd)>,
+    ) -> RestartResult<RestartOutcome> {
+        self.config().request_restart_with_fds(options, fds).await
+    }
+
+    /// Ask the running process to shut down without starting a new process.
+    pub async fn shutdown(&self) -> RestartResult<()> {
+        self.connect()
+            .await?
+            .send_command(AdminCommand::Shutdown)
+            .await
+    }
+
+    /// Ask the running process to reload its configuration.
+    pub async fn reload(&self) -> RestartResult<()> {
+        self.connect()
+            .await?
+            .send_command(AdminCommand::Reload)
+            .await
+    }
+
+    /// Ask the running process to reopen its log files, e.g. after rotating them.
+    pub async fn reopen_logs(&self) -> RestartResult<()> {
+        self.connect()
+            .await?
+            .send_command(AdminCommand::ReopenLogs)
+            .await
+    }
+
+    pub async fn status(&self) -> RestartResult<StatusReport> {
+        self.connect().await?.query_status().await
+    }
+
+    /// Subscribe to the lifecycle events of the running process. Returns its current status and the
+    /// events that follow, which end once the running process exits.
+    pub async fn subscribe(
+        &self,
+    ) -> RestartResult<(
+        StatusReport,
+        impl Stream<Item = RestartResult<RestartEvent>> + Send,
+    )> {
+        let mut rpc = self.connect().await?;
+        let status = rpc.subscribe().await?;
+        let events = futures::stream::try_unfold(rpc, |mut rpc| async move {
+            Ok(rpc.next_event().await?.map(|event| (event, rpc)))
+        });
+        Ok((status, events))
+    }
+
+    /// Restart the running process back into its previous binary slot, see `update::Slots`.
+    pub async fn rollback(&self, options: RestartOptions) -> RestartResult<RestartOutcome> {
+        self.connect().await?.send_rollback_command(options).await
+    }
+
+    /// Wait for the restart in progress, if any, to complete.
+    pub async fn wait_for_restart(&self) -> RestartResult<Option<RestartOutcome>> {
+        self.connect().await?.wait_for_restart().await
+    }
+
+    /// Cancel the restart in progress, or only the restart with the given ID.
+    pub async fn cancel_restart(
+        &self,
+        restart_id: Option<RestartId>,
+    ) -> RestartResult<Option<RestartId>> {
+        self.connect().await?.cancel_restart(restart_id).await
+    }
+
+    /// Commit the restart in progress, or only the restart with the given ID, once it awaits a
+    /// commit, see `RestartOptions::await_commit`.
+    pub async fn commit_restart(
+        &self,
+        restart_id: Option<RestartId>,
+    ) -> RestartResult<Option<RestartOutcome>> {
+        self.connect().await?.commit_restart(restart_id).await
+    }
+
+    fn config(&self) -> RestartConfig {
+        RestartConfig {
+            enabled: true,
+            coordination_socket_path: self.socket_path.clone(),
+            child_output_hook: self.child_output_hook.clone(),
+            ..Default::default()
+        }
+    }
+
+    async fn connect(&self) -> RestartResult<RestartCoordinationSocket> {
+        let socket = UnixStream::connect(&self.socket_path)
+            .await
+            .map_err(|source| Error::Connect {
+                path: self.socket_path.clone(),
+                source,
+            })?;
+        let mut socket = RestartCoordinationSocket::new(socket);
+        if let Some(hook) = self.child_output_hook.clone() {
+            socket.on_child_output(hook);
+        }
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restart_coordination_socket::{RestartMessage, RestartRequest, RestartResponse};
+    use std::fs::{self, DirBuilder};
+    use std::os::unix::fs::DirBuilderExt;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use std::sync::Mutex;
+    use tokio::time::Instant;
+
+    /// Serve restart requests on `path` as an instance would, completing them with `pid`, or
+    /// failing them if it is `None`. Returns when each restart was requested.
+    fn fake_instance(path: &Path, pid: Option<u32>) -> Arc<Mutex<Vec<Instant>>> {
+        let listener = UnixListener::bind(path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let listener = tokio::net::UnixListener::from_std(listener).unwrap();
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requested);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut rpc = RestartCoordinationSocket::new(stream);
+                let Ok(RestartMessage::Request(RestartRequest::TryRestartWith(options))) =
+                    rpc.receive_message().await
+                else {
+                    continue;
+                };
+                log.lock().unwrap().push(Instant::now());
+                let restart_id = options.restart_id.unwrap_or_else(|| "test".into());
+                let outcome = match pid {
+                    Some(pid) => RestartResponse::RestartComplete(pid),
+                    None => RestartResponse::RestartFailed("failed".into()),
+                };
+                for response in [RestartResponse::RestartStarted(restart_id), outcome] {
+                    let _ = rpc.send_message(RestartMessage::Response(response)).await;
+                }
+            }
+        });
+        requested
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rolling_restart() {
+        let dir = std::env::temp_dir().join(format!("shellflip-rolling-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let instances = [("a", Some(1)), ("b", None), ("c", Some(3))];
+        let requested: Vec<_> = instances
+            .iter()
+            .map(|(name, pid)| fake_instance(&dir.join(format!("{name}.sock")), *pid))
+            .collect();
+        let clients = || {
+            instances.iter().map(|(name, _)| {
+                let client = AdminClient::new(dir.join(format!("{name}.sock")));
+                (Some(name.to_string()), client)
+            })
+        };
+
+        // The instances after the one that fails are skipped.
+        let report = restart_instances(clients(), &RollingRestartConfig::default()).await;
+        let results: Vec<_> = report
+            .instances
+            .iter()
+            .map(|i| i.result.as_ref().map(|r| r.as_ref().map(|o| o.pid).ok()))
+            .collect();
+        assert_eq!(results, [Some(Some(1)), Some(None), None]);
+        assert!(!report.is_success());
+        let failed: Vec<_> = report.failed().map(|i| i.instance.as_deref()).collect();
+        assert_eq!(failed, [Some("b")]);
+        assert!(requested[2].lock().unwrap().is_empty());
+
+        // Unless told to keep going, pausing between instances.
+        let config = RollingRestartConfig {
+            pause: Duration::from_secs(10),
+            keep_going: true,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let report = restart_instances(clients(), &config).await;
+        assert!(matches!(report.instances[2].result, Some(Ok(ref o)) if o.pid == 3));
+        assert_eq!(report.failed().count(), 1);
+        for (i, requested) in requested.iter().enumerate() {
+            let at = *requested.lock().unwrap().last().unwrap();
+            assert_eq!(at - start, Duration::from_secs(10) * i as u32);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_query_instances() {
+        let dir = std::env::temp_dir().join(format!("shellflip-admin-{}", process::id()));
+        DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        let socket = |instance: &str| discovery::InstanceSocket {
+            instance: Some(instance.into()),
+            path: dir.join(format!("web@{instance}.sock")),
+        };
+        // An instance that hangs, and one that is gone.
+        let _hung = UnixListener::bind(socket("hung").path).unwrap();
+        drop(UnixListener::bind(socket("gone").path).unwrap());
+
+        let start = tokio::time::Instant::now();
+        let found = query_instances(vec![socket("gone"), socket("hung")])
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), STATUS_TIMEOUT);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].instance.as_deref(), Some("hung"));
+        assert!(
+            matches!(&found[0].status, Err(Error::Io(e)) if e.kind() == io::ErrorKind::TimedOut)
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
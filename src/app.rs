@@ -0,0 +1,488 @@
+//! Runs an application with graceful restarts, without the usual boilerplate.
+//!
+//! `run` starts the restart task, receives state from the old process and runs the main function
+//! of the application alongside the restart task. Once a restart completes, the main function is
+//! dropped, which stops accepting new work, and `run` waits for the tasks holding shutdown handles
+//! to complete. With `AfterHandover::Standby`, it then waits until the process is terminated.
+//!
+//! With the `macros` feature, the `#[shellflip::main]` attribute does all of this for an async main
+//! function, which takes a `Context`:
+//!
+//! ```ignore
+//! #[shellflip::main(socket = "/tmp/app.sock")]
+//! async fn main(mut ctx: shellflip::app::Context) -> anyhow::Result<()> {
+//!     let listener = shellflip::listeners::listener_or_bind("http", "0.0.0.0:8080")?;
+//!     ctx.ready();
+//!     // Accept connections, spawning a task for each with `ctx.shutdown.spawn`.
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The restart coordination socket is taken from the `SHELLFLIP_SOCKET` environment variable, or
+//! the `socket` argument. Pass `service = "name"` instead of `socket` to serve it at the default
+//! path of the service, see `discovery`, where `shellflip-admin --service name` finds it. When
+//! running several instances of the service, also pass `instance = expr`, whose value, e.g. an ID
+//! or port number, is formatted into the name of the instance. Pass `config = some_fn` to start
+//! from the `RestartConfig` returned by `some_fn` instead of the default one, e.g. to set a
+//! lifecycle handler.
+use crate::diagnostics;
+use crate::discovery;
+use crate::fds::{self, FdManifest};
+use crate::lifecycle::{receive_from_old_process, PipeReader};
+use crate::restart_state::{CancelRequest, SharedRestartState};
+use crate::{
+    generation, restart_id, standby, AfterHandover, Error, RestartConfig, RestartId, RestartResult,
+    ShutdownCoordinator,
+};
+use futures::FutureExt;
+use std::any::Any;
+use std::env;
+use std::fs::remove_file;
+use std::future::Future;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::oneshot;
+
+/// The environment variable that sets the path of the restart coordination socket.
+pub const ENV_SOCKET: &str = "SHELLFLIP_SOCKET";
+
+/// How long a panicking main function waits for the restart in progress to be aborted.
+const ABORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What `run` reports when the main function panics, and the restart task when a restart panics.
+#[derive(Clone, Debug)]
+pub struct MainPanic {
+    /// The message the main function panicked with.
+    pub message: String,
+    /// The restart that was in progress and has been aborted, killing the new process.
+    pub aborted_restart: Option<RestartId>,
+}
+
+/// Told by `run` that the main function panicked, e.g. to raise an alert, before the panic
+/// continues to unwind, or by the restart task that a restart panicked and failed. Set it as
+/// `RestartConfig::panic_hook`. Implemented for closures taking a `MainPanic`.
+pub trait PanicHook: Send + Sync {
+    fn main_panicked(&self, panic: &MainPanic);
+}
+
+impl<F: Fn(&MainPanic) + Send + Sync> PanicHook for F {
+    fn main_panicked(&self, panic: &MainPanic) {
+        self(panic)
+    }
+}
+
+/// What the main function of the application is given by `run`.
+pub struct Context {
+    /// Take shutdown handles from this, or spawn tasks with it, so that they are waited on after a
+    /// restart. Don't move it into spawned tasks, as shutdown needs it back once the main
+    /// function is dropped.
+    pub shutdown: Arc<ShutdownCoordinator>,
+    /// See `shellflip::generation`.
+    pub generation: u32,
+    /// See `shellflip::restart_id`.
+    pub restart_id: Option<RestartId>,
+    /// State sent by the old process, see `lifecycle::receive_from_old_process`.
+    pub handover: Option<PipeReader>,
+    /// The fds inherited from the old process, if it sent a manifest, see `fds::fd_manifest`.
+    pub fd_manifest: Option<&'static FdManifest>,
+    ready: Option<oneshot::Sender<()>>,
+}
+
+impl Context {
+    /// Signal readiness, after which the old process exits and this process handles restarts.
+    /// Call this once state received from the old process has been validated and the process is
+    /// able to serve.
+    pub fn ready(&mut self) {
+        if let Some(ready) = self.ready.take() {
+            let _ = ready.send(());
+        }
+    }
+}
+
+/// Enables the restart coordination socket in `config`, at the path in `ENV_SOCKET` or else at
+/// `socket`. If neither is set, `config` is left as it is.
+pub fn configure(mut config: RestartConfig, socket: Option<&str>) -> RestartConfig {
+    let path = env::var_os(ENV_SOCKET)
+        .map(PathBuf::from)
+        .or(socket.map(PathBuf::from));
+    if let Some(path) = path {
+        config.enabled = true;
+        config.coordination_socket_path = path;
+    }
+    config
+}
+
+/// Enables the restart coordination socket in `config`, at the path of `service` from
+/// `discovery::socket_path`, and creates the directory it is bound in. If the name is invalid, or
+/// the directory fails `discovery::check_socket_dir`, the socket is left disabled.
+pub fn configure_service(config: RestartConfig, service: &str) -> RestartConfig {
+    configure_path(config, discovery::socket_path(service))
+}
+
+/// Enables the restart coordination socket in `config`, at the path of `instance` of `service`
+/// from `discovery::instance_socket_path`, and creates the directory it is bound in, as
+/// `configure_service` does.
+pub fn configure_instance(config: RestartConfig, service: &str, instance: &str) -> RestartConfig {
+    configure_path(config, discovery::instance_socket_path(service, instance))
+}
+
+fn configure_path(mut config: RestartConfig, path: io::Result<PathBuf>) -> RestartConfig {
+    let path = match path {
+        Ok(path) => path,
+        Err(e) => {
+            diagnostics::warn!("Not serving the restart coordination socket: {}", e);
+            return config;
+        }
+    };
+    if let Err(e) = discovery::create_socket_dir(&path) {
+        diagnostics::warn!(
+            "Not serving the restart coordination socket at {}: {}",
+            path.display(),
+            e
+        );
+        return config;
+    }
+    config.enabled = true;
+    config.coordination_socket_path = path;
+    config
+}
+
+/// Runs `main` with graceful restarts. See the module documentation.
+///
+/// Returns once `main` returns, or once a restart completed and the tasks holding shutdown handles
+/// have completed. Returns right away, without running `main`, if the process was only started to
+/// report its handshake, see `RestartConfig::handshake`. If the restart task fails, the error is
+/// returned after shutting down.
+///
+/// If `main` panics, the restart in progress, if any, is aborted, the restart coordination socket
+/// is removed unless a new process may still take it over, and `RestartConfig::panic_hook` is
+/// called before the panic continues to unwind, without waiting for the tasks holding shutdown
+/// handles.
+pub async fn run<F, Fut, E>(config: RestartConfig, main: F) -> Result<(), E>
+where
+    F: FnOnce(Context) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: From<Error>,
+{
+    let after_handover = config.after_handover;
+    let socket = config
+        .enabled
+        .then(|| config.coordination_socket_path.clone());
+    let panic_hook = config.panic_hook.clone();
+    let restart_task = match config.try_into_restart_task() {
+        Err(Error::HandshakeReported) => return Ok(()),
+        res => res?,
+    };
+    let state = crate::restart_state();
+    let shutdown = Arc::new(ShutdownCoordinator::new());
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let ctx = Context {
+        shutdown: Arc::clone(&shutdown),
+        generation: generation(),
+        restart_id: restart_id(),
+        // This must come before receiving the state that follows the manifest.
+        fd_manifest: fds::fd_manifest(),
+        handover: receive_from_old_process(),
+        ready: Some(ready_tx),
+    };
+
+    let mut main = Box::pin(AssertUnwindSafe(main(ctx)).catch_unwind());
+    let mut restart = Box::pin(async move {
+        // Polling the restart task signals readiness.
+        if ready_rx.await.is_err() {
+            futures::future::pending::<()>().await;
+        }
+        restart_task.await
+    });
+    let mut handed_over = false;
+    let res = select! {
+        res = &mut main => res,
+        res = &mut restart => Ok(match res {
+            Ok(child) => {
+                diagnostics::info!("Restart complete, new process is {}, draining", child.id());
+                handed_over = true;
+                Ok(())
+            }
+            Err(Error::RestartedExisting(outcome)) => {
+                diagnostics::info!(
+                    "Restarted the running instance, new process is {}",
+                    outcome.pid
+                );
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }),
+    };
+    drop(main);
+    let res = match res {
+        Ok(res) => res,
+        Err(panic) => panicked(restart, state, socket, panic_hook, panic).await,
+    };
+
+    match Arc::try_unwrap(shutdown) {
+        Ok(shutdown) => shutdown.shutdown().await,
+        Err(_) => {
+            diagnostics::warn!(
+                "Not waiting for tasks to complete, as the shutdown coordinator is in use"
+            )
+        }
+    }
+    if handed_over && after_handover == AfterHandover::Standby {
+        standby().await.map_err(Error::from)?;
+    }
+    res
+}
+
+/// Aborts the restart in progress once the main function has panicked, removes the restart
+/// coordination socket unless a new process has taken or may still take it over, reports the
+/// panic to `hook` and resumes unwinding.
+async fn panicked(
+    mut restart: Pin<Box<impl Future<Output = RestartResult<process::Child>>>>,
+    state: Option<SharedRestartState>,
+    socket: Option<PathBuf>,
+    hook: Option<Arc<dyn PanicHook>>,
+    panic: Box<dyn Any + Send>,
+) -> ! {
+    let message = panic_message(&*panic);
+    diagnostics::error!("The main function panicked: {}", message);
+    let in_progress = state.as_ref().and_then(SharedRestartState::in_progress);
+    let mut aborted_restart = None;
+    let mut settled = true;
+    if let (Some(state), Some(in_progress)) = (state, in_progress) {
+        match state.cancel(Some(&in_progress.restart_id)) {
+            CancelRequest::Cancelling(id) => {
+                diagnostics::warn!("Aborting restart {}", id);
+                aborted_restart = Some(id);
+            }
+            CancelRequest::TooLate(id) => {
+                diagnostics::warn!("Restart {} can no longer be aborted, completing it", id);
+            }
+            CancelRequest::NotInProgress => {}
+        }
+        // The restart task reaps the new process and answers the client that requested the
+        // restart.
+        let mut status = state.subscribe();
+        select! {
+            _ = &mut restart => {}
+            _ = status.wait_for(|s| s.in_progress.is_none()) => {}
+            _ = tokio::time::sleep(ABORT_TIMEOUT) => {
+                diagnostics::warn!("Restart still in progress after {:?}", ABORT_TIMEOUT);
+            }
+        }
+        // Unless the restart settled without handing over, the new process may still take over
+        // the socket.
+        let status = status.borrow();
+        settled = status.in_progress.is_none()
+            && status.last_result.as_ref().is_some_and(|completed| {
+                completed.restart_id == in_progress.restart_id && completed.result.is_err()
+            });
+    }
+    drop(restart);
+    if let Some(path) = socket.filter(|_| settled) {
+        // The restart task no longer serves the socket, so clients shouldn't find it.
+        let _ = remove_file(path);
+    }
+    if let Some(hook) = hook {
+        hook.main_panicked(&MainPanic {
+            message,
+            aborted_restart,
+        });
+    }
+    panic::resume_unwind(panic)
+}
+
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic payload".into(),
+    }
+}
+
+/// Runs `future` to completion on a new multi-threaded tokio runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build the tokio runtime")
+        .block_on(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_process::InProcessSocket;
+    use crate::lifecycle::{LifecycleHandler, PipeWriter};
+    use crate::monitor::PidFd;
+    use crate::restart_coordination_socket::ChildIdentity;
+    use crate::RestartOptions;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_run_waits_for_tasks() {
+        let (done_tx, done_rx) = oneshot::channel();
+        let res: Result<(), Error> = run(RestartConfig::default(), |mut ctx| async move {
+            assert!(ctx.handover.is_none());
+            ctx.ready();
+            ctx.shutdown.spawn(async move {
+                tokio::task::yield_now().await;
+                done_tx.send(()).unwrap();
+            });
+            Ok(())
+        })
+        .await;
+        res.unwrap();
+        done_rx.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_panic() {
+        let reported = Arc::new(std::sync::Mutex::new(None));
+        let hook_reported = Arc::clone(&reported);
+        let config = RestartConfig {
+            panic_hook: Some(Arc::new(move |panic: &MainPanic| {
+                *hook_reported.lock().unwrap() = Some(panic.clone());
+            })),
+            ..Default::default()
+        };
+        let run = run(config, |_| async {
+            tokio::task::yield_now().await;
+            if true {
+                panic!("main failed");
+            }
+            Ok::<_, Error>(())
+        });
+        let panic = AssertUnwindSafe(run).catch_unwind().await.unwrap_err();
+        assert_eq!(panic_message(&*panic), "main failed");
+        let reported = reported.lock().unwrap().take().unwrap();
+        assert_eq!(reported.message, "main failed");
+        assert!(reported.aborted_restart.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_restart_panic() {
+        struct Panicking(Arc<Mutex<Option<u32>>>);
+
+        #[async_trait]
+        impl LifecycleHandler for Panicking {
+            async fn new_process_spawned(&mut self, child: &ChildIdentity, _: Option<&PidFd>) {
+                *self.0.lock().unwrap() = Some(child.pid);
+                panic!("handler failed");
+            }
+        }
+
+        let spawned = Arc::new(Mutex::new(None));
+        let reported = Arc::new(Mutex::new(None));
+        let hook_reported = Arc::clone(&reported);
+        let socket = InProcessSocket::new();
+        let config = RestartConfig {
+            enabled: true,
+            coordination_socket_path: "/nonexistent/shellflip.sock".into(),
+            in_process: Some(socket.clone()),
+            lifecycle_handler: Box::new(Panicking(Arc::clone(&spawned))),
+            // The new process runs this test binary, which this makes exit before running tests.
+            environment: vec![("RUST_TEST_THREADS".into(), "none".into())],
+            panic_hook: Some(Arc::new(move |panic: &MainPanic| {
+                *hook_reported.lock().unwrap() = Some(panic.clone());
+            })),
+            ..Default::default()
+        };
+        let task = tokio::spawn(config.try_into_restart_task().unwrap());
+
+        let client = || RestartConfig {
+            enabled: true,
+            in_process: Some(socket.clone()),
+            ..Default::default()
+        };
+        let restart_id = RestartId::from("panicking");
+        let options = RestartOptions {
+            restart_id: Some(restart_id.clone()),
+            ..Default::default()
+        };
+        assert!(client().request_restart_with(options).await.is_err());
+
+        // The new process has been killed and reaped.
+        let pid = spawned.lock().unwrap().unwrap();
+        assert_eq!(unsafe { libc::kill(pid as libc::pid_t, 0) }, -1);
+        let reported = reported.lock().unwrap().take().unwrap();
+        assert_eq!(reported.message, "handler failed");
+        assert_eq!(reported.aborted_restart.as_ref(), Some(&restart_id));
+
+        assert!(matches!(
+            task.await.unwrap(),
+            Err(Error::Panicked { restart_id: id, .. }) if id == restart_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stateless_restart() {
+        #[derive(Default)]
+        struct Events {
+            spawned: bool,
+            sent_state: bool,
+        }
+
+        struct Recording(Arc<Mutex<Events>>);
+
+        #[async_trait]
+        impl LifecycleHandler for Recording {
+            async fn send_to_new_process(&mut self, _: PipeWriter) -> io::Result<()> {
+                self.0.lock().unwrap().sent_state = true;
+                Ok(())
+            }
+
+            async fn new_process_spawned(&mut self, _: &ChildIdentity, _: Option<&PidFd>) {
+                self.0.lock().unwrap().spawned = true;
+            }
+        }
+
+        let events = Arc::new(Mutex::new(Events::default()));
+        let socket = InProcessSocket::new();
+        let config = RestartConfig {
+            enabled: true,
+            coordination_socket_path: "/nonexistent/shellflip.sock".into(),
+            in_process: Some(socket.clone()),
+            lifecycle_handler: Box::new(Recording(Arc::clone(&events))),
+            // The new process runs this test binary, which this makes exit before running tests.
+            environment: vec![("RUST_TEST_THREADS".into(), "none".into())],
+            ..Default::default()
+        };
+        let _task = tokio::spawn(config.try_into_restart_task().unwrap());
+
+        let client = RestartConfig {
+            enabled: true,
+            in_process: Some(socket.clone()),
+            ..Default::default()
+        };
+        let options = RestartOptions {
+            stateless: true,
+            ..Default::default()
+        };
+        // The new process exits without signalling readiness.
+        assert!(client.request_restart_with(options).await.is_err());
+        let events = events.lock().unwrap();
+        assert!(events.spawned);
+        assert!(!events.sent_state);
+    }
+
+    #[test]
+    fn test_configure() {
+        let config = configure(RestartConfig::default(), None);
+        if env::var_os(ENV_SOCKET).is_none() {
+            assert!(!config.enabled);
+            let config = configure(RestartConfig::default(), Some("/tmp/app.sock"));
+            assert!(config.enabled);
+            assert_eq!(
+                config.coordination_socket_path,
+                PathBuf::from("/tmp/app.sock")
+            );
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! A record of who sent each request to the restart coordination socket.
+//!
+//! Every request is logged at info level with the `shellflip::audit` target, with the pid, uid and
+//! gid of the client as structured fields, and passed to `RestartConfig::audit_hook` if set, so
+//! that restarts in production can be attributed to a person or deployment job. The client that
+//! requested the restart in progress is also part of the restart status.
+use crate::diagnostics;
+use crate::restart_coordination_socket::{PeerCredentials, RestartRequest};
+
+/// A request received on the restart coordination socket.
+#[derive(Clone, Copy, Debug)]
+pub struct ControlRequest<'a> {
+    /// The client, if the kernel reported its credentials.
+    pub peer: Option<PeerCredentials>,
+    pub request: &'a RestartRequest,
+}
+
+/// Receives every request to the restart coordination socket before it is handled. Implemented
+/// for closures taking a `ControlRequest`.
+pub trait AuditHook: Send + Sync {
+    fn control_request(&self, request: &ControlRequest<'_>);
+}
+
+impl<F: Fn(&ControlRequest<'_>) + Send + Sync> AuditHook for F {
+    fn control_request(&self, request: &ControlRequest<'_>) {
+        self(request)
+    }
+}
+
+/// A short description of `request` for the audit log.
+pub fn describe(request: &RestartRequest) -> String {
+    match request {
+        RestartRequest::TryRestart | RestartRequest::TryRestartWith(_) => "restart".into(),
+        RestartRequest::TryRestartWithFds { fd_names, .. } => {
+            format!("restart with fds {}", fd_names.join(","))
+        }
+        RestartRequest::Status => "status".into(),
+        RestartRequest::WaitForRestart => "wait".into(),
+        RestartRequest::CancelRestart(_) => "cancel".into(),
+        RestartRequest::CommitRestart(_) => "commit".into(),
+        RestartRequest::Command(command) => format!("{command} command"),
+        RestartRequest::Rollback(_) => "rollback".into(),
+        RestartRequest::Subscribe => "subscribe".into(),
+    }
+}
+
+pub(crate) fn record(
+    hook: Option<&dyn AuditHook>,
+    peer: Option<PeerCredentials>,
+    request: &RestartRequest,
+) {
+    let description = describe(request);
+    match peer {
+        Some(peer) => {
+            let pid = peer.pid.map(|pid| pid.to_string()).unwrap_or_default();
+            diagnostics::info!(
+                peer_pid = pid, uid = peer.uid, gid = peer.gid;
+                "Control request: {} from {}",
+                description,
+                peer
+            );
+        }
+        None => diagnostics::info!("Control request: {} from unknown peer", description),
+    }
+    if let Some(hook) = hook {
+        hook.control_request(&ControlRequest { peer, request });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restart_coordination_socket::{AdminCommand, RestartCoordinationSocket};
+    use std::sync::Mutex;
+    use tokio::net::UnixStream;
+
+    #[tokio::test]
+    async fn test_record() {
+        let (client, _server) = UnixStream::pair().unwrap();
+        let peer = RestartCoordinationSocket::new(client)
+            .peer_credentials()
+            .unwrap();
+        assert_eq!(peer.pid, Some(std::process::id()));
+
+        let seen = Mutex::new(Vec::new());
+        let hook = |r: &ControlRequest<'_>| {
+            seen.lock().unwrap().push((r.peer, describe(r.request)));
+        };
+        let request = RestartRequest::Command(AdminCommand::Reload);
+        record(Some(&hook), Some(peer), &request);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [(Some(peer), "reload command".to_string())]
+        );
+    }
+}
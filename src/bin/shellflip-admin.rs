@@ -0,0 +1,324 @@
+//! Manage any process that uses shellflip through its restart coordination socket.
+use clap::{Parser, Subcommand};
+use futures::{pin_mut, TryStreamExt};
+use shellflip::admin::{list_instances, rolling_restart, AdminClient, RollingRestartConfig};
+use shellflip::app::ENV_SOCKET;
+use shellflip::{RestartOptions, RestartResult};
+use std::env;
+use std::io;
+use std::os::fd::{BorrowedFd, RawFd};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Restart coordination socket path
+    #[arg(short, long, conflicts_with = "service")]
+    socket: Option<PathBuf>,
+    /// Find the socket of this service at its default path, which `SHELLFLIP_SOCKET` overrides
+    #[arg(long)]
+    service: Option<String>,
+    /// With --service, the instance of the service to manage
+    #[arg(long, requires = "service")]
+    instance: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(flatten)]
+    Process(ProcessCommand),
+    #[command(flatten)]
+    Service(ServiceCommand),
+}
+
+/// Commands on the process serving a socket.
+#[derive(Subcommand)]
+enum ProcessCommand {
+    /// Restart the running process
+    Restart {
+        /// Restart ID to use, for correlating logs
+        #[arg(long)]
+        id: Option<String>,
+        /// Wait for any restart in progress, then restart again
+        #[arg(long)]
+        queue: bool,
+        /// Show the output of the new process until it is ready
+        #[arg(long)]
+        relay_output: bool,
+        /// Keep watching the new process for this many seconds after the restart
+        #[arg(long)]
+        monitor_secs: Option<u64>,
+        /// Keep the old process serving until the restart is committed, aborting it if that
+        /// doesn't happen within this many seconds
+        #[arg(long)]
+        await_commit_secs: Option<u64>,
+        /// Don't hand state over to the new process, which starts with fresh state
+        #[arg(long)]
+        stateless: bool,
+        /// Pass fd FD of this command to the new process under NAME, e.g. `--fd config=3 3<file`
+        #[arg(long, value_name = "NAME=FD", value_parser = parse_fd)]
+        fd: Vec<(String, RawFd)>,
+    },
+    /// Ask the running process to shut down
+    Shutdown,
+    /// Ask the running process to reload its configuration
+    Reload,
+    /// Ask the running process to reopen its log files, e.g. after rotating them
+    ReopenLogs,
+    /// Print the restart status of the running process as JSON
+    Status,
+    /// Print the restart status, then lifecycle events as they happen, as JSON lines
+    Events,
+    /// Wait for the restart in progress to complete
+    Wait,
+    /// Cancel the restart in progress
+    Cancel {
+        /// Only cancel the restart with this ID
+        #[arg(long)]
+        id: Option<String>,
+    },
+    /// Commit the restart in progress, once the new process is ready
+    Commit {
+        /// Only commit the restart with this ID
+        #[arg(long)]
+        id: Option<String>,
+    },
+    /// Restart the running process back into its previous binary slot
+    Rollback {
+        /// Restart ID to use, for correlating logs
+        #[arg(long)]
+        id: Option<String>,
+    },
+}
+
+/// Commands on every instance of the service given with --service.
+#[derive(Subcommand)]
+enum ServiceCommand {
+    /// Restart every running instance of the service given with --service, one after another,
+    /// and print the outcome for each as JSON lines
+    RestartAll {
+        /// Restart ID to use for each instance, for correlating logs
+        #[arg(long)]
+        id: Option<String>,
+        /// Wait for any restart in progress, then restart again
+        #[arg(long)]
+        queue: bool,
+        /// Wait this many seconds between the restarts of two instances
+        #[arg(long, default_value_t = 0)]
+        pause_secs: u64,
+        /// Carry on with the remaining instances when one fails to restart
+        #[arg(long)]
+        keep_going: bool,
+    },
+    /// Print the running instances of the service given with --service and their status, as JSON
+    /// lines
+    List,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    let res = match args.command {
+        Command::Service(command) => match args.service {
+            Some(service) => run_for_service(&service, command).await,
+            None => {
+                eprintln!("shellflip-admin: this command needs --service");
+                return ExitCode::FAILURE;
+            }
+        },
+        Command::Process(command) => match client(args.socket, args.service, args.instance) {
+            Ok(Some(client)) => run(client, command).await.map_err(|e| e.to_string()),
+            Ok(None) => {
+                eprintln!("shellflip-admin: pass --socket or --service, or set {ENV_SOCKET}");
+                return ExitCode::FAILURE;
+            }
+            Err(e) => Err(e.to_string()),
+        },
+    };
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("shellflip-admin: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn client(
+    socket: Option<PathBuf>,
+    service: Option<String>,
+    instance: Option<String>,
+) -> io::Result<Option<AdminClient>> {
+    Ok(match (socket, service, instance) {
+        (Some(socket), _, _) => Some(AdminClient::new(socket)),
+        (None, Some(service), Some(instance)) => {
+            Some(AdminClient::for_instance(&service, &instance)?)
+        }
+        (None, Some(service), None) => Some(AdminClient::for_service(&service)?),
+        (None, None, _) => env::var_os(ENV_SOCKET).map(AdminClient::new),
+    })
+}
+
+fn parse_fd(arg: &str) -> Result<(String, RawFd), String> {
+    let (name, fd) = arg.split_once('=').ok_or("expected NAME=FD")?;
+    let fd = fd.parse().map_err(|e| format!("invalid fd {fd:?}: {e}"))?;
+    Ok((name.to_string(), fd))
+}
+
+async fn run_for_service(service: &str, command: ServiceCommand) -> Result<(), String> {
+    match command {
+        ServiceCommand::List => {
+            for instance in list_instances(service).await.map_err(|e| e.to_string())? {
+                let line = match instance.status {
+                    Ok(status) => serde_json::json!({
+                        "instance": instance.instance,
+                        "socket": instance.client.socket_path(),
+                        "status": status,
+                    }),
+                    Err(e) => serde_json::json!({
+                        "instance": instance.instance,
+                        "socket": instance.client.socket_path(),
+                        "error": e.to_string(),
+                    }),
+                };
+                println!("{line}");
+            }
+        }
+        ServiceCommand::RestartAll {
+            id,
+            queue,
+            pause_secs,
+            keep_going,
+        } => {
+            let config = RollingRestartConfig {
+                options: RestartOptions {
+                    restart_id: id.map(Into::into),
+                    queue,
+                    ..Default::default()
+                },
+                pause: Duration::from_secs(pause_secs),
+                keep_going,
+            };
+            let report = rolling_restart(service, &config)
+                .await
+                .map_err(|e| e.to_string())?;
+            for instance in &report.instances {
+                let outcome = match &instance.result {
+                    Some(Ok(outcome)) => serde_json::json!({
+                        "restart_id": outcome.restart_id.to_string(),
+                        "pid": outcome.pid,
+                    }),
+                    Some(Err(e)) => serde_json::json!({ "error": e.to_string() }),
+                    None => serde_json::json!({ "skipped": true }),
+                };
+                let line = serde_json::json!({
+                    "instance": instance.instance,
+                    "socket": instance.client.socket_path(),
+                    "outcome": outcome,
+                });
+                println!("{line}");
+            }
+            if report.instances.is_empty() {
+                return Err(format!("no running instances of {service}"));
+            }
+            let failed = report.failed().count();
+            if failed > 0 {
+                return Err(format!(
+                    "{failed} of {} instances failed to restart",
+                    report.instances.len()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run(client: AdminClient, command: ProcessCommand) -> RestartResult<()> {
+    match command {
+        ProcessCommand::Restart {
+            id,
+            queue,
+            relay_output,
+            monitor_secs,
+            await_commit_secs,
+            stateless,
+            fd,
+        } => {
+            let options = RestartOptions {
+                restart_id: id.map(Into::into),
+                queue,
+                relay_output,
+                monitor_for: monitor_secs.map(Duration::from_secs),
+                await_commit: await_commit_secs.map(Duration::from_secs),
+                stateless,
+            };
+            let client = client.on_child_output(|line| eprintln!("{line}"));
+            let outcome = match fd.is_empty() {
+                true => client.restart(options).await?,
+                false => {
+                    let fds = fd
+                        .into_iter()
+                        .map(|(name, fd)| {
+                            let fd = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+                            Ok((name, fd))
+                        })
+                        .collect::<io::Result<_>>()?;
+                    client.restart_with_fds(options, fds).await?
+                }
+            };
+            println!(
+                "restart {} complete, new pid {}",
+                outcome.restart_id, outcome.pid
+            );
+        }
+        ProcessCommand::Shutdown => client.shutdown().await?,
+        ProcessCommand::Reload => client.reload().await?,
+        ProcessCommand::ReopenLogs => client.reopen_logs().await?,
+        ProcessCommand::Status => {
+            let status = client.status().await?;
+            println!("{}", serde_json::to_string_pretty(&status).unwrap());
+        }
+        ProcessCommand::Events => {
+            let (status, events) = client.subscribe().await?;
+            println!("{}", serde_json::to_string(&status).unwrap());
+            pin_mut!(events);
+            while let Some(event) = events.try_next().await? {
+                println!("{}", serde_json::to_string(&event).unwrap());
+            }
+        }
+        ProcessCommand::Wait => match client.wait_for_restart().await? {
+            Some(outcome) => println!(
+                "restart {} complete, new pid {}",
+                outcome.restart_id, outcome.pid
+            ),
+            None => println!("no restart in progress"),
+        },
+        ProcessCommand::Cancel { id } => match client.cancel_restart(id.map(Into::into)).await? {
+            Some(id) => println!("restart {id} cancelled"),
+            None => println!("no restart in progress"),
+        },
+        ProcessCommand::Commit { id } => match client.commit_restart(id.map(Into::into)).await? {
+            Some(outcome) => println!(
+                "restart {} complete, new pid {}",
+                outcome.restart_id, outcome.pid
+            ),
+            None => println!("no restart in progress"),
+        },
+        ProcessCommand::Rollback { id } => {
+            let options = RestartOptions {
+                restart_id: id.map(Into::into),
+                ..Default::default()
+            };
+            let outcome = client.rollback(options).await?;
+            println!(
+                "rollback {} complete, new pid {}",
+                outcome.restart_id, outcome.pid
+            );
+        }
+    }
+    Ok(())
+}
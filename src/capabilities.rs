@@ -0,0 +1,569 @@
+//! Restarting services that rely on capabilities, from file capabilities, a setuid binary or the
+//! ambient set.
+//!
+//! Which capabilities survive exec depends on the binary being executed, see `capabilities(7)`:
+//!
+//! - A binary with file capabilities gets those in its permitted set, limited by the bounding set,
+//!   along with those in both the inheritable set of this process and the inheritable file
+//!   capabilities. Ambient capabilities are dropped.
+//! - A binary that runs as root, including one that is setuid root, gets the bounding set, unless
+//!   securebits say otherwise, which this module doesn't account for.
+//! - Any other binary only gets the ambient set. Ambient capabilities are dropped for setuid and
+//!   setgid binaries as well.
+//! - With `no_new_privs`, file capabilities and setuid are ignored.
+//!
+//! The ambient set of the new process is inherited from the thread that spawns it, so `Ambient`
+//! policies are applied in the new process before it executes the binary, and the new process is
+//! forked even with `SpawnMethod::PosixSpawn`. This process keeps its own capabilities. Before
+//! spawning, the capabilities the new process would have are worked out from the binary, the
+//! restart thread and the `Ambient` policy, and the restart is refused if any in
+//! `CapabilityPolicy::required` would be missing, e.g. because a new binary was installed without
+//! running `setcap`. The error is an `Error::Spawn` whose source wraps a `MissingCapabilities`.
+use crate::diagnostics;
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+macro_rules! capabilities {
+    ($($name:ident = $number:literal,)*) => {
+        impl Capability {
+            $(pub const $name: Capability = Capability($number);)*
+        }
+
+        const NAMES: &[(&str, Capability)] = &[
+            $((concat!("CAP_", stringify!($name)), Capability($number)),)*
+        ];
+    };
+}
+
+/// A Linux capability, e.g. `Capability::NET_BIND_SERVICE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Capability(u8);
+
+capabilities! {
+    CHOWN = 0,
+    DAC_OVERRIDE = 1,
+    DAC_READ_SEARCH = 2,
+    FOWNER = 3,
+    FSETID = 4,
+    KILL = 5,
+    SETGID = 6,
+    SETUID = 7,
+    SETPCAP = 8,
+    LINUX_IMMUTABLE = 9,
+    NET_BIND_SERVICE = 10,
+    NET_BROADCAST = 11,
+    NET_ADMIN = 12,
+    NET_RAW = 13,
+    IPC_LOCK = 14,
+    IPC_OWNER = 15,
+    SYS_MODULE = 16,
+    SYS_RAWIO = 17,
+    SYS_CHROOT = 18,
+    SYS_PTRACE = 19,
+    SYS_PACCT = 20,
+    SYS_ADMIN = 21,
+    SYS_BOOT = 22,
+    SYS_NICE = 23,
+    SYS_RESOURCE = 24,
+    SYS_TIME = 25,
+    SYS_TTY_CONFIG = 26,
+    MKNOD = 27,
+    LEASE = 28,
+    AUDIT_WRITE = 29,
+    AUDIT_CONTROL = 30,
+    SETFCAP = 31,
+    MAC_OVERRIDE = 32,
+    MAC_ADMIN = 33,
+    SYSLOG = 34,
+    WAKE_ALARM = 35,
+    BLOCK_SUSPEND = 36,
+    AUDIT_READ = 37,
+    PERFMON = 38,
+    BPF = 39,
+    CHECKPOINT_RESTORE = 40,
+}
+
+impl Capability {
+    /// The capability with the given number, for those newer than this crate.
+    pub fn new(number: u8) -> Option<Self> {
+        (number < 64).then_some(Capability(number))
+    }
+
+    pub fn number(self) -> u8 {
+        self.0
+    }
+
+    /// Whether the calling thread has this capability in its effective set.
+    pub fn is_effective(self) -> io::Result<bool> {
+        Ok(ThreadCaps::current()?.effective & self.bit() != 0)
+    }
+
+    fn bit(self) -> u64 {
+        1 << self.0
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match NAMES.iter().find(|(_, cap)| cap == self) {
+            Some((name, _)) => f.write_str(name),
+            None => write!(f, "capability {}", self.0),
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = io::Error;
+
+    /// Parses names such as `CAP_NET_BIND_SERVICE`, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|(_, cap)| *cap)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown capability {s:?}"),
+                )
+            })
+    }
+}
+
+/// What to do with the ambient capabilities of the new process.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Ambient {
+    /// Pass on the ambient set of the restart thread, which is that of this process unless it
+    /// changed since the restart thread was started.
+    #[default]
+    Keep,
+    /// Drop all ambient capabilities.
+    Clear,
+    /// Raise these capabilities in the ambient set, so that a binary without file capabilities
+    /// keeps them. They must be in the permitted set of this process, otherwise the restart fails.
+    Raise(Vec<Capability>),
+}
+
+/// How the capabilities of this process are passed on to the new process.
+#[derive(Clone, Debug, Default)]
+pub struct CapabilityPolicy {
+    pub ambient: Ambient,
+    /// Refuse to restart if the new process would lack any of these in its permitted set.
+    pub required: Vec<Capability>,
+}
+
+/// The capabilities that the new process would lack, which refused the restart.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "{} would start without {}",
+    binary.display(),
+    missing.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+)]
+pub struct MissingCapabilities {
+    pub binary: PathBuf,
+    pub missing: Vec<Capability>,
+}
+
+impl CapabilityPolicy {
+    /// Whether the ambient set is changed in the new process.
+    pub(crate) fn changes_ambient(&self) -> bool {
+        self.ambient != Ambient::Keep
+    }
+
+    /// Check that the new process would get the required capabilities when `program` is executed
+    /// with `environment` added to that of this process.
+    pub(crate) fn prepare(
+        &self,
+        program: &OsStr,
+        environment: &[(OsString, OsString)],
+    ) -> io::Result<()> {
+        if self.required.is_empty() && !self.changes_ambient() {
+            return Ok(());
+        }
+        let thread = ThreadCaps::current()?;
+        if let Ambient::Raise(caps) = &self.ambient {
+            let missing: Vec<_> = caps
+                .iter()
+                .filter(|cap| thread.permitted & cap.bit() == 0)
+                .map(ToString::to_string)
+                .collect();
+            if !missing.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "can't raise {} in the ambient set, as they are not permitted",
+                        missing.join(", ")
+                    ),
+                ));
+            }
+        }
+        if self.required.is_empty() {
+            return Ok(());
+        }
+
+        let path = environment
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "PATH")
+            .map(|(_, path)| path.clone())
+            .or_else(|| std::env::var_os("PATH"));
+        let binary = find_program(program, path.as_deref())?;
+        let permitted = permitted_after_exec(&self.with_ambient(thread), &Binary::read(&binary)?);
+        diagnostics::debug!(
+            "The new process will have the capabilities {}",
+            names(permitted)
+        );
+        let missing: Vec<_> = self
+            .required
+            .iter()
+            .copied()
+            .filter(|cap| permitted & cap.bit() == 0)
+            .collect();
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                MissingCapabilities { binary, missing },
+            )),
+        }
+    }
+
+    /// The capabilities of a thread that applied the ambient policy.
+    fn with_ambient(&self, thread: ThreadCaps) -> ThreadCaps {
+        match &self.ambient {
+            Ambient::Keep => thread,
+            Ambient::Clear => ThreadCaps {
+                ambient: 0,
+                ..thread
+            },
+            // Raising a capability in the ambient set makes it inheritable as well.
+            Ambient::Raise(caps) => {
+                let raised = caps.iter().fold(0, |set, cap| set | cap.bit());
+                ThreadCaps {
+                    inheritable: thread.inheritable | raised,
+                    ambient: thread.ambient | raised,
+                    ..thread
+                }
+            }
+        }
+    }
+
+    /// Apply the ambient policy to the calling thread. This runs in the new process between fork
+    /// and exec, so it only makes system calls.
+    pub(crate) fn apply_ambient(&self) -> io::Result<()> {
+        match &self.ambient {
+            Ambient::Keep => Ok(()),
+            Ambient::Clear => prctl_ambient(libc::PR_CAP_AMBIENT_CLEAR_ALL, 0),
+            Ambient::Raise(caps) => raise_ambient(caps),
+        }
+    }
+}
+
+/// The capability sets of the calling thread.
+#[derive(Clone, Copy, Debug, Default)]
+struct ThreadCaps {
+    effective: u64,
+    permitted: u64,
+    inheritable: u64,
+    bounding: u64,
+    ambient: u64,
+    no_new_privs: bool,
+    euid: u32,
+}
+
+impl ThreadCaps {
+    fn current() -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(
+            "/proc/thread-self/status",
+        )?))
+    }
+
+    fn parse(status: &str) -> Self {
+        let mut caps = ThreadCaps::default();
+        for line in status.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            let set = || u64::from_str_radix(value, 16).unwrap_or(0);
+            match key {
+                "CapEff" => caps.effective = set(),
+                "CapPrm" => caps.permitted = set(),
+                "CapInh" => caps.inheritable = set(),
+                "CapBnd" => caps.bounding = set(),
+                "CapAmb" => caps.ambient = set(),
+                "NoNewPrivs" => caps.no_new_privs = value == "1",
+                // Real, effective, saved and filesystem uid.
+                "Uid" => {
+                    let mut uids = value.split_whitespace().filter_map(|u| u.parse().ok());
+                    caps.euid = uids.nth(1).unwrap_or(u32::MAX);
+                }
+                _ => {}
+            }
+        }
+        caps
+    }
+}
+
+/// What about a binary affects the capabilities it runs with.
+#[derive(Clone, Copy, Debug, Default)]
+struct Binary {
+    /// The owner, if the binary is setuid.
+    setuid: Option<u32>,
+    setgid: bool,
+    /// Permitted and inheritable file capabilities.
+    file_caps: Option<(u64, u64)>,
+}
+
+impl Binary {
+    fn read(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mode = metadata.mode();
+        Ok(Binary {
+            setuid: (mode & libc::S_ISUID != 0).then_some(metadata.uid()),
+            setgid: mode & libc::S_ISGID != 0,
+            file_caps: read_file_caps(path)?,
+        })
+    }
+}
+
+/// The permitted set of a process that the calling thread spawns to execute `binary`.
+fn permitted_after_exec(thread: &ThreadCaps, binary: &Binary) -> u64 {
+    let binary = match thread.no_new_privs {
+        true => Binary::default(),
+        false => *binary,
+    };
+    let euid = binary.setuid.unwrap_or(thread.euid);
+    if euid == 0 {
+        return thread.inheritable | thread.bounding;
+    }
+    let privileged = binary.setuid.is_some() || binary.setgid || binary.file_caps.is_some();
+    let ambient = match privileged {
+        true => 0,
+        false => thread.ambient,
+    };
+    let (file_permitted, file_inheritable) = binary.file_caps.unwrap_or_default();
+    (thread.inheritable & file_inheritable) | (file_permitted & thread.bounding) | ambient
+}
+
+/// Reads the `security.capability` extended attribute, see `vfs_cap_data` in
+/// `linux/capability.h`.
+fn read_file_caps(path: &Path) -> io::Result<Option<(u64, u64)>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = [0u8; 24];
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            c"security.capability".as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+        )
+    };
+    if len < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+            _ => Err(e),
+        };
+    }
+    Ok(parse_file_caps(&buf[..len as usize]))
+}
+
+fn parse_file_caps(data: &[u8]) -> Option<(u64, u64)> {
+    let word = |i: usize| -> Option<u64> {
+        let bytes = data.get(i * 4..i * 4 + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()).into())
+    };
+    let version = word(0)? as u32 & 0xff00_0000;
+    match version {
+        // Version 1 has 32 bits.
+        0x0100_0000 => Some((word(1)?, word(2)?)),
+        // Version 3 adds the root uid of the user namespace, after the same fields as version 2.
+        0x0200_0000 | 0x0300_0000 => Some((word(1)? | word(3)? << 32, word(2)? | word(4)? << 32)),
+        _ => None,
+    }
+}
+
+fn raise_ambient(caps: &[Capability]) -> io::Result<()> {
+    // A capability must be inheritable to be raised in the ambient set.
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for cap in caps {
+        data[cap.0 as usize / 32].inheritable |= 1 << (cap.0 % 32);
+    }
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for cap in caps {
+        prctl_ambient(libc::PR_CAP_AMBIENT_RAISE, cap.0.into())?;
+    }
+    Ok(())
+}
+
+fn prctl_ambient(op: libc::c_int, cap: libc::c_ulong) -> io::Result<()> {
+    match unsafe { libc::prctl(libc::PR_CAP_AMBIENT, op, cap, 0, 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Finds `program` like `Command` does, searching `path` if it has no slash.
+fn find_program(program: &OsStr, path: Option<&OsStr>) -> io::Result<PathBuf> {
+    if program.as_bytes().contains(&b'/') {
+        return Ok(PathBuf::from(program));
+    }
+    path.iter()
+        .flat_map(std::env::split_paths)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "program not found in PATH"))
+}
+
+fn names(set: u64) -> String {
+    let names: Vec<_> = (0..64)
+        .filter(|n| set & (1 << n) != 0)
+        .map(|n| Capability(n).to_string())
+        .collect();
+    match names.is_empty() {
+        true => "none".into(),
+        false => names.join(", "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIND: u64 = 1 << 10;
+    const ADMIN: u64 = 1 << 12;
+
+    #[test]
+    fn test_permitted_after_exec() {
+        let thread = ThreadCaps {
+            bounding: !0,
+            ambient: ADMIN,
+            euid: 1000,
+            ..Default::default()
+        };
+        let plain = Binary::default();
+        assert_eq!(permitted_after_exec(&thread, &plain), ADMIN);
+
+        // File capabilities replace the ambient set.
+        let file_caps = Binary {
+            file_caps: Some((BIND, 0)),
+            ..Default::default()
+        };
+        assert_eq!(permitted_after_exec(&thread, &file_caps), BIND);
+        let no_new_privs = ThreadCaps {
+            no_new_privs: true,
+            ..thread
+        };
+        assert_eq!(permitted_after_exec(&no_new_privs, &file_caps), ADMIN);
+
+        let setuid_root = Binary {
+            setuid: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(permitted_after_exec(&thread, &setuid_root), !0);
+        let setuid_user = Binary {
+            setuid: Some(1001),
+            ..Default::default()
+        };
+        assert_eq!(permitted_after_exec(&thread, &setuid_user), 0);
+    }
+
+    #[test]
+    fn test_ambient_policy() {
+        let thread = ThreadCaps {
+            inheritable: BIND,
+            ambient: BIND,
+            ..Default::default()
+        };
+        let clear = CapabilityPolicy {
+            ambient: Ambient::Clear,
+            ..Default::default()
+        };
+        assert_eq!(clear.with_ambient(thread).ambient, 0);
+        let raise = CapabilityPolicy {
+            ambient: Ambient::Raise(vec![Capability::NET_ADMIN]),
+            ..Default::default()
+        };
+        let raised = raise.with_ambient(thread);
+        assert_eq!(
+            (raised.inheritable, raised.ambient),
+            (BIND | ADMIN, BIND | ADMIN)
+        );
+
+        // Capabilities beyond the last one the kernel knows are never permitted.
+        let unknown = CapabilityPolicy {
+            ambient: Ambient::Raise(vec![Capability::new(63).unwrap()]),
+            ..Default::default()
+        };
+        let err = unknown.prepare(OsStr::new("true"), &[]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_find_program() {
+        let path = OsStr::new("/nonexistent:/bin:/usr/bin");
+        assert!(find_program(OsStr::new("sh"), Some(path)).is_ok());
+        assert!(find_program(OsStr::new("sh"), Some(OsStr::new("/nonexistent"))).is_err());
+        assert!(find_program(OsStr::new("sh"), None).is_err());
+        let absolute = find_program(OsStr::new("/bin/sh"), None).unwrap();
+        assert_eq!(absolute, Path::new("/bin/sh"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut v2 = Vec::new();
+        for word in [0x0200_0001u32, BIND as u32, 0, 1, 0] {
+            v2.extend(word.to_le_bytes());
+        }
+        assert_eq!(parse_file_caps(&v2), Some((BIND | 1 << 32, 0)));
+        assert_eq!(parse_file_caps(&v2[..8]), None);
+
+        let status = "Name:\tapp\nUid:\t1000\t0\t0\t0\nCapInh:\t0000000000000000\n\
+                      CapEff:\t0000000000000400\nCapBnd:\t000001ffffffffff\n\
+                      CapAmb:\t0000000000001000\nNoNewPrivs:\t0\n";
+        let caps = ThreadCaps::parse(status);
+        assert_eq!(caps.euid, 0);
+        assert_eq!(caps.ambient, ADMIN);
+        assert_eq!(caps.effective, BIND);
+        assert_eq!(caps.bounding, (1 << 41) - 1);
+
+        assert_eq!(
+            "cap_net_bind_service".parse::<Capability>().unwrap(),
+            Capability::NET_BIND_SERVICE
+        );
+        assert_eq!(Capability::SYS_ADMIN.to_string(), "CAP_SYS_ADMIN");
+        assert_eq!(names(BIND | ADMIN), "CAP_NET_BIND_SERVICE, CAP_NET_ADMIN");
+    }
+}
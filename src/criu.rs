@@ -0,0 +1,446 @@
+//! Carrying process trees that the service can't modify across restarts with CRIU, e.g. sidecars
+//! that keep state in memory and have no handover of their own.
+//!
+//! Instead of serialising the state of such a process, the old process `checkpoint`s its tree,
+//! which dumps it to images with `criu dump` and stops it, and sends the resulting `Checkpoint`s
+//! to the new process with `write_to`. The new process reads them with `read_from` and `restore`s
+//! each tree, which becomes a child of the new process and is tracked by the `reaper` module, so
+//! that it is supervised like the new process's own helpers. Open connections survive if CRIU is
+//! told to preserve them, e.g. with `--tcp-established` in `CriuConfig::args`.
+//!
+//! ```no_run
+//! # use shellflip::criu::{self, Checkpoint, CriuConfig};
+//! # use shellflip::lifecycle::*;
+//! struct App {
+//!     criu: CriuConfig,
+//!     sidecar: u32,
+//!     checkpoint: Option<Checkpoint>,
+//! }
+//!
+//! #[async_trait::async_trait]
+//! impl LifecycleHandler for App {
+//!     async fn send_to_new_process(&mut self, mut write_pipe: PipeWriter) -> std::io::Result<()> {
+//!         let checkpoint = criu::checkpoint(&self.criu, "sidecar", self.sidecar).await?;
+//!         criu::write_to(std::slice::from_ref(&checkpoint), &mut write_pipe).await?;
+//!         self.checkpoint = Some(checkpoint);
+//!         Ok(())
+//!     }
+//!
+//!     async fn new_process_failed(&mut self) {
+//!         // Take the sidecar back, unless the new process restored it before failing.
+//!         if let Some(checkpoint) = self.checkpoint.take() {
+//!             if let Ok(pid) = criu::restore(&self.criu, &checkpoint).await {
+//!                 self.sidecar = pid;
+//!             }
+//!         }
+//!     }
+//! }
+//!
+//! # async fn child(config: CriuConfig) -> std::io::Result<()> {
+//! // In the new process:
+//! if let Some(mut pipe) = receive_from_old_process() {
+//!     for checkpoint in criu::read_from(&mut pipe).await? {
+//!         criu::restore(&config, &checkpoint).await?;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! CRIU restores processes under their original pids, so a tree is down from the checkpoint until
+//! it is restored, and can only be restored once. If the new process fails after restoring a tree,
+//! the restored processes are reparented like any other helper of a process that exited. CRIU
+//! needs `CAP_SYS_ADMIN`, or `CAP_CHECKPOINT_RESTORE` and `CAP_SYS_PTRACE` on recent kernels.
+use crate::diagnostics;
+use crate::handover::HandoverRecord;
+use crate::monitor::PidFd;
+use crate::reaper;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::process::Command;
+use tokio::time::{sleep, timeout, timeout_at, Instant};
+
+const SCHEMA_VERSION: u16 = 1;
+
+const TAG_CHECKPOINTS: u16 = 1;
+
+const TAG_CHECKPOINT_NAME: u16 = 1;
+const TAG_CHECKPOINT_PID: u16 = 2;
+const TAG_CHECKPOINT_IMAGES: u16 = 3;
+
+const DUMP_LOG: &str = "dump.log";
+const RESTORE_LOG: &str = "restore.log";
+const PID_FILE: &str = "restored.pid";
+/// The number of lines of the CRIU log included in errors.
+const LOG_TAIL_LINES: usize = 5;
+/// How long to wait for the processes of a checkpointed tree to be gone, so that their pids are
+/// free to be restored.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Settings for running CRIU.
+#[derive(Clone, Debug)]
+pub struct CriuConfig {
+    /// The `criu` binary, looked up in `PATH` if it is not a path.
+    pub criu: PathBuf,
+    /// The directory under which the images of each tree are written, in a subdirectory named
+    /// after the tree. It must be readable by the new process.
+    pub images_dir: PathBuf,
+    /// Extra arguments for both `criu dump` and `criu restore`, e.g. `--tcp-established` or
+    /// `--shell-job`, as most options must be given to both.
+    pub args: Vec<OsString>,
+    /// How long `criu dump` or `criu restore` may take before it is killed.
+    pub timeout: Duration,
+}
+
+impl Default for CriuConfig {
+    fn default() -> Self {
+        CriuConfig {
+            criu: "criu".into(),
+            images_dir: std::env::temp_dir().join("shellflip-criu"),
+            args: Vec::new(),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A process tree dumped by `checkpoint`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// The name the tree was checkpointed under, which it is tracked as once restored.
+    pub name: String,
+    /// The pid of the root of the tree, which it is restored with.
+    pub pid: u32,
+    /// The directory holding the images.
+    pub images: PathBuf,
+}
+
+/// Dump the process tree rooted at `pid` with `criu dump`, which stops it, and wait for its
+/// processes to be gone. `name` may not contain `/`. Any images from an earlier checkpoint under
+/// the same name are replaced. If `pid` is tracked by the `reaper` module, it stops being tracked
+/// and is reaped. Otherwise, if it is a child of this process, the caller must reap it, e.g. with
+/// `Child::wait`, before the tree can be restored under the same pid.
+pub async fn checkpoint(config: &CriuConfig, name: &str, pid: u32) -> io::Result<Checkpoint> {
+    if name.is_empty() || name.contains('/') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid checkpoint name {name:?}"),
+        ));
+    }
+    let images = config.images_dir.join(name);
+    match fs::remove_dir_all(&images) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => fs::create_dir_all(&images)?,
+    }
+    let tree = Tree::open(pid);
+    // Only reap the root if this process manages it. Other processes are reaped by their parents.
+    let reap = reaper::tracked().iter().any(|p| p.pid == pid && !p.adopted);
+    reaper::untrack(pid);
+    diagnostics::info!("Checkpointing {} ({}) with CRIU", name, pid);
+    let mut cmd = Command::new(&config.criu);
+    cmd.arg("dump")
+        .arg("--tree")
+        .arg(pid.to_string())
+        .arg("--images-dir")
+        .arg(&images)
+        .arg("--log-file")
+        .arg(DUMP_LOG);
+    run(config, cmd, &images.join(DUMP_LOG)).await?;
+    tree.wait_gone().await?;
+    if reap {
+        unsafe { libc::waitpid(pid as libc::pid_t, std::ptr::null_mut(), libc::WNOHANG) };
+    }
+    Ok(Checkpoint {
+        name: name.to_string(),
+        pid,
+        images,
+    })
+}
+
+/// Restore a process tree from `checkpoint` with `criu restore`. The root of the tree becomes a
+/// child of this process and is tracked by the `reaper` module under the checkpoint's name. Returns
+/// its pid. The images are removed once the tree is restored.
+pub async fn restore(config: &CriuConfig, checkpoint: &Checkpoint) -> io::Result<u32> {
+    let pid_file = checkpoint.images.join(PID_FILE);
+    match fs::remove_file(&pid_file) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    diagnostics::info!(
+        "Restoring {} ({}) with CRIU",
+        checkpoint.name,
+        checkpoint.pid
+    );
+    let mut cmd = Command::new(&config.criu);
+    // Restoring the tree as a sibling of criu makes it a child of this process, rather than of
+    // criu, which exits once the tree is running.
+    cmd.arg("restore")
+        .arg("--images-dir")
+        .arg(&checkpoint.images)
+        .arg("--log-file")
+        .arg(RESTORE_LOG)
+        .arg("--restore-detached")
+        .arg("--restore-sibling")
+        .arg("--pidfile")
+        .arg(&pid_file);
+    run(config, cmd, &checkpoint.images.join(RESTORE_LOG)).await?;
+    let pid = fs::read_to_string(&pid_file)?
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid CRIU pid file"))?;
+    reaper::track(&checkpoint.name, pid);
+    if let Err(e) = fs::remove_dir_all(&checkpoint.images) {
+        diagnostics::warn!(
+            "Failed to remove CRIU images {}: {}",
+            checkpoint.images.display(),
+            e
+        );
+    }
+    Ok(pid)
+}
+
+/// Send `checkpoints` to the new process, e.g. in `LifecycleHandler::send_to_new_process`.
+pub async fn write_to<W: AsyncWrite + Unpin + ?Sized>(
+    checkpoints: &[Checkpoint],
+    w: &mut W,
+) -> io::Result<()> {
+    let checkpoints: Vec<_> = checkpoints
+        .iter()
+        .map(|checkpoint| {
+            let mut r = HandoverRecord::new(SCHEMA_VERSION);
+            r.put_str(TAG_CHECKPOINT_NAME, &checkpoint.name)
+                .put_u64(TAG_CHECKPOINT_PID, checkpoint.pid.into())
+                .put_str(TAG_CHECKPOINT_IMAGES, &checkpoint.images.to_string_lossy());
+            r
+        })
+        .collect();
+    let mut record = HandoverRecord::new(SCHEMA_VERSION);
+    record.put_records(TAG_CHECKPOINTS, &checkpoints);
+    record.write_to(w).await
+}
+
+/// Receive the checkpoints sent by the old process with `write_to`.
+pub async fn read_from<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Vec<Checkpoint>> {
+    HandoverRecord::read_from(r)
+        .await?
+        .get_records(TAG_CHECKPOINTS)?
+        .iter()
+        .map(|r| {
+            let pid = r.get_u64(TAG_CHECKPOINT_PID)?.unwrap_or_default();
+            Ok(Checkpoint {
+                name: r.get_str(TAG_CHECKPOINT_NAME)?.unwrap_or_default().into(),
+                pid: pid.try_into().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid checkpoint pid")
+                })?,
+                images: r.get_str(TAG_CHECKPOINT_IMAGES)?.unwrap_or_default().into(),
+            })
+        })
+        .collect()
+}
+
+/// Run a CRIU command, failing with the end of its log if it fails.
+async fn run(config: &CriuConfig, mut cmd: Command, log: &Path) -> io::Result<()> {
+    cmd.args(&config.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let output = match timeout(config.timeout, cmd.output()).await {
+        Ok(output) => output.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to run {}: {}", config.criu.display(), e),
+            )
+        })?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "CRIU timed out")),
+    };
+    if output.status.success() {
+        return Ok(());
+    }
+    // CRIU logs to the log file rather than to stderr once it has opened it.
+    let log = fs::read_to_string(log).unwrap_or_default();
+    let log = match log.is_empty() {
+        true => String::from_utf8_lossy(&output.stderr).into_owned(),
+        false => log,
+    };
+    let lines: Vec<_> = log.lines().collect();
+    let tail = lines[lines.len().saturating_sub(LOG_TAIL_LINES)..].join("\n");
+    Err(io::Error::other(format!(
+        "CRIU {}: {}",
+        output.status, tail
+    )))
+}
+
+/// The pids of the process `pid` and its descendants.
+fn tree(pid: u32) -> Vec<u32> {
+    let mut pids = vec![pid];
+    let mut i = 0;
+    while i < pids.len() {
+        let tasks = fs::read_dir(format!("/proc/{}/task", pids[i]));
+        for task in tasks.into_iter().flatten().flatten() {
+            let children = fs::read_to_string(task.path().join("children")).unwrap_or_default();
+            pids.extend(
+                children
+                    .split_whitespace()
+                    .filter_map(|p| p.parse::<u32>().ok()),
+            );
+        }
+        i += 1;
+    }
+    pids
+}
+
+/// The processes of a tree being checkpointed, opened before the dump so that their exit can be
+/// awaited without reaping processes that belong to someone else.
+struct Tree(Vec<(u32, Option<PidFd>)>);
+
+impl Tree {
+    fn open(pid: u32) -> Self {
+        Tree(
+            tree(pid)
+                .into_iter()
+                .map(|pid| (pid, PidFd::open(pid).ok()))
+                .collect(),
+        )
+    }
+
+    /// Wait until the processes are gone, which includes zombies whose parents did not reap them
+    /// yet.
+    async fn wait_gone(&self) -> io::Result<()> {
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        for (pid, pidfd) in &self.0 {
+            let exited = async {
+                match pidfd {
+                    Some(pidfd) => pidfd.exited().await,
+                    None => {
+                        while !gone(*pid) {
+                            sleep(Duration::from_millis(10)).await;
+                        }
+                        Ok(())
+                    }
+                }
+            };
+            match timeout_at(deadline, exited).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("checkpointed process {pid} is still running"),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the process `pid` exited, i.e. it doesn't exist or is a zombie.
+fn gone(pid: u32) -> bool {
+    let Ok(stat) = fs::read_to_string(format!("/proc/{pid}/stat")) else {
+        return true;
+    };
+    // The state follows the command name, which is in parentheses and may contain anything.
+    let state = stat.rsplit_once(')').map(|(_, rest)| rest.trim_start());
+    matches!(state.and_then(|s| s.chars().next()), Some('Z' | 'X'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("shellflip-criu-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(dir: &Path) -> CriuConfig {
+        CriuConfig {
+            images_dir: dir.join("images"),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_checkpoints_record() {
+        let checkpoints = vec![
+            Checkpoint {
+                name: "a".into(),
+                pid: 10,
+                images: "/tmp/a".into(),
+            },
+            Checkpoint {
+                name: "b".into(),
+                pid: 20,
+                images: "/tmp/b".into(),
+            },
+        ];
+        let mut buf = Vec::new();
+        write_to(&checkpoints, &mut buf).await.unwrap();
+        assert_eq!(read_from(&mut buf.as_slice()).await.unwrap(), checkpoints);
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let dir = temp_dir("errors");
+        let mut config = config(&dir);
+        assert!(checkpoint(&config, "a/b", 1).await.is_err());
+
+        // The end of the log explains why CRIU failed.
+        let criu = dir.join("criu");
+        fs::write(
+            &criu,
+            "#!/bin/sh\necho 'Error (criu/cr-dump.c:1): no' > \"$5/$7\"\nexit 1\n",
+        )
+        .unwrap();
+        fs::set_permissions(&criu, fs::Permissions::from_mode(0o755)).unwrap();
+        config.criu = criu;
+        let err = checkpoint(&config, "sidecar", process::id())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("cr-dump.c:1"), "{err}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_restore() {
+        let dir = temp_dir("restore");
+        let mut config = config(&dir);
+        // The child shares the session of the test.
+        config.args.push("--shell-job".into());
+        // CRIU is rarely installed, and needs privileges that tests rarely have.
+        let check = Command::new(&config.criu).arg("check").output().await;
+        if !check.is_ok_and(|output| output.status.success()) {
+            fs::remove_dir_all(dir).unwrap();
+            return;
+        }
+        let mut child = process::Command::new("sleep")
+            .arg("1000")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let checkpoint = checkpoint(&config, "sleep", child.id()).await.unwrap();
+        // The child is left for its parent to reap, which frees its pid for the restore.
+        assert!(gone(child.id()));
+        child.wait().unwrap();
+        assert!(!Path::new(&format!("/proc/{}", child.id())).exists());
+        let pid = restore(&config, &checkpoint).await.unwrap();
+        assert_eq!(pid, child.id());
+        assert!(reaper::tracked().iter().any(|p| p.pid == pid));
+        // The restored process is a child of this process under the same pid.
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGKILL);
+            libc::waitpid(pid as libc::pid_t, std::ptr::null_mut(), 0);
+        }
+        reaper::untrack(pid);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
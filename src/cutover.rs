@@ -0,0 +1,526 @@
+//! Shifting new connections to the new process gradually, rather than all at once.
+//!
+//! The new process inherits the listeners of the old one, so once it is ready both processes accept
+//! from the same sockets, and each new connection goes to whichever process accepts it first.
+//! Normally the old process stops accepting as soon as the restart completes. With
+//! `RestartConfig::cutover_ramp`, it instead keeps accepting for a share of the time that decreases
+//! linearly from all of it to nothing over the ramp, so the new process takes a growing share of
+//! new connections while its error rate can be watched.
+//!
+//! This relies on both processes sharing the accept queue of each listener. Listeners that the new
+//! process binds itself with `SO_REUSEPORT` are separate sockets, and the kernel assigns each new
+//! connection to one of them by a hash of its addresses, whether or not their process is accepting.
+//! The new process then takes its share of the hash, e.g. half with two sockets, from the start of
+//! the ramp, and pausing the old process only delays the connections assigned to its sockets,
+//! which are reset if they are still queued when it closes them, unless they are parked, see the
+//! `parking` module.
+//!
+//! The ramp starts as soon as the new process is ready. Combined with
+//! `RestartOptions::await_commit`, the restart can then be committed once the ramp looks healthy,
+//! or cancelled to return all connections to the old process.
+//!
+//! Accept loops opt into this by awaiting `accept_turn` before each accept:
+//!
+//! ```no_run
+//! # async fn example(listener: tokio::net::TcpListener) -> std::io::Result<()> {
+//! while shellflip::cutover::accept_turn().await {
+//!     let (stream, _) = listener.accept().await?;
+//!     // ...
+//! #   drop(stream);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `listeners::ListenerGuard::accept_turn` awaits this as well, so accept loops that pause with a
+//! guard await that instead.
+//!
+//! # Accept handoff
+//!
+//! Connections queue up in the backlog of a listener until a process accepts them. If the old
+//! process stops accepting before the new one starts to, which may be a while after it reported
+//! readiness, queued connections wait, and time out if the gap is long enough. With
+//! `RestartConfig::accept_handoff`, the old process keeps accepting until the new process reports
+//! that it is accepting, over the pipe it used to report readiness. Any ramp starts at that point.
+//!
+//! `accept_turn` reports this on its first call in the new process, or call `accepting` when
+//! accepting some other way. If the new process does neither within the `accept_handoff` period,
+//! the old process stops waiting for it. New processes that predate this close the pipe instead,
+//! which is taken as accepting.
+//!
+//! # Backlog
+//!
+//! With `RestartConfig::watch_backlog`, while new connections move over, the old process samples
+//! how many connections wait in the accept queue of each TCP listener, until shortly after it stops
+//! accepting. The result is logged,
+//! and returned by `last_report`, as evidence that no connection waited on a listener that neither
+//! process accepted from. This is only supported on Linux.
+use crate::pipes::{set_cloexec, CompletionSender};
+use crate::{diagnostics, listeners, RestartId};
+use std::env;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Set for a new process that should report when it starts accepting.
+pub(crate) const ENV_ACCEPT_HANDOFF: &str = "OXY_ACCEPT_HANDOFF";
+/// Each cycle, this process accepts for its share of the cycle and then pauses for the rest.
+const CYCLE: Duration = Duration::from_millis(100);
+/// How often accept queues are sampled during the cutover.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+/// How long accept queues are sampled for after this process stops accepting.
+const SETTLE: Duration = Duration::from_secs(1);
+
+static STATE: Mutex<State> = Mutex::new(State::new());
+/// Whether this process started accepting, which the old process may be waiting for.
+static ACCEPTING: AtomicBool = AtomicBool::new(false);
+/// The pipe to report accepting on, until this process is accepting.
+static ACCEPT_NOTIFY: Mutex<Option<CompletionSender>> = Mutex::new(None);
+/// Whether `report_accepting` took the pipe. The environment variable is left as it is, as
+/// changing the environment of a multithreaded process is unsound, and the next new process gets
+/// its own.
+static HANDOFF_TAKEN: AtomicBool = AtomicBool::new(false);
+static LAST_REPORT: Mutex<Option<CutoverReport>> = Mutex::new(None);
+
+/// What happened to the accept queues of the listeners during the most recent cutover.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CutoverReport {
+    /// From when the new process was ready until sampling ended, shortly after this process
+    /// stopped accepting or the restart failed.
+    pub duration: Duration,
+    pub listeners: Vec<ListenerBacklog>,
+}
+
+/// The accept queue of a TCP listener during a cutover. Listeners that are part of a group are
+/// reported once for each socket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenerBacklog {
+    pub name: String,
+    /// The maximum number of connections that can wait to be accepted.
+    pub capacity: u32,
+    /// Connections waiting to be accepted when the new process was ready.
+    pub queued_before: u32,
+    /// Connections waiting to be accepted when sampling ended.
+    pub queued_after: u32,
+    pub max_queued: u32,
+    /// The longest time that connections waited without the queue getting any shorter, an
+    /// upper bound on how long neither process accepted from the listener.
+    pub longest_stall: Duration,
+}
+
+impl ListenerBacklog {
+    fn sample(&mut self, queued: u32, previous: Option<(Instant, u32)>, now: Instant) -> Instant {
+        self.queued_after = queued;
+        self.max_queued = self.max_queued.max(queued);
+        match previous {
+            Some((stalled_since, previous)) if queued > 0 && queued >= previous => {
+                self.longest_stall = self.longest_stall.max(now - stalled_since);
+                stalled_since
+            }
+            _ => now,
+        }
+    }
+}
+
+/// Returns the report of the most recent cutover from this process to a new one, once it ended,
+/// if `RestartConfig::watch_backlog` is set.
+pub fn last_report() -> Option<CutoverReport> {
+    LAST_REPORT.lock().unwrap().clone()
+}
+
+/// Where the handover of new connections to the new process stands.
+#[derive(Clone, Debug, PartialEq)]
+struct State {
+    /// Whether a new process is ready.
+    ready: bool,
+    /// Whether the restart completed, so this process stops accepting once the ramp, if any, ends.
+    completed: bool,
+    /// When the new process started accepting, if it did.
+    accepting_since: Option<Instant>,
+    /// When to stop waiting for the new process to report that it is accepting.
+    accept_deadline: Option<Instant>,
+    ramp: Option<Duration>,
+    /// The restart that spawned the new process, so that a report from one that was killed is
+    /// ignored.
+    restart: Option<RestartId>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Turn {
+    Accept,
+    Wait(Duration),
+    Stop,
+}
+
+impl State {
+    const fn new() -> Self {
+        State {
+            ready: false,
+            completed: false,
+            accepting_since: None,
+            accept_deadline: None,
+            ramp: None,
+            restart: None,
+        }
+    }
+
+    /// When the new process took over accepting, if it did.
+    fn handoff(&self, now: Instant) -> Option<Instant> {
+        if !self.ready {
+            return None;
+        }
+        self.accepting_since
+            .or(self.accept_deadline.filter(|deadline| *deadline <= now))
+    }
+
+    fn share(&self, now: Instant) -> f64 {
+        let Some(handoff) = self.handoff(now) else {
+            return 1.0;
+        };
+        match self.ramp {
+            Some(period) => {
+                let elapsed = now.saturating_duration_since(handoff);
+                1.0 - (elapsed.as_secs_f64() / period.as_secs_f64()).min(1.0)
+            }
+            // Both processes serve until the restart is committed.
+            None if !self.completed => 1.0,
+            None => 0.0,
+        }
+    }
+
+    fn turn(&self, now: Instant) -> Turn {
+        let Some(handoff) = self.handoff(now) else {
+            return Turn::Accept;
+        };
+        let share = self.share(now);
+        if share <= 0.0 {
+            // Until the restart is committed, it may still be cancelled.
+            return match self.completed {
+                true => Turn::Stop,
+                false => Turn::Wait(CYCLE),
+            };
+        }
+        let elapsed = now.saturating_duration_since(handoff).as_nanos();
+        let position = Duration::from_nanos((elapsed % CYCLE.as_nanos()) as u64);
+        if position < CYCLE.mul_f64(share) {
+            Turn::Accept
+        } else {
+            Turn::Wait(CYCLE - position)
+        }
+    }
+}
+
+/// Waits until this process should accept the next connection. Returns false once the new process
+/// has taken over, and this process should stop accepting altogether.
+///
+/// Until a new process is ready, this returns true right away. Without a cutover ramp, it returns
+/// false once the restart completes. The first call reports to the old process, if any, that this
+/// process is accepting, see `accepting`.
+pub async fn accept_turn() -> bool {
+    if !ACCEPTING.load(Ordering::Relaxed) {
+        accepting();
+    }
+    loop {
+        let turn = STATE.lock().unwrap().turn(Instant::now());
+        match turn {
+            Turn::Accept => return true,
+            Turn::Wait(wait) => tokio::time::sleep(wait).await,
+            Turn::Stop => return false,
+        }
+    }
+}
+
+/// Reports to the old process, if it is waiting for it, that this process is accepting
+/// connections, so that it can stop. If this process is not ready yet, the report is sent along
+/// with readiness.
+pub fn accepting() {
+    ACCEPTING.store(true, Ordering::Relaxed);
+    if let Some(mut sender) = ACCEPT_NOTIFY.lock().unwrap().take() {
+        if let Err(e) = sender.send() {
+            diagnostics::warn!("Failed to report accepting to the old process: {}", e);
+        }
+    }
+}
+
+/// The share of the time this process accepts new connections, from 1 before a new process is
+/// accepting down to 0 once it has taken over.
+pub fn accept_share() -> f64 {
+    STATE.lock().unwrap().share(Instant::now())
+}
+
+/// Keeps the pipe this process reported readiness on, if the old process waits for it to report
+/// that it is accepting as well.
+pub(crate) fn report_accepting(mut sender: CompletionSender) {
+    if env::var_os(ENV_ACCEPT_HANDOFF).is_none() || HANDOFF_TAKEN.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    // Don't leak the pipe into the next process while holding on to it.
+    if let Err(e) = set_cloexec(sender.0.as_raw_fd()) {
+        diagnostics::warn!("Failed to set close-on-exec on the readiness pipe: {}", e);
+    }
+    // `accepting` sets the flag before taking the lock, so it either sees the pipe or was seen.
+    let mut notify = ACCEPT_NOTIFY.lock().unwrap();
+    if !ACCEPTING.load(Ordering::Relaxed) {
+        *notify = Some(sender);
+    } else if let Err(e) = sender.send() {
+        diagnostics::warn!("Failed to report accepting to the old process: {}", e);
+    }
+}
+
+/// The new process is ready, so start handing new connections over to it, or wait up to
+/// `accept_handoff` for it to report that it is accepting first. With `backlog`, the accept queues
+/// are sampled meanwhile.
+pub(crate) fn new_process_ready(
+    restart_id: &RestartId,
+    ramp: Option<Duration>,
+    accept_handoff: Option<Duration>,
+    backlog: bool,
+) {
+    let now = Instant::now();
+    *STATE.lock().unwrap() = State {
+        ready: true,
+        completed: false,
+        accepting_since: accept_handoff.is_none().then_some(now),
+        accept_deadline: accept_handoff.map(|timeout| now + timeout),
+        ramp,
+        restart: Some(restart_id.clone()),
+    };
+    if backlog {
+        watch_backlog(restart_id.clone());
+    }
+}
+
+/// Sample the accept queues of the registered listeners until the cutover for `restart` ends.
+fn watch_backlog(restart: RestartId) {
+    let listeners: Vec<(String, OwnedFd)> = match listeners::for_new_process() {
+        Ok(listeners) => listeners
+            .into_entries()
+            .filter(|(_, fd)| accept_queue(fd.as_fd()).is_some())
+            .collect(),
+        Err(e) => {
+            diagnostics::warn!("Failed to watch listener backlogs: {}", e);
+            return;
+        }
+    };
+    if listeners.is_empty() {
+        return;
+    }
+
+    thread::spawn(move || {
+        let start = Instant::now();
+        let mut reports: Vec<ListenerBacklog> = listeners
+            .iter()
+            .map(|(name, fd)| {
+                let (queued, capacity) = accept_queue(fd.as_fd()).unwrap_or_default();
+                ListenerBacklog {
+                    name: name.clone(),
+                    capacity,
+                    queued_before: queued,
+                    queued_after: queued,
+                    max_queued: queued,
+                    longest_stall: Duration::ZERO,
+                }
+            })
+            .collect();
+        let mut stalls: Vec<(Instant, u32)> =
+            reports.iter().map(|r| (start, r.queued_before)).collect();
+        let mut stopped = None;
+        loop {
+            thread::sleep(SAMPLE_INTERVAL);
+            let now = Instant::now();
+            for ((report, (_, fd)), stall) in reports.iter_mut().zip(&listeners).zip(&mut stalls) {
+                if let Some((queued, _)) = accept_queue(fd.as_fd()) {
+                    *stall = (report.sample(queued, Some(*stall), now), queued);
+                }
+            }
+
+            let state = STATE.lock().unwrap().clone();
+            if !state.ready || state.restart.as_ref() != Some(&restart) {
+                break;
+            }
+            if state.turn(now) == Turn::Stop {
+                let stopped = *stopped.get_or_insert(now);
+                if now - stopped >= SETTLE {
+                    break;
+                }
+            }
+        }
+
+        let report = CutoverReport {
+            duration: start.elapsed(),
+            listeners: reports,
+        };
+        for l in &report.listeners {
+            diagnostics::info!(
+                listener = l.name,
+                max_queued = l.max_queued,
+                longest_stall_ms = l.longest_stall.as_millis();
+                "Listener {} backlog during cutover: {} queued before, {} after, at most {} of {}, \
+                 longest stall {:?}",
+                l.name,
+                l.queued_before,
+                l.queued_after,
+                l.max_queued,
+                l.capacity,
+                l.longest_stall
+            );
+        }
+        *LAST_REPORT.lock().unwrap() = Some(report);
+    });
+}
+
+/// Returns the number of connections waiting to be accepted on a TCP listener, and how many may
+/// wait, or `None` for other sockets.
+#[cfg(target_os = "linux")]
+fn accept_queue(fd: BorrowedFd<'_>) -> Option<(u32, u32)> {
+    // The state of a listening socket in `tcp_info`.
+    const TCP_LISTEN: u8 = 10;
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    // For listening sockets, these fields hold the length and capacity of the accept queue.
+    (res == 0 && info.tcpi_state == TCP_LISTEN).then_some((info.tcpi_unacked, info.tcpi_sacked))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn accept_queue(_fd: BorrowedFd<'_>) -> Option<(u32, u32)> {
+    None
+}
+
+/// The new process reported that it is accepting.
+pub(crate) fn new_process_accepting(restart_id: &RestartId) {
+    let mut state = STATE.lock().unwrap();
+    let current = state.restart.as_ref() == Some(restart_id);
+    if state.ready && current && state.accepting_since.is_none() {
+        state.accepting_since = Some(Instant::now());
+    }
+}
+
+/// The restart failed or was cancelled, so take all new connections back.
+pub(crate) fn reset() {
+    *STATE.lock().unwrap() = State::new();
+}
+
+/// The restart completed. A ramp in progress carries on until it ends.
+pub(crate) fn handed_over() {
+    let mut state = STATE.lock().unwrap();
+    if !state.ready {
+        state.ready = true;
+        state.accepting_since = Some(Instant::now());
+    }
+    state.completed = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turn() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let state = State {
+            ready: true,
+            completed: true,
+            accepting_since: Some(start),
+            ramp: Some(Duration::from_secs(10)),
+            ..State::new()
+        };
+
+        assert_eq!(state.share(at(2500)), 0.75);
+        // At 75%, accept for the first 75ms of each cycle.
+        assert_eq!(state.turn(at(2510)), Turn::Accept);
+        assert_eq!(state.turn(at(2570)), Turn::Accept);
+        assert_eq!(state.turn(at(2580)), Turn::Wait(Duration::from_millis(20)));
+        assert_eq!(state.turn(at(9950)), Turn::Wait(Duration::from_millis(50)));
+        assert_eq!(state.turn(at(10000)), Turn::Stop);
+
+        // Until the restart is committed, it may be cancelled after the ramp.
+        let uncommitted = State {
+            completed: false,
+            ..state.clone()
+        };
+        assert_eq!(uncommitted.turn(at(10000)), Turn::Wait(CYCLE));
+
+        assert_eq!(State::new().turn(at(0)), Turn::Accept);
+        let handed_over = State {
+            ramp: None,
+            ..state
+        };
+        assert_eq!(handed_over.turn(at(0)), Turn::Stop);
+    }
+
+    #[test]
+    fn test_backlog_sample() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut report = ListenerBacklog {
+            name: "http".into(),
+            capacity: 128,
+            queued_before: 2,
+            queued_after: 2,
+            max_queued: 2,
+            longest_stall: Duration::ZERO,
+        };
+
+        // The queue doesn't get shorter for 30ms, then drains.
+        let stall = report.sample(3, Some((at(0), 2)), at(10));
+        let stall = report.sample(3, Some((stall, 3)), at(30));
+        let stall = report.sample(1, Some((stall, 3)), at(40));
+        report.sample(1, Some((stall, 1)), at(50));
+        assert_eq!(report.longest_stall, Duration::from_millis(30));
+        assert_eq!(report.max_queued, 3);
+        assert_eq!(report.queued_after, 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_accept_queue() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (queued, capacity) = accept_queue(listener.as_fd()).unwrap();
+        assert_eq!(queued, 1);
+        assert!(capacity > 0);
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert_eq!(accept_queue(udp.as_fd()), None);
+    }
+
+    #[test]
+    fn test_accept_handoff() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let state = State {
+            ready: true,
+            completed: true,
+            accept_deadline: Some(at(1000)),
+            ..State::new()
+        };
+
+        // Keep accepting until the new process is accepting, or the deadline passes.
+        assert_eq!(state.turn(at(500)), Turn::Accept);
+        assert_eq!(state.turn(at(1000)), Turn::Stop);
+        let accepting = State {
+            accepting_since: Some(at(200)),
+            ..state.clone()
+        };
+        assert_eq!(accepting.turn(at(500)), Turn::Stop);
+
+        // A ramp starts once the new process is accepting.
+        let ramp = State {
+            ramp: Some(Duration::from_secs(1)),
+            ..accepting.clone()
+        };
+        assert_eq!(ramp.share(at(700)), 0.5);
+    }
+}
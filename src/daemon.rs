@@ -0,0 +1,150 @@
+//! Detaching from the terminal as a traditional daemon, in a way that survives restarts.
+//!
+//! `Daemonize::daemonize` forks twice, starts a new session, changes to the root directory and
+//! redirects the standard streams, like `daemon(3)`. The process that ran it exits once the
+//! daemon is set up, so that an init script can start the daemon in the foreground.
+//!
+//! A process started by a restart must not do this again: the old process waits for it to signal
+//! readiness through a pipe, relays its output if `RestartConfig::relay_child_output` is set, and
+//! reports its pid as the new main pid, so it has to remain the process that was spawned. So in a
+//! new process, `daemonize` only takes note of the pid file. The pid file is rewritten with the
+//! pid of the new process by the old one once the restart completes, rather than by the new
+//! process when it starts, so that it never names a process that failed to start.
+//!
+//! Call `daemonize` at the start of `main`, before starting the tokio runtime or any other thread,
+//! as only the thread that forks is carried over to the daemon.
+use crate::pipes::{create_paired_pipes, PipeMode};
+use crate::{diagnostics, restart_id};
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{chdir, dup2, fork, setsid, ForkResult};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::OnceLock;
+
+/// The pid file of this daemon, if any.
+static PID_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// How to detach from the terminal.
+#[derive(Clone, Debug)]
+pub struct Daemonize {
+    /// Write the pid of the daemon to this file. It is kept up to date across restarts.
+    pub pid_file: Option<PathBuf>,
+    /// The working directory of the daemon.
+    pub working_dir: PathBuf,
+    /// Redirect stdout to this file, appending to it. Defaults to `/dev/null`.
+    pub stdout: Option<PathBuf>,
+    /// Redirect stderr to this file, appending to it. Defaults to `/dev/null`.
+    pub stderr: Option<PathBuf>,
+    /// The file mode creation mask of the daemon.
+    pub umask: u32,
+}
+
+impl Default for Daemonize {
+    fn default() -> Self {
+        Daemonize {
+            pid_file: None,
+            working_dir: PathBuf::from("/"),
+            stdout: None,
+            stderr: None,
+            umask: 0o027,
+        }
+    }
+}
+
+impl Daemonize {
+    /// Detach from the terminal, unless this process was started by a restart, see the module
+    /// documentation. Returns in the daemon; the process that called it exits with 0 once the
+    /// daemon is set up, or with 1 if that failed.
+    pub fn daemonize(&self) -> io::Result<()> {
+        if let Some(path) = &self.pid_file {
+            let _ = PID_FILE.set(path.clone());
+        }
+        if restart_id().is_some() {
+            diagnostics::debug!("Started by a restart, not detaching again");
+            return Ok(());
+        }
+
+        let (mut ready_r, mut ready_w) = create_paired_pipes(PipeMode::ChildWrites)?;
+        // Safety: the caller makes sure there is no other thread.
+        match unsafe { fork() }? {
+            ForkResult::Parent { .. } => {
+                drop(ready_w);
+                let mut byte = [0];
+                let code = match ready_r.read(&mut byte) {
+                    Ok(1) => 0,
+                    _ => 1,
+                };
+                process::exit(code);
+            }
+            ForkResult::Child => drop(ready_r),
+        }
+
+        setsid()?;
+        // Fork again, so that the daemon is not a session leader and can't acquire a terminal.
+        match unsafe { fork() }? {
+            ForkResult::Parent { .. } => process::exit(0),
+            ForkResult::Child => {}
+        }
+
+        chdir(&self.working_dir)?;
+        umask(Mode::from_bits_truncate(self.umask as libc::mode_t));
+        redirect(libc::STDIN_FILENO, &File::open("/dev/null")?)?;
+        redirect(libc::STDOUT_FILENO, &open_output(self.stdout.as_deref())?)?;
+        redirect(libc::STDERR_FILENO, &open_output(self.stderr.as_deref())?)?;
+        if let Some(path) = &self.pid_file {
+            write_pid_file(path, process::id())?;
+        }
+
+        ready_w.write_all(b"R")?;
+        Ok(())
+    }
+}
+
+/// Point the pid file at the new process once a restart completed.
+pub(crate) fn handed_over(pid: u32) {
+    if let Some(path) = PID_FILE.get() {
+        if let Err(e) = write_pid_file(path, pid) {
+            diagnostics::error!("Failed to update pid file {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Atomically replace the contents of the pid file at `path`.
+fn write_pid_file(path: &Path, pid: u32) -> io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.tmp", process::id()));
+    let tmp = path.with_file_name(name);
+    fs::write(&tmp, format!("{pid}\n"))?;
+    let res = fs::rename(&tmp, path);
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+fn open_output(path: Option<&Path>) -> io::Result<File> {
+    let path = path.unwrap_or(Path::new("/dev/null"));
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn redirect(fd: libc::c_int, file: &File) -> io::Result<()> {
+    dup2(file.as_raw_fd(), fd)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_pid_file() {
+        let path = std::env::temp_dir().join(format!("shellflip-pid-{}", process::id()));
+        write_pid_file(&path, 42).unwrap();
+        write_pid_file(&path, 43).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "43\n");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,340 @@
+//! A small D-Bus interface to the restart coordination socket, for systems that control services
+//! over D-Bus. Requires the `dbus` feature.
+//!
+//! Set `RestartConfig::dbus` to own a well-known name on the system or session bus, and serve the
+//! `com.cloudflare.Shellflip1` interface at `/com/cloudflare/Shellflip`. Calls are handled by the
+//! same code as requests on the restart coordination socket.
+//!
+//! - `Restart() -> (s restart_id, u pid)` restarts the process, and replies once the restart is
+//!   complete.
+//! - `Status() -> s` returns the `StatusReport` as JSON.
+//! - `Reload()` sends `AdminCommand::Reload` to the application.
+//!
+//! The new process takes over the name from the old one, which queues for it again in case the new
+//! process fails. The reply to `Restart` comes from the old process, so it is lost if the old
+//! process exits right after the restart rather than draining first. Failed calls return a
+//! `com.cloudflare.Shellflip1.Error.*` error with a message.
+//!
+//! ```text
+//! busctl call org.example.Service /com/cloudflare/Shellflip com.cloudflare.Shellflip1 Restart
+//! ```
+//!
+//! Who may own the name and call the methods is up to the bus policy, e.g. a file in
+//! `/etc/dbus-1/system.d` for the system bus, which by default denies both. Calls are further
+//! subject to `DbusConfig::policy`, which is checked against the credentials that the bus reports
+//! for the caller, and the audit log records the caller with those credentials.
+//!
+//! Connecting to the bus and requesting the name happen in the background, so failing to own the
+//! name is logged rather than returned by `RestartConfig::try_into_restart_task`.
+use crate::diagnostics;
+use crate::endpoints::EndpointPolicy;
+use crate::restart_coordination_socket::{PeerCredentials, RestartCoordinationSocket};
+use crate::{AdminCommand, Error, RestartResult};
+use nix::unistd::{Uid, User};
+use std::io;
+use std::time::Duration;
+use tokio::net::UnixStream;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use zbus::fdo::{self, RequestNameFlags, RequestNameReply};
+use zbus::message::Header;
+use zbus::names::{BusName, WellKnownName};
+use zbus::{connection, interface, Connection, DBusError};
+
+pub const INTERFACE: &str = "com.cloudflare.Shellflip1";
+pub const OBJECT_PATH: &str = "/com/cloudflare/Shellflip";
+
+/// How long to wait for the bus while connecting and requesting the name.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The number of calls that may wait for the restart task to pick them up.
+const CONNECTION_BACKLOG: usize = 16;
+
+/// The bus to connect to.
+#[derive(Clone, Debug, Default)]
+pub enum Bus {
+    /// The system bus, at `DBUS_SYSTEM_BUS_ADDRESS` or the default address.
+    #[default]
+    System,
+    /// The session bus, at `DBUS_SESSION_BUS_ADDRESS`.
+    Session,
+    /// The bus at this D-Bus address, e.g. `unix:path=/run/dbus/system_bus_socket`.
+    Address(String),
+}
+
+/// Settings for the D-Bus interface.
+#[derive(Clone, Debug, Default)]
+pub struct DbusConfig {
+    pub bus: Bus,
+    /// The well-known name to own, e.g. `org.example.Service`.
+    pub name: String,
+    /// Which callers and calls to serve, by the credentials that the bus reports for the caller.
+    /// The default serves every call that the bus policy lets through.
+    pub policy: EndpointPolicy,
+}
+
+/// Connect to the bus, request the name and serve the interface in a new task. Each call is
+/// handled over a connection sent to the restart task through the returned channel, along with
+/// the credentials of the caller. The interface stops when the channel closes.
+pub(crate) fn spawn(config: DbusConfig) -> RestartResult<Receiver<(UnixStream, PeerCredentials)>> {
+    let name = WellKnownName::try_from(config.name.clone()).map_err(|e| {
+        Error::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid D-Bus name {:?}: {}", config.name, e),
+        ))
+    })?;
+    let (tx, rx) = channel(CONNECTION_BACKLOG);
+    let builder = match &config.bus {
+        Bus::System => connection::Builder::system(),
+        Bus::Session => connection::Builder::session(),
+        Bus::Address(address) => connection::Builder::address(address.as_str()),
+    };
+    let builder = builder
+        .and_then(|builder| {
+            builder.serve_at(
+                OBJECT_PATH,
+                Shellflip {
+                    connector: tx.clone(),
+                },
+            )
+        })
+        .map_err(|e| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("failed to connect to D-Bus: {e}"),
+            ))
+        })?;
+    tokio::spawn(async move {
+        let res = tokio::time::timeout(HANDSHAKE_TIMEOUT, own_name(builder, &name)).await;
+        let connection = match res {
+            Ok(Ok(connection)) => connection,
+            Ok(Err(e)) => {
+                diagnostics::error!("Failed to own D-Bus name {}: {}", name, e);
+                return;
+            }
+            Err(_) => {
+                diagnostics::error!("Failed to own D-Bus name {}: timed out", name);
+                return;
+            }
+        };
+        // The restart task completed, so the new process serves the interface from now on.
+        tx.closed().await;
+        let _ = connection
+            .object_server()
+            .remove::<Shellflip, _>(OBJECT_PATH)
+            .await;
+        let _ = connection.release_name(&name).await;
+    });
+    Ok(rx)
+}
+
+/// Connects to the bus and requests the name.
+async fn own_name(
+    builder: connection::Builder<'_>,
+    name: &WellKnownName<'_>,
+) -> zbus::Result<Connection> {
+    let connection = builder.build().await?;
+    let flags = RequestNameFlags::AllowReplacement | RequestNameFlags::ReplaceExisting;
+    match connection.request_name_with_flags(name, flags).await? {
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => {
+            diagnostics::info!("Owning D-Bus name {}", name)
+        }
+        RequestNameReply::InQueue => diagnostics::warn!(
+            "D-Bus name {} is owned by a process that doesn't allow replacing it, waiting for it",
+            name
+        ),
+        reply => {
+            return Err(zbus::Error::Failure(format!(
+                "RequestName returned {reply:?}"
+            )))
+        }
+    }
+    Ok(connection)
+}
+
+/// Errors returned to callers, named `com.cloudflare.Shellflip1.Error.*`.
+#[derive(DBusError, Debug)]
+#[zbus(prefix = "com.cloudflare.Shellflip1.Error")]
+enum CallError {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    Failed(String),
+    AlreadyRestarting(String),
+}
+
+impl From<Error> for CallError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::AlreadyRestarting(_) => CallError::AlreadyRestarting(e.to_string()),
+            e => CallError::Failed(e.to_string()),
+        }
+    }
+}
+
+/// The object served at `OBJECT_PATH`.
+struct Shellflip {
+    connector: Sender<(UnixStream, PeerCredentials)>,
+}
+
+#[interface(name = "com.cloudflare.Shellflip1")]
+impl Shellflip {
+    async fn restart(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<(String, u32), CallError> {
+        let peer = caller(&header, connection).await?;
+        diagnostics::info!("Restart requested through D-Bus by {}", peer);
+        let outcome = self
+            .connect(peer)
+            .await?
+            .send_restart_command_with(Default::default())
+            .await?;
+        Ok((outcome.restart_id.as_str().into(), outcome.pid))
+    }
+
+    async fn status(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<String, CallError> {
+        let peer = caller(&header, connection).await?;
+        let status = self.connect(peer).await?.query_status().await?;
+        Ok(serde_json::to_string(&status).expect("status is serializable"))
+    }
+
+    async fn reload(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<(), CallError> {
+        let peer = caller(&header, connection).await?;
+        self.connect(peer)
+            .await?
+            .send_command(AdminCommand::Reload)
+            .await?;
+        Ok(())
+    }
+}
+
+impl Shellflip {
+    /// Connect to the restart task as a restart coordination socket client would, for `peer`.
+    async fn connect(&self, peer: PeerCredentials) -> RestartResult<RestartCoordinationSocket> {
+        let (client, server) = UnixStream::pair()?;
+        self.connector
+            .send((server, peer))
+            .await
+            .map_err(|_| Error::AcceptorTerminated)?;
+        Ok(RestartCoordinationSocket::new(client))
+    }
+}
+
+/// The credentials of the sender of a call, as reported by the bus. The bus only reports the
+/// groups of the sender, so `gid` is the primary group of its user, or `u32::MAX` if the user is
+/// unknown.
+async fn caller(
+    header: &Header<'_>,
+    connection: &Connection,
+) -> Result<PeerCredentials, CallError> {
+    let unknown = || CallError::Failed("the credentials of the caller are unknown".into());
+    let sender = header.sender().ok_or_else(unknown)?;
+    let credentials = fdo::DBusProxy::new(connection)
+        .await?
+        .get_connection_credentials(BusName::Unique(sender.to_owned()))
+        .await
+        .map_err(zbus::Error::from)?;
+    let uid = credentials.unix_user_id().ok_or_else(unknown)?;
+    let user = tokio::task::spawn_blocking(move || User::from_uid(Uid::from_raw(uid))).await;
+    let gid = match user {
+        Ok(Ok(Some(user))) => user.gid.as_raw(),
+        _ => u32::MAX,
+    };
+    Ok(PeerCredentials {
+        pid: credentials.process_id(),
+        uid,
+        gid,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::net::UnixStream as StdUnixStream;
+    use std::process::{Command, Stdio};
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_serve() {
+        let dir = env::temp_dir().join(format!("shellflip-dbus-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bus");
+        let address = format!("unix:path={}", path.display());
+        let daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--nopidfile"])
+            .arg(format!("--address={address}"))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        // The tests don't need a D-Bus installation.
+        let Ok(mut daemon) = daemon else {
+            return;
+        };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while StdUnixStream::connect(&path).is_err() {
+            if daemon.try_wait().unwrap().is_some() {
+                // The daemon can't run here, e.g. for lack of a configuration.
+                return;
+            }
+            if Instant::now() > deadline {
+                daemon.kill().unwrap();
+                daemon.wait().unwrap();
+                panic!("dbus-daemon didn't listen on {}", path.display());
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let name = "com.cloudflare.ShellflipTest";
+        let mut connections = spawn(DbusConfig {
+            bus: Bus::Address(address.clone()),
+            name: name.into(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let client = connection::Builder::address(address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let bus = fdo::DBusProxy::new(&client).await.unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !bus.name_has_owner(name.try_into().unwrap()).await.unwrap() {
+            assert!(Instant::now() < deadline, "{name} was never owned");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let call = tokio::spawn(async move {
+            client
+                .call_method(Some(name), OBJECT_PATH, Some(INTERFACE), "Reload", &())
+                .await
+        });
+        // Answer the call as the restart task would if the application took no admin commands.
+        let (sock, peer) = connections.recv().await.unwrap();
+        assert_eq!(peer.pid, Some(std::process::id()));
+        assert_eq!(peer.uid, nix::unistd::geteuid().as_raw());
+        let mut server = RestartCoordinationSocket::new(sock);
+        server.receive_message().await.unwrap();
+        drop(server);
+        match call.await.unwrap() {
+            Err(zbus::Error::MethodError(error_name, _, _)) => {
+                assert_eq!(
+                    error_name.as_str(),
+                    "com.cloudflare.Shellflip1.Error.Failed"
+                )
+            }
+            res => panic!("unexpected reply {res:?}"),
+        }
+
+        drop(connections);
+        daemon.kill().unwrap();
+        daemon.wait().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+//! Where the diagnostics logged by shellflip itself go.
+//!
+//! By default, they are logged with the `log` crate. Applications that log in some other way, e.g.
+//! with `tracing`, can instead pass them to their own logging stack with `set_sink`. Besides the
+//! message, a `Diagnostic` carries structured fields, such as the restart ID and the pid of the new
+//! process, for the messages about a restart.
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+pub use log::Level;
+
+static SINK: RwLock<Option<Arc<dyn DiagnosticsSink>>> = RwLock::new(None);
+
+/// A message logged by shellflip.
+#[derive(Clone, Copy)]
+pub struct Diagnostic<'a> {
+    pub level: Level,
+    /// The module that logged the message, e.g. `shellflip::listeners`.
+    pub target: &'a str,
+    pub message: fmt::Arguments<'a>,
+    /// Structured values that the message is about, which are also part of the message.
+    pub fields: &'a [(&'static str, &'a dyn fmt::Display)],
+}
+
+impl Diagnostic<'_> {
+    /// Returns the value of the field called `key`, if any.
+    pub fn field(&self, key: &str) -> Option<&dyn fmt::Display> {
+        self.fields.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+    }
+}
+
+impl fmt::Debug for Diagnostic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<_> = self
+            .fields
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect();
+        f.debug_struct("Diagnostic")
+            .field("level", &self.level)
+            .field("target", &self.target)
+            .field("message", &self.message)
+            .field("fields", &fields)
+            .finish()
+    }
+}
+
+/// Receives the diagnostics logged by shellflip. Implemented for closures taking a `Diagnostic`.
+pub trait DiagnosticsSink: Send + Sync {
+    fn emit(&self, diagnostic: &Diagnostic<'_>);
+}
+
+impl<F: Fn(&Diagnostic<'_>) + Send + Sync> DiagnosticsSink for F {
+    fn emit(&self, diagnostic: &Diagnostic<'_>) {
+        self(diagnostic)
+    }
+}
+
+/// Pass all further diagnostics to `sink` instead of the `log` crate.
+pub fn set_sink(sink: impl DiagnosticsSink + 'static) {
+    *SINK.write().unwrap() = Some(Arc::new(sink));
+}
+
+/// Log all further diagnostics with the `log` crate again.
+pub fn reset_sink() {
+    *SINK.write().unwrap() = None;
+}
+
+#[doc(hidden)]
+pub(crate) fn dispatch(
+    level: Level,
+    target: &str,
+    fields: &[(&'static str, &dyn fmt::Display)],
+    message: fmt::Arguments<'_>,
+) {
+    let sink = SINK.read().unwrap().clone();
+    match sink {
+        Some(sink) => sink.emit(&Diagnostic {
+            level,
+            target,
+            message,
+            fields,
+        }),
+        None => log::log!(target: target, level, "{}", message),
+    }
+}
+
+/// Logs a message, optionally preceded by structured fields: `info!(pid = pid; "message")`.
+macro_rules! diagnostic {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        $crate::diagnostics::dispatch(
+            $level,
+            module_path!(),
+            &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),+],
+            format_args!($($arg)+),
+        )
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::diagnostics::dispatch($level, module_path!(), &[], format_args!($($arg)+))
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::diagnostics::diagnostic!(::log::Level::Error, $($arg)+) };
+}
+
+// Named so that it doesn't clash with the `warn` attribute when imported.
+macro_rules! warning {
+    ($($arg:tt)+) => { $crate::diagnostics::diagnostic!(::log::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::diagnostics::diagnostic!(::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::diagnostics::diagnostic!(::log::Level::Debug, $($arg)+) };
+}
+
+pub(crate) use {debug, diagnostic, error, info, warning as warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_sink() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink_received = Arc::clone(&received);
+        set_sink(move |d: &Diagnostic<'_>| {
+            if d.target == module_path!() {
+                let pid = d.field("pid").map(|v| v.to_string());
+                sink_received
+                    .lock()
+                    .unwrap()
+                    .push((d.level, d.message.to_string(), pid));
+            }
+        });
+        info!(pid = 42; "Spawned process {}", 42);
+        warn!("Something happened");
+        reset_sink();
+        info!("Not received");
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            [
+                (Level::Info, "Spawned process 42".into(), Some("42".into())),
+                (Level::Warn, "Something happened".into(), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,407 @@
+//! Where the restart coordination socket of a service lives by default, so that the service and
+//! the tools that manage it agree on it without passing the path around.
+//!
+//! The socket of the service `name` is found at the first of:
+//!
+//! 1. the path in the `SHELLFLIP_SOCKET` environment variable, see `app::ENV_SOCKET`;
+//! 2. `$XDG_RUNTIME_DIR/shellflip/<name>.sock`;
+//! 3. `/run/shellflip/<name>.sock` for root, or `/tmp/shellflip-<uid>/<name>.sock` for any other
+//!    user.
+//!
+//! As the directories are per user, instances of the same service run by different users don't
+//! clash. The directory must belong to the user and not be writable by anyone else, or serving and
+//! connecting to the socket fails, see `check_socket_dir`. `#[shellflip::main(service = "name")]`
+//! and `app::configure_service` serve the socket there, and `admin::AdminClient::for_service` and
+//! `shellflip-admin --service name` connect to it.
+//!
+//! Several instances of the same service, e.g. one per listening port, each serve their socket at
+//! `<name>@<instance>.sock` in the same directory instead, see `instance_socket_path`. If
+//! `SHELLFLIP_SOCKET` contains `{instance}`, it is a template for the sockets of the instances
+//! rather than a path, in which `{instance}` and `{service}` are replaced. `{instance}` may only
+//! appear once, in the file name, so that `instances` can find the sockets of every instance in
+//! one directory. `admin::list_instances` queries their status.
+//!
+//! Service and instance names may not be empty or contain `/` or `@`, see `check_name`.
+//!
+//! ```no_run
+//! let config = shellflip::app::configure_instance(Default::default(), "myservice", "8080");
+//! assert_eq!(
+//!     config.coordination_socket_path,
+//!     shellflip::discovery::instance_socket_path("myservice", "8080")?
+//! );
+//! # Ok::<(), std::io::Error>(())
+//! ```
+use crate::app::ENV_SOCKET;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// The environment variable that names the per-user runtime directory.
+pub const ENV_RUNTIME_DIR: &str = "XDG_RUNTIME_DIR";
+
+const INSTANCE: &str = "{instance}";
+const SERVICE: &str = "{service}";
+
+/// The path of the restart coordination socket of `service`. Fails if the name is invalid, see
+/// `check_name`.
+pub fn socket_path(service: &str) -> io::Result<PathBuf> {
+    Env::current().socket_path(service)
+}
+
+/// The path of the restart coordination socket of one of several instances of `service`. The
+/// instance is a plain name, e.g. an ID or the port the instance listens on. Fails if either name
+/// is invalid, see `check_name`, or if `SHELLFLIP_SOCKET` is a template with `{instance}` outside
+/// the file name.
+pub fn instance_socket_path(service: &str, instance: &str) -> io::Result<PathBuf> {
+    Env::current().instance_socket_path(service, instance)
+}
+
+/// Check that `name` can be used as the name of a service or instance: it may not be empty, and
+/// may not contain `/`, which would put the socket in another directory, or `@`, which separates
+/// the service from the instance.
+pub fn check_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name.contains(['/', '@', '\0']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid service or instance name {name:?}"),
+        ));
+    }
+    Ok(())
+}
+
+/// The directory holding the restart coordination sockets of the services of this user, unless
+/// `SHELLFLIP_SOCKET` overrides their path.
+pub fn socket_dir() -> PathBuf {
+    Env::current().runtime_dir()
+}
+
+/// The restart coordination socket of an instance of a service, found by `instances`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InstanceSocket {
+    /// `None` for the socket at `socket_path`, of a service run without instances.
+    pub instance: Option<String>,
+    pub path: PathBuf,
+}
+
+/// The sockets of the instances of `service`, sorted by instance. The sockets may be left over
+/// from processes that are gone, so connecting to them can still fail.
+pub fn instances(service: &str) -> io::Result<Vec<InstanceSocket>> {
+    Env::current().instances(service)
+}
+
+/// Create the directory that the socket at `path` is bound in, accessible only to this user, if it
+/// doesn't exist yet, then check it as `check_socket_dir` does.
+pub fn create_socket_dir(path: &Path) -> io::Result<()> {
+    let Some(dir) = socket_dir_of(path) else {
+        return Ok(());
+    };
+    match fs::symlink_metadata(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        _ => {}
+    }
+    check_dir(dir)
+}
+
+/// Check that the directory that the socket at `path` is bound in belongs to this user and that
+/// no one else can write to it, so that no one else can have replaced the socket. The directory
+/// must not be a symlink.
+pub fn check_socket_dir(path: &Path) -> io::Result<()> {
+    match socket_dir_of(path) {
+        Some(dir) => check_dir(dir),
+        None => Ok(()),
+    }
+}
+
+fn socket_dir_of(path: &Path) -> Option<&Path> {
+    path.parent().filter(|dir| !dir.as_os_str().is_empty())
+}
+
+fn check_dir(dir: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    let uid = unsafe { libc::geteuid() };
+    let denied = |reason: String| {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("socket directory {} {}", dir.display(), reason),
+        ))
+    };
+    if !metadata.is_dir() {
+        return denied("is not a directory".into());
+    }
+    if metadata.uid() != uid {
+        return denied(format!("belongs to uid {}, not {}", metadata.uid(), uid));
+    }
+    // Even with the sticky bit set, others could bind the socket before this user does.
+    let mode = metadata.permissions().mode();
+    if mode & 0o022 != 0 {
+        return denied(format!("is writable by others (mode {:o})", mode & 0o7777));
+    }
+    Ok(())
+}
+
+fn is_socket(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.file_type().is_socket())
+}
+
+/// The environment that socket paths are resolved in.
+struct Env {
+    socket: Option<OsString>,
+    runtime_dir: Option<OsString>,
+    uid: u32,
+}
+
+impl Env {
+    fn current() -> Self {
+        Env {
+            socket: env::var_os(ENV_SOCKET).filter(|path| !path.is_empty()),
+            runtime_dir: env::var_os(ENV_RUNTIME_DIR),
+            uid: unsafe { libc::geteuid() },
+        }
+    }
+
+    /// `SHELLFLIP_SOCKET`, if it is a template for the sockets of instances.
+    fn socket_template(&self) -> Option<&str> {
+        self.socket
+            .as_ref()?
+            .to_str()
+            .filter(|path| path.contains(INSTANCE))
+    }
+
+    fn socket_path(&self, service: &str) -> io::Result<PathBuf> {
+        check_name(service)?;
+        Ok(match &self.socket {
+            Some(path) if self.socket_template().is_none() => path.into(),
+            _ => self.runtime_dir().join(format!("{service}.sock")),
+        })
+    }
+
+    fn instance_socket_path(&self, service: &str, instance: &str) -> io::Result<PathBuf> {
+        check_name(instance)?;
+        Ok(match &self.socket {
+            Some(path) if self.socket_template().is_none() => path.into(),
+            _ => {
+                let template = self.instance_template(service)?;
+                PathBuf::from(template.to_string_lossy().replace(INSTANCE, instance))
+            }
+        })
+    }
+
+    /// The path of the sockets of the instances of `service`, with `{instance}` in place of the
+    /// instance.
+    fn instance_template(&self, service: &str) -> io::Result<PathBuf> {
+        check_name(service)?;
+        let Some(template) = self.socket_template() else {
+            return Ok(self
+                .runtime_dir()
+                .join(format!("{service}@{INSTANCE}.sock")));
+        };
+        let template = PathBuf::from(template.replace(SERVICE, service));
+        let in_file_name = template
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.matches(INSTANCE).count() == 1);
+        let in_dir = template
+            .parent()
+            .is_some_and(|dir| dir.to_string_lossy().contains(INSTANCE));
+        if !in_file_name || in_dir {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} may only contain {} once, in the file name: {}",
+                    ENV_SOCKET,
+                    INSTANCE,
+                    template.display()
+                ),
+            ));
+        }
+        Ok(template)
+    }
+
+    fn instances(&self, service: &str) -> io::Result<Vec<InstanceSocket>> {
+        let mut found = Vec::new();
+        let path = self.socket_path(service)?;
+        if is_socket(&path) {
+            found.push(InstanceSocket {
+                instance: None,
+                path,
+            });
+        }
+        let template = self.instance_template(service)?;
+        let (Some(dir), Some(name)) = (template.parent(), template.file_name()) else {
+            return Ok(found);
+        };
+        let Some((prefix, suffix)) = name.to_str().and_then(|name| name.split_once(INSTANCE))
+        else {
+            return Ok(found);
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(found),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let instance = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(prefix)?.strip_suffix(suffix))
+                .filter(|instance| check_name(instance).is_ok());
+            if let Some(instance) = instance {
+                if entry.file_type()?.is_socket() {
+                    found.push(InstanceSocket {
+                        instance: Some(instance.to_string()),
+                        path: entry.path(),
+                    });
+                }
+            }
+        }
+        found.sort_by(|a, b| a.instance.cmp(&b.instance));
+        Ok(found)
+    }
+
+    fn runtime_dir(&self) -> PathBuf {
+        match self
+            .runtime_dir
+            .as_ref()
+            .filter(|dir| Path::new(dir).is_absolute())
+        {
+            Some(dir) => PathBuf::from(dir).join("shellflip"),
+            None if self.uid == 0 => "/run/shellflip".into(),
+            None => PathBuf::from(format!("/tmp/shellflip-{}", self.uid)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+
+    #[test]
+    fn test_resolve() {
+        let env = |socket: Option<&str>, runtime_dir: Option<&str>, uid| Env {
+            socket: socket.map(Into::into),
+            runtime_dir: runtime_dir.map(Into::into),
+            uid,
+        };
+        let user = env(None, Some("/run/user/1000"), 1000);
+        assert_eq!(
+            user.socket_path("web").unwrap(),
+            PathBuf::from("/run/user/1000/shellflip/web.sock")
+        );
+        assert_eq!(
+            user.instance_socket_path("web", "8080").unwrap(),
+            PathBuf::from("/run/user/1000/shellflip/web@8080.sock")
+        );
+        assert_eq!(
+            env(None, Some("relative"), 1000)
+                .socket_path("web")
+                .unwrap(),
+            PathBuf::from("/tmp/shellflip-1000/web.sock")
+        );
+        assert_eq!(
+            env(None, None, 0).socket_path("web").unwrap(),
+            PathBuf::from("/run/shellflip/web.sock")
+        );
+
+        let fixed = env(Some("/tmp/web.sock"), None, 0);
+        assert_eq!(
+            fixed.socket_path("web").unwrap(),
+            PathBuf::from("/tmp/web.sock")
+        );
+        assert_eq!(
+            fixed.instance_socket_path("web", "a").unwrap(),
+            PathBuf::from("/tmp/web.sock")
+        );
+
+        let template = env(Some("/srv/{service}/{instance}.sock"), None, 0);
+        assert_eq!(
+            template.socket_path("web").unwrap(),
+            PathBuf::from("/run/shellflip/web.sock")
+        );
+        assert_eq!(
+            template.instance_socket_path("web", "a").unwrap(),
+            PathBuf::from("/srv/web/a.sock")
+        );
+
+        // Instances can only be found if they differ in their file names.
+        for invalid in [
+            "/srv/{instance}/web.sock",
+            "/srv/{instance}/{instance}.sock",
+        ] {
+            let err = env(Some(invalid), None, 0)
+                .instance_socket_path("web", "a")
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_check_name() {
+        let user = Env {
+            socket: None,
+            runtime_dir: Some("/run/user/1000".into()),
+            uid: 1000,
+        };
+        for invalid in ["", "../web", "web@a", "a/b"] {
+            assert!(check_name(invalid).is_err());
+            assert!(user.socket_path(invalid).is_err());
+            assert!(user.instance_socket_path("web", invalid).is_err());
+        }
+        check_name("web-1.example").unwrap();
+    }
+
+    #[test]
+    fn test_instances() {
+        let dir = env::temp_dir().join(format!("shellflip-instances-{}", process::id()));
+        fs::create_dir(&dir).unwrap();
+        let _b = UnixListener::bind(dir.join("web@b.sock")).unwrap();
+        let _a = UnixListener::bind(dir.join("web@a.sock")).unwrap();
+        let _other = UnixListener::bind(dir.join("api@a.sock")).unwrap();
+        fs::write(dir.join("web@c.sock"), b"").unwrap();
+        // Sockets of invalid instance names are not instances.
+        let _invalid = UnixListener::bind(dir.join("web@a@b.sock")).unwrap();
+
+        let env = Env {
+            socket: Some(dir.join("{service}@{instance}.sock").into()),
+            runtime_dir: Some(dir.clone().into()),
+            uid: 0,
+        };
+        let found = env.instances("web").unwrap();
+        let found: Vec<_> = found.into_iter().map(|s| s.instance).collect();
+        assert_eq!(found, [Some("a".to_string()), Some("b".to_string())]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_create_socket_dir() {
+        let dir = env::temp_dir().join(format!("shellflip-discovery-{}", process::id()));
+        create_socket_dir(&dir.join("nested").join("web.sock")).unwrap();
+        let metadata = fs::metadata(dir.join("nested")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+        // Existing directories are left as they are.
+        create_socket_dir(&dir.join("nested").join("web.sock")).unwrap();
+        create_socket_dir(Path::new("web.sock")).unwrap();
+        check_socket_dir(&dir.join("nested").join("web.sock")).unwrap();
+
+        // Directories others can write to are refused, even if they are sticky like /tmp.
+        let shared = dir.join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o1777)).unwrap();
+        let err = create_socket_dir(&shared.join("web.sock")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(check_socket_dir(&shared.join("web.sock")).is_err());
+
+        // As are symlinks, even to a directory that would pass.
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(dir.join("nested"), &link).unwrap();
+        assert!(check_socket_dir(&link.join("web.sock")).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
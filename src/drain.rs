@@ -0,0 +1,251 @@
+//! Closing keepalive connections when shutdown starts.
+//!
+//! HTTP/2 and HTTP/1.1 keepalive clients hold on to idle connections for as long as the server lets
+//! them, so a process that waits for its connections to close before exiting may be kept around
+//! for hours after a restart. A `ConnectionDrain` tells each connection handler when to stop
+//! reusing its connection, by sending GOAWAY or `Connection: close`, and tracks when they are done.
+//!
+//! Once shutdown starts, connections go through the phases of `DrainPhase`:
+//!
+//! 1. For `ConnectionDrainConfig::lame_duck`, connections keep serving as usual, so that load
+//!    balancers notice the process is going away, e.g. from failing health checks, before clients
+//!    are asked to reconnect.
+//! 2. Connections are then told to send GOAWAY, or `Connection: close` with their next response,
+//!    and to close once their in-flight requests have completed. Idle connections close right away.
+//! 3. Connections still open `ConnectionDrainConfig::close_timeout` later are told to close
+//!    immediately.
+//!
+//! ```no_run
+//! use shellflip::drain::{ConnectionDrain, ConnectionDrainConfig, DrainPhase};
+//! use shellflip::shutdown::{ShutdownCoordinator, ShutdownSignal};
+//!
+//! # async fn example(listener: tokio::net::TcpListener) -> std::io::Result<()> {
+//! let coordinator = ShutdownCoordinator::new();
+//! let drain = ConnectionDrain::new(
+//!     ShutdownSignal::from(&*coordinator.handle()),
+//!     ConnectionDrainConfig::default(),
+//! );
+//! loop {
+//!     let (stream, peer) = listener.accept().await?;
+//!     let mut conn = drain.connection(coordinator.named_handle(peer.to_string()));
+//!     tokio::spawn(async move {
+//!         tokio::select! {
+//!             // Serve requests, adding `Connection: close` once `conn.keep_alive()` is false...
+//!             _ = async {} => {}
+//!             _ = conn.wait_for(DrainPhase::GoAway) => {
+//!                 // Send GOAWAY and finish the in-flight streams, unless told to close first.
+//!             }
+//!         }
+//! #       drop(stream);
+//!     });
+//! }
+//! # }
+//! ```
+//!
+//! For WebSocket sessions, see `websocket`. For long polls and server-sent event streams, see
+//! `ShutdownSignal::with_policy`. For UDP services, see `udp`.
+use crate::diagnostics;
+use crate::shutdown::{ShutdownHandle, ShutdownSignal};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::watch;
+
+pub mod udp;
+pub mod websocket;
+
+/// Settings for a `ConnectionDrain`.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionDrainConfig {
+    /// How long connections keep serving as usual once shutdown starts, before they are told to
+    /// close.
+    pub lame_duck: Duration,
+    /// How long connections have to complete their in-flight requests once they have been told to
+    /// close, before they are told to close immediately. `None` waits for them indefinitely.
+    pub close_timeout: Option<Duration>,
+}
+
+/// What connection handlers should do at each stage of a drain, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DrainPhase {
+    /// Shutdown has not started.
+    Serving,
+    /// Shutdown has started, but connections keep serving as usual.
+    LameDuck,
+    /// Send GOAWAY, or `Connection: close` with the next response, and close the connection once
+    /// the in-flight requests have completed.
+    GoAway,
+    /// Close the connection now.
+    Close,
+}
+
+struct Shared {
+    phase_tx: watch::Sender<DrainPhase>,
+    /// The number of connections that are still open.
+    active_tx: watch::Sender<usize>,
+}
+
+/// Tells connection handlers when to close their keepalive connections once shutdown starts, and
+/// tracks which of them are still open. Clones share the same connections.
+#[derive(Clone)]
+pub struct ConnectionDrain {
+    shared: Arc<Shared>,
+}
+
+impl ConnectionDrain {
+    /// Start a drain when `signal` is signalled. This spawns a task, so it must be called from
+    /// within a tokio runtime.
+    pub fn new(mut signal: ShutdownSignal, config: ConnectionDrainConfig) -> Self {
+        let shared = Arc::new(Shared {
+            phase_tx: watch::channel(DrainPhase::Serving).0,
+            active_tx: watch::channel(0).0,
+        });
+        let weak = Arc::downgrade(&shared);
+        tokio::spawn(async move {
+            signal.on_shutdown().await;
+            run(weak, config).await;
+        });
+        ConnectionDrain { shared }
+    }
+
+    /// Track a connection. `handle` is held until the returned `DrainingConnection` is dropped,
+    /// which should happen once the connection has been closed.
+    pub fn connection(&self, handle: Arc<ShutdownHandle>) -> DrainingConnection {
+        self.shared.active_tx.send_modify(|n| *n += 1);
+        DrainingConnection {
+            phase_rx: self.shared.phase_tx.subscribe(),
+            shared: Arc::clone(&self.shared),
+            _handle: handle,
+        }
+    }
+
+    /// The current phase of the drain.
+    pub fn phase(&self) -> DrainPhase {
+        *self.shared.phase_tx.borrow()
+    }
+
+    /// The number of connections that are still open.
+    pub fn active(&self) -> usize {
+        *self.shared.active_tx.borrow()
+    }
+
+    /// Wait until shutdown has started and all connections have been closed.
+    pub async fn closed(&self) {
+        let mut phase_rx = self.shared.phase_tx.subscribe();
+        let _ = phase_rx.wait_for(|p| *p != DrainPhase::Serving).await;
+        let mut active_rx = self.shared.active_tx.subscribe();
+        let _ = active_rx.wait_for(|n| *n == 0).await;
+    }
+}
+
+/// Move the drain through its phases, from once shutdown has started.
+async fn run(shared: Weak<Shared>, config: ConnectionDrainConfig) {
+    let set_phase = |phase| {
+        let shared = shared.upgrade()?;
+        shared.phase_tx.send_replace(phase);
+        let active = *shared.active_tx.borrow();
+        Some(active)
+    };
+
+    if !config.lame_duck.is_zero() {
+        set_phase(DrainPhase::LameDuck);
+        diagnostics::info!(
+            "Shutdown started, lame duck period of {:?} before closing connections",
+            config.lame_duck
+        );
+        tokio::time::sleep(config.lame_duck).await;
+    }
+    let Some(active) = set_phase(DrainPhase::GoAway) else {
+        return;
+    };
+    diagnostics::info!("Telling {} keepalive connections to close", active);
+
+    let Some(close_timeout) = config.close_timeout else {
+        return;
+    };
+    tokio::time::sleep(close_timeout).await;
+    if let Some(active) = set_phase(DrainPhase::Close).filter(|n| *n > 0) {
+        diagnostics::warn!(
+            "{} connections still open {:?} after being told to close, closing them now",
+            active,
+            close_timeout
+        );
+    }
+}
+
+/// A connection tracked by a `ConnectionDrain`. Drop it once the connection has been closed.
+pub struct DrainingConnection {
+    phase_rx: watch::Receiver<DrainPhase>,
+    shared: Arc<Shared>,
+    _handle: Arc<ShutdownHandle>,
+}
+
+impl DrainingConnection {
+    /// The current phase of the drain.
+    pub fn phase(&self) -> DrainPhase {
+        *self.phase_rx.borrow()
+    }
+
+    /// Returns whether the connection may still be reused for further requests. Once this returns
+    /// false, HTTP/1.1 responses should carry `Connection: close`.
+    pub fn keep_alive(&self) -> bool {
+        self.phase() < DrainPhase::GoAway
+    }
+
+    /// Wait until the drain has reached `phase`, and return the phase it is in. Returns right away
+    /// if it already has.
+    pub async fn wait_for(&mut self, phase: DrainPhase) -> DrainPhase {
+        // This can't fail, as `shared` holds the sender.
+        let _ = self.phase_rx.wait_for(|p| *p >= phase).await;
+        self.phase()
+    }
+}
+
+impl Drop for DrainingConnection {
+    fn drop(&mut self) {
+        self.shared.active_tx.send_modify(|n| *n -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown::ShutdownCoordinator;
+    use futures::FutureExt;
+    use tokio::time::{sleep, Instant};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connection_drain() {
+        let coordinator = ShutdownCoordinator::new();
+        let drain = ConnectionDrain::new(
+            ShutdownSignal::from(&*coordinator.handle()),
+            ConnectionDrainConfig {
+                lame_duck: Duration::from_secs(5),
+                close_timeout: Some(Duration::from_secs(10)),
+            },
+        );
+        let mut idle = drain.connection(coordinator.named_handle("idle"));
+        let mut busy = drain.connection(coordinator.named_handle("busy"));
+        assert_eq!(drain.active(), 2);
+        assert!(busy.keep_alive());
+        assert!(drain.closed().now_or_never().is_none());
+
+        let start = Instant::now();
+        let shutdown = tokio::spawn(coordinator.shutdown());
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(busy.phase(), DrainPhase::LameDuck);
+        assert!(busy.keep_alive());
+
+        assert_eq!(idle.wait_for(DrainPhase::GoAway).await, DrainPhase::GoAway);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        assert!(!busy.keep_alive());
+        drop(idle);
+        assert_eq!(drain.active(), 1);
+
+        assert_eq!(busy.wait_for(DrainPhase::Close).await, DrainPhase::Close);
+        assert_eq!(start.elapsed(), Duration::from_secs(15));
+        assert!(!shutdown.is_finished());
+        drop(busy);
+        drain.closed().await;
+        shutdown.await.unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+//! Answering the UDP requests received before shutdown, e.g. for DNS, NTP or syslog services.
+//!
+//! A UDP socket passed to the new process with `files::register` is shared by both processes
+//! during the restart, so the kernel hands each datagram to whichever of them reads it first. The
+//! old process should stop reading once shutdown starts, but a request it has already read is
+//! only answered if it keeps running until the response has been sent. `UdpRequests` receives
+//! requests until shutdown starts, and holds a shutdown handle for each request until it has been
+//! answered or dropped, so shutdown waits for them.
+//!
+//! Once shutdown starts, `UdpRequests::recv` still returns the datagrams that are already queued
+//! on the socket, without waiting for more. Those would otherwise be dropped when this process
+//! closes a socket of its own, e.g. one bound with `SO_REUSEPORT` rather than passed on.
+//!
+//! ```no_run
+//! use shellflip::drain::udp::UdpRequests;
+//! use std::sync::Arc;
+//!
+//! # async fn example(coordinator: &shellflip::ShutdownCoordinator) -> std::io::Result<()> {
+//! let socket = match shellflip::files::take_inherited("dns") {
+//!     Some(fd) => std::net::UdpSocket::from(fd),
+//!     None => std::net::UdpSocket::bind("127.0.0.1:5353")?,
+//! };
+//! shellflip::files::register("dns", &socket)?;
+//! socket.set_nonblocking(true)?;
+//! let socket = Arc::new(tokio::net::UdpSocket::from_std(socket)?);
+//!
+//! let mut requests = UdpRequests::new(socket, coordinator.handle());
+//! let mut buf = [0; 512];
+//! while let Some(request) = requests.recv(&mut buf).await {
+//!     let request = request?;
+//!     let query = buf[..request.len].to_vec();
+//!     tokio::spawn(async move {
+//!         // Resolve the query...
+//!         let _ = request.respond(&query).await;
+//!     });
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::diagnostics;
+use crate::shutdown::{ShutdownHandle, ShutdownSignal};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+/// Receives requests from a UDP socket until shutdown starts, and tracks those that have not been
+/// answered yet.
+pub struct UdpRequests {
+    socket: Arc<UdpSocket>,
+    signal: ShutdownSignal,
+    /// Released once shutdown has started and the queued datagrams have been received.
+    handle: Option<Arc<ShutdownHandle>>,
+    in_flight: Arc<AtomicUsize>,
+    shutdown: bool,
+}
+
+impl UdpRequests {
+    /// Receive requests from `socket` until shutdown is signalled to `handle`, which is held until
+    /// `recv` returns `None`.
+    pub fn new(socket: Arc<UdpSocket>, handle: Arc<ShutdownHandle>) -> Self {
+        UdpRequests {
+            socket,
+            signal: ShutdownSignal::from(&*handle),
+            handle: Some(handle),
+            in_flight: Default::default(),
+            shutdown: false,
+        }
+    }
+
+    /// Receive the next request into `buf`. Once shutdown has started, this only returns the
+    /// requests that are already queued on the socket, and then `None`.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> Option<io::Result<UdpRequest>> {
+        if !self.shutdown {
+            tokio::select! {
+                biased;
+                _ = self.signal.on_shutdown() => self.shutdown = true,
+                res = self.socket.recv_from(buf) => return Some(res.map(|r| self.request(r))),
+            }
+        }
+        self.handle.as_ref()?;
+        match self.socket.try_recv_from(buf) {
+            Ok(r) => Some(Ok(self.request(r))),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                diagnostics::info!(
+                    "Stopped receiving UDP requests on {:?}, {} still in flight",
+                    self.socket.local_addr(),
+                    self.in_flight()
+                );
+                self.handle = None;
+                None
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// The number of requests that have been received but not answered or dropped yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    fn request(&self, (len, peer): (usize, SocketAddr)) -> UdpRequest {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        UdpRequest {
+            len,
+            peer,
+            socket: Arc::clone(&self.socket),
+            in_flight: Arc::clone(&self.in_flight),
+            _handle: self.handle.clone(),
+        }
+    }
+}
+
+/// A request received by `UdpRequests`. Shutdown waits for it to be answered with `respond`, or
+/// dropped if it needs no answer.
+pub struct UdpRequest {
+    /// The length of the request in the buffer passed to `UdpRequests::recv`.
+    pub len: usize,
+    /// The address the request came from.
+    pub peer: SocketAddr,
+    socket: Arc<UdpSocket>,
+    in_flight: Arc<AtomicUsize>,
+    _handle: Option<Arc<ShutdownHandle>>,
+}
+
+impl UdpRequest {
+    /// Send `response` to the peer the request came from.
+    pub async fn respond(self, response: &[u8]) -> io::Result<()> {
+        self.socket.send_to(response, self.peer).await?;
+        Ok(())
+    }
+}
+
+impl Drop for UdpRequest {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown::ShutdownCoordinator;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn test_udp_requests() {
+        let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server.local_addr().unwrap()).await.unwrap();
+        let coordinator = ShutdownCoordinator::new();
+        let mut requests = UdpRequests::new(server, coordinator.handle());
+        let mut buf = [0; 16];
+
+        client.send(b"query-1").await.unwrap();
+        let first = requests.recv(&mut buf).await.unwrap().unwrap();
+        assert_eq!(&buf[..first.len], b"query-1");
+        assert_eq!(first.peer, client.local_addr().unwrap());
+
+        // Queued when shutdown starts, so still received.
+        client.send(b"query-2").await.unwrap();
+        let mut shutdown = tokio::spawn(coordinator.shutdown());
+        let second = requests.recv(&mut buf).await.unwrap().unwrap();
+        assert_eq!(&buf[..second.len], b"query-2");
+        assert!(requests.recv(&mut buf).await.is_none());
+        assert_eq!(requests.in_flight(), 2);
+
+        second.respond(b"answer-2").await.unwrap();
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"answer-2");
+        tokio::task::yield_now().await;
+        assert!((&mut shutdown).now_or_never().is_none());
+
+        drop(first);
+        assert_eq!(requests.in_flight(), 0);
+        shutdown.await.unwrap();
+    }
+}
@@ -0,0 +1,422 @@
+//! Draining WebSocket sessions, and resuming them in the new process.
+//!
+//! A `WebSocketDrain` is a `ConnectionDrain` for WebSocket sessions. When a session is told to
+//! close, it should send a close frame with the code and reason from
+//! `WebSocketSession::close_frame`, which tells clients whether the service is restarting, so they
+//! can reconnect right away, or stopping. Whether it is restarting is taken from the restart task
+//! in `WebSocketDrainConfig::restart`.
+//!
+//! Sessions can also keep a small amount of state, e.g. their subscriptions, which is handed over
+//! to the new process in a `SessionSnapshot`. Clients that reconnect with the same session ID, e.g.
+//! from a resume token, can then carry on where they left off. The state is captured when it is
+//! handed over, which happens while the sessions are still served by this process, so it should be
+//! the state needed to resume a session rather than anything that changes with every message.
+//!
+//! ```no_run
+//! # use shellflip::drain::websocket::{SessionSnapshot, WebSocketDrain};
+//! # use shellflip::lifecycle::*;
+//! struct App {
+//!     sessions: WebSocketDrain,
+//! }
+//!
+//! #[async_trait::async_trait]
+//! impl LifecycleHandler for App {
+//!     async fn send_to_new_process(&mut self, mut write_pipe: PipeWriter) -> std::io::Result<()> {
+//!         self.sessions.snapshot().write_to(&mut write_pipe).await
+//!     }
+//! }
+//!
+//! # async fn child() -> std::io::Result<()> {
+//! // In the new process:
+//! let mut resumable = SessionSnapshot::default();
+//! if let Some(mut pipe) = receive_from_old_process() {
+//!     resumable = SessionSnapshot::read_from(&mut pipe).await?;
+//! }
+//! // When a client reconnects with a session ID:
+//! if let Some(state) = resumable.take("session-1") {
+//!     // Restore the session's subscriptions...
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use super::{ConnectionDrain, ConnectionDrainConfig, DrainPhase, DrainingConnection};
+use crate::handover::HandoverRecord;
+use crate::shutdown::{ShutdownHandle, ShutdownSignal};
+use crate::RestartWatcher;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const SCHEMA_VERSION: u16 = 1;
+
+const TAG_SESSIONS: u16 = 1;
+
+const TAG_SESSION_ID: u16 = 1;
+const TAG_SESSION_STATE: u16 = 2;
+
+/// Why this process is shutting down, which determines the close frame sent to clients.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ShutdownReason {
+    /// A new process takes over, so clients should reconnect right away.
+    Restart,
+    /// The service is stopping.
+    #[default]
+    Stop,
+}
+
+impl ShutdownReason {
+    /// The close frame to send to clients.
+    pub fn close_frame(self) -> CloseFrame {
+        match self {
+            ShutdownReason::Restart => CloseFrame {
+                code: 1012,
+                reason: "service restart",
+            },
+            ShutdownReason::Stop => CloseFrame {
+                code: 1001,
+                reason: "going away",
+            },
+        }
+    }
+}
+
+/// The status code and reason of a WebSocket close frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: &'static str,
+}
+
+/// Settings for a `WebSocketDrain`.
+#[derive(Clone, Debug)]
+pub struct WebSocketDrainConfig {
+    /// When sessions are told to send a close frame, and when to stop waiting for clients to
+    /// complete the closing handshake.
+    pub connections: ConnectionDrainConfig,
+    /// The maximum length of the state of each session, see `WebSocketSession::set_state`.
+    pub max_state_len: usize,
+    /// The restart task of this process. Once it has handed over to a new process, sessions close
+    /// with `ShutdownReason::Restart`, and otherwise with `ShutdownReason::Stop`, unless the reason
+    /// was set with `WebSocketDrain::set_reason`.
+    pub restart: Option<RestartWatcher>,
+}
+
+impl Default for WebSocketDrainConfig {
+    fn default() -> Self {
+        WebSocketDrainConfig {
+            connections: ConnectionDrainConfig::default(),
+            max_state_len: 4096,
+            restart: None,
+        }
+    }
+}
+
+/// The state passed to `WebSocketSession::set_state` is longer than
+/// `WebSocketDrainConfig::max_state_len`.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[error("session state of {len} bytes exceeds the limit of {limit} bytes")]
+pub struct StateTooLarge {
+    pub len: usize,
+    pub limit: usize,
+}
+
+struct Shared {
+    /// The reason set by `WebSocketDrain::set_reason`, which takes precedence over `restart`.
+    reason: Mutex<Option<ShutdownReason>>,
+    restart: Option<RestartWatcher>,
+    /// The state of each open session that has any, by session ID, along with the key of the
+    /// `WebSocketSession` that set it. Sessions can share an ID, e.g. while a client reconnects
+    /// before its previous connection is closed, and only the session that set the state clears it.
+    states: Mutex<HashMap<String, (u64, Vec<u8>)>>,
+    next_key: AtomicU64,
+    max_state_len: usize,
+}
+
+impl Shared {
+    fn reason(&self) -> ShutdownReason {
+        if let Some(reason) = *self.reason.lock().unwrap() {
+            return reason;
+        }
+        match self.restart.as_ref().and_then(RestartWatcher::result) {
+            Some(Ok(_)) => ShutdownReason::Restart,
+            _ => ShutdownReason::Stop,
+        }
+    }
+}
+
+/// Tells WebSocket sessions when and how to close once shutdown starts, and keeps their state
+/// for handing over. Clones share the same sessions.
+#[derive(Clone)]
+pub struct WebSocketDrain {
+    connections: ConnectionDrain,
+    shared: Arc<Shared>,
+}
+
+impl WebSocketDrain {
+    /// Start a drain when `signal` is signalled. This spawns a task, so it must be called from
+    /// within a tokio runtime.
+    pub fn new(signal: ShutdownSignal, config: WebSocketDrainConfig) -> Self {
+        WebSocketDrain {
+            connections: ConnectionDrain::new(signal, config.connections),
+            shared: Arc::new(Shared {
+                reason: Default::default(),
+                restart: config.restart,
+                states: Default::default(),
+                next_key: Default::default(),
+                max_state_len: config.max_state_len,
+            }),
+        }
+    }
+
+    /// Set the reason for shutting down, before sessions are told to close, instead of taking it
+    /// from `WebSocketDrainConfig::restart`.
+    pub fn set_reason(&self, reason: ShutdownReason) {
+        *self.shared.reason.lock().unwrap() = Some(reason);
+    }
+
+    /// Track a session. `handle` is held until the returned `WebSocketSession` is dropped, which
+    /// should happen once the connection has been closed.
+    pub fn session(&self, id: impl Into<String>, handle: Arc<ShutdownHandle>) -> WebSocketSession {
+        WebSocketSession {
+            conn: self.connections.connection(handle),
+            id: id.into(),
+            key: self.shared.next_key.fetch_add(1, Ordering::Relaxed),
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// The drain of the sessions' connections, e.g. to wait until they are all closed.
+    pub fn connections(&self) -> &ConnectionDrain {
+        &self.connections
+    }
+
+    /// The state of the open sessions, to hand over to the new process.
+    pub fn snapshot(&self) -> SessionSnapshot {
+        let states = self.shared.states.lock().unwrap();
+        SessionSnapshot {
+            states: states
+                .iter()
+                .map(|(id, (_, state))| (id.clone(), state.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// A WebSocket session tracked by a `WebSocketDrain`. Drop it once the connection has been closed.
+pub struct WebSocketSession {
+    conn: DrainingConnection,
+    id: String,
+    key: u64,
+    shared: Arc<Shared>,
+}
+
+impl WebSocketSession {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Set the state to hand over for this session, replacing any previous state, including that of
+    /// other sessions with the same ID.
+    pub fn set_state(&self, state: &[u8]) -> Result<(), StateTooLarge> {
+        if state.len() > self.shared.max_state_len {
+            return Err(StateTooLarge {
+                len: state.len(),
+                limit: self.shared.max_state_len,
+            });
+        }
+        let mut states = self.shared.states.lock().unwrap();
+        states.insert(self.id.clone(), (self.key, state.to_vec()));
+        Ok(())
+    }
+
+    /// Clear the state of this session, unless another session with the same ID replaced it.
+    pub fn clear_state(&self) {
+        let mut states = self.shared.states.lock().unwrap();
+        if states
+            .get(&self.id)
+            .is_some_and(|(key, _)| *key == self.key)
+        {
+            states.remove(&self.id);
+        }
+    }
+
+    /// The close frame to send once the drain reaches `DrainPhase::GoAway`.
+    pub fn close_frame(&self) -> CloseFrame {
+        self.shared.reason().close_frame()
+    }
+
+    /// The current phase of the drain.
+    pub fn phase(&self) -> DrainPhase {
+        self.conn.phase()
+    }
+
+    /// Wait until the drain has reached `phase`, and return the phase it is in. At
+    /// `DrainPhase::GoAway`, send the close frame and wait for the client to complete the closing
+    /// handshake. At `DrainPhase::Close`, close the connection without waiting any longer.
+    pub async fn wait_for(&mut self, phase: DrainPhase) -> DrainPhase {
+        self.conn.wait_for(phase).await
+    }
+}
+
+impl Drop for WebSocketSession {
+    fn drop(&mut self) {
+        self.clear_state();
+    }
+}
+
+/// The state of WebSocket sessions by session ID, handed over to the new process.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionSnapshot {
+    states: HashMap<String, Vec<u8>>,
+}
+
+impl SessionSnapshot {
+    pub fn get(&self, id: &str) -> Option<&[u8]> {
+        self.states.get(id).map(Vec::as_slice)
+    }
+
+    /// Remove and return the state of a session, so that it can only be resumed once.
+    pub fn take(&mut self, id: &str) -> Option<Vec<u8>> {
+        self.states.remove(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    pub fn to_record(&self) -> HandoverRecord {
+        let sessions: Vec<_> = self
+            .states
+            .iter()
+            .map(|(id, state)| {
+                let mut r = HandoverRecord::new(SCHEMA_VERSION);
+                r.put_str(TAG_SESSION_ID, id)
+                    .put_bytes(TAG_SESSION_STATE, state.as_slice());
+                r
+            })
+            .collect();
+        let mut record = HandoverRecord::new(SCHEMA_VERSION);
+        record.put_records(TAG_SESSIONS, &sessions);
+        record
+    }
+
+    pub fn from_record(record: &HandoverRecord) -> io::Result<Self> {
+        let states = record
+            .get_records(TAG_SESSIONS)?
+            .iter()
+            .map(|r| {
+                Ok((
+                    r.get_str(TAG_SESSION_ID)?.unwrap_or_default().into(),
+                    r.get_bytes(TAG_SESSION_STATE).unwrap_or_default().into(),
+                ))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(SessionSnapshot { states })
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        self.to_record().write_to(w).await
+    }
+
+    pub async fn read_from<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Self> {
+        Self::from_record(&HandoverRecord::read_from(r).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown::ShutdownCoordinator;
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    async fn test_websocket_drain() {
+        let coordinator = ShutdownCoordinator::new();
+        let drain = WebSocketDrain::new(
+            ShutdownSignal::from(&*coordinator.handle()),
+            WebSocketDrainConfig {
+                max_state_len: 8,
+                ..Default::default()
+            },
+        );
+        let mut session = drain.session("a", coordinator.handle());
+        let other = drain.session("b", coordinator.handle());
+        session.set_state(b"topic-1").unwrap();
+        other.set_state(b"topic-2").unwrap();
+        assert_eq!(
+            other.set_state(b"too-long-state"),
+            Err(StateTooLarge { len: 14, limit: 8 })
+        );
+        drop(other);
+
+        let (mut r, mut w) = tokio::io::duplex(1024);
+        drain.snapshot().write_to(&mut w).await.unwrap();
+        let mut snapshot = SessionSnapshot::read_from(&mut r).await.unwrap();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot.take("a").as_deref(), Some(&b"topic-1"[..]));
+        assert_eq!(snapshot.take("a"), None);
+
+        assert_eq!(session.close_frame().code, 1001);
+        drain.set_reason(ShutdownReason::Restart);
+        let shutdown = tokio::spawn(coordinator.shutdown());
+        assert_eq!(
+            session.wait_for(DrainPhase::GoAway).await,
+            DrainPhase::GoAway
+        );
+        assert_eq!(
+            session.close_frame(),
+            CloseFrame {
+                code: 1012,
+                reason: "service restart"
+            }
+        );
+        drop(session);
+        drain.connections().closed().await;
+        shutdown.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_same_session_id() {
+        let coordinator = ShutdownCoordinator::new();
+        let signal = ShutdownSignal::from(&*coordinator.handle());
+        let drain = WebSocketDrain::new(signal, WebSocketDrainConfig::default());
+        let previous = drain.session("a", coordinator.handle());
+        previous.set_state(b"old").unwrap();
+        // The client reconnects before its previous connection is closed.
+        let reconnected = drain.session("a", coordinator.handle());
+        reconnected.set_state(b"new").unwrap();
+        drop(previous);
+        assert_eq!(drain.snapshot().get("a"), Some(&b"new"[..]));
+        drop(reconnected);
+        assert!(drain.snapshot().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reason_from_restart() {
+        let (tx, rx) = watch::channel(None);
+        let coordinator = ShutdownCoordinator::new();
+        let drain = WebSocketDrain::new(
+            ShutdownSignal::from(&*coordinator.handle()),
+            WebSocketDrainConfig {
+                restart: Some(RestartWatcher { rx }),
+                ..Default::default()
+            },
+        );
+        let session = drain.session("a", coordinator.handle());
+        assert_eq!(session.close_frame().code, 1001);
+        tx.send(Some(Err(Arc::new(crate::Error::RestartThreadGone))))
+            .unwrap();
+        assert_eq!(session.close_frame().code, 1001);
+        tx.send(Some(Ok(1234))).unwrap();
+        assert_eq!(session.close_frame().code, 1012);
+        drain.set_reason(ShutdownReason::Stop);
+        assert_eq!(session.close_frame().code, 1001);
+    }
+}
@@ -0,0 +1,292 @@
+//! Serving the restart coordination socket on several endpoints at once, each with its own policy,
+//! e.g. a unix socket that local operators may restart the service through, and a vsock port on
+//! which the hypervisor's agent may only query its status.
+//!
+//! Set `RestartConfig::endpoints` to serve further endpoints next to
+//! `RestartConfig::coordination_socket_path`, whose policy is
+//! `RestartConfig::coordination_socket_policy`. Connections to every endpoint speak the same
+//! protocol and are handled by the same code, and the listeners are passed to the new process on
+//! restart, which picks up the listener of each endpoint by its address, so endpoints may be added,
+//! removed or reordered between versions. Requests that the policy of their endpoint doesn't allow
+//! are answered with `RestartResponse::RestartFailed`.
+//!
+//! ```no_run
+//! use shellflip::endpoints::{Endpoint, EndpointAddr, EndpointPolicy};
+//! use shellflip::RestartConfig;
+//!
+//! let config = RestartConfig {
+//!     enabled: true,
+//!     coordination_socket_path: "/run/service/restart.sock".into(),
+//!     endpoints: vec![Endpoint {
+//!         addr: EndpointAddr::Unix("/run/service/status.sock".into()),
+//!         policy: EndpointPolicy {
+//!             read_only: true,
+//!             ..Default::default()
+//!         },
+//!     }],
+//!     ..Default::default()
+//! };
+//! ```
+use crate::diagnostics;
+use crate::files;
+use crate::listeners;
+use crate::restart_coordination_socket::{PeerCredentials, RestartRequest};
+use crate::{Error, RestartResult};
+use std::collections::HashSet;
+use std::io;
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::select;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// The number of connections that may wait for the restart task to pick them up.
+const CONNECTION_BACKLOG: usize = 16;
+
+/// A further endpoint to serve the restart coordination socket on.
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    pub addr: EndpointAddr,
+    pub policy: EndpointPolicy,
+}
+
+/// Where an endpoint listens.
+#[derive(Clone, Debug)]
+pub enum EndpointAddr {
+    /// A unix socket at this path.
+    Unix(PathBuf),
+    /// A vsock port, see the `vsock` module. vsock connections carry no credentials, so the
+    /// policy can't limit the users allowed on it.
+    #[cfg(target_os = "linux")]
+    Vsock(crate::vsock::VsockConfig),
+}
+
+/// Which clients and requests an endpoint serves. The default serves every request of anyone who
+/// can connect.
+#[derive(Clone, Debug, Default)]
+pub struct EndpointPolicy {
+    /// Only serve clients running as one of these users.
+    pub allowed_uids: Option<Vec<u32>>,
+    /// Only serve requests that change nothing: querying the status, and waiting for or
+    /// subscribing to restarts.
+    pub read_only: bool,
+}
+
+impl EndpointPolicy {
+    /// Returns why `request` from the client with credentials `peer` is refused, if it is.
+    pub(crate) fn check(
+        &self,
+        request: &RestartRequest,
+        peer: Option<&PeerCredentials>,
+    ) -> Result<(), String> {
+        if let Some(allowed) = &self.allowed_uids {
+            match peer {
+                Some(peer) if allowed.contains(&peer.uid) => {}
+                Some(peer) => return Err(format!("uid {} is not allowed here", peer.uid)),
+                None => return Err("the credentials of the client are unknown".into()),
+            }
+        }
+        let read_only = matches!(
+            request,
+            RestartRequest::Status | RestartRequest::WaitForRestart | RestartRequest::Subscribe
+        );
+        if self.read_only && !read_only {
+            return Err(format!(
+                "{} requests are not allowed here",
+                crate::audit::describe(request)
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl EndpointAddr {
+    /// The name under which the listener is passed to the new process, which identifies the
+    /// endpoint across versions. Names may not contain `,` or `=`, so those are escaped.
+    fn listener_name(&self) -> String {
+        match self {
+            EndpointAddr::Unix(path) => {
+                let path = path.to_string_lossy();
+                let escaped = path
+                    .replace('%', "%25")
+                    .replace(',', "%2C")
+                    .replace('=', "%3D");
+                format!("shellflip-endpoint-unix:{escaped}")
+            }
+            #[cfg(target_os = "linux")]
+            EndpointAddr::Vsock(config) => {
+                format!("shellflip-endpoint-vsock:{}:{}", config.cid, config.port)
+            }
+        }
+    }
+}
+
+/// Listen on each endpoint, or take over the listener of the old process, and serve it in a new
+/// task. Each connection is sent to the restart task through the channel returned for its
+/// endpoint, along with the policy of the endpoint. The listeners stop when the channels close.
+pub(crate) fn spawn(
+    endpoints: Vec<Endpoint>,
+) -> RestartResult<Vec<(Receiver<UnixStream>, Arc<EndpointPolicy>)>> {
+    let mut names = HashSet::new();
+    for endpoint in &endpoints {
+        if !names.insert(endpoint.addr.listener_name()) {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("endpoint {:?} is given more than once", endpoint.addr),
+            )));
+        }
+        #[cfg(target_os = "linux")]
+        if let EndpointAddr::Vsock(config) = &endpoint.addr {
+            if endpoint.policy.allowed_uids.is_some() {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "vsock port {} can't be limited to users, as vsock connections carry no \
+                         credentials",
+                        config.port
+                    ),
+                )));
+            }
+        }
+    }
+    endpoints
+        .into_iter()
+        .map(|endpoint| {
+            let name = endpoint.addr.listener_name();
+            let connections = match endpoint.addr {
+                EndpointAddr::Unix(path) => spawn_unix(&name, &path)?,
+                #[cfg(target_os = "linux")]
+                EndpointAddr::Vsock(config) => crate::vsock::spawn(&name, config)?,
+            };
+            Ok((connections, Arc::new(endpoint.policy)))
+        })
+        .collect()
+}
+
+fn spawn_unix(name: &str, path: &Path) -> RestartResult<Receiver<UnixStream>> {
+    let listener = listen_unix(name, path).map_err(|source| Error::Bind {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    files::register(name, &listener)?;
+    listener.set_nonblocking(true)?;
+    let listener = UnixListener::from_std(listener)?;
+    let (tx, rx) = channel(CONNECTION_BACKLOG);
+    tokio::spawn(serve_unix(listener, tx));
+    Ok(rx)
+}
+
+fn listen_unix(name: &str, path: &Path) -> io::Result<StdUnixListener> {
+    if let Some(fd) = files::take_inherited(name) {
+        let listener = StdUnixListener::from(fd);
+        match listener.local_addr() {
+            Ok(addr) if addr.as_pathname() == Some(path) => {
+                diagnostics::info!(
+                    "Using restart coordination endpoint {} of the old process",
+                    path.display()
+                );
+                return Ok(listener);
+            }
+            Ok(addr) => diagnostics::info!(
+                "Closing inherited restart coordination endpoint {:?}, as it is not {}",
+                addr,
+                path.display()
+            ),
+            Err(e) => diagnostics::info!("Closing inherited restart coordination endpoint: {}", e),
+        }
+    }
+    listeners::remove_stale_socket(path);
+    StdUnixListener::bind(path)
+}
+
+async fn serve_unix(listener: UnixListener, connector: Sender<UnixStream>) {
+    loop {
+        let accepted = select! {
+            // The restart task completed, so the new process serves the listener from now on.
+            _ = connector.closed() => return,
+            accepted = listener.accept() => accepted,
+        };
+        match accepted {
+            Ok((stream, _)) => {
+                if connector.send(stream).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => diagnostics::error!("Restart coordination endpoint accept error: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restart_coordination_socket::RestartOptions;
+    use std::env;
+    use std::fs::remove_file;
+    use std::process;
+
+    #[test]
+    fn test_check() {
+        let peer = |uid| PeerCredentials {
+            pid: None,
+            uid,
+            gid: 0,
+        };
+        let restart = RestartRequest::TryRestartWith(RestartOptions::default());
+        let policy = EndpointPolicy::default();
+        assert_eq!(policy.check(&restart, None), Ok(()));
+
+        let policy = EndpointPolicy {
+            allowed_uids: Some(vec![0, 1000]),
+            read_only: true,
+        };
+        assert_eq!(
+            policy.check(&RestartRequest::Status, Some(&peer(1000))),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(&restart, Some(&peer(1000))),
+            Err("restart requests are not allowed here".into())
+        );
+        assert_eq!(
+            policy.check(&RestartRequest::Status, Some(&peer(33))),
+            Err("uid 33 is not allowed here".into())
+        );
+        assert!(policy.check(&RestartRequest::Status, None).is_err());
+    }
+
+    #[test]
+    fn test_listener_name() {
+        let unix = |path: &str| EndpointAddr::Unix(path.into()).listener_name();
+        assert_eq!(
+            unix("/run/a,b=c%d.sock"),
+            "shellflip-endpoint-unix:/run/a%2Cb%3Dc%25d.sock"
+        );
+        assert_ne!(unix("/run/a.sock"), unix("/run/b.sock"));
+    }
+
+    #[tokio::test]
+    async fn test_spawn_unix() {
+        let path = env::temp_dir().join(format!("shellflip-endpoint-{}.sock", process::id()));
+        let endpoint = Endpoint {
+            addr: EndpointAddr::Unix(path.clone()),
+            policy: EndpointPolicy {
+                read_only: true,
+                ..Default::default()
+            },
+        };
+        let name = endpoint.addr.listener_name();
+        assert!(spawn(vec![endpoint.clone(), endpoint.clone()]).is_err());
+        let mut served = spawn(vec![endpoint]).unwrap();
+        let (mut connections, policy) = served.remove(0);
+        assert!(policy.read_only);
+
+        let _client = UnixStream::connect(&path).await.unwrap();
+        connections.recv().await.unwrap();
+
+        drop(connections);
+        files::unregister(&name);
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+//! Errors returned when requesting or performing restarts.
+use crate::handshake::IncompatibleBinary;
+use crate::preflight::PreflightRefused;
+use crate::restart_coordination_socket::{
+    AlreadyRestarting, ProcessExited, RestartCancelled, RestartTimedOut, StartupFailed,
+    UnsupportedRequest,
+};
+use crate::{RestartId, RestartOutcome};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+pub type RestartResult<T> = Result<T, Error>;
+
+/// Any failure to request or perform a restart. Match on the variant to tell failure classes
+/// apart, rather than on the error message.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The restart coordination socket could not be bound.
+    #[error("failed to bind restart coordination socket {}: {source}", path.display())]
+    Bind { path: PathBuf, source: io::Error },
+    /// Another instance already serves the restart coordination socket, and
+    /// `RestartConfig::existing_instance` is `ExistingInstance::Refuse`. The pid of the running
+    /// instance is only known on Linux.
+    #[error(
+        "{} already serves restart coordination socket {}",
+        pid.map_or("another instance".into(), |pid| format!("instance {pid}")),
+        path.display()
+    )]
+    AlreadyRunning { path: PathBuf, pid: Option<u32> },
+    /// Another instance already served the restart coordination socket, and was asked to restart
+    /// instead of starting this process, as `RestartConfig::existing_instance` is
+    /// `ExistingInstance::Restart`.
+    #[error(
+        "restarted the running instance instead of starting: restart {} started process {}",
+        .0.restart_id,
+        .0.pid
+    )]
+    RestartedExisting(RestartOutcome),
+    /// The process was started by the `RestartConfig::handshake` check of another process, and
+    /// reported its handshake instead of creating a restart task. It should exit without starting.
+    #[error("reported the handshake instead of starting")]
+    HandshakeReported,
+    /// The restart coordination socket is not enabled in the `RestartConfig`.
+    #[error("no restart coordination socket defined in config")]
+    NoCoordinationSocket,
+    /// The running process could not be reached on the restart coordination socket.
+    #[error("failed to connect to restart coordination socket {}: {source}", path.display())]
+    Connect { path: PathBuf, source: io::Error },
+    /// The other end of the restart coordination socket sent something that could not be
+    /// understood, or closed the connection mid-conversation.
+    #[error("restart coordination protocol error: {0}")]
+    Protocol(String),
+    /// The running process does not understand the request.
+    #[error(transparent)]
+    Unsupported(#[from] UnsupportedRequest),
+    /// The request was rejected because another restart is in progress.
+    #[error(transparent)]
+    AlreadyRestarting(#[from] AlreadyRestarting),
+    /// The restart was cancelled before the new process was ready.
+    #[error(transparent)]
+    Cancelled(#[from] RestartCancelled),
+    /// The restart completed before it could be cancelled.
+    #[error("restart {0} completed before it could be cancelled")]
+    AlreadyCompleted(RestartId),
+    /// The restart did not complete in time.
+    #[error(transparent)]
+    TimedOut(#[from] RestartTimedOut),
+    /// The running process rejected the request or failed to restart, for the given reason. The
+    /// restart ID is not known to clients of running processes that predate restart IDs.
+    #[error(
+        "{}: {reason}",
+        restart_id.as_ref().map_or("request failed".into(), |id| format!("restart {id} failed"))
+    )]
+    Rejected {
+        restart_id: Option<RestartId>,
+        reason: String,
+    },
+    /// The new process could not be spawned.
+    #[error("restart {restart_id} failed to spawn the new process: {source}")]
+    Spawn {
+        restart_id: RestartId,
+        source: io::Error,
+    },
+    /// State could not be sent to the new process.
+    #[error("restart {restart_id} failed to hand over to the new process: {source}")]
+    Handover {
+        restart_id: RestartId,
+        source: io::Error,
+    },
+    /// The new process exited before it was ready.
+    #[error("restart {restart_id} failed: {failure}")]
+    StartupFailed {
+        restart_id: RestartId,
+        failure: StartupFailed,
+    },
+    /// The restart awaited a commit, see `RestartOptions::await_commit`, which did not arrive in
+    /// time, so the new process was killed.
+    #[error("restart {restart_id} was not committed within {timeout:?}")]
+    NotCommitted {
+        restart_id: RestartId,
+        timeout: Duration,
+    },
+    /// The restart was refused or timed out waiting because the process this one was restarted
+    /// from is still running, see `RestartConfig::lingering_generation`.
+    #[error("restart {restart_id} refused: the previous generation (pid {pid}) is still running")]
+    PreviousGenerationRunning { restart_id: RestartId, pid: u32 },
+    /// A check in `RestartConfig::preflight_checks` refused the restart before the new process
+    /// was spawned.
+    #[error("restart {restart_id} refused: {refused}")]
+    PreflightRefused {
+        restart_id: RestartId,
+        refused: PreflightRefused,
+    },
+    /// The new binary can't read what this process hands over, see `RestartConfig::handshake`.
+    #[error("restart {restart_id} refused: {incompatible}")]
+    IncompatibleBinary {
+        restart_id: RestartId,
+        incompatible: IncompatibleBinary,
+    },
+    /// The restart panicked, e.g. in the lifecycle handler, and the new process was killed.
+    #[error("restart {restart_id} panicked: {message}")]
+    Panicked {
+        restart_id: RestartId,
+        message: String,
+    },
+    /// The new process exited shortly after the restart completed.
+    #[error(transparent)]
+    ProcessExited(#[from] ProcessExited),
+    /// The thread that spawns new processes exited, so no further restarts are possible.
+    #[error("restart thread exited")]
+    RestartThreadGone,
+    /// The restart coordination socket stopped accepting connections.
+    #[error("restart coordination socket acceptor terminated")]
+    AcceptorTerminated,
+    /// Any other I/O error, e.g. on the restart coordination socket connection.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Protocol(e.to_string())
+    }
+}
+
+impl Error {
+    pub(crate) fn unexpected_message() -> Self {
+        Error::Protocol("unexpected message received".into())
+    }
+
+    /// The error for a restart that failed in the restart task.
+    pub(crate) fn restart_failed(restart_id: RestartId, e: ChildSpawnError) -> Self {
+        match e {
+            ChildSpawnError::RestartThreadGone => Error::RestartThreadGone,
+            ChildSpawnError::ChildError(source) => Error::Spawn { restart_id, source },
+            ChildSpawnError::HandoverError(source) => Error::Handover { restart_id, source },
+            ChildSpawnError::StartupFailed(failure) => Error::StartupFailed {
+                restart_id,
+                failure,
+            },
+            ChildSpawnError::NotCommitted(timeout) => Error::NotCommitted {
+                restart_id,
+                timeout,
+            },
+            ChildSpawnError::PreviousGenerationRunning(pid) => {
+                Error::PreviousGenerationRunning { restart_id, pid }
+            }
+            ChildSpawnError::PreflightRefused(refused) => Error::PreflightRefused {
+                restart_id,
+                refused,
+            },
+            ChildSpawnError::IncompatibleBinary(incompatible) => Error::IncompatibleBinary {
+                restart_id,
+                incompatible,
+            },
+            ChildSpawnError::Panicked(message) => Error::Panicked {
+                restart_id,
+                message,
+            },
+        }
+    }
+}
+
+/// Indicates an error that happened during child forking.
+#[derive(Error, Debug)]
+pub enum ChildSpawnError {
+    #[error("Restart thread exited")]
+    RestartThreadGone,
+    #[error("Child failed to start: {0}")]
+    ChildError(io::Error),
+    #[error("Failed to hand over to child: {0}")]
+    HandoverError(io::Error),
+    #[error("Child failed to start: {0}")]
+    StartupFailed(StartupFailed),
+    #[error("Restart was not committed within {0:?}")]
+    NotCommitted(Duration),
+    #[error("The previous generation (pid {0}) is still running")]
+    PreviousGenerationRunning(u32),
+    #[error("{0}")]
+    PreflightRefused(PreflightRefused),
+    #[error("{0}")]
+    IncompatibleBinary(IncompatibleBinary),
+    #[error("Restart panicked: {0}")]
+    Panicked(String),
+}
+
+impl From<io::Error> for ChildSpawnError {
+    fn from(e: io::Error) -> Self {
+        ChildSpawnError::ChildError(e)
+    }
+}
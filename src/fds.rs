@@ -0,0 +1,378 @@
+//! Control over which file descriptors are inherited by the new process.
+//!
+//! Any fd that does not have FD_CLOEXEC set is silently inherited across exec. This is easy to get
+//! wrong: a library opening a file or socket without `O_CLOEXEC` will leak it into every future
+//! generation of the process, along with database connections or secrets it may refer to.
+//! `FdLeakPolicy` lets you audit the set of fds that would be inherited just before the child is
+//! spawned, and optionally close anything that was not explicitly registered for inheritance.
+//!
+//! # Fd numbering
+//!
+//! The new process finds the fds it inherits as follows:
+//!
+//! - `RestartConfig::inherited_fds` and the fds returned by `LifecycleHandler::fds_for_new_process`
+//!   keep their numbers.
+//! - Files registered with the `files` module keep their numbers, and are looked up by name.
+//! - Listeners from the `listeners` module keep their numbers, unless
+//!   `RestartConfig::emit_listen_fds` moves them to consecutive numbers starting at 3. Either way
+//!   they are looked up by name.
+//!
+//! Numbers that are not looked up by name are a contract between the old and new versions of the
+//! application, which breaks if a version opens its fds in a different order. Set
+//! `RestartConfig::send_fd_manifest` to also describe every fd above in an `FdManifest`, which is
+//! sent ahead of any state on the handover pipe. The new process reads it with `fd_manifest`, or
+//! implicitly when calling `lifecycle::receive_from_old_process`, so both versions must understand
+//! the manifest before it is enabled.
+use crate::{diagnostics, ENV_HANDOVER_PIPE};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::mem::ManuallyDrop;
+use std::os::fd::{FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Set in the new process if an `FdManifest` precedes the state on the handover pipe.
+pub(crate) const ENV_FD_MANIFEST: &str = "OXY_FD_MANIFEST";
+/// The manifest is preceded by its length, and must not be larger than this.
+const MAX_MANIFEST_LEN: u32 = 1 << 20;
+
+/// The manifest received from the old process.
+static MANIFEST: OnceLock<Option<FdManifest>> = OnceLock::new();
+
+/// What to do with file descriptors that would be inherited by the new process without having
+/// been registered for inheritance.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FdLeakPolicy {
+    /// Do not inspect open fds. This is the historical behaviour.
+    #[default]
+    Ignore,
+    /// Log a warning for each unregistered fd that will be inherited.
+    Report,
+    /// Log a warning and close each unregistered fd in the child before exec.
+    Close,
+}
+
+/// A file descriptor that would be inherited by the child without being registered.
+#[derive(Debug)]
+pub(crate) struct LeakedFd {
+    pub(crate) fd: RawFd,
+    /// What the fd refers to, if it could be determined.
+    pub(crate) target: Option<PathBuf>,
+}
+
+/// Returns all open fds of this process that do not have FD_CLOEXEC set and are not in `allowed`.
+/// Standard input, output and error are always allowed.
+///
+/// Other threads may open or close fds concurrently, so the result is a best-effort snapshot.
+pub(crate) fn find_leaked_fds(allowed: &[RawFd]) -> io::Result<Vec<LeakedFd>> {
+    let mut leaked = Vec::new();
+
+    for entry in fs::read_dir(fd_dir())? {
+        let fd: RawFd = match entry?.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(fd) => fd,
+            None => continue,
+        };
+        if fd <= libc::STDERR_FILENO || allowed.contains(&fd) {
+            continue;
+        }
+
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        // The fd may have been the directory handle used for this iteration, or closed by another
+        // thread in the meantime. Either way it won't be inherited.
+        if flags < 0 || flags & libc::FD_CLOEXEC != 0 {
+            continue;
+        }
+
+        leaked.push(LeakedFd {
+            fd,
+            target: fs::read_link(fd_dir().join(fd.to_string())).ok(),
+        });
+    }
+
+    Ok(leaked)
+}
+
+/// What an fd inherited from the old process is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FdKind {
+    /// An fd from `RestartConfig::inherited_fds` or `LifecycleHandler::fds_for_new_process`.
+    Inherited,
+    /// A member of a listener group from the `listeners` module.
+    Listener,
+    /// A file registered with the `files` module.
+    File,
+    /// A listener group that the old process hands over later, on the socket with this fd
+    /// number, see `listeners::deferred`.
+    DeferredListener,
+    /// An fd of a kind that this version does not know about.
+    #[serde(other)]
+    Unknown,
+}
+
+/// An fd inherited from the old process.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub kind: FdKind,
+    /// The name of the listener group or file. Fds of kind `FdKind::Inherited` have no name.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The fd number in the new process.
+    pub fd: RawFd,
+    /// How the fd was set up, if it is a packet or raw socket.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packet: Option<PacketSocket>,
+}
+
+/// A packet or raw socket, as described in the manifest so the new process can check that it
+/// carried over as expected, see `files::packet`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PacketSocket {
+    /// `AF_PACKET`, `AF_INET` or `AF_INET6`.
+    pub family: i32,
+    /// `SOCK_RAW` or `SOCK_DGRAM`.
+    pub socket_type: i32,
+    /// The protocol, as passed to `socket(2)`.
+    pub protocol: i32,
+    /// The fanout group the socket is a member of, if any, see `PacketFanout`.
+    #[serde(default)]
+    pub fanout: Option<PacketFanout>,
+    /// The number of instructions of the attached classic BPF filter, if any.
+    #[serde(default)]
+    pub filter_len: Option<u32>,
+}
+
+/// A fanout group of `AF_PACKET` sockets, see `PACKET_FANOUT` in `packet(7)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PacketFanout {
+    pub group: u16,
+    /// The mode, e.g. `PACKET_FANOUT_HASH`, combined with flags such as
+    /// `PACKET_FANOUT_FLAG_DEFRAG`.
+    pub mode: u16,
+}
+
+/// Every fd the new process inherits from the old one, along with what it is for. See the module
+/// documentation.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FdManifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl FdManifest {
+    pub(crate) fn push(&mut self, kind: FdKind, name: Option<&str>, fd: RawFd) {
+        self.entries.push(ManifestEntry {
+            kind,
+            name: name.map(Into::into),
+            fd,
+            packet: None,
+        });
+    }
+
+    /// Describe the packet and raw sockets among the entries, which must all be open.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn describe_packet_sockets(&mut self) {
+        for entry in &mut self.entries {
+            let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(entry.fd) };
+            entry.packet = crate::files::packet::describe(fd).ok().flatten();
+        }
+    }
+
+    /// Returns the numbers of the fds of `kind` called `name`, in the order they were passed.
+    pub fn get<'a>(&'a self, kind: FdKind, name: &'a str) -> impl Iterator<Item = RawFd> + 'a {
+        self.entries
+            .iter()
+            .filter(move |e| e.kind == kind && e.name.as_deref() == Some(name))
+            .map(|e| e.fd)
+    }
+
+    /// The manifest as it is sent on the handover pipe, preceded by its length.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let json = serde_json::to_vec(self).expect("manifest is serializable");
+        let mut encoded = (json.len() as u32).to_be_bytes().to_vec();
+        encoded.extend(json);
+        encoded
+    }
+
+    fn decode(mut reader: impl Read) -> io::Result<Self> {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len);
+        if len > MAX_MANIFEST_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("fd manifest of {len} bytes is too large"),
+            ));
+        }
+        let mut json = vec![0; len as usize];
+        reader.read_exact(&mut json)?;
+        serde_json::from_slice(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Returns the manifest of the fds inherited from the old process, if it sent one. The first call
+/// reads it from the handover pipe, so it must not be made after reading state from the pipe
+/// yourself. `lifecycle::receive_from_old_process` calls this first.
+///
+/// The behaviour of this function is undefined if the environment variables used by this crate to
+/// pass file descriptor numbers were set by something other than shellflip spawning a new instance
+/// of the calling process.
+pub fn fd_manifest() -> Option<&'static FdManifest> {
+    MANIFEST
+        .get_or_init(|| {
+            env::var_os(ENV_FD_MANIFEST)?;
+            let fd: RawFd = env::var(ENV_HANDOVER_PIPE).ok()?.parse().ok()?;
+            // The pipe is still needed for the state that follows.
+            let pipe = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
+            match FdManifest::decode(&*pipe) {
+                Ok(manifest) => Some(manifest),
+                Err(e) => {
+                    diagnostics::error!("Failed to read fd manifest from the old process: {}", e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// Whether any of `fds` is close-on-exec.
+pub(crate) fn any_cloexec(fds: &[RawFd]) -> io::Result<bool> {
+    for &fd in fds {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if flags & libc::FD_CLOEXEC != 0 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Clears FD_CLOEXEC on fds so that a process spawned without running code before exec inherits
+/// them, and sets it again when dropped. Processes spawned by other threads in the meantime inherit
+/// them too, so this is only used on copies made for the new process.
+pub(crate) struct Inheritable {
+    fds: Vec<RawFd>,
+}
+
+impl Inheritable {
+    pub(crate) fn new(fds: &[RawFd]) -> io::Result<Self> {
+        let mut inheritable = Inheritable { fds: Vec::new() };
+        for &fd in fds {
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+            if flags < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if flags & libc::FD_CLOEXEC == 0 || inheritable.fds.contains(&fd) {
+                continue;
+            }
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            inheritable.fds.push(fd);
+        }
+        Ok(inheritable)
+    }
+}
+
+impl Drop for Inheritable {
+    fn drop(&mut self) {
+        for &fd in &self.fds {
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+            if flags >= 0 {
+                unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) };
+            }
+        }
+    }
+}
+
+fn fd_dir() -> PathBuf {
+    if cfg!(target_os = "linux") {
+        PathBuf::from("/proc/self/fd")
+    } else {
+        PathBuf::from("/dev/fd")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    fn inheritable_pipe() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn test_find_leaked_fds() {
+        let (r, w) = inheritable_pipe();
+        let (r, w) = (r.as_raw_fd(), w.as_raw_fd());
+
+        let leaked: Vec<RawFd> = find_leaked_fds(&[w])
+            .unwrap()
+            .into_iter()
+            .map(|l| l.fd)
+            .collect();
+        assert!(leaked.contains(&r));
+        assert!(!leaked.contains(&w));
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let mut manifest = FdManifest::default();
+        manifest.push(FdKind::Inherited, None, 5);
+        manifest.push(FdKind::Listener, Some("http"), 3);
+        manifest.push(FdKind::Listener, Some("http"), 4);
+        manifest.push(FdKind::File, Some("http"), 6);
+
+        let decoded = FdManifest::decode(&manifest.encode()[..]).unwrap();
+        assert_eq!(decoded, manifest);
+        assert_eq!(
+            decoded.get(FdKind::Listener, "http").collect::<Vec<_>>(),
+            [3, 4]
+        );
+        assert!(FdManifest::decode(&manifest.encode()[..10]).is_err());
+    }
+
+    #[test]
+    fn test_manifest_unknown_kind() {
+        let json = br#"{"entries":[{"kind":"socket_map","name":"x","fd":7}]}"#;
+        let mut encoded = (json.len() as u32).to_be_bytes().to_vec();
+        encoded.extend(json);
+        let decoded = FdManifest::decode(&encoded[..]).unwrap();
+        assert_eq!(decoded.entries[0].kind, FdKind::Unknown);
+        assert_eq!(decoded.entries[0].fd, 7);
+    }
+
+    #[test]
+    fn test_cloexec_fds_are_not_leaked() {
+        let file = fs::File::open("/dev/null").unwrap();
+        let leaked = find_leaked_fds(&[]).unwrap();
+        assert!(!leaked.iter().any(|l| l.fd == file.as_raw_fd()));
+    }
+
+    #[test]
+    fn test_inheritable() {
+        let (r, w) = inheritable_pipe();
+        let cloexec = |fd: &OwnedFd| {
+            let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+            flags & libc::FD_CLOEXEC != 0
+        };
+        unsafe { libc::fcntl(r.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+
+        assert!(any_cloexec(&[w.as_raw_fd(), r.as_raw_fd()]).unwrap());
+        assert!(!any_cloexec(&[w.as_raw_fd()]).unwrap());
+
+        let inheritable = Inheritable::new(&[r.as_raw_fd(), w.as_raw_fd()]).unwrap();
+        assert!(!cloexec(&r));
+        assert!(!cloexec(&w));
+        drop(inheritable);
+        // Only the flags that were cleared are restored.
+        assert!(cloexec(&r));
+        assert!(!cloexec(&w));
+    }
+}
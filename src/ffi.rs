@@ -0,0 +1,250 @@
+//! C bindings for the admin client, for sidecars and agents that are not written in Rust.
+//!
+//! Build a shared library with:
+//!
+//! ```text
+//! cargo rustc --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! The declarations are in `include/shellflip.h`. Every function returns `SHELLFLIP_OK` or a
+//! negative error code, and the message of the last error on the calling thread is available
+//! from `shellflip_last_error`. Each call blocks until the running process responds.
+use crate::admin::AdminClient;
+use crate::{Error, RestartResult};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+pub const SHELLFLIP_OK: c_int = 0;
+/// A required pointer was null or a string was not valid UTF-8.
+pub const SHELLFLIP_ERR_INVALID_ARGUMENT: c_int = -1;
+/// The restart coordination socket could not be connected to.
+pub const SHELLFLIP_ERR_CONNECT: c_int = -2;
+/// The running process rejected the request, or the restart failed.
+pub const SHELLFLIP_ERR_REJECTED: c_int = -3;
+/// The running process does not support the request.
+pub const SHELLFLIP_ERR_UNSUPPORTED: c_int = -4;
+/// Any other failure, e.g. a protocol error.
+pub const SHELLFLIP_ERR_OTHER: c_int = -5;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+fn error_code(e: &Error) -> c_int {
+    match e {
+        Error::Connect { .. } | Error::NoCoordinationSocket => SHELLFLIP_ERR_CONNECT,
+        Error::Rejected { .. }
+        | Error::AlreadyRestarting(_)
+        | Error::StartupFailed { .. }
+        | Error::PreflightRefused { .. }
+        | Error::IncompatibleBinary { .. }
+        | Error::PreviousGenerationRunning { .. }
+        | Error::Cancelled(_)
+        | Error::ProcessExited(_) => SHELLFLIP_ERR_REJECTED,
+        Error::Unsupported(_) => SHELLFLIP_ERR_UNSUPPORTED,
+        _ => SHELLFLIP_ERR_OTHER,
+    }
+}
+
+/// Run a request against the socket at `socket_path`, converting failures and panics into error
+/// codes.
+unsafe fn call<T, F, Fut>(socket_path: *const c_char, f: F) -> Result<T, c_int>
+where
+    F: FnOnce(AdminClient) -> Fut,
+    Fut: Future<Output = RestartResult<T>>,
+{
+    if socket_path.is_null() {
+        set_last_error("socket path is null".into());
+        return Err(SHELLFLIP_ERR_INVALID_ARGUMENT);
+    }
+    let socket_path = match CStr::from_ptr(socket_path).to_str() {
+        Ok(path) => path,
+        Err(e) => {
+            set_last_error(format!("socket path is not valid UTF-8: {e}"));
+            return Err(SHELLFLIP_ERR_INVALID_ARGUMENT);
+        }
+    };
+
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(f(AdminClient::new(socket_path)))
+    }));
+    match res {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            Err(error_code(&e))
+        }
+        Err(_) => {
+            set_last_error("panicked while handling the request".into());
+            Err(SHELLFLIP_ERR_OTHER)
+        }
+    }
+}
+
+/// Restart the process listening on `socket_path`, and store the pid of the new process in
+/// `pid_out` if it is not null.
+///
+/// # Safety
+///
+/// `socket_path` must be a valid nul-terminated string, and `pid_out` must be null or valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn shellflip_request_restart(
+    socket_path: *const c_char,
+    pid_out: *mut u32,
+) -> c_int {
+    match call(socket_path, |client| async move {
+        client.restart(Default::default()).await
+    }) {
+        Ok(outcome) => {
+            if !pid_out.is_null() {
+                *pid_out = outcome.pid;
+            }
+            SHELLFLIP_OK
+        }
+        Err(code) => code,
+    }
+}
+
+/// Query the restart status of the process listening on `socket_path`. On success, `json_out` is
+/// set to a JSON object that must be freed with `shellflip_free_string`.
+///
+/// # Safety
+///
+/// `socket_path` must be a valid nul-terminated string, and `json_out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn shellflip_status(
+    socket_path: *const c_char,
+    json_out: *mut *mut c_char,
+) -> c_int {
+    if json_out.is_null() {
+        set_last_error("json_out is null".into());
+        return SHELLFLIP_ERR_INVALID_ARGUMENT;
+    }
+    match call(socket_path, |client| async move { client.status().await }) {
+        Ok(status) => {
+            let json = serde_json::to_string(&status).expect("status is serializable");
+            *json_out = CString::new(json).unwrap_or_default().into_raw();
+            SHELLFLIP_OK
+        }
+        Err(code) => code,
+    }
+}
+
+/// Ask the process listening on `socket_path` to shut down.
+///
+/// # Safety
+///
+/// `socket_path` must be a valid nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn shellflip_shutdown(socket_path: *const c_char) -> c_int {
+    match call(socket_path, |client| async move { client.shutdown().await }) {
+        Ok(()) => SHELLFLIP_OK,
+        Err(code) => code,
+    }
+}
+
+/// Returns the message of the last error on this thread, or null if there was none. The string
+/// is owned by the library and valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn shellflip_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Free a string returned by this library.
+///
+/// # Safety
+///
+/// `s` must be null or a string returned by this library that has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn shellflip_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restart_coordination_socket::{
+        RestartCoordinationSocket, RestartMessage, RestartResponse,
+    };
+    use crate::StatusReport;
+    use std::thread;
+    use std::time::Duration;
+
+    fn last_error() -> String {
+        let e = shellflip_last_error();
+        assert!(!e.is_null());
+        unsafe { CStr::from_ptr(e) }.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_status() {
+        let path = std::env::temp_dir().join(format!("shellflip-ffi-{}.sock", std::process::id()));
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            sock.set_nonblocking(true).unwrap();
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async {
+                let sock = tokio::net::UnixStream::from_std(sock).unwrap();
+                let mut rpc = RestartCoordinationSocket::new(sock);
+                rpc.receive_message().await.unwrap();
+                let status = StatusReport {
+                    pid: 42,
+                    generation: 1,
+                    uptime: Duration::from_secs(1),
+                    restart_id: None,
+                    in_progress: None,
+                    active_handles: None,
+                    drain: None,
+                    last_error: None,
+                    ancestry: vec![],
+                };
+                let response = RestartMessage::Response(RestartResponse::Status(status));
+                rpc.send_message(response).await.unwrap();
+            });
+        });
+
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let mut json = ptr::null_mut();
+        assert_eq!(
+            unsafe { shellflip_status(c_path.as_ptr(), &mut json) },
+            SHELLFLIP_OK
+        );
+        let status = unsafe { CStr::from_ptr(json) }.to_str().unwrap();
+        assert!(status.contains(r#""pid":42"#));
+        unsafe { shellflip_free_string(json) };
+        server.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut pid = 0;
+        assert_eq!(
+            unsafe { shellflip_request_restart(ptr::null(), &mut pid) },
+            SHELLFLIP_ERR_INVALID_ARGUMENT
+        );
+        assert_eq!(last_error(), "socket path is null");
+
+        let c_path = CString::new("/nonexistent/shellflip.sock").unwrap();
+        assert_eq!(
+            unsafe { shellflip_shutdown(c_path.as_ptr()) },
+            SHELLFLIP_ERR_CONNECT
+        );
+        assert!(last_error().contains("/nonexistent/shellflip.sock"));
+    }
+}
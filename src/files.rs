@@ -0,0 +1,467 @@
+//! Open files that are passed to the new process by name.
+//!
+//! This works like the `listeners` module, for file descriptors that are not listening sockets.
+//! `register` keeps a copy of an fd, which the new process inherits along with its name, and
+//! picks up with `take_inherited`. Names may not be empty or contain `,` or `=`. Fds that the
+//! restart requester passes with `RestartConfig::request_restart_with_fds` are picked up the same
+//! way.
+//!
+//! # Locks
+//!
+//! Services often hold an advisory lock, e.g. on their data directory, to make sure only one
+//! instance uses it. The new process cannot simply take the lock again: the old process still
+//! holds it until it has drained, so blocking on it deadlocks the restart, and not blocking makes
+//! the new process fail to start. `lock_file` solves this by passing the fd that holds the lock to
+//! the new process, so that both processes hold the lock during the restart, and the new process
+//! keeps it once the old one exits.
+//!
+//! This relies on the semantics of `flock(2)` locks, which belong to the open file description
+//! rather than to a process. The lock is shared by every copy of the fd, in any process, and only
+//! released once all of them are closed, or when one of them unlocks it explicitly. So:
+//!
+//! - Don't unlock the file in the old process as part of shutting down, as that releases the lock
+//!   for the new process as well. Closing the fd, or exiting, is enough.
+//! - If the new process fails to start, its copy is closed when it exits, and the old process
+//!   still holds the lock.
+//! - POSIX record locks taken with `fcntl(F_SETLK)` belong to a process, and are not inherited by
+//!   the new process, which is a child of the old one. Passing their fd does not pass the lock.
+//!   Linux open file description locks (`F_OFD_SETLK`) behave like `flock` locks and can be passed.
+//!
+//! # Database files
+//!
+//! Embedded databases can be handed over without closing and reopening them, which would
+//! otherwise mean running recovery or replaying a write-ahead log in the new process. Open the
+//! files with `open_file`, and the new process gets the same open files back. Libraries that only
+//! accept a path can usually be given `/proc/self/fd/N` for an inherited fd.
+//!
+//! Both processes have the files open during the restart, so the old process must stop writing
+//! before the new one starts to. Implement `LifecycleHandler::quiesce_writes` to do so, e.g. by
+//! finishing the current transaction, checkpointing the write-ahead log and holding off further
+//! writes. If the restart fails, `LifecycleHandler::resume_writes` is called so the old process
+//! can carry on.
+//!
+//! Closing any fd of a file drops all POSIX record locks this process holds on it. The new process
+//! inherits the copies that `register` keeps, without shellflip opening or closing any others, so
+//! restarting doesn't drop the locks of databases that rely on them, like SQLite. Unregistering a
+//! file, or registering another one under its name, closes the copy and so does drop them.
+//!
+//! # Other kinds of fds
+//!
+//! Submodules reuse inherited fds of kinds that carry kernel state which would be lost by opening
+//! them again, after checking that the inherited fd is what the new process expects.
+//!
+//! Many of these fds are read from, e.g. sockets, devices and event queues, and each message,
+//! packet or event on them is received by whichever process reads it first. The old process must
+//! therefore stop reading before the new one starts to, e.g. in `LifecycleHandler::quiesce_writes`,
+//! and resume in `LifecycleHandler::resume_writes` if the restart fails. Anything it read must be
+//! dealt with by then too, like answering requests, as the new process can't pick up where it left
+//! off.
+use crate::diagnostics;
+use crate::pipes::set_cloexec;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+#[cfg(target_os = "linux")]
+pub mod bpf;
+#[cfg(target_os = "linux")]
+pub mod fsnotify;
+#[cfg(target_os = "linux")]
+pub mod netfilter;
+#[cfg(target_os = "linux")]
+pub mod netlink;
+#[cfg(target_os = "linux")]
+pub mod packet;
+#[cfg(target_os = "linux")]
+pub mod pty;
+#[cfg(target_os = "linux")]
+pub mod transparent;
+#[cfg(target_os = "linux")]
+pub mod tun;
+
+pub(crate) const ENV_FILES: &str = "OXY_FILES";
+
+/// Files inherited from the old process that have not been claimed yet.
+static INHERITED: OnceLock<Mutex<BTreeMap<String, OwnedFd>>> = OnceLock::new();
+/// Copies of the files to pass to the next process. They are shared with `NewProcessFiles` rather
+/// than duplicated, as closing a duplicate would drop POSIX record locks.
+static REGISTERED: Mutex<BTreeMap<String, Arc<OwnedFd>>> = Mutex::new(BTreeMap::new());
+
+/// Pass a copy of `fd` to the next process under `name`, replacing any fd registered earlier under
+/// the same name. The copy refers to the same open file description, so it shares the file offset,
+/// status flags and `flock` locks with `fd`. Fails if `name` is empty or contains `,` or `=`.
+pub fn register(name: &str, fd: impl AsFd) -> io::Result<()> {
+    if name.is_empty() || name.contains([',', '=']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid file name {name:?}"),
+        ));
+    }
+    let fd = fd.as_fd().try_clone_to_owned()?;
+    REGISTERED
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), Arc::new(fd));
+    Ok(())
+}
+
+/// Stop passing the fd registered under `name` to the next process.
+pub fn unregister(name: &str) {
+    REGISTERED.lock().unwrap().remove(name);
+}
+
+/// Returns the fd the old process registered under `name`, if any. Each inherited fd can only be
+/// taken once. It is not passed on to the next process unless it is registered again.
+pub fn take_inherited(name: &str) -> Option<OwnedFd> {
+    inherited().lock().unwrap().remove(name)
+}
+
+/// Takes every inherited fd whose name starts with `prefix`, for kinds of fds that are registered
+/// under names the new process doesn't know in advance.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn take_inherited_with_prefix(prefix: &str) -> BTreeMap<String, OwnedFd> {
+    let mut inherited = inherited().lock().unwrap();
+    let names: Vec<String> = inherited
+        .keys()
+        .filter(|name| name.starts_with(prefix))
+        .cloned()
+        .collect();
+    names
+        .into_iter()
+        .filter_map(|name| Some((name.clone(), inherited.remove(&name)?)))
+        .collect()
+}
+
+/// Opens the file at `path`, creating it if needed, and takes an exclusive `flock` lock on it. The
+/// file is passed to the next process under `name`, which keeps holding the lock.
+///
+/// If the old process passed a file under `name` that is still the file at `path`, it is reused
+/// along with its lock. Otherwise this fails with `io::ErrorKind::WouldBlock` instead of waiting if
+/// another process holds the lock. See the module documentation for how the lock is shared.
+pub fn lock_file(name: &str, path: impl AsRef<Path>) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    let file = open_file(name, path, &options)?;
+    // Locking the open file description that holds the lock again succeeds, so this also checks
+    // that the inherited lock is still held.
+    if let Err(e) = flock(file.as_fd(), libc::LOCK_EX | libc::LOCK_NB) {
+        unregister(name);
+        return Err(e);
+    }
+    Ok(file)
+}
+
+/// Opens the file at `path` with `options`, or reuses the file the old process passed under
+/// `name` if it is still the file at `path`. The file is passed to the next process under `name`.
+///
+/// An inherited file keeps the access mode and status flags it was opened with in the old process,
+/// as well as its file offset, which the old process shares until it closes its copies.
+pub fn open_file(name: &str, path: impl AsRef<Path>, options: &OpenOptions) -> io::Result<File> {
+    let path = path.as_ref();
+    let file = match take_inherited(name).map(File::from) {
+        Some(file) if is_same_file(&file, path) => {
+            diagnostics::info!(
+                "Using file {} on {:?} inherited from the old process",
+                name,
+                path
+            );
+            file
+        }
+        other => {
+            if other.is_some() {
+                diagnostics::info!("Closing inherited file {}, as it is not {:?}", name, path);
+            }
+            options.open(path)?
+        }
+    };
+
+    register(name, &file)?;
+    Ok(file)
+}
+
+/// Reuses the fd the old process passed under `name` if `reuse` accepts it, which may also adjust
+/// it, or else opens a new one with `open`. The fd is passed to the next process under `name`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn reuse_or_open(
+    name: &str,
+    reuse: impl FnOnce(BorrowedFd<'_>) -> io::Result<()>,
+    open: impl FnOnce() -> io::Result<OwnedFd>,
+) -> io::Result<OwnedFd> {
+    let fd = match take_inherited(name) {
+        Some(fd) => match reuse(fd.as_fd()) {
+            Ok(()) => {
+                diagnostics::info!("Using fd {} inherited from the old process", name);
+                fd
+            }
+            Err(e) => {
+                diagnostics::info!("Closing inherited fd {}: {}", name, e);
+                open()?
+            }
+        },
+        None => open()?,
+    };
+    register(name, &fd)?;
+    Ok(fd)
+}
+
+/// Reads an integer socket option.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn sockopt(
+    fd: BorrowedFd<'_>,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    get_sockopt(fd, level, name, &mut value)?;
+    Ok(value)
+}
+
+/// Reads a socket option into `value`. Options longer than `T` are truncated.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn get_sockopt<T>(
+    fd: BorrowedFd<'_>,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &mut T,
+) -> io::Result<()> {
+    let mut len = std::mem::size_of::<T>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            (value as *mut T).cast(),
+            &mut len,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Sets a socket option to `value`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn set_sockopt<T>(
+    fd: BorrowedFd<'_>,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    let len = std::mem::size_of::<T>() as libc::socklen_t;
+    let res =
+        unsafe { libc::setsockopt(fd.as_raw_fd(), level, name, (value as *const T).cast(), len) };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn is_same_file(file: &File, path: &Path) -> bool {
+    match (file.metadata(), path.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+fn flock(fd: BorrowedFd<'_>, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        if unsafe { libc::flock(fd.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+fn inherited() -> &'static Mutex<BTreeMap<String, OwnedFd>> {
+    INHERITED.get_or_init(|| {
+        let value = env::var(ENV_FILES).unwrap_or_default();
+        let files = parse_files(&value)
+            .into_iter()
+            .filter_map(|(name, fd)| {
+                // Don't leak inherited files into the next process unless they are registered.
+                if let Err(e) = set_cloexec(fd) {
+                    diagnostics::warn!("Ignoring inherited file {} with fd {}: {}", name, fd, e);
+                    return None;
+                }
+                Some((name, unsafe { OwnedFd::from_raw_fd(fd) }))
+            })
+            .collect();
+        Mutex::new(files)
+    })
+}
+
+fn parse_files(value: &str) -> BTreeMap<String, RawFd> {
+    value
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let parsed = entry
+                .split_once('=')
+                .and_then(|(name, fd)| Some((name.to_string(), fd.parse().ok()?)));
+            if parsed.is_none() {
+                diagnostics::warn!("Ignoring malformed inherited file {:?}", entry);
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// The registered files for a new process, which are kept open until it is spawned even if they
+/// are unregistered meanwhile. The new process inherits the registered fds themselves.
+pub(crate) struct NewProcessFiles {
+    files: Vec<(String, Arc<OwnedFd>)>,
+}
+
+pub(crate) fn for_new_process() -> io::Result<NewProcessFiles> {
+    let files = REGISTERED
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, fd)| (name.clone(), Arc::clone(fd)))
+        .collect();
+    Ok(NewProcessFiles { files })
+}
+
+impl NewProcessFiles {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Pass `fd` under `name` as well, instead of any registered file of the same name.
+    pub(crate) fn insert(&mut self, name: String, fd: OwnedFd) {
+        self.files.retain(|(n, _)| *n != name);
+        self.files.push((name, Arc::new(fd)));
+    }
+
+    pub(crate) fn fds(&self) -> impl Iterator<Item = RawFd> + '_ {
+        self.files.iter().map(|(_, fd)| fd.as_raw_fd())
+    }
+
+    /// The name of each file and its fd number, which is the same in the new process.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&str, RawFd)> + '_ {
+        self.files
+            .iter()
+            .map(|(name, fd)| (name.as_str(), fd.as_raw_fd()))
+    }
+
+    /// The value of `ENV_FILES`.
+    pub(crate) fn env(&self) -> String {
+        self.files
+            .iter()
+            .map(|(name, fd)| format!("{}={}", name, fd.as_raw_fd()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pretend the registered copy of a file was inherited from the old process.
+    pub(super) fn inherit(name: &str) {
+        let fd = REGISTERED.lock().unwrap().remove(name).unwrap();
+        let fd = Arc::try_unwrap(fd).unwrap();
+        inherited().lock().unwrap().insert(name.into(), fd);
+    }
+
+    /// Pass the fd registered under `name` on as if to a new process, and check that `reopen`
+    /// reuses it rather than opening another one. The fd is unregistered afterwards.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(super) fn assert_reused<T: AsFd>(name: &str, reopen: impl FnOnce() -> io::Result<T>) -> T {
+        let registered = REGISTERED.lock().unwrap()[name].as_raw_fd();
+        inherit(name);
+        let reopened = reopen().unwrap();
+        assert_eq!(
+            reopened.as_fd().as_raw_fd(),
+            registered,
+            "{name} was not reused"
+        );
+        unregister(name);
+        reopened
+    }
+
+    #[test]
+    fn test_lock_file() {
+        let path = env::temp_dir().join(format!("shellflip-lock-{}", std::process::id()));
+        let file = lock_file("lock", &path).unwrap();
+
+        // A separate open file description can't take the lock.
+        let other = File::open(&path).unwrap();
+        let err = flock(other.as_fd(), libc::LOCK_EX | libc::LOCK_NB).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let err = lock_file("lock-other", &path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // The inherited fd still holds the lock after the old process closes its copy.
+        inherit("lock");
+        drop(file);
+        let file = lock_file("lock", &path).unwrap();
+        let err = flock(other.as_fd(), libc::LOCK_EX | libc::LOCK_NB).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        drop(file);
+        unregister("lock");
+        flock(other.as_fd(), libc::LOCK_EX | libc::LOCK_NB).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_open_file() {
+        use std::io::{Read, Seek, Write};
+
+        let path = env::temp_dir().join(format!("shellflip-db-{}", std::process::id()));
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(true);
+        let mut file = open_file("db", &path, &options).unwrap();
+        file.write_all(b"state").unwrap();
+
+        inherit("db");
+        drop(file);
+        let mut file = open_file("db", &path, &options).unwrap();
+        // Reopening would have truncated the file.
+        file.rewind().unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "state");
+
+        unregister("db");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_new_process_env() {
+        let file = File::open("/dev/null").unwrap();
+        register("env-test", &file).unwrap();
+        let registered = REGISTERED.lock().unwrap()["env-test"].as_raw_fd();
+        let files = for_new_process().unwrap();
+        assert!(!files.is_empty());
+        let parsed = parse_files(&files.env());
+        // The registered fd itself is passed on, not a duplicate.
+        assert_eq!(parsed["env-test"], registered);
+        assert!(files.fds().any(|fd| fd == registered));
+        unregister("env-test");
+
+        for name in ["", "a,b", "a=b"] {
+            let err = register(name, &file).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_parse_files() {
+        let parsed = parse_files("lock=3,data=4,bad=x,bad");
+        assert_eq!(parsed["lock"], 3);
+        assert_eq!(parsed["data"], 4);
+        assert!(!parsed.contains_key("bad"));
+    }
+}
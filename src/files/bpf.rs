@@ -0,0 +1,229 @@
+//! eBPF maps, programs and links, e.g. of XDP or tc based services.
+//!
+//! BPF objects live as long as something refers to them: an fd, a pin in a BPF filesystem, an
+//! attachment, or a program that uses a map. A service that loads its programs and maps without
+//! pinning them loses them, and the flow state in its maps, when it exits. `object` passes their
+//! fds to the new process instead, which keeps them alive:
+//!
+//! - Both processes share the same objects during the restart. Updates to a map by either process
+//!   are visible to the other, and to the programs using it, so the old process should stop
+//!   updating maps before the new one starts to, e.g. in `LifecycleHandler::quiesce_writes`.
+//! - Programs attached through a `bpf_link`, e.g. with `BPF_LINK_CREATE`, are detached when the
+//!   last fd of the link is closed, so the link fds must be passed as well. Programs attached
+//!   without a link, e.g. XDP programs attached through netlink, stay attached to their interface
+//!   either way.
+//! - A new version may load new programs, reusing the inherited maps, and replace the attached
+//!   programs atomically with `BPF_LINK_UPDATE`. It should only reuse maps whose layout it
+//!   expects, which is what the `reuse` check of `object` is for.
+//! - Objects that are pinned can also be found with `BPF_OBJ_GET`, which passing fds doesn't
+//!   require, so unprivileged services don't need access to a BPF filesystem.
+//!
+//! ```no_run
+//! # fn create_flow_table() -> std::io::Result<std::os::fd::OwnedFd> { unimplemented!() }
+//! use shellflip::files::bpf::{self, BpfObject};
+//!
+//! // Reuse the flow table of the old process, if it has the layout this version expects.
+//! let flows = bpf::object(
+//!     "flows",
+//!     |object| matches!(object, BpfObject::Map { key_size: 16, value_size: 32, .. }),
+//!     create_flow_table,
+//! )?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+use super::reuse_or_open;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+
+/// A BPF object, as described by the kernel in `/proc/self/fdinfo`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BpfObject {
+    Map {
+        id: u32,
+        /// The `BPF_MAP_TYPE_*` of the map.
+        map_type: u32,
+        key_size: u32,
+        value_size: u32,
+        max_entries: u32,
+    },
+    Program {
+        id: u32,
+        /// The `BPF_PROG_TYPE_*` of the program.
+        prog_type: u32,
+        /// The hash of the program's instructions, as shown by `bpftool`.
+        tag: String,
+    },
+    Link {
+        id: u32,
+        /// The kind of link, e.g. `xdp` or `tcx`.
+        link_type: String,
+        /// The ID of the program attached through the link.
+        prog_id: u32,
+    },
+}
+
+/// Returns the BPF object that the old process passed under `name` if `reuse` accepts it, or else
+/// the object returned by `open`. The object is passed to the next process under `name`.
+pub fn object(
+    name: &str,
+    reuse: impl FnOnce(&BpfObject) -> bool,
+    open: impl FnOnce() -> io::Result<OwnedFd>,
+) -> io::Result<OwnedFd> {
+    reuse_or_open(
+        name,
+        |fd| match describe(fd)? {
+            Some(object) if reuse(&object) => Ok(()),
+            Some(object) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{object:?} is not the expected object"),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a BPF object",
+            )),
+        },
+        open,
+    )
+}
+
+/// Describes `fd` if it is a BPF map, program or link.
+pub fn describe(fd: BorrowedFd<'_>) -> io::Result<Option<BpfObject>> {
+    let fdinfo = fs::read_to_string(format!("/proc/self/fdinfo/{}", fd.as_raw_fd()))?;
+    Ok(parse_fdinfo(&fdinfo))
+}
+
+fn parse_fdinfo(fdinfo: &str) -> Option<BpfObject> {
+    let fields: HashMap<&str, &str> = fdinfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key, value.trim()))
+        .collect();
+    let number = |key: &str| -> Option<u32> { fields.get(key)?.parse().ok() };
+    if let Some(map_type) = number("map_type") {
+        return Some(BpfObject::Map {
+            id: number("map_id")?,
+            map_type,
+            key_size: number("key_size")?,
+            value_size: number("value_size")?,
+            max_entries: number("max_entries")?,
+        });
+    }
+    if let Some(link_type) = fields.get("link_type") {
+        return Some(BpfObject::Link {
+            id: number("link_id")?,
+            link_type: link_type.to_string(),
+            prog_id: number("prog_id")?,
+        });
+    }
+    if let Some(prog_type) = number("prog_type") {
+        return Some(BpfObject::Program {
+            id: number("prog_id")?,
+            prog_type,
+            tag: fields.get("prog_tag")?.to_string(),
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{assert_reused, inherit};
+    use super::super::{register, unregister};
+    use super::*;
+    use std::os::fd::{AsFd, FromRawFd};
+
+    #[test]
+    fn test_parse_fdinfo() {
+        let map = "pos:\t0\nflags:\t02000002\nmnt_id:\t15\nino:\t1057\nmap_type:\t1\n\
+                   key_size:\t4\nvalue_size:\t8\nmax_entries:\t16\nmap_flags:\t0x0\n\
+                   map_extra:\t0x0\nmemlock:\t4096\nmap_id:\t7\nfrozen:\t0\n";
+        assert_eq!(
+            parse_fdinfo(map),
+            Some(BpfObject::Map {
+                id: 7,
+                map_type: 1,
+                key_size: 4,
+                value_size: 8,
+                max_entries: 16,
+            })
+        );
+        let prog = "pos:\t0\nprog_type:\t6\nprog_jited:\t1\nprog_tag:\ta04f5eef06a7f555\n\
+                    memlock:\t4096\nprog_id:\t21\n";
+        assert_eq!(
+            parse_fdinfo(prog),
+            Some(BpfObject::Program {
+                id: 21,
+                prog_type: 6,
+                tag: "a04f5eef06a7f555".into(),
+            })
+        );
+        let link = "pos:\t0\nlink_type:\txdp\nlink_id:\t3\nprog_tag:\ta04f5eef06a7f555\n\
+                    prog_id:\t21\n";
+        assert_eq!(
+            parse_fdinfo(link),
+            Some(BpfObject::Link {
+                id: 3,
+                link_type: "xdp".into(),
+                prog_id: 21,
+            })
+        );
+        assert_eq!(parse_fdinfo("pos:\t0\nflags:\t02\n"), None);
+    }
+
+    #[test]
+    fn test_object() {
+        let create = || {
+            // struct bpf_attr for BPF_MAP_CREATE of a BPF_MAP_TYPE_HASH map.
+            let mut attr = [0u32; 30];
+            attr[..4].copy_from_slice(&[1, 4, 8, 16]);
+            let fd = unsafe {
+                libc::syscall(
+                    libc::SYS_bpf,
+                    0,
+                    attr.as_ptr(),
+                    std::mem::size_of_val(&attr),
+                )
+            };
+            match fd {
+                fd if fd < 0 => Err(io::Error::last_os_error()),
+                fd => Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) }),
+            }
+        };
+        // Creating maps needs CAP_BPF, unless unprivileged BPF is enabled.
+        let map = match object("map", |_| true, create) {
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EPERM | libc::ENOSYS)) => return,
+            res => res.unwrap(),
+        };
+        let Some(BpfObject::Map { id, .. }) = describe(map.as_fd()).unwrap() else {
+            panic!("not a map");
+        };
+
+        let reused = assert_reused("map", || {
+            object(
+                "map",
+                |object| matches!(object, BpfObject::Map { key_size: 4, .. }),
+                create,
+            )
+        });
+        assert!(matches!(
+            describe(reused.as_fd()).unwrap(),
+            Some(BpfObject::Map { id: reused_id, .. }) if reused_id == id
+        ));
+
+        // A new version expecting another layout creates its own map.
+        register("map", &reused).unwrap();
+        inherit("map");
+        let other = object(
+            "map",
+            |object| matches!(object, BpfObject::Map { key_size: 16, .. }),
+            create,
+        )
+        .unwrap();
+        assert!(!matches!(
+            describe(other.as_fd()).unwrap(),
+            Some(BpfObject::Map { id: other_id, .. }) if other_id == id
+        ));
+        unregister("map");
+    }
+}
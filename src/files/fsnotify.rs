@@ -0,0 +1,289 @@
+//! inotify and fanotify instances, e.g. of services that watch their configuration or a spool
+//! directory.
+//!
+//! Watches belong to the inotify or fanotify instance, so a new process that sets up its own
+//! misses the events that happen until its watches are in place. `inotify` and `fanotify` pass the
+//! instance to the new process instead, which keeps its watches, so events queue up until the new
+//! process reads them.
+//!
+//! inotify events refer to watch descriptors rather than paths, so `Inotify` keeps the path of
+//! each watch in a memfd that is passed along with the instance, and the new process gets the same
+//! watch descriptors and paths back. Watches that were added to the fd directly are kept by the
+//! kernel but have no path; adding them again with `Inotify::add_watch` returns the same watch
+//! descriptor and records its path. fanotify events carry an fd or a file handle of the file
+//! instead, so they need no such map.
+//!
+//! The old process must stop reading events during the restart, as described in the `files`
+//! module. fanotify permission events block the accessing process until they are answered, so the
+//! old process should answer those it has read before then.
+use super::{register, reuse_or_open, take_inherited};
+use crate::diagnostics;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Marks a record of a removed watch in the watch log.
+const REMOVED: u32 = u32::MAX;
+
+/// An inotify instance that keeps track of the path of each watch, and is passed to the next
+/// process with its watches.
+#[derive(Debug)]
+pub struct Inotify {
+    fd: OwnedFd,
+    watches: BTreeMap<i32, PathBuf>,
+    /// A log of added and removed watches, which the next process replays.
+    log: File,
+}
+
+/// Returns the inotify instance that the old process passed under `name`, with its watches, or
+/// creates a new one. The instance is passed to the next process under `name`, and the paths of its
+/// watches under `name.watches`.
+pub fn inotify(name: &str) -> io::Result<Inotify> {
+    let mut reused = false;
+    let fd = reuse_or_open(
+        name,
+        |fd| {
+            expect_anon_inode(fd, "inotify")?;
+            reused = true;
+            Ok(())
+        },
+        || match unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+        },
+    )?;
+    let log_name = format!("{name}.watches");
+    let mut watches = match (reused, take_inherited(&log_name)) {
+        (true, Some(log)) => read_log(&mut File::from(log))?,
+        (true, None) => {
+            diagnostics::warn!(
+                "Inherited inotify instance {} without the paths of its watches",
+                name
+            );
+            BTreeMap::new()
+        }
+        (false, _) => BTreeMap::new(),
+    };
+    // Watches whose file was deleted, or that were removed after the log was read, are gone.
+    let active = active_watches(fd.as_fd())?;
+    watches.retain(|wd, _| active.contains(wd));
+
+    let log = create_log()?;
+    let mut inotify = Inotify { fd, watches, log };
+    for (wd, path) in &inotify.watches {
+        write_record(&mut inotify.log, *wd, Some(path))?;
+    }
+    register(&log_name, &inotify.log)?;
+    Ok(inotify)
+}
+
+impl Inotify {
+    /// Adds a watch of `path` for the events in `mask`, e.g. `libc::IN_CLOSE_WRITE`, and returns
+    /// its watch descriptor. Watching a file that is already watched replaces the watch's mask.
+    pub fn add_watch(&mut self, path: impl AsRef<Path>, mask: u32) -> io::Result<i32> {
+        let path = path.as_ref();
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), c_path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        write_record(&mut self.log, wd, Some(path))?;
+        self.watches.insert(wd, path.to_path_buf());
+        Ok(wd)
+    }
+
+    /// Removes the watch `wd`.
+    pub fn remove_watch(&mut self, wd: i32) -> io::Result<()> {
+        if unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.forget(wd)
+    }
+
+    /// Forgets the path of `wd` once the kernel has removed the watch, which it reports with an
+    /// `IN_IGNORED` event, e.g. when the watched file is deleted.
+    pub fn forget(&mut self, wd: i32) -> io::Result<()> {
+        if self.watches.remove(&wd).is_some() {
+            write_record(&mut self.log, wd, None)?;
+        }
+        Ok(())
+    }
+
+    /// The path of the watch `wd`, as it was passed to `add_watch`.
+    pub fn path(&self, wd: i32) -> Option<&Path> {
+        self.watches.get(&wd).map(PathBuf::as_path)
+    }
+
+    /// The watch descriptors and paths of all watches.
+    pub fn watches(&self) -> impl Iterator<Item = (i32, &Path)> + '_ {
+        self.watches.iter().map(|(wd, path)| (*wd, path.as_path()))
+    }
+}
+
+impl AsFd for Inotify {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for Inotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Returns the fanotify instance that the old process passed under `name`, with its marks, or else
+/// the instance returned by `open`, which should set up its marks. The instance is passed to the
+/// next process under `name`.
+pub fn fanotify(name: &str, open: impl FnOnce() -> io::Result<OwnedFd>) -> io::Result<OwnedFd> {
+    reuse_or_open(name, |fd| expect_anon_inode(fd, "[fanotify]"), open)
+}
+
+fn expect_anon_inode(fd: BorrowedFd<'_>, kind: &str) -> io::Result<()> {
+    let target = fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd()))?;
+    match target.as_os_str().as_bytes().strip_prefix(b"anon_inode:") {
+        Some(k) if k == kind.as_bytes() => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{target:?} is not an {kind} instance"),
+        )),
+    }
+}
+
+/// The watch descriptors of an inotify instance, as listed in `/proc/self/fdinfo`.
+fn active_watches(fd: BorrowedFd<'_>) -> io::Result<BTreeSet<i32>> {
+    let fdinfo = fs::read_to_string(format!("/proc/self/fdinfo/{}", fd.as_raw_fd()))?;
+    Ok(fdinfo
+        .lines()
+        .filter_map(|line| {
+            line.strip_prefix("inotify wd:")?
+                .split(' ')
+                .next()?
+                .parse()
+                .ok()
+        })
+        .collect())
+}
+
+fn create_log() -> io::Result<File> {
+    let fd = unsafe { libc::memfd_create(c"shellflip-inotify".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Appends a record of an added watch, or of a removed one if `path` is `None`. Each record is the
+/// watch descriptor and the length of the path as big-endian integers, followed by the path.
+fn write_record(log: &mut File, wd: i32, path: Option<&Path>) -> io::Result<()> {
+    let path = path.map_or(&[][..], |p| p.as_os_str().as_bytes());
+    let len = match path.is_empty() {
+        true => REMOVED,
+        false => path.len() as u32,
+    };
+    let mut record = Vec::with_capacity(8 + path.len());
+    record.extend_from_slice(&wd.to_be_bytes());
+    record.extend_from_slice(&len.to_be_bytes());
+    record.extend_from_slice(path);
+    log.write_all(&record)
+}
+
+fn read_log(log: &mut File) -> io::Result<BTreeMap<i32, PathBuf>> {
+    // The file offset is shared with the old process's copy, which is at the end.
+    log.rewind()?;
+    let mut buf = Vec::new();
+    log.read_to_end(&mut buf)?;
+    Ok(parse_log(&buf))
+}
+
+fn parse_log(mut buf: &[u8]) -> BTreeMap<i32, PathBuf> {
+    let mut watches = BTreeMap::new();
+    while buf.len() >= 8 {
+        let wd = i32::from_be_bytes(buf[..4].try_into().unwrap());
+        let len = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+        buf = &buf[8..];
+        if len == REMOVED {
+            watches.remove(&wd);
+            continue;
+        }
+        let Some(path) = buf.get(..len as usize) else {
+            // The old process was interrupted while writing the record.
+            break;
+        };
+        watches.insert(wd, PathBuf::from(OsStr::from_bytes(path)));
+        buf = &buf[len as usize..];
+    }
+    watches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::inherit;
+    use super::super::unregister;
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_parse_log() {
+        let mut log = Vec::new();
+        for (wd, len, path) in [
+            (1, 4, &b"/etc"[..]),
+            (2, 4, b"/var"),
+            (1, REMOVED, b""),
+            (3, 4, b"/sr"),
+        ] {
+            log.extend_from_slice(&i32::to_be_bytes(wd));
+            log.extend_from_slice(&u32::to_be_bytes(len));
+            log.extend_from_slice(path);
+        }
+        // The last record is truncated.
+        assert_eq!(parse_log(&log), BTreeMap::from([(2, "/var".into())]));
+    }
+
+    #[test]
+    fn test_inotify() {
+        let read_event = |fd: RawFd| {
+            let mut buf = [0u8; 4096];
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+            assert!(n > 0, "{}", io::Error::last_os_error());
+            unsafe { std::ptr::read_unaligned(buf.as_ptr().cast::<libc::inotify_event>()) }
+        };
+        let dir = env::temp_dir().join(format!("shellflip-inotify-{}", std::process::id()));
+        // Only this test touches the watched directories, so no other events come in.
+        let other_dir = dir.join("other");
+        fs::create_dir_all(&other_dir).unwrap();
+        let mut inotify = inotify("inotify").unwrap();
+        let wd = inotify.add_watch(&dir, libc::IN_CREATE).unwrap();
+        let other = inotify.add_watch(&other_dir, libc::IN_DELETE).unwrap();
+        inotify.remove_watch(other).unwrap();
+        assert_eq!(read_event(inotify.as_raw_fd()).mask, libc::IN_IGNORED);
+
+        inherit("inotify");
+        inherit("inotify.watches");
+        let old = inotify;
+        // An event while the old process is shutting down.
+        File::create(dir.join("file")).unwrap();
+        let inherited = self::inotify("inotify").unwrap();
+        drop(old);
+        assert_eq!(
+            inherited.watches().collect::<Vec<_>>(),
+            [(wd, dir.as_path())]
+        );
+        let event = read_event(inherited.as_raw_fd());
+        assert_eq!(event.wd, wd);
+        assert_eq!(inherited.path(event.wd), Some(dir.as_path()));
+
+        // An fd of another kind is not reused.
+        super::super::register("inotify", File::open(&dir).unwrap()).unwrap();
+        inherit("inotify");
+        let fresh = self::inotify("inotify").unwrap();
+        assert_eq!(fresh.watches().count(), 0);
+        unregister("inotify");
+        unregister("inotify.watches");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
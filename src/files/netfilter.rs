@@ -0,0 +1,240 @@
+//! Netfilter queue and conntrack sockets, e.g. of firewall helpers using `NFQUEUE`.
+//!
+//! An `NFQUEUE` queue is bound to the netlink socket that bound it, and is unbound when that socket
+//! is closed, dropping the packets that are waiting for a verdict. Packets sent to a queue that
+//! nothing is bound to are dropped too, unless the rule uses `--queue-bypass`. `queue` passes the
+//! socket to the new process instead, so the queue stays bound and packets wait in the kernel
+//! until the new process reads them.
+//!
+//! The old process must stop reading from the socket during the restart, as described in the
+//! `files` module. Only the process that received a packet knows its ID, which the verdict refers
+//! to, so the old process must also issue the verdicts of all packets it has received by then.
+//! `PendingVerdicts` keeps track of those packets:
+//!
+//! ```no_run
+//! # async fn example() {
+//! use shellflip::files::netfilter::PendingVerdicts;
+//!
+//! let pending = PendingVerdicts::new();
+//! // For each packet read from the queue, kept until its verdict has been sent.
+//! let packet = pending.received();
+//! drop(packet);
+//! // In `LifecycleHandler::quiesce_writes`, once the old process has stopped reading.
+//! pending.settled().await;
+//! # }
+//! ```
+//!
+//! Conntrack event sockets are netlink sockets, which `conntrack_events` passes on with their
+//! group subscriptions. Dumps of the conntrack table that the old process requested should be
+//! completed before the restart, as the rest of the dump would otherwise go to the new process.
+use super::{netlink, reuse_or_open, sockopt};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+// Conntrack event groups, from linux/netfilter/nfnetlink.h.
+pub const NFNLGRP_CONNTRACK_NEW: u32 = 1;
+pub const NFNLGRP_CONNTRACK_UPDATE: u32 = 2;
+pub const NFNLGRP_CONNTRACK_DESTROY: u32 = 3;
+
+/// Lists the bound queues, with the netlink port ID of the socket each is bound to.
+const QUEUES: &str = "/proc/net/netfilter/nfnetlink_queue";
+
+/// Returns the socket that the old process passed under `name` if `queue_num` is bound to it, or
+/// else the socket returned by `open`, which should bind the queue and configure it, e.g. with
+/// `NFQNL_CFG_CMD_BIND` and `NFQNL_MSG_CONFIG`. The socket is passed to the next process under
+/// `name`.
+pub fn queue(
+    name: &str,
+    queue_num: u16,
+    open: impl FnOnce() -> io::Result<OwnedFd>,
+) -> io::Result<OwnedFd> {
+    reuse_or_open(
+        name,
+        |fd| {
+            if sockopt(fd, libc::SOL_SOCKET, libc::SO_DOMAIN)? != libc::AF_NETLINK
+                || sockopt(fd, libc::SOL_SOCKET, libc::SO_PROTOCOL)? != libc::NETLINK_NETFILTER
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "not a netfilter netlink socket",
+                ));
+            }
+            let queues = match fs::read_to_string(QUEUES) {
+                Ok(queues) => parse_queues(&queues),
+                // nfnetlink_queue is not loaded, so no queue is bound.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            let port_id = port_id(fd)?;
+            match queues.iter().find(|(num, _)| *num == queue_num) {
+                Some((_, peer)) if *peer == port_id => Ok(()),
+                Some((_, peer)) => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("queue {queue_num} is bound to port {peer} rather than {port_id}"),
+                )),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("queue {queue_num} is not bound"),
+                )),
+            }
+        },
+        open,
+    )
+}
+
+/// Returns the conntrack event socket that the old process passed under `name`, or opens a new one,
+/// subscribed to the `NFNLGRP_CONNTRACK_*` `groups`. See `netlink::socket`.
+pub fn conntrack_events(name: &str, groups: &[u32]) -> io::Result<OwnedFd> {
+    netlink::socket(name, libc::NETLINK_NETFILTER, groups)
+}
+
+/// The queue numbers and port IDs of the bound queues.
+fn parse_queues(queues: &str) -> Vec<(u16, u32)> {
+    queues
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?.parse().ok()?, fields.next()?.parse().ok()?))
+        })
+        .collect()
+}
+
+fn port_id(fd: BorrowedFd<'_>) -> io::Result<u32> {
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockname(
+            fd.as_raw_fd(),
+            (&mut addr as *mut libc::sockaddr_nl).cast(),
+            &mut len,
+        )
+    };
+    match res {
+        0 => Ok(addr.nl_pid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Counts the queued packets that have been received but have no verdict yet.
+#[derive(Clone, Debug)]
+pub struct PendingVerdicts {
+    count: Arc<watch::Sender<usize>>,
+}
+
+impl PendingVerdicts {
+    pub fn new() -> Self {
+        let (count, _) = watch::channel(0);
+        Self {
+            count: Arc::new(count),
+        }
+    }
+
+    /// Records a received packet, until the returned guard is dropped once its verdict is sent.
+    pub fn received(&self) -> PendingVerdict {
+        self.count.send_modify(|count| *count += 1);
+        PendingVerdict {
+            count: self.count.clone(),
+        }
+    }
+
+    /// The number of packets waiting for a verdict.
+    pub fn count(&self) -> usize {
+        *self.count.borrow()
+    }
+
+    /// Waits until every received packet has a verdict.
+    pub async fn settled(&self) {
+        let mut count = self.count.subscribe();
+        while *count.borrow_and_update() > 0 {
+            // The sender is kept alive by `self`.
+            let _ = count.changed().await;
+        }
+    }
+}
+
+impl Default for PendingVerdicts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A packet waiting for a verdict, see `PendingVerdicts::received`.
+#[derive(Debug)]
+pub struct PendingVerdict {
+    count: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for PendingVerdict {
+    fn drop(&mut self) {
+        self.count.send_modify(|count| *count -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::inherit;
+    use super::super::unregister;
+    use super::*;
+    use std::os::fd::{AsFd, FromRawFd};
+    use std::os::unix::fs::MetadataExt;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_queues() {
+        let queues = "    0   4012     0 2 65531     0     0        0  1\n\
+                      \x20   7   4013     3 2  1500     0     0       17  1\n";
+        assert_eq!(parse_queues(queues), [(0, 4012), (7, 4013)]);
+        assert!(parse_queues("").is_empty());
+    }
+
+    #[test]
+    fn test_queue() {
+        let open = || {
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                    libc::NETLINK_NETFILTER,
+                )
+            };
+            match fd {
+                fd if fd < 0 => Err(io::Error::last_os_error()),
+                fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+            }
+        };
+        // Binding a queue needs CAP_NET_ADMIN, so the queue is not bound to this socket.
+        let fd = queue("nfqueue", 65535, open).unwrap();
+        inherit("nfqueue");
+        let reopened = queue("nfqueue", 65535, open).unwrap();
+        let inode = |fd: BorrowedFd<'_>| fs::metadata(format!("/proc/self/fd/{}", fd.as_raw_fd()));
+        assert_ne!(
+            inode(reopened.as_fd()).unwrap().ino(),
+            inode(fd.as_fd()).unwrap().ino()
+        );
+        unregister("nfqueue");
+    }
+
+    #[tokio::test]
+    async fn test_pending_verdicts() {
+        let pending = PendingVerdicts::new();
+        pending.settled().await;
+
+        let first = pending.received();
+        let second = pending.received();
+        assert_eq!(pending.count(), 2);
+        let settled = tokio::spawn({
+            let pending = pending.clone();
+            async move { pending.settled().await }
+        });
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!settled.is_finished());
+        drop(second);
+        settled.await.unwrap();
+        assert_eq!(pending.count(), 0);
+    }
+}
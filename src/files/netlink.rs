@@ -0,0 +1,190 @@
+//! Netlink sockets, e.g. the rtnetlink sockets of routing daemons, with their multicast group
+//! subscriptions.
+//!
+//! A netlink socket only receives the events of a group from when it joins it, so a new process
+//! that opens its own socket misses the events that happen during the restart. `socket` passes the
+//! socket to the new process instead, which keeps its subscriptions, so the events queue up in its
+//! receive buffer until the new process reads them.
+//!
+//! The old process must stop reading from the socket during the restart, as described in the
+//! `files` module. Requests that the old process sent on the socket should be answered before then,
+//! as the answers would otherwise go to the new process. If events arrive faster than the new
+//! process starts, the buffer overflows and reading fails with `ENOBUFS`, as it would for a slow
+//! reader, so a large `SO_RCVBUF` may be worthwhile.
+use super::{reuse_or_open, set_sockopt, sockopt};
+use crate::diagnostics;
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+
+/// Returns the netlink socket of `protocol`, e.g. `libc::NETLINK_ROUTE`, that the old process
+/// passed under `name`, or opens a new one. The socket is subscribed to the multicast `groups`,
+/// e.g. `RTNLGRP_LINK`, and to no others, and is passed to the next process under `name`.
+///
+/// The subscriptions of an inherited socket are checked. Groups it is missing are joined, with a
+/// warning, as events of those groups may have been lost.
+pub fn socket(name: &str, protocol: libc::c_int, groups: &[u32]) -> io::Result<OwnedFd> {
+    reuse_or_open(
+        name,
+        |fd| {
+            if sockopt(fd, libc::SOL_SOCKET, libc::SO_DOMAIN)? != libc::AF_NETLINK
+                || sockopt(fd, libc::SOL_SOCKET, libc::SO_PROTOCOL)? != protocol
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("not a netlink socket of protocol {protocol}"),
+                ));
+            }
+            let joined = memberships(fd)?;
+            for group in groups.iter().filter(|g| !joined.contains(g)) {
+                diagnostics::warn!(
+                    "Inherited netlink socket {} was not subscribed to group {}, events may have \
+                     been lost",
+                    name,
+                    group
+                );
+                join(fd, *group)?;
+            }
+            for group in joined.iter().filter(|g| !groups.contains(g)) {
+                set_sockopt(fd, libc::SOL_NETLINK, libc::NETLINK_DROP_MEMBERSHIP, group)?;
+            }
+            Ok(())
+        },
+        || open(protocol, groups),
+    )
+}
+
+/// Returns the multicast groups a netlink socket is subscribed to.
+pub fn memberships(fd: BorrowedFd<'_>) -> io::Result<Vec<u32>> {
+    // A bitmap of the groups, which is as long as the highest group of the protocol needs.
+    let mut bitmap = vec![0u32; 1];
+    loop {
+        let mut len = (bitmap.len() * mem::size_of::<u32>()) as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_NETLINK,
+                libc::NETLINK_LIST_MEMBERSHIPS,
+                bitmap.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let needed = (len as usize).div_ceil(mem::size_of::<u32>());
+        if needed <= bitmap.len() {
+            break;
+        }
+        bitmap.resize(needed, 0);
+    }
+    Ok(groups(&bitmap))
+}
+
+/// The groups in a membership bitmap, which are numbered from 1.
+fn groups(bitmap: &[u32]) -> Vec<u32> {
+    (0..bitmap.len() as u32 * 32)
+        .filter(|bit| bitmap[*bit as usize / 32] & (1 << (bit % 32)) != 0)
+        .map(|bit| bit + 1)
+        .collect()
+}
+
+fn join(fd: BorrowedFd<'_>, group: u32) -> io::Result<()> {
+    set_sockopt(fd, libc::SOL_NETLINK, libc::NETLINK_ADD_MEMBERSHIP, &group)
+}
+
+fn open(protocol: libc::c_int, groups: &[u32]) -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            protocol,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    let res = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            (&addr as *const libc::sockaddr_nl).cast(),
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for group in groups {
+        join(fd.as_fd(), *group)?;
+    }
+    Ok(fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::register;
+    use super::super::tests::{assert_reused, inherit};
+    use super::*;
+
+    // From linux/rtnetlink.h.
+    const RTNLGRP_LINK: u32 = 1;
+    const RTNLGRP_IPV4_IFADDR: u32 = 5;
+    const RTNLGRP_IPV6_ROUTE: u32 = 11;
+
+    #[test]
+    fn test_socket() {
+        let fd = socket(
+            "rtnl",
+            libc::NETLINK_ROUTE,
+            &[RTNLGRP_LINK, RTNLGRP_IPV4_IFADDR],
+        )
+        .unwrap();
+        assert_eq!(
+            memberships(fd.as_fd()).unwrap(),
+            [RTNLGRP_LINK, RTNLGRP_IPV4_IFADDR]
+        );
+
+        // The new version subscribes to different groups on the same socket.
+        let inherited = assert_reused("rtnl", || {
+            socket(
+                "rtnl",
+                libc::NETLINK_ROUTE,
+                &[RTNLGRP_LINK, RTNLGRP_IPV6_ROUTE],
+            )
+        });
+        let id = |fd: BorrowedFd<'_>| {
+            let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+            unsafe {
+                libc::getsockname(
+                    fd.as_raw_fd(),
+                    (&mut addr as *mut libc::sockaddr_nl).cast(),
+                    &mut len,
+                )
+            };
+            addr.nl_pid
+        };
+        assert_eq!(id(inherited.as_fd()), id(fd.as_fd()));
+        assert_eq!(
+            memberships(inherited.as_fd()).unwrap(),
+            [RTNLGRP_LINK, RTNLGRP_IPV6_ROUTE]
+        );
+
+        // A socket of another protocol is replaced.
+        register("rtnl", &inherited).unwrap();
+        inherit("rtnl");
+        let other = socket("rtnl", libc::NETLINK_NETFILTER, &[]).unwrap();
+        let protocol = sockopt(other.as_fd(), libc::SOL_SOCKET, libc::SO_PROTOCOL).unwrap();
+        assert_eq!(protocol, libc::NETLINK_NETFILTER);
+        super::super::unregister("rtnl");
+    }
+
+    #[test]
+    fn test_groups() {
+        assert_eq!(groups(&[0b101, 1]), [1, 3, 33]);
+        assert!(groups(&[0]).is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+//! `AF_PACKET` and raw IP sockets, e.g. of packet capture and DDoS scrubbing daemons.
+//!
+//! Closing a packet socket drops the packets queued on it, and a new socket only receives packets
+//! once it is set up, so opening one in the new process loses traffic during the restart. `socket`
+//! passes the socket to the new process instead, along with its bound interface, fanout group
+//! membership, attached filters and `PACKET_RX_RING`. The ring has to be mapped again with `mmap`,
+//! using the same parameters, which the old process can send along with its state; setting it up
+//! again fails with `EBUSY`.
+//!
+//! The old process must stop reading from the socket during the restart, as described in the
+//! `files` module.
+//!
+//! With `RestartConfig::send_fd_manifest`, each packet socket the new process inherits is described
+//! by `ManifestEntry::packet`, so it can check that the fanout group and filter carried over.
+use super::{reuse_or_open, sockopt};
+use crate::diagnostics;
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+
+pub use crate::fds::{PacketFanout, PacketSocket};
+
+/// From `linux/if_packet.h`.
+const PACKET_FANOUT: libc::c_int = 18;
+
+/// Returns the packet or raw socket that the old process passed under `name`, or else the socket
+/// returned by `open`, which should set it up completely, e.g. bind it to an interface and join a
+/// fanout group. The socket is passed to the next process under `name`.
+pub fn socket(name: &str, open: impl FnOnce() -> io::Result<OwnedFd>) -> io::Result<OwnedFd> {
+    reuse_or_open(
+        name,
+        |fd| match describe(fd)? {
+            Some(packet) => {
+                diagnostics::info!("Inherited packet socket {}: {:?}", name, packet);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a packet or raw socket",
+            )),
+        },
+        open,
+    )
+}
+
+/// Describes `fd` if it is an `AF_PACKET` socket, or a raw `AF_INET` or `AF_INET6` socket.
+pub fn describe(fd: BorrowedFd<'_>) -> io::Result<Option<PacketSocket>> {
+    let family = match sockopt(fd, libc::SOL_SOCKET, libc::SO_DOMAIN) {
+        Ok(family) => family,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSOCK) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let socket_type = sockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE)?;
+    let packet = match (family, socket_type) {
+        (libc::AF_PACKET, _) => true,
+        (libc::AF_INET | libc::AF_INET6, libc::SOCK_RAW) => false,
+        _ => return Ok(None),
+    };
+    let fanout = match packet {
+        true => sockopt(fd, libc::SOL_PACKET, PACKET_FANOUT)?,
+        false => 0,
+    };
+    Ok(Some(PacketSocket {
+        family,
+        socket_type,
+        protocol: sockopt(fd, libc::SOL_SOCKET, libc::SO_PROTOCOL)?,
+        // The group ID is in the low 16 bits, and 0 if the socket is not in a group.
+        fanout: (fanout != 0).then_some(PacketFanout {
+            group: fanout as u16,
+            mode: (fanout >> 16) as u16,
+        }),
+        filter_len: Some(filter_len(fd)?).filter(|len| *len > 0),
+    }))
+}
+
+/// The number of instructions of the classic BPF filter attached to a socket.
+fn filter_len(fd: BorrowedFd<'_>) -> io::Result<u32> {
+    // With a length of 0, the length of the filter is returned instead of the filter.
+    let mut len: libc::socklen_t = 0;
+    let res = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_GET_FILTER,
+            std::ptr::null_mut(),
+            &mut len,
+        )
+    };
+    match res {
+        0 => Ok(len),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::set_sockopt;
+    use super::super::tests::assert_reused;
+    use super::*;
+    use std::net::UdpSocket;
+    use std::os::fd::{AsFd, FromRawFd};
+
+    #[test]
+    fn test_socket() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert_eq!(describe(udp.as_fd()).unwrap(), None);
+
+        let open = || {
+            let protocol = (libc::ETH_P_ALL as u16).to_be() as libc::c_int;
+            let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            // Accept no packets.
+            let filter = [libc::sock_filter {
+                code: (libc::BPF_RET | libc::BPF_K) as u16,
+                jt: 0,
+                jf: 0,
+                k: 0,
+            }];
+            let program = libc::sock_fprog {
+                len: 1,
+                filter: filter.as_ptr().cast_mut(),
+            };
+            set_sockopt(
+                fd.as_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ATTACH_FILTER,
+                &program,
+            )?;
+            let fanout = 42 | (libc::PACKET_FANOUT_HASH << 16);
+            set_sockopt(fd.as_fd(), libc::SOL_PACKET, PACKET_FANOUT, &fanout)?;
+            Ok(fd)
+        };
+        // Packet sockets need CAP_NET_RAW.
+        let fd = match socket("capture", open) {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => return,
+            res => res.unwrap(),
+        };
+
+        let inherited = assert_reused("capture", || socket("capture", open));
+        let packet = describe(inherited.as_fd()).unwrap().unwrap();
+        assert_eq!(packet.family, libc::AF_PACKET);
+        assert_eq!(
+            packet.fanout,
+            Some(PacketFanout {
+                group: 42,
+                mode: libc::PACKET_FANOUT_HASH as u16,
+            })
+        );
+        assert_eq!(packet.filter_len, Some(1));
+        drop(fd);
+    }
+}
@@ -0,0 +1,177 @@
+//! PTY masters of interactive sessions, e.g. of SSH gateways and terminal multiplexers.
+//!
+//! Closing the last fd of a PTY master hangs up the session, which sends `SIGHUP` to the processes
+//! running in it. `register` passes the master to the new process instead, along with metadata the
+//! service needs to carry on with the session, e.g. its user and the connection it belongs to, so
+//! sessions survive an upgrade. The terminal keeps its settings and window size, and output written
+//! meanwhile is buffered by the PTY until the new process reads it, or the writer blocks.
+//!
+//! Sessions are registered under an ID of the service's choosing, and the new process takes all of
+//! them with `take_inherited`, as it can't know their IDs in advance. The old process must stop
+//! reading from the masters during the restart, as described in the `files` module.
+//!
+//! The processes running in a session are children of the old process. Once it exits they are
+//! reparented to the nearest subreaper or to init, so the new process can't wait for them, and
+//! should detect the end of a session by reading `EIO` from the master instead.
+use super::{register as register_fd, take_inherited_with_prefix, unregister as unregister_fd};
+use crate::diagnostics;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+
+/// The prefix of the names of the masters.
+const PREFIX: &str = "pty.";
+/// The prefix of the names of the metadata, which is distinct so that any ID can be told apart.
+const SESSION_PREFIX: &str = "pty-session.";
+
+/// A session inherited from the old process.
+#[derive(Debug)]
+pub struct Session<M> {
+    /// The ID the session was registered under.
+    pub id: String,
+    pub master: OwnedFd,
+    pub metadata: M,
+}
+
+impl<M> Session<M> {
+    /// The path of the session's terminal, e.g. `/dev/pts/3`.
+    pub fn terminal(&self) -> io::Result<PathBuf> {
+        terminal(self.master.as_fd())
+    }
+}
+
+/// Passes the PTY `master` of the session `id` to the next process, along with `metadata`,
+/// replacing what was registered earlier under the same ID. Fails if `id` is empty or contains `,`
+/// or `=`.
+pub fn register<M: Serialize>(id: &str, master: impl AsFd, metadata: &M) -> io::Result<()> {
+    let master = master.as_fd();
+    terminal(master)?;
+    let session = write_metadata(metadata)?;
+    register_fd(&format!("{SESSION_PREFIX}{id}"), &session)?;
+    register_fd(&format!("{PREFIX}{id}"), master)
+}
+
+/// Stops passing the session `id` to the next process, e.g. once it has ended.
+pub fn unregister(id: &str) {
+    unregister_fd(&format!("{PREFIX}{id}"));
+    unregister_fd(&format!("{SESSION_PREFIX}{id}"));
+}
+
+/// Takes all sessions that the old process passed. They are not passed on to the next process
+/// unless they are registered again. Sessions that are not PTY masters, or whose metadata can't be
+/// read as an `M`, are closed with a warning.
+pub fn take_inherited<M: DeserializeOwned>() -> Vec<Session<M>> {
+    let masters = take_inherited_with_prefix(PREFIX);
+    let mut metadata_fds = take_inherited_with_prefix(SESSION_PREFIX);
+    let mut sessions = Vec::new();
+    for (name, master) in masters {
+        let id = name[PREFIX.len()..].to_string();
+        let session = metadata_fds.remove(&format!("{SESSION_PREFIX}{id}"));
+        let metadata = match session {
+            Some(session) => terminal(master.as_fd()).and_then(|_| read_metadata(session)),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "the session's metadata is missing",
+            )),
+        };
+        match metadata {
+            Ok(metadata) => sessions.push(Session {
+                id,
+                master,
+                metadata,
+            }),
+            Err(e) => diagnostics::warn!("Closing inherited PTY session {}: {}", id, e),
+        }
+    }
+    for name in metadata_fds.keys() {
+        let id = &name[SESSION_PREFIX.len()..];
+        diagnostics::warn!("Ignoring inherited PTY session {} without a master", id);
+    }
+    sessions
+}
+
+/// The path of the terminal of a PTY master, which also checks that `master` is one.
+fn terminal(master: BorrowedFd<'_>) -> io::Result<PathBuf> {
+    let mut number: libc::c_uint = 0;
+    if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCGPTN, &mut number) } < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("not a PTY master: {}", io::Error::last_os_error()),
+        ));
+    }
+    Ok(PathBuf::from(format!("/dev/pts/{number}")))
+}
+
+fn write_metadata<M: Serialize>(metadata: &M) -> io::Result<File> {
+    let fd = unsafe { libc::memfd_create(c"shellflip-pty".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(&serde_json::to_vec(metadata)?)?;
+    Ok(file)
+}
+
+fn read_metadata<M: DeserializeOwned>(session: OwnedFd) -> io::Result<M> {
+    let mut file = File::from(session);
+    // The file offset is shared with the old process's copy, which is at the end.
+    file.rewind()?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::inherit;
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Metadata {
+        user: String,
+        columns: u16,
+    }
+
+    fn open_master() -> OwnedFd {
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+        assert!(fd >= 0, "{}", io::Error::last_os_error());
+        unsafe { OwnedFd::from_raw_fd(fd) }
+    }
+
+    #[test]
+    fn test_sessions() {
+        let master = open_master();
+        let metadata = Metadata {
+            user: "alice".into(),
+            columns: 80,
+        };
+        register("1", &master, &metadata).unwrap();
+        register("2", open_master(), &metadata).unwrap();
+        register("3.session", open_master(), &metadata).unwrap();
+        assert!(register("4", File::open("/dev/null").unwrap(), &metadata).is_err());
+        assert!(register("5,6", open_master(), &metadata).is_err());
+        inherit("pty.1");
+        inherit("pty-session.1");
+        inherit("pty.2");
+        inherit("pty.3.session");
+        inherit("pty-session.3.session");
+        unregister("2");
+
+        let sessions = take_inherited::<Metadata>();
+        let ids: Vec<_> = sessions.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["1", "3.session"]);
+        assert_eq!(sessions[0].metadata, metadata);
+        assert_eq!(
+            sessions[0].terminal().unwrap(),
+            terminal(master.as_fd()).unwrap()
+        );
+        // The sessions have been taken, including the one without metadata.
+        assert!(take_inherited::<Metadata>().is_empty());
+        unregister("1");
+        unregister("3.session");
+    }
+}
@@ -0,0 +1,160 @@
+//! Sockets of transparent proxies, e.g. SOCKS or TPROXY proxies, that are created with
+//! `IP_TRANSPARENT` and a firewall mark.
+//!
+//! Both options are set on the socket, so an inherited socket keeps them, but setting them needs
+//! `CAP_NET_ADMIN`. A new process that runs with fewer capabilities than the old one, e.g. after a
+//! change to its unit file, would serve the inherited sockets, only to fail when it next needs to
+//! open one. `socket` sets the options on the inherited socket again, so that the new process
+//! refuses the handover right away, with an `OptionRejected` error, if the kernel rejects them.
+use super::{register, set_sockopt, sockopt, take_inherited};
+use crate::diagnostics;
+use std::io;
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use thiserror::Error;
+
+/// The options of a transparent proxy socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransparentOptions {
+    /// `IP_TRANSPARENT`, or `IPV6_TRANSPARENT` for IPv6 sockets.
+    pub transparent: bool,
+    /// The `SO_MARK` firewall mark, which is left as it is if `None`.
+    pub mark: Option<u32>,
+}
+
+impl Default for TransparentOptions {
+    fn default() -> Self {
+        TransparentOptions {
+            transparent: true,
+            mark: None,
+        }
+    }
+}
+
+/// The kernel rejected an option of a transparent proxy socket, usually because this process
+/// lacks `CAP_NET_ADMIN`.
+#[derive(Error, Debug)]
+#[error("the kernel rejected {option} on transparent proxy socket {name}: {source}")]
+pub struct OptionRejected {
+    pub name: String,
+    pub option: &'static str,
+    pub source: io::Error,
+}
+
+/// Returns the socket that the old process passed under `name`, or else the socket returned by
+/// `open`, with `options` set. `open` should set the options itself before binding the socket, as
+/// binding to a non-local address needs `IP_TRANSPARENT`. The socket is passed to the next process
+/// under `name`.
+///
+/// Fails with an `OptionRejected` error, which keeps the kind of the underlying error, if the
+/// options can't be set.
+pub fn socket(
+    name: &str,
+    options: &TransparentOptions,
+    open: impl FnOnce() -> io::Result<OwnedFd>,
+) -> io::Result<OwnedFd> {
+    let fd = match take_inherited(name) {
+        Some(fd) => match describe(fd.as_fd()) {
+            Ok(inherited) => {
+                diagnostics::info!(
+                    "Inherited transparent proxy socket {}: {:?}",
+                    name,
+                    inherited
+                );
+                fd
+            }
+            Err(e) => {
+                diagnostics::info!("Closing inherited fd {}: {}", name, e);
+                open()?
+            }
+        },
+        None => open()?,
+    };
+    apply(name, fd.as_fd(), options)?;
+    register(name, &fd)?;
+    Ok(fd)
+}
+
+/// Returns the options of an `AF_INET` or `AF_INET6` socket.
+pub fn describe(fd: BorrowedFd<'_>) -> io::Result<TransparentOptions> {
+    let (level, option, _) = transparent_option(fd)?;
+    let mark = sockopt(fd, libc::SOL_SOCKET, libc::SO_MARK)? as u32;
+    Ok(TransparentOptions {
+        transparent: sockopt(fd, level, option)? != 0,
+        mark: Some(mark).filter(|mark| *mark != 0),
+    })
+}
+
+/// Set `options` on the socket, even if it has them already, to check that the kernel allows it.
+fn apply(name: &str, fd: BorrowedFd<'_>, options: &TransparentOptions) -> io::Result<()> {
+    let rejected = |option, source: io::Error| {
+        let kind = source.kind();
+        let name = name.to_string();
+        io::Error::new(
+            kind,
+            OptionRejected {
+                name,
+                option,
+                source,
+            },
+        )
+    };
+    let (level, option, option_name) = transparent_option(fd)?;
+    let transparent = options.transparent as libc::c_int;
+    set_sockopt(fd, level, option, &transparent).map_err(|e| rejected(option_name, e))?;
+    if let Some(mark) = options.mark {
+        set_sockopt(fd, libc::SOL_SOCKET, libc::SO_MARK, &mark)
+            .map_err(|e| rejected("SO_MARK", e))?;
+    }
+    Ok(())
+}
+
+/// The level, number and name of the transparent option for the address family of the socket.
+fn transparent_option(fd: BorrowedFd<'_>) -> io::Result<(libc::c_int, libc::c_int, &'static str)> {
+    match sockopt(fd, libc::SOL_SOCKET, libc::SO_DOMAIN)? {
+        libc::AF_INET => Ok((libc::SOL_IP, libc::IP_TRANSPARENT, "IP_TRANSPARENT")),
+        libc::AF_INET6 => Ok((libc::SOL_IPV6, libc::IPV6_TRANSPARENT, "IPV6_TRANSPARENT")),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not an IPv4 or IPv6 socket",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::assert_reused;
+    use super::*;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn test_socket() {
+        let open = || Ok(UdpSocket::bind("127.0.0.1:0")?.into());
+        let options = TransparentOptions {
+            transparent: true,
+            mark: Some(7),
+        };
+        let fd = match socket("tproxy", &options, open) {
+            // Without CAP_NET_ADMIN, the socket is refused.
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                let rejected = e.get_ref().unwrap().downcast_ref::<OptionRejected>();
+                assert_eq!(rejected.unwrap().option, "IP_TRANSPARENT");
+                return;
+            }
+            res => res.unwrap(),
+        };
+        assert_eq!(describe(fd.as_fd()).unwrap(), options);
+
+        let inherited = assert_reused("tproxy", || socket("tproxy", &options, open));
+        assert_eq!(describe(inherited.as_fd()).unwrap(), options);
+
+        let plain: OwnedFd = UdpSocket::bind("127.0.0.1:0").unwrap().into();
+        let described = describe(plain.as_fd()).unwrap();
+        assert_eq!(
+            described,
+            TransparentOptions {
+                transparent: false,
+                mark: None,
+            }
+        );
+    }
+}
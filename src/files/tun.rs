@@ -0,0 +1,129 @@
+//! TUN and TAP devices, e.g. of VPN servers.
+//!
+//! A TUN or TAP device that isn't persistent is removed once its last fd is closed, along with its
+//! addresses and routes, and packets routed to it meanwhile are dropped. `device` passes the fd to
+//! the new process instead, so the device stays up and tunnel traffic keeps flowing while the new
+//! process takes over the session state, which the old process can send with
+//! `LifecycleHandler::send_to_new_process`. Each queue of a multi-queue device is a separate fd,
+//! which is passed under its own name.
+//!
+//! The old process must stop reading from the device during the restart, as described in the
+//! `files` module.
+use super::reuse_or_open;
+use std::ffi::CStr;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+
+/// The flags that determine how packets are read and written, which an inherited device must have
+/// as requested.
+const MODE_FLAGS: libc::c_int =
+    libc::IFF_TUN | libc::IFF_TAP | libc::IFF_NO_PI | libc::IFF_VNET_HDR | libc::IFF_MULTI_QUEUE;
+
+/// Returns the TUN or TAP device that the old process passed under `name`, or attaches to a new
+/// one, along with the name of its interface. The device is passed to the next process under
+/// `name`.
+///
+/// `interface` is the name of the interface, e.g. `tun0`, or a pattern like `tun%d` to let the
+/// kernel pick one. `flags` are passed to `TUNSETIFF`, e.g. `libc::IFF_TUN | libc::IFF_NO_PI`. An
+/// inherited device is only reused if it matches both.
+pub fn device(name: &str, interface: &str, flags: libc::c_int) -> io::Result<(OwnedFd, String)> {
+    let fd = reuse_or_open(
+        name,
+        |fd| {
+            let (attached, attached_flags) = get_iff(fd)?;
+            if !interface.contains('%') && attached != interface {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("attached to {attached} rather than {interface}"),
+                ));
+            }
+            if attached_flags & MODE_FLAGS != flags & MODE_FLAGS {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("has flags {attached_flags:#x} rather than {flags:#x}"),
+                ));
+            }
+            Ok(())
+        },
+        || open(interface, flags),
+    )?;
+    let (attached, _) = get_iff(fd.as_fd())?;
+    Ok((fd, attached))
+}
+
+fn open(interface: &str, flags: libc::c_int) -> io::Result<OwnedFd> {
+    // std opens files with O_CLOEXEC.
+    let fd: OwnedFd = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")?
+        .into();
+    let mut ifr = ifreq(interface)?;
+    ifr.ifr_ifru.ifru_flags = flags as libc::c_short;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut ifr) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// The interface a TUN or TAP fd is attached to, and its flags.
+fn get_iff(fd: BorrowedFd<'_>) -> io::Result<(String, libc::c_int)> {
+    let mut ifr = ifreq("")?;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNGETIFF, &mut ifr) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let name = unsafe { CStr::from_ptr(ifr.ifr_name.as_ptr()) };
+    let flags = unsafe { ifr.ifr_ifru.ifru_flags } as u16;
+    Ok((name.to_string_lossy().into_owned(), flags.into()))
+}
+
+fn ifreq(interface: &str) -> io::Result<libc::ifreq> {
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    // The name must leave room for the terminating NUL.
+    if interface.len() >= ifr.ifr_name.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("interface name {interface:?} is too long"),
+        ));
+    }
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(interface.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(ifr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{assert_reused, inherit};
+    use super::super::{register, unregister};
+    use super::*;
+
+    #[test]
+    fn test_device() {
+        let flags = libc::IFF_TUN | libc::IFF_NO_PI;
+        // Creating a device needs CAP_NET_ADMIN and /dev/net/tun.
+        let (fd, interface) = match device("tun", "shellflip%d", flags) {
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EPERM | libc::ENOENT)) => return,
+            res => res.unwrap(),
+        };
+        assert!(interface.starts_with("shellflip"));
+
+        // The device still exists, as the inherited fd is open.
+        let fd = assert_reused("tun", || {
+            drop(fd);
+            let (fd, inherited) = device("tun", &interface, flags)?;
+            assert_eq!(inherited, interface);
+            Ok(fd)
+        });
+
+        // A TAP device is not the TUN device.
+        register("tun", &fd).unwrap();
+        inherit("tun");
+        let (tap, tap_interface) =
+            device("tun", "shellflip%d", libc::IFF_TAP | libc::IFF_NO_PI).unwrap();
+        assert_ne!(tap_interface, interface);
+        drop((fd, tap));
+        unregister("tun");
+    }
+}
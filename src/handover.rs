@@ -0,0 +1,564 @@
+//! A versioned tag-length-value record format for handover data.
+//!
+//! Data sent with `LifecycleHandler::send_to_new_process` is read by a different binary, which may
+//! be older or newer than the one that wrote it. A `HandoverRecord` is a set of fields keyed by
+//! numeric tags, so a reader can skip fields it does not know about and treat fields that are
+//! missing as absent, rather than failing to parse the whole record. Integer fields may also be
+//! widened between versions, as they are read back from any length up to 8 bytes.
+//!
+//! Tags should never be reused for a field with a different meaning. The schema version is up to
+//! the application, and can be used to reject records from versions that are too old.
+//!
+//! ```no_run
+//! # async fn example(mut write_pipe: shellflip::lifecycle::PipeWriter) -> std::io::Result<()> {
+//! use shellflip::handover::HandoverRecord;
+//! use shellflip::lifecycle::receive_from_old_process;
+//!
+//! const TAG_GENERATION: u16 = 1;
+//! const TAG_NAME: u16 = 2;
+//!
+//! // In the old process:
+//! let mut record = HandoverRecord::new(1);
+//! record.put_u64(TAG_GENERATION, 7).put_str(TAG_NAME, "example");
+//! record.write_to(&mut write_pipe).await?;
+//!
+//! // In the new process:
+//! if let Some(mut pipe) = receive_from_old_process() {
+//!     let record = HandoverRecord::read_from(&mut pipe).await?;
+//!     let generation = record.get_u64(TAG_GENERATION)?.unwrap_or(0);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Submodules provide records for common kinds of state.
+//!
+//! With the `macros` feature, `#[derive(Handover)]` implements the `Handover` trait for a struct
+//! with named fields, so that it can be sent with `send` and received with `receive`. Each field
+//! needs a tag, and its type must implement `HandoverField`:
+//!
+//! ```ignore
+//! #[derive(Handover)]
+//! #[handover(version = 2)]
+//! struct State {
+//!     #[handover(tag = 1)]
+//!     generation: u64,
+//!     // Writers older than version 2 don't send this, so it is defaulted for them.
+//!     #[handover(tag = 2, since = 2)]
+//!     name: String,
+//!     // Defaulted whenever it is missing, with `Default::default` or the given function.
+//!     #[handover(tag = 3, default = "default_limit")]
+//!     limit: u32,
+//!     // Not handed over at all.
+//!     #[handover(skip)]
+//!     cache: Vec<u8>,
+//! }
+//! ```
+//!
+//! Any other field that is missing from the received record is an error.
+#[cfg(target_os = "linux")]
+pub mod cache;
+pub mod certs;
+pub mod counters;
+pub mod pool;
+pub mod rate_limit;
+pub mod table;
+
+#[cfg(feature = "macros")]
+pub use shellflip_macros::Handover;
+
+use std::collections::BTreeMap;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The version of the encoding itself, as opposed to the application's schema version.
+pub(crate) const FORMAT_VERSION: u8 = 1;
+/// Records larger than this are rejected when reading, to avoid allocating for corrupt lengths.
+const MAX_RECORD_LEN: u32 = 64 * 1024 * 1024;
+/// The length of the format version, schema version and body length.
+const HEADER_LEN: usize = 1 + 2 + 4;
+
+/// A set of tagged fields with an application-defined schema version.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HandoverRecord {
+    version: u16,
+    fields: BTreeMap<u16, Vec<u8>>,
+}
+
+impl HandoverRecord {
+    /// Create an empty record with the given schema version.
+    pub fn new(version: u16) -> Self {
+        HandoverRecord {
+            version,
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// The schema version of the writer.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// The tags of all fields in this record, including those unknown to the reader.
+    pub fn tags(&self) -> impl Iterator<Item = u16> + '_ {
+        self.fields.keys().copied()
+    }
+
+    /// Set a field to raw bytes, replacing any previous value with the same tag.
+    pub fn put_bytes(&mut self, tag: u16, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.fields.insert(tag, value.into());
+        self
+    }
+
+    pub fn put_u64(&mut self, tag: u16, value: u64) -> &mut Self {
+        self.put_bytes(tag, value.to_be_bytes())
+    }
+
+    pub fn put_i64(&mut self, tag: u16, value: i64) -> &mut Self {
+        self.put_bytes(tag, value.to_be_bytes())
+    }
+
+    pub fn put_bool(&mut self, tag: u16, value: bool) -> &mut Self {
+        self.put_bytes(tag, [value as u8])
+    }
+
+    pub fn put_str(&mut self, tag: u16, value: &str) -> &mut Self {
+        self.put_bytes(tag, value.as_bytes())
+    }
+
+    /// Set a field to a nested record, which has its own schema version.
+    pub fn put_record(&mut self, tag: u16, value: &HandoverRecord) -> &mut Self {
+        self.put_bytes(tag, value.to_bytes())
+    }
+
+    /// Set a field to a list of nested records.
+    pub fn put_records<'a>(
+        &mut self,
+        tag: u16,
+        values: impl IntoIterator<Item = &'a HandoverRecord>,
+    ) -> &mut Self {
+        let bytes: Vec<u8> = values.into_iter().flat_map(|r| r.to_bytes()).collect();
+        self.put_bytes(tag, bytes)
+    }
+
+    /// Get the raw bytes of a field, or `None` if the writer did not set it.
+    pub fn get_bytes(&self, tag: u16) -> Option<&[u8]> {
+        self.fields.get(&tag).map(Vec::as_slice)
+    }
+
+    /// Get an unsigned integer field, which may have been written with any width up to 8 bytes.
+    pub fn get_u64(&self, tag: u16) -> io::Result<Option<u64>> {
+        self.get_int(tag, u64::from_be_bytes, 0)
+    }
+
+    /// Get a signed integer field, which may have been written with any width up to 8 bytes.
+    pub fn get_i64(&self, tag: u16) -> io::Result<Option<i64>> {
+        self.get_int(tag, i64::from_be_bytes, 0xff)
+    }
+
+    pub fn get_bool(&self, tag: u16) -> io::Result<Option<bool>> {
+        match self.get_bytes(tag) {
+            None => Ok(None),
+            Some([b]) => Ok(Some(*b != 0)),
+            Some(_) => Err(invalid_field(tag, "expected a bool")),
+        }
+    }
+
+    pub fn get_str(&self, tag: u16) -> io::Result<Option<&str>> {
+        self.get_bytes(tag)
+            .map(|b| std::str::from_utf8(b).map_err(|_| invalid_field(tag, "expected UTF-8")))
+            .transpose()
+    }
+
+    pub fn get_record(&self, tag: u16) -> io::Result<Option<HandoverRecord>> {
+        self.get_bytes(tag)
+            .map(HandoverRecord::from_bytes)
+            .transpose()
+    }
+
+    /// Get a list of nested records set with `put_records`. A missing field is an empty list.
+    pub fn get_records(&self, tag: u16) -> io::Result<Vec<HandoverRecord>> {
+        let mut bytes = self.get_bytes(tag).unwrap_or_default();
+        let mut records = Vec::new();
+        while !bytes.is_empty() {
+            let (header, rest) = split(bytes, HEADER_LEN)?;
+            let (version, body_len) = parse_header(header.try_into().unwrap())?;
+            let (body, rest) = split(rest, body_len as usize)?;
+            records.push(Self::parse_body(version, body)?);
+            bytes = rest;
+        }
+        Ok(records)
+    }
+
+    fn get_int<T>(
+        &self,
+        tag: u16,
+        from_be_bytes: impl Fn([u8; 8]) -> T,
+        sign_fill: u8,
+    ) -> io::Result<Option<T>> {
+        let bytes = match self.get_bytes(tag) {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        if bytes.len() > 8 {
+            return Err(invalid_field(tag, "integer is wider than 8 bytes"));
+        }
+        let negative = bytes.first().is_some_and(|b| b & 0x80 != 0);
+        let mut buf = [if negative { sign_fill } else { 0 }; 8];
+        buf[8 - bytes.len()..].copy_from_slice(bytes);
+        Ok(Some(from_be_bytes(buf)))
+    }
+
+    /// Encode the record, including its header.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let body_len: usize = self.fields.values().map(|v| 2 + 4 + v.len()).sum();
+        let mut buf = Vec::with_capacity(HEADER_LEN + body_len);
+        buf.push(FORMAT_VERSION);
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.extend_from_slice(&(body_len as u32).to_be_bytes());
+        for (tag, value) in &self.fields {
+            buf.extend_from_slice(&tag.to_be_bytes());
+            buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            buf.extend_from_slice(value);
+        }
+        buf
+    }
+
+    /// Decode a record produced by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let (header, body) = split(bytes, HEADER_LEN)?;
+        let (version, body_len) = parse_header(header.try_into().unwrap())?;
+        if body.len() != body_len as usize {
+            return Err(invalid_data("record length does not match its header"));
+        }
+        Self::parse_body(version, body)
+    }
+
+    /// Write the record to the handover pipe or any other writer.
+    pub async fn write_to<W: AsyncWrite + Unpin + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.to_bytes()).await?;
+        w.flush().await
+    }
+
+    /// Read a single record written by `write_to`.
+    pub async fn read_from<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        r.read_exact(&mut header).await?;
+        let (version, body_len) = parse_header(header)?;
+        let mut body = vec![0; body_len as usize];
+        r.read_exact(&mut body).await?;
+        Self::parse_body(version, &body)
+    }
+
+    fn parse_body(version: u16, mut body: &[u8]) -> io::Result<Self> {
+        let mut fields = BTreeMap::new();
+        while !body.is_empty() {
+            let (field_header, rest) = split(body, 6)?;
+            let tag = u16::from_be_bytes([field_header[0], field_header[1]]);
+            let len = u32::from_be_bytes(field_header[2..].try_into().unwrap());
+            let (value, rest) = split(rest, len as usize)?;
+            fields.insert(tag, value.to_vec());
+            body = rest;
+        }
+        Ok(HandoverRecord { version, fields })
+    }
+}
+
+/// State that is converted to and from a `HandoverRecord`, usually with `#[derive(Handover)]`.
+pub trait Handover: Sized {
+    /// The schema version of the records written by this version of the application.
+    const VERSION: u16;
+
+    fn to_record(&self) -> HandoverRecord;
+
+    fn from_record(record: &HandoverRecord) -> io::Result<Self>;
+}
+
+/// Write `value` to the handover pipe or any other writer.
+pub async fn send<T: Handover, W: AsyncWrite + Unpin + ?Sized>(
+    value: &T,
+    w: &mut W,
+) -> io::Result<()> {
+    value.to_record().write_to(w).await
+}
+
+/// Read a value written by `send`.
+pub async fn receive<T: Handover, R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<T> {
+    T::from_record(&HandoverRecord::read_from(r).await?)
+}
+
+/// A type that can be stored in a single field of a `HandoverRecord`.
+pub trait HandoverField: Sized {
+    fn put(&self, record: &mut HandoverRecord, tag: u16);
+
+    /// Returns `None` if the field is missing.
+    fn get(record: &HandoverRecord, tag: u16) -> io::Result<Option<Self>>;
+}
+
+macro_rules! int_field {
+    ($put:ident, $get:ident, $wide:ty, $($ty:ty),*) => {
+        $(impl HandoverField for $ty {
+            fn put(&self, record: &mut HandoverRecord, tag: u16) {
+                record.$put(tag, *self as $wide);
+            }
+
+            fn get(record: &HandoverRecord, tag: u16) -> io::Result<Option<Self>> {
+                record
+                    .$get(tag)?
+                    .map(|v| {
+                        Self::try_from(v).map_err(|_| invalid_field(tag, "integer is too large"))
+                    })
+                    .transpose()
+            }
+        })*
+    };
+}
+
+int_field!(put_u64, get_u64, u64, u8, u16, u32, u64, usize);
+int_field!(put_i64, get_i64, i64, i8, i16, i32, i64, isize);
+
+impl HandoverField for bool {
+    fn put(&self, record: &mut HandoverRecord, tag: u16) {
+        record.put_bool(tag, *self);
+    }
+
+    fn get(record: &HandoverRecord, tag: u16) -> io::Result<Option<Self>> {
+        record.get_bool(tag)
+    }
+}
+
+impl HandoverField for String {
+    fn put(&self, record: &mut HandoverRecord, tag: u16) {
+        record.put_str(tag, self);
+    }
+
+    fn get(record: &HandoverRecord, tag: u16) -> io::Result<Option<Self>> {
+        Ok(record.get_str(tag)?.map(Into::into))
+    }
+}
+
+impl HandoverField for HandoverRecord {
+    fn put(&self, record: &mut HandoverRecord, tag: u16) {
+        record.put_record(tag, self);
+    }
+
+    fn get(record: &HandoverRecord, tag: u16) -> io::Result<Option<Self>> {
+        record.get_record(tag)
+    }
+}
+
+/// Not setting the field stands for `None`.
+impl<T: HandoverField> HandoverField for Option<T> {
+    fn put(&self, record: &mut HandoverRecord, tag: u16) {
+        if let Some(value) = self {
+            value.put(record, tag);
+        }
+    }
+
+    fn get(record: &HandoverRecord, tag: u16) -> io::Result<Option<Self>> {
+        Ok(Some(T::get(record, tag)?))
+    }
+}
+
+/// Each element is stored in a nested record.
+impl<T: HandoverField> HandoverField for Vec<T> {
+    fn put(&self, record: &mut HandoverRecord, tag: u16) {
+        let elements: Vec<_> = self
+            .iter()
+            .map(|value| {
+                let mut element = HandoverRecord::new(0);
+                value.put(&mut element, 1);
+                element
+            })
+            .collect();
+        record.put_records(tag, &elements);
+    }
+
+    fn get(record: &HandoverRecord, tag: u16) -> io::Result<Option<Self>> {
+        if record.get_bytes(tag).is_none() {
+            return Ok(None);
+        }
+        record
+            .get_records(tag)?
+            .iter()
+            .map(|element| T::get(element, 1)?.ok_or_else(|| invalid_field(tag, "missing element")))
+            .collect::<io::Result<_>>()
+            .map(Some)
+    }
+}
+
+/// The error for a field that `#[derive(Handover)]` requires but the record is missing.
+#[doc(hidden)]
+pub fn missing_field(tag: u16, name: &str) -> io::Error {
+    invalid_field(tag, &format!("missing required field {name}"))
+}
+
+fn parse_header(header: [u8; HEADER_LEN]) -> io::Result<(u16, u32)> {
+    if header[0] != FORMAT_VERSION {
+        return Err(invalid_data(format!(
+            "unsupported handover record format {}",
+            header[0]
+        )));
+    }
+    let version = u16::from_be_bytes([header[1], header[2]]);
+    let body_len = u32::from_be_bytes(header[3..].try_into().unwrap());
+    if body_len > MAX_RECORD_LEN {
+        return Err(invalid_data(format!(
+            "handover record of {body_len} bytes is too large"
+        )));
+    }
+    Ok((version, body_len))
+}
+
+fn split(bytes: &[u8], at: usize) -> io::Result<(&[u8], &[u8])> {
+    if bytes.len() < at {
+        return Err(invalid_data("truncated handover record"));
+    }
+    Ok(bytes.split_at(at))
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn invalid_field(tag: u16, msg: &str) -> io::Error {
+    invalid_data(format!("handover record field {tag}: {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let mut nested = HandoverRecord::new(3);
+        nested.put_bool(1, true);
+        let mut record = HandoverRecord::new(2);
+        record
+            .put_u64(1, 42)
+            .put_i64(2, -5)
+            .put_str(3, "hello")
+            .put_record(4, &nested);
+        let copy = record.clone();
+        record.put_records(5, [&nested, &copy]);
+
+        let (mut r, mut w) = tokio::io::duplex(1024);
+        record.write_to(&mut w).await.unwrap();
+        let received = HandoverRecord::read_from(&mut r).await.unwrap();
+
+        assert_eq!(received, record);
+        assert_eq!(received.version(), 2);
+        assert_eq!(received.get_u64(1).unwrap(), Some(42));
+        assert_eq!(received.get_i64(2).unwrap(), Some(-5));
+        assert_eq!(received.get_str(3).unwrap(), Some("hello"));
+        assert_eq!(received.get_record(4).unwrap(), Some(nested.clone()));
+        assert_eq!(received.get_records(5).unwrap().len(), 2);
+        assert_eq!(received.get_u64(6).unwrap(), None);
+        assert!(received.get_records(6).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_schema_evolution() {
+        // A newer writer added field 9 and a narrower reader wrote field 1 as a single byte.
+        let mut newer = HandoverRecord::new(2);
+        newer
+            .put_bytes(1, [7])
+            .put_bytes(2, [0xff, 0xfe])
+            .put_str(9, "new");
+
+        let older = HandoverRecord::from_bytes(&newer.to_bytes()).unwrap();
+        assert_eq!(older.get_u64(1).unwrap(), Some(7));
+        assert_eq!(older.get_i64(2).unwrap(), Some(-2));
+        assert_eq!(older.tags().collect::<Vec<_>>(), vec![1, 2, 9]);
+    }
+
+    #[test]
+    fn test_fields() {
+        let mut record = HandoverRecord::new(1);
+        300u16.put(&mut record, 1);
+        (-3i8).put(&mut record, 2);
+        vec![String::from("a"), String::from("b")].put(&mut record, 3);
+        None::<u32>.put(&mut record, 4);
+        Vec::<u8>::new().put(&mut record, 5);
+
+        assert_eq!(u16::get(&record, 1).unwrap(), Some(300));
+        assert!(u8::get(&record, 1).is_err());
+        assert_eq!(i32::get(&record, 2).unwrap(), Some(-3));
+        assert_eq!(
+            Vec::<String>::get(&record, 3).unwrap(),
+            Some(vec!["a".into(), "b".into()])
+        );
+        assert_eq!(Option::<u32>::get(&record, 4).unwrap(), Some(None));
+        assert_eq!(Vec::<u8>::get(&record, 5).unwrap(), Some(vec![]));
+        assert_eq!(Vec::<u8>::get(&record, 6).unwrap(), None);
+    }
+
+    #[cfg(feature = "macros")]
+    #[tokio::test]
+    async fn test_derive() {
+        #[derive(Debug, Default, PartialEq, Handover)]
+        #[handover(version = 1)]
+        struct V1 {
+            #[handover(tag = 1)]
+            generation: u64,
+        }
+
+        #[derive(Debug, PartialEq, Handover)]
+        #[handover(version = 2)]
+        struct V2 {
+            #[handover(tag = 1)]
+            generation: u64,
+            #[handover(tag = 2, since = 2)]
+            name: String,
+            #[handover(tag = 3, default = "default_limit")]
+            limit: u32,
+            #[handover(tag = 4)]
+            nested: Option<V1>,
+            #[handover(skip)]
+            cache: Vec<u8>,
+        }
+
+        fn default_limit() -> u32 {
+            10
+        }
+
+        let v2 = V2 {
+            generation: 7,
+            name: "new".into(),
+            limit: 3,
+            nested: Some(V1 { generation: 1 }),
+            cache: vec![1],
+        };
+        let (mut r, mut w) = tokio::io::duplex(1024);
+        send(&v2, &mut w).await.unwrap();
+        let received: V2 = receive(&mut r).await.unwrap();
+        assert_eq!(received.limit, 3);
+        assert_eq!(received.nested, v2.nested);
+        assert!(received.cache.is_empty());
+
+        // A newer reader fills in what an older writer didn't send.
+        let from_v1 = V2::from_record(&V1 { generation: 5 }.to_record()).unwrap();
+        assert_eq!(from_v1.generation, 5);
+        assert_eq!(from_v1.name, "");
+        assert_eq!(from_v1.limit, 10);
+        // An older reader ignores the new fields.
+        assert_eq!(V1::from_record(&v2.to_record()).unwrap().generation, 7);
+        // Fields without a default are required.
+        assert!(V1::from_record(&HandoverRecord::new(2)).is_err());
+    }
+
+    #[test]
+    fn test_invalid_records() {
+        let mut record = HandoverRecord::new(1);
+        record.put_bytes(1, [0; 9]).put_bytes(2, [2]);
+        assert!(record.get_u64(1).is_err());
+        assert!(record.get_bool(2).is_ok());
+        assert!(record.get_str(2).is_ok());
+
+        let bytes = record.to_bytes();
+        assert!(HandoverRecord::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut bad_format = bytes.clone();
+        bad_format[0] = 99;
+        assert!(HandoverRecord::from_bytes(&bad_format).is_err());
+    }
+}
@@ -0,0 +1,375 @@
+//! Handover of an in-memory key/value cache through shared memory.
+//!
+//! Copying a large cache through the handover pipe doubles its memory footprint while the child
+//! deserialises it, and delays readiness until the copy completes. Instead, the old process can
+//! write a `CacheSnapshot` into a sealed memfd, which the new process inherits and maps read-only.
+//! The child wraps it in a `ReadThroughCache`, which serves lookups from the snapshot until entries
+//! are replaced locally, so the cache does not start cold after every upgrade.
+//!
+//! ```no_run
+//! # use shellflip::handover::cache::{CacheSnapshot, ReadThroughCache, SharedCache};
+//! # use shellflip::lifecycle::*;
+//! # use std::collections::HashMap;
+//! # use std::os::fd::{AsRawFd, RawFd};
+//! struct App {
+//!     cache: HashMap<Vec<u8>, Vec<u8>>,
+//!     snapshot: Option<CacheSnapshot>,
+//! }
+//!
+//! #[async_trait::async_trait]
+//! impl LifecycleHandler for App {
+//!     async fn fds_for_new_process(&mut self) -> Vec<RawFd> {
+//!         let entries = self.cache.iter().map(|(k, v)| (k.as_slice(), v.as_slice()));
+//!         self.snapshot = CacheSnapshot::new(entries).ok();
+//!         self.snapshot.iter().map(|s| s.as_raw_fd()).collect()
+//!     }
+//!
+//!     async fn send_to_new_process(&mut self, mut write_pipe: PipeWriter) -> std::io::Result<()> {
+//!         match self.snapshot.take() {
+//!             Some(snapshot) => snapshot.write_to(&mut write_pipe).await,
+//!             None => Ok(()),
+//!         }
+//!     }
+//! }
+//!
+//! # async fn child() -> std::io::Result<()> {
+//! // In the new process:
+//! let mut cache = ReadThroughCache::default();
+//! if let Some(mut pipe) = receive_from_old_process() {
+//!     cache = ReadThroughCache::new(unsafe { SharedCache::read_from(&mut pipe).await? });
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use super::HandoverRecord;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr::NonNull;
+use std::{ptr, slice};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const SCHEMA_VERSION: u16 = 1;
+const TAG_FD: u16 = 1;
+const TAG_LEN: u16 = 2;
+
+const MAGIC: &[u8; 8] = b"SFCACHE1";
+/// The length of the magic and entry count.
+const HEADER_LEN: usize = 16;
+/// The length of the key offset, key length, value offset and value length of an entry.
+const INDEX_ENTRY_LEN: usize = 32;
+
+/// A snapshot of a cache written to a sealed memfd, ready to be inherited by the new process.
+///
+/// The layout is a header containing the number of entries, followed by an index of entries
+/// sorted by key, followed by the keys and values. All integers are big-endian u64s.
+pub struct CacheSnapshot {
+    fd: OwnedFd,
+    len: usize,
+}
+
+impl CacheSnapshot {
+    /// Write the entries into a new memfd. If a key occurs more than once, the last value is kept.
+    pub fn new<'a>(entries: impl IntoIterator<Item = (&'a [u8], &'a [u8])>) -> io::Result<Self> {
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        // A stable sort keeps duplicate keys in insertion order, so the last one wins.
+        entries.sort_by_key(|(k, _)| *k);
+        entries.reverse();
+        entries.dedup_by_key(|(k, _)| *k);
+        entries.reverse();
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&(entries.len() as u64).to_be_bytes());
+        let mut offset = HEADER_LEN + entries.len() * INDEX_ENTRY_LEN;
+        for (k, v) in &entries {
+            for n in [offset, k.len(), offset + k.len(), v.len()] {
+                buf.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+            offset += k.len() + v.len();
+        }
+        for (k, v) in &entries {
+            buf.extend_from_slice(k);
+            buf.extend_from_slice(v);
+        }
+
+        let fd = unsafe {
+            libc::memfd_create(
+                c"shellflip-cache".as_ptr(),
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(&buf)?;
+
+        // The child can rely on the contents not changing under it.
+        let seals =
+            libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+        if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(CacheSnapshot {
+            fd: file.into(),
+            len: buf.len(),
+        })
+    }
+
+    /// Tell the new process where to find the snapshot. The fd must have been returned from
+    /// `LifecycleHandler::fds_for_new_process` so that the new process inherited it.
+    pub async fn write_to<W: AsyncWrite + Unpin + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        let mut record = HandoverRecord::new(SCHEMA_VERSION);
+        record
+            .put_i64(TAG_FD, self.fd.as_raw_fd().into())
+            .put_u64(TAG_LEN, self.len as u64);
+        record.write_to(w).await
+    }
+}
+
+impl AsRawFd for CacheSnapshot {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// A read-only mapping of a `CacheSnapshot` inherited from the old process.
+pub struct SharedCache {
+    ptr: NonNull<u8>,
+    map_len: usize,
+    entries: usize,
+}
+
+// The mapping is read-only and the memfd is sealed against writes.
+unsafe impl Send for SharedCache {}
+unsafe impl Sync for SharedCache {}
+
+impl SharedCache {
+    /// Map the snapshot described by a record written with `CacheSnapshot::write_to`.
+    ///
+    /// # Safety
+    /// The fd in the record must have been inherited from the old process, and is owned by the
+    /// returned `SharedCache`. As with `receive_from_old_process`, the behaviour is undefined if
+    /// the record was produced by anything other than the old process.
+    pub async unsafe fn read_from<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Self> {
+        let record = HandoverRecord::read_from(r).await?;
+        let (fd, len) = match (record.get_i64(TAG_FD)?, record.get_u64(TAG_LEN)?) {
+            (Some(fd), Some(len)) => (fd, len),
+            _ => return Err(invalid_data("cache record is missing the fd or length")),
+        };
+        let fd = RawFd::try_from(fd).map_err(|_| invalid_data("invalid cache fd"))?;
+        Self::from_fd(OwnedFd::from_raw_fd(fd), len as usize)
+    }
+
+    fn from_fd(fd: OwnedFd, len: usize) -> io::Result<Self> {
+        // Accessing a mapping beyond the end of the file would raise SIGBUS.
+        let file_len = File::from(fd.try_clone()?).metadata()?.len();
+        if len < HEADER_LEN || file_len < len as u64 {
+            return Err(invalid_data("cache snapshot is truncated"));
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // The mapping stays valid after the fd is closed.
+        drop(fd);
+
+        let mut cache = SharedCache {
+            ptr: NonNull::new(ptr as *mut u8).unwrap(),
+            map_len: len,
+            entries: 0,
+        };
+        cache.validate()?;
+        Ok(cache)
+    }
+
+    fn validate(&mut self) -> io::Result<()> {
+        let data = self.data();
+        if &data[..8] != MAGIC {
+            return Err(invalid_data("not a cache snapshot"));
+        }
+        let entries = read_u64(data, 8) as usize;
+        let index_end = entries
+            .checked_mul(INDEX_ENTRY_LEN)
+            .and_then(|n| n.checked_add(HEADER_LEN))
+            .filter(|n| *n <= data.len())
+            .ok_or_else(|| invalid_data("cache snapshot index is truncated"))?;
+        for i in 0..entries {
+            let base = HEADER_LEN + i * INDEX_ENTRY_LEN;
+            for (off, len) in [(base, base + 8), (base + 16, base + 24)] {
+                let (off, len) = (read_u64(data, off), read_u64(data, len));
+                if off < index_end as u64
+                    || off
+                        .checked_add(len)
+                        .is_none_or(|end| end > data.len() as u64)
+                {
+                    return Err(invalid_data("cache snapshot entry is out of bounds"));
+                }
+            }
+        }
+        self.entries = entries;
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.map_len) }
+    }
+
+    /// The key and value of the entry at `i` in the sorted index.
+    fn entry(&self, i: usize) -> (&[u8], &[u8]) {
+        let data = self.data();
+        let base = HEADER_LEN + i * INDEX_ENTRY_LEN;
+        let field = |n: usize| read_u64(data, base + n * 8) as usize;
+        (
+            &data[field(0)..field(0) + field(1)],
+            &data[field(2)..field(2) + field(3)],
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        let (mut lo, mut hi) = (0, self.entries);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let (k, v) = self.entry(mid);
+            match k.cmp(key) {
+                std::cmp::Ordering::Equal => return Some(v),
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+            }
+        }
+        None
+    }
+
+    /// Iterate over all entries, sorted by key.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> + '_ {
+        (0..self.entries).map(|i| self.entry(i))
+    }
+}
+
+impl Drop for SharedCache {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.map_len);
+        }
+    }
+}
+
+/// A cache that serves entries from a `SharedCache` snapshot until they are replaced or removed
+/// locally.
+#[derive(Default)]
+pub struct ReadThroughCache {
+    snapshot: Option<SharedCache>,
+    /// Local changes, where `None` marks an entry removed from the snapshot.
+    local: HashMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl ReadThroughCache {
+    pub fn new(snapshot: SharedCache) -> Self {
+        ReadThroughCache {
+            snapshot: Some(snapshot),
+            local: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        match self.local.get(key) {
+            Some(value) => value.as_deref(),
+            None => self.snapshot.as_ref()?.get(key),
+        }
+    }
+
+    pub fn insert(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.local.insert(key.into(), Some(value.into()));
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        if self.snapshot.as_ref().is_some_and(|s| s.get(key).is_some()) {
+            self.local.insert(key.to_vec(), None);
+        } else {
+            self.local.remove(key);
+        }
+    }
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> CacheSnapshot {
+        let entries: [(&[u8], &[u8]); 4] =
+            [(b"b", b"2"), (b"a", b"1"), (b"c", b"old"), (b"c", b"3")];
+        CacheSnapshot::new(entries).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_shared_cache() {
+        let snapshot = snapshot();
+        // Simulate inheriting the fd under a different number.
+        let fd = snapshot.fd.try_clone().unwrap();
+        let cache = SharedCache::from_fd(fd, snapshot.len).unwrap();
+        drop(snapshot);
+
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(b"a"), Some(&b"1"[..]));
+        assert_eq!(cache.get(b"c"), Some(&b"3"[..]));
+        assert_eq!(cache.get(b"d"), None);
+        let keys: Vec<_> = cache.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![b"a", b"b", b"c"]);
+    }
+
+    #[tokio::test]
+    async fn test_read_through_cache() {
+        let snapshot = snapshot();
+        let (mut r, mut w) = tokio::io::duplex(1024);
+        snapshot.write_to(&mut w).await.unwrap();
+        // The test shares the fd table with the "old process", so hand over a duplicate.
+        let record = HandoverRecord::read_from(&mut r).await.unwrap();
+        assert_eq!(
+            record.get_i64(TAG_FD).unwrap(),
+            Some(snapshot.fd.as_raw_fd().into())
+        );
+        let shared = SharedCache::from_fd(snapshot.fd.try_clone().unwrap(), snapshot.len).unwrap();
+
+        let mut cache = ReadThroughCache::new(shared);
+        cache.insert(&b"a"[..], &b"new"[..]);
+        cache.remove(b"b");
+        assert_eq!(cache.get(b"a"), Some(&b"new"[..]));
+        assert_eq!(cache.get(b"b"), None);
+        assert_eq!(cache.get(b"c"), Some(&b"3"[..]));
+    }
+
+    #[test]
+    fn test_snapshot_is_sealed() {
+        let snapshot = snapshot();
+        let mut file = File::from(snapshot.fd.try_clone().unwrap());
+        assert!(file.write_all(b"x").is_err());
+    }
+}
@@ -0,0 +1,359 @@
+//! Handover of loaded TLS certificates and keys, so that both processes serve the same certificate
+//! while they overlap.
+//!
+//! If a certificate is renewed on disk while the old process still serves the one it loaded
+//! earlier, a new process that loads it from disk serves the renewed certificate alongside the old
+//! process serving the previous one, until the old process exits. Clients then see either from one
+//! connection to the next. Instead, the old process sends the certificates it serves in a
+//! `CertSnapshot`, along with the modification times of the files it loaded them from. The new
+//! process calls `CertSnapshot::resolve` for each certificate, which reuses what it was handed
+//! unless the `ReloadPolicy` says to load the certificate from disk, and loads it from disk if it
+//! was not handed over.
+//!
+//! ```no_run
+//! # use shellflip::handover::certs::{CertSnapshot, LoadedCert, ReloadPolicy};
+//! # async fn example(
+//! #     mut write_pipe: shellflip::lifecycle::PipeWriter,
+//! #     mut read_pipe: shellflip::lifecycle::PipeReader,
+//! #     serving: LoadedCert,
+//! # ) -> std::io::Result<()> {
+//! // In the old process:
+//! CertSnapshot::new([serving]).write_to(&mut write_pipe).await?;
+//! // In the new process:
+//! let snapshot = CertSnapshot::read_from(&mut read_pipe).await?;
+//! let (cert_path, key_path) = ("/etc/tls/www.pem", "/etc/tls/www.key");
+//! let cert = snapshot.resolve("www", cert_path, key_path, ReloadPolicy::Never)?;
+//! // Once the old process exited:
+//! if let Some(renewed) = cert.reload_if_changed()? {
+//!     // Serve `renewed` from now on.
+//! #   drop(renewed);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The private keys are written to the handover pipe as they are, so they should only be sent on
+//! the handover pipe itself, and not e.g. to a file.
+use super::{missing_field, HandoverRecord};
+use crate::diagnostics;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const SCHEMA_VERSION: u16 = 1;
+
+const TAG_CERTS: u16 = 1;
+
+const TAG_CERT_NAME: u16 = 1;
+const TAG_CERT_PATH: u16 = 2;
+const TAG_KEY_PATH: u16 = 3;
+const TAG_CERT_PEM: u16 = 4;
+const TAG_KEY_PEM: u16 = 5;
+const TAG_CERT_MTIME: u16 = 6;
+const TAG_KEY_MTIME: u16 = 7;
+
+/// How many times `LoadedCert::load` reads the files again if they are modified while it reads
+/// them.
+const LOAD_ATTEMPTS: usize = 3;
+
+/// A certificate chain and its private key, as loaded from a pair of files.
+#[derive(Clone, PartialEq, Eq)]
+pub struct LoadedCert {
+    /// The name the application uses for the certificate, e.g. the server name it is served for.
+    pub name: String,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// The contents of `cert_path`, usually a PEM encoded chain.
+    pub cert: Vec<u8>,
+    /// The contents of `key_path`, usually a PEM encoded private key.
+    pub key: Vec<u8>,
+    /// The modification time of `cert_path` when it was read.
+    pub cert_modified: Option<SystemTime>,
+    /// The modification time of `key_path` when it was read.
+    pub key_modified: Option<SystemTime>,
+}
+
+// Keeps the private key out of logs.
+impl std::fmt::Debug for LoadedCert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoadedCert")
+            .field("name", &self.name)
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .field("cert_modified", &self.cert_modified)
+            .field("key_modified", &self.key_modified)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LoadedCert {
+    /// Read a certificate chain and private key from disk.
+    pub fn load(
+        name: &str,
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> io::Result<Self> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        // A renewal replaces the certificate and the key one after the other, so a pair read in
+        // between doesn't match. The files are read again until neither was modified while they
+        // were read. If they keep changing, the modification times from before the last read are
+        // kept, so that the pair looks changed the next time it is checked.
+        let mut attempt = 1;
+        loop {
+            let cert_modified = modified(&cert_path)?;
+            let key_modified = modified(&key_path)?;
+            let cert = fs::read(&cert_path)?;
+            let key = fs::read(&key_path)?;
+            let stable =
+                modified(&cert_path)? == cert_modified && modified(&key_path)? == key_modified;
+            if stable || attempt == LOAD_ATTEMPTS {
+                return Ok(LoadedCert {
+                    name: name.to_string(),
+                    cert,
+                    key,
+                    cert_path,
+                    key_path,
+                    cert_modified,
+                    key_modified,
+                });
+            }
+            diagnostics::debug!(
+                "Certificate {} changed while it was read, reading it again",
+                name
+            );
+            attempt += 1;
+        }
+    }
+
+    /// Whether either file was modified since it was read.
+    pub fn changed_on_disk(&self) -> io::Result<bool> {
+        Ok(modified(&self.cert_path)? != self.cert_modified
+            || modified(&self.key_path)? != self.key_modified)
+    }
+
+    /// Read the certificate from disk again if either file was modified since it was read.
+    pub fn reload_if_changed(&self) -> io::Result<Option<Self>> {
+        match self.changed_on_disk()? {
+            true => Self::load(&self.name, &self.cert_path, &self.key_path).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    fn to_record(&self) -> io::Result<HandoverRecord> {
+        let mut r = HandoverRecord::new(SCHEMA_VERSION);
+        r.put_str(TAG_CERT_NAME, &self.name)
+            .put_str(TAG_CERT_PATH, path_str(&self.cert_path)?)
+            .put_str(TAG_KEY_PATH, path_str(&self.key_path)?)
+            .put_bytes(TAG_CERT_PEM, self.cert.clone())
+            .put_bytes(TAG_KEY_PEM, self.key.clone());
+        if let Some(modified) = self.cert_modified {
+            r.put_u64(TAG_CERT_MTIME, nanos_since_epoch(modified));
+        }
+        if let Some(modified) = self.key_modified {
+            r.put_u64(TAG_KEY_MTIME, nanos_since_epoch(modified));
+        }
+        Ok(r)
+    }
+
+    fn from_record(r: &HandoverRecord) -> io::Result<Self> {
+        let mtime = |tag| -> io::Result<Option<SystemTime>> {
+            Ok(r.get_u64(tag)?
+                .map(|nanos| UNIX_EPOCH + Duration::from_nanos(nanos)))
+        };
+        let string = |tag, name| -> io::Result<&str> {
+            r.get_str(tag)?.ok_or_else(|| missing_field(tag, name))
+        };
+        let bytes = |tag, name| -> io::Result<Vec<u8>> {
+            let bytes = r.get_bytes(tag).ok_or_else(|| missing_field(tag, name))?;
+            Ok(bytes.to_vec())
+        };
+        Ok(LoadedCert {
+            name: string(TAG_CERT_NAME, "name")?.into(),
+            cert_path: string(TAG_CERT_PATH, "cert_path")?.into(),
+            key_path: string(TAG_KEY_PATH, "key_path")?.into(),
+            cert: bytes(TAG_CERT_PEM, "cert")?,
+            key: bytes(TAG_KEY_PEM, "key")?,
+            cert_modified: mtime(TAG_CERT_MTIME)?,
+            key_modified: mtime(TAG_KEY_MTIME)?,
+        })
+    }
+}
+
+fn modified(path: &Path) -> io::Result<Option<SystemTime>> {
+    Ok(fs::metadata(path)?.modified().ok())
+}
+
+fn nanos_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+fn path_str(path: &Path) -> io::Result<&str> {
+    path.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("path {} is not UTF-8", path.display()),
+        )
+    })
+}
+
+/// Whether the new process reuses a certificate it was handed, or loads it from disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReloadPolicy {
+    /// Reuse the certificate unless its files were modified since the old process read them. The
+    /// processes serve different certificates while they overlap if it was renewed.
+    #[default]
+    IfChanged,
+    /// Always reuse the certificate, so that both processes serve the same one. Call
+    /// `LoadedCert::reload_if_changed` once the old process exited to pick up renewals.
+    Never,
+}
+
+/// The certificates served by the old process, by name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CertSnapshot {
+    certs: BTreeMap<String, LoadedCert>,
+}
+
+impl CertSnapshot {
+    pub fn new(certs: impl IntoIterator<Item = LoadedCert>) -> Self {
+        CertSnapshot {
+            certs: certs.into_iter().map(|c| (c.name.clone(), c)).collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LoadedCert> {
+        self.certs.get(name)
+    }
+
+    /// The certificate `name` to serve from the given files: the one that was handed over if it
+    /// was loaded from the same files and `policy` allows reusing it, otherwise the files as they
+    /// are on disk.
+    pub fn resolve(
+        &self,
+        name: &str,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+        policy: ReloadPolicy,
+    ) -> io::Result<LoadedCert> {
+        let (cert_path, key_path) = (cert_path.as_ref(), key_path.as_ref());
+        let handed_over = self
+            .certs
+            .get(name)
+            .filter(|c| c.cert_path == cert_path && c.key_path == key_path);
+        let Some(cert) = handed_over else {
+            return LoadedCert::load(name, cert_path, key_path);
+        };
+        // Files that are gone can't be reloaded, so keep serving what was handed over.
+        if policy == ReloadPolicy::IfChanged && cert.changed_on_disk().unwrap_or(false) {
+            diagnostics::info!("Certificate {} changed on disk, reloading it", name);
+            return LoadedCert::load(name, cert_path, key_path);
+        }
+        Ok(cert.clone())
+    }
+
+    pub fn to_record(&self) -> io::Result<HandoverRecord> {
+        let certs = self
+            .certs
+            .values()
+            .map(LoadedCert::to_record)
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut record = HandoverRecord::new(SCHEMA_VERSION);
+        record.put_records(TAG_CERTS, &certs);
+        Ok(record)
+    }
+
+    pub fn from_record(record: &HandoverRecord) -> io::Result<Self> {
+        let certs = record
+            .get_records(TAG_CERTS)?
+            .iter()
+            .map(LoadedCert::from_record)
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self::new(certs))
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        self.to_record()?.write_to(w).await
+    }
+
+    pub async fn read_from<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Self> {
+        Self::from_record(&HandoverRecord::read_from(r).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let dir = std::env::temp_dir().join(format!("shellflip-certs-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("www.pem"), dir.join("www.key"));
+        fs::write(&cert_path, "old cert").unwrap();
+        fs::write(&key_path, "old key").unwrap();
+
+        let serving = LoadedCert::load("www", &cert_path, &key_path).unwrap();
+        let (mut r, mut w) = tokio::io::duplex(4096);
+        CertSnapshot::new([serving.clone()])
+            .write_to(&mut w)
+            .await
+            .unwrap();
+        let snapshot = CertSnapshot::read_from(&mut r).await.unwrap();
+        assert_eq!(snapshot.get("www"), Some(&serving));
+
+        // Renew the certificate on disk.
+        fs::write(&cert_path, "new cert").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&cert_path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        let policy = ReloadPolicy::Never;
+        let cert = snapshot.resolve("www", &cert_path, &key_path, policy);
+        let cert = cert.unwrap();
+        assert_eq!(cert.cert, b"old cert");
+        let renewed = cert.reload_if_changed().unwrap().unwrap();
+        assert_eq!(renewed.cert, b"new cert");
+        assert_eq!(renewed.reload_if_changed().unwrap(), None);
+
+        let policy = ReloadPolicy::IfChanged;
+        let cert = snapshot.resolve("www", &cert_path, &key_path, policy);
+        assert_eq!(cert.unwrap().cert, b"new cert");
+        // Certificates that were not handed over are loaded from disk.
+        let cert = snapshot.resolve("api", &cert_path, &key_path, policy);
+        assert_eq!(cert.unwrap().name, "api");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_from_record_missing_field() {
+        let cert = LoadedCert {
+            name: "www".into(),
+            cert_path: "/etc/tls/www.pem".into(),
+            key_path: "/etc/tls/www.key".into(),
+            cert: b"cert".to_vec(),
+            key: b"key".to_vec(),
+            cert_modified: None,
+            key_modified: None,
+        };
+        let record = cert.to_record().unwrap();
+        assert_eq!(LoadedCert::from_record(&record).unwrap(), cert);
+
+        let mut without_key = HandoverRecord::new(SCHEMA_VERSION);
+        for tag in record.tags().filter(|&tag| tag != TAG_KEY_PEM) {
+            without_key.put_bytes(tag, record.get_bytes(tag).unwrap());
+        }
+        let err = LoadedCert::from_record(&without_key).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
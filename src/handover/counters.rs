@@ -0,0 +1,160 @@
+//! Handover of monotonic counter values, so exported counters don't reset to zero on every
+//! restart and break `rate()` queries.
+//!
+//! Applications that keep counters in another metrics library can send a `CounterSnapshot` and
+//! add each value to the corresponding counter in the new process. Alternatively, `Counters` is a
+//! minimal registry that takes and restores snapshots itself.
+//!
+//! The old process keeps counting while it drains, so its values should be snapshotted as late
+//! as possible, and it should stop being scraped once the new process is ready. Otherwise the
+//! counters may appear to go backwards by the number of events counted in between.
+use super::HandoverRecord;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const SCHEMA_VERSION: u16 = 1;
+
+const TAG_COUNTERS: u16 = 1;
+
+const TAG_COUNTER_NAME: u16 = 1;
+const TAG_COUNTER_VALUE: u16 = 2;
+
+/// Counter values by name. Names should include any labels, e.g.
+/// `http_requests_total{code="200"}`, as the snapshot has no notion of labels.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CounterSnapshot {
+    values: BTreeMap<String, u64>,
+}
+
+impl CounterSnapshot {
+    pub fn new(values: impl IntoIterator<Item = (String, u64)>) -> Self {
+        CounterSnapshot {
+            values: values.into_iter().collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.values.get(name).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> + '_ {
+        self.values.iter().map(|(k, v)| (k.as_str(), *v))
+    }
+
+    pub fn to_record(&self) -> HandoverRecord {
+        let counters: Vec<_> = self
+            .values
+            .iter()
+            .map(|(name, value)| {
+                let mut r = HandoverRecord::new(SCHEMA_VERSION);
+                r.put_str(TAG_COUNTER_NAME, name)
+                    .put_u64(TAG_COUNTER_VALUE, *value);
+                r
+            })
+            .collect();
+        let mut record = HandoverRecord::new(SCHEMA_VERSION);
+        record.put_records(TAG_COUNTERS, &counters);
+        record
+    }
+
+    pub fn from_record(record: &HandoverRecord) -> io::Result<Self> {
+        let values = record
+            .get_records(TAG_COUNTERS)?
+            .iter()
+            .map(|r| {
+                Ok((
+                    r.get_str(TAG_COUNTER_NAME)?.unwrap_or_default().into(),
+                    r.get_u64(TAG_COUNTER_VALUE)?.unwrap_or_default(),
+                ))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(CounterSnapshot { values })
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        self.to_record().write_to(w).await
+    }
+
+    pub async fn read_from<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Self> {
+        Self::from_record(&HandoverRecord::read_from(r).await?)
+    }
+}
+
+/// A registry of named monotonic counters.
+#[derive(Clone, Default)]
+pub struct Counters {
+    counters: Arc<Mutex<BTreeMap<String, Counter>>>,
+}
+
+impl Counters {
+    /// Get the counter with the given name, creating it if necessary.
+    pub fn counter(&self, name: &str) -> Counter {
+        self.counters
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn snapshot(&self) -> CounterSnapshot {
+        CounterSnapshot::new(
+            self.counters
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, c)| (name.clone(), c.get())),
+        )
+    }
+
+    /// Add the values from the old process to the counters of this process.
+    pub fn restore(&self, snapshot: &CounterSnapshot) {
+        for (name, value) in snapshot.iter() {
+            self.counter(name).add(value);
+        }
+    }
+}
+
+/// A monotonic counter that can be shared between tasks.
+#[derive(Clone, Debug, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_counter_continuity() {
+        let old = Counters::default();
+        old.counter("requests_total").add(41);
+        old.counter(r#"errors_total{code="500"}"#).inc();
+
+        let (mut r, mut w) = tokio::io::duplex(1024);
+        old.snapshot().write_to(&mut w).await.unwrap();
+
+        let new = Counters::default();
+        let requests = new.counter("requests_total");
+        requests.inc();
+        new.restore(&CounterSnapshot::read_from(&mut r).await.unwrap());
+
+        assert_eq!(requests.get(), 42);
+        assert_eq!(new.snapshot().get(r#"errors_total{code="500"}"#), Some(1));
+    }
+}
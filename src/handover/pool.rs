@@ -0,0 +1,215 @@
+//! Handover of connection pool shape, so the new process can pre-warm its pools.
+//!
+//! A freshly started process usually opens pool connections lazily, so the first requests after a
+//! restart pay for connection setup and statement preparation. Instead, the old process can send a
+//! `PoolSnapshot` describing how many connections it holds to each backend, and the new process
+//! can call `PoolSnapshot::warm_up` to open an equivalent number before it signals readiness.
+use super::HandoverRecord;
+use crate::diagnostics;
+use futures::stream::{self, StreamExt};
+use std::future::Future;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const SCHEMA_VERSION: u16 = 1;
+
+const TAG_NAME: u16 = 1;
+const TAG_BACKENDS: u16 = 2;
+const TAG_PREPARED_STATEMENTS: u16 = 3;
+
+const TAG_BACKEND_ADDRESS: u16 = 1;
+const TAG_BACKEND_CONNECTIONS: u16 = 2;
+const TAG_BACKEND_HEALTHY: u16 = 3;
+
+const TAG_STATEMENT_NAME: u16 = 1;
+
+/// The shape of a connection pool at the time of the restart.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PoolSnapshot {
+    /// Identifies the pool, if the process has more than one.
+    pub name: String,
+    pub backends: Vec<BackendSnapshot>,
+    /// Names of the statements prepared on each connection.
+    pub prepared_statements: Vec<String>,
+}
+
+/// The state of the pool's connections to a single backend.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackendSnapshot {
+    pub address: String,
+    /// The number of open connections.
+    pub connections: u32,
+    /// Whether the old process considered the backend healthy. Unhealthy backends are not warmed.
+    pub healthy: bool,
+}
+
+/// The result of `PoolSnapshot::warm_up`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WarmUpReport {
+    pub opened: u32,
+    pub failed: u32,
+}
+
+impl PoolSnapshot {
+    /// The total number of open connections across all backends.
+    pub fn size(&self) -> u32 {
+        self.backends.iter().map(|b| b.connections).sum()
+    }
+
+    pub fn to_record(&self) -> HandoverRecord {
+        let backends: Vec<_> = self
+            .backends
+            .iter()
+            .map(|b| {
+                let mut r = HandoverRecord::new(SCHEMA_VERSION);
+                r.put_str(TAG_BACKEND_ADDRESS, &b.address)
+                    .put_u64(TAG_BACKEND_CONNECTIONS, b.connections.into())
+                    .put_bool(TAG_BACKEND_HEALTHY, b.healthy);
+                r
+            })
+            .collect();
+        let statements: Vec<_> = self
+            .prepared_statements
+            .iter()
+            .map(|s| {
+                let mut r = HandoverRecord::new(SCHEMA_VERSION);
+                r.put_str(TAG_STATEMENT_NAME, s);
+                r
+            })
+            .collect();
+
+        let mut record = HandoverRecord::new(SCHEMA_VERSION);
+        record
+            .put_str(TAG_NAME, &self.name)
+            .put_records(TAG_BACKENDS, &backends)
+            .put_records(TAG_PREPARED_STATEMENTS, &statements);
+        record
+    }
+
+    pub fn from_record(record: &HandoverRecord) -> io::Result<Self> {
+        let backends = record
+            .get_records(TAG_BACKENDS)?
+            .iter()
+            .map(|r| {
+                Ok(BackendSnapshot {
+                    address: r.get_str(TAG_BACKEND_ADDRESS)?.unwrap_or_default().into(),
+                    connections: r
+                        .get_u64(TAG_BACKEND_CONNECTIONS)?
+                        .unwrap_or_default()
+                        .try_into()
+                        .unwrap_or(u32::MAX),
+                    healthy: r.get_bool(TAG_BACKEND_HEALTHY)?.unwrap_or_default(),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        let prepared_statements = record
+            .get_records(TAG_PREPARED_STATEMENTS)?
+            .iter()
+            .filter_map(|r| r.get_str(TAG_STATEMENT_NAME).transpose())
+            .map(|s| s.map(String::from))
+            .collect::<io::Result<_>>()?;
+
+        Ok(PoolSnapshot {
+            name: record.get_str(TAG_NAME)?.unwrap_or_default().into(),
+            backends,
+            prepared_statements,
+        })
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        self.to_record().write_to(w).await
+    }
+
+    pub async fn read_from<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Self> {
+        Self::from_record(&HandoverRecord::read_from(r).await?)
+    }
+
+    /// Open as many connections to each healthy backend as the old process had, running at most
+    /// `concurrency` calls to `open` at a time. `open` should add the connection to the pool and
+    /// prepare `prepared_statements` on it. Failures are counted but do not stop the warm-up.
+    pub async fn warm_up<F, Fut>(&self, concurrency: usize, open: F) -> WarmUpReport
+    where
+        F: Fn(&BackendSnapshot) -> Fut,
+        Fut: Future<Output = io::Result<()>>,
+    {
+        let attempts = self
+            .backends
+            .iter()
+            .filter(|b| b.healthy)
+            .flat_map(|b| (0..b.connections).map(move |_| b));
+
+        stream::iter(attempts)
+            .map(&open)
+            .buffer_unordered(concurrency.max(1))
+            .fold(WarmUpReport::default(), |mut report, res| async move {
+                match res {
+                    Ok(()) => report.opened += 1,
+                    Err(e) => {
+                        diagnostics::warn!("Failed to pre-warm pool connection: {}", e);
+                        report.failed += 1;
+                    }
+                }
+                report
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn snapshot() -> PoolSnapshot {
+        PoolSnapshot {
+            name: "primary".into(),
+            backends: vec![
+                BackendSnapshot {
+                    address: "db1:5432".into(),
+                    connections: 3,
+                    healthy: true,
+                },
+                BackendSnapshot {
+                    address: "db2:5432".into(),
+                    connections: 2,
+                    healthy: false,
+                },
+            ],
+            prepared_statements: vec!["get_user".into()],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let (mut r, mut w) = tokio::io::duplex(1024);
+        snapshot().write_to(&mut w).await.unwrap();
+        let received = PoolSnapshot::read_from(&mut r).await.unwrap();
+        assert_eq!(received, snapshot());
+        assert_eq!(received.size(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_warm_up() {
+        let calls = AtomicU32::new(0);
+        let report = snapshot()
+            .warm_up(2, |backend| {
+                assert_eq!(backend.address, "db1:5432");
+                let n = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if n == 0 {
+                        Err(io::Error::other("connection refused"))
+                    } else {
+                        Ok(())
+                    }
+                }
+            })
+            .await;
+        assert_eq!(
+            report,
+            WarmUpReport {
+                opened: 2,
+                failed: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! Handover of rate-limiter token buckets, so clients don't get a fresh burst of quota every time
+//! the process restarts.
+//!
+//! `Instant`s can't be sent to another process, and wall clock timestamps can jump while the
+//! restart is in progress. Instead, a `RateLimitSnapshot` records how long ago each bucket was last
+//! refilled, and the new process re-bases those ages onto its own clock when calling `restore`. The
+//! time spent in transit is measured with the wall clock, and ignored if the clock went backwards.
+use super::HandoverRecord;
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const SCHEMA_VERSION: u16 = 1;
+
+const TAG_TAKEN_AT: u16 = 1;
+const TAG_BUCKETS: u16 = 2;
+
+const TAG_BUCKET_KEY: u16 = 1;
+const TAG_BUCKET_TOKENS: u16 = 2;
+const TAG_BUCKET_AGE: u16 = 3;
+
+/// The state of a single token bucket.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BucketState {
+    /// Identifies what is being limited, e.g. a client address or API key.
+    pub key: String,
+    /// The number of tokens left at `last_refill`.
+    pub tokens: f64,
+    pub last_refill: Instant,
+}
+
+/// A point-in-time copy of a set of token buckets.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitSnapshot {
+    taken_at: SystemTime,
+    /// Key, tokens and time since the last refill of each bucket.
+    buckets: Vec<(String, f64, Duration)>,
+}
+
+impl RateLimitSnapshot {
+    pub fn new(buckets: impl IntoIterator<Item = BucketState>) -> Self {
+        Self::new_at(buckets, Instant::now(), SystemTime::now())
+    }
+
+    fn new_at(
+        buckets: impl IntoIterator<Item = BucketState>,
+        now: Instant,
+        taken_at: SystemTime,
+    ) -> Self {
+        RateLimitSnapshot {
+            taken_at,
+            buckets: buckets
+                .into_iter()
+                .map(|b| {
+                    (
+                        b.key,
+                        b.tokens,
+                        now.saturating_duration_since(b.last_refill),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// The number of buckets in the snapshot.
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Rebuild the buckets with `last_refill` times relative to this process's clock.
+    pub fn restore(&self) -> Vec<BucketState> {
+        self.restore_at(Instant::now(), SystemTime::now())
+    }
+
+    fn restore_at(&self, now: Instant, wall_now: SystemTime) -> Vec<BucketState> {
+        let in_transit = wall_now.duration_since(self.taken_at).unwrap_or_default();
+        self.buckets
+            .iter()
+            .map(|(key, tokens, age)| BucketState {
+                key: key.clone(),
+                tokens: *tokens,
+                // If this process's clock can't go back that far, err on the side of giving fewer
+                // tokens rather than more.
+                last_refill: now.checked_sub(*age + in_transit).unwrap_or(now),
+            })
+            .collect()
+    }
+
+    pub fn to_record(&self) -> HandoverRecord {
+        let buckets: Vec<_> = self
+            .buckets
+            .iter()
+            .map(|(key, tokens, age)| {
+                let mut r = HandoverRecord::new(SCHEMA_VERSION);
+                r.put_str(TAG_BUCKET_KEY, key)
+                    .put_u64(TAG_BUCKET_TOKENS, tokens.to_bits())
+                    .put_u64(TAG_BUCKET_AGE, duration_to_nanos(*age));
+                r
+            })
+            .collect();
+
+        let taken_at = self.taken_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = HandoverRecord::new(SCHEMA_VERSION);
+        record
+            .put_u64(TAG_TAKEN_AT, duration_to_nanos(taken_at))
+            .put_records(TAG_BUCKETS, &buckets);
+        record
+    }
+
+    pub fn from_record(record: &HandoverRecord) -> io::Result<Self> {
+        let buckets = record
+            .get_records(TAG_BUCKETS)?
+            .iter()
+            .map(|r| {
+                Ok((
+                    r.get_str(TAG_BUCKET_KEY)?.unwrap_or_default().into(),
+                    f64::from_bits(r.get_u64(TAG_BUCKET_TOKENS)?.unwrap_or_default()),
+                    Duration::from_nanos(r.get_u64(TAG_BUCKET_AGE)?.unwrap_or_default()),
+                ))
+            })
+            .collect::<io::Result<_>>()?;
+        let taken_at = Duration::from_nanos(record.get_u64(TAG_TAKEN_AT)?.unwrap_or_default());
+
+        Ok(RateLimitSnapshot {
+            taken_at: UNIX_EPOCH + taken_at,
+            buckets,
+        })
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        self.to_record().write_to(w).await
+    }
+
+    pub async fn read_from<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Self> {
+        Self::from_record(&HandoverRecord::read_from(r).await?)
+    }
+}
+
+fn duration_to_nanos(d: Duration) -> u64 {
+    d.as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let snapshot = RateLimitSnapshot::new([BucketState {
+            key: "10.0.0.1".into(),
+            tokens: 2.5,
+            last_refill: Instant::now(),
+        }]);
+        let (mut r, mut w) = tokio::io::duplex(1024);
+        snapshot.write_to(&mut w).await.unwrap();
+        let received = RateLimitSnapshot::read_from(&mut r).await.unwrap();
+        assert_eq!(received.buckets, snapshot.buckets);
+        assert_eq!(received.restore()[0].tokens, 2.5);
+    }
+
+    #[test]
+    fn test_clock_correction() {
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let bucket = BucketState {
+            key: "client".into(),
+            tokens: 1.0,
+            last_refill: now - Duration::from_secs(3),
+        };
+        let snapshot = RateLimitSnapshot::new_at([bucket], now, wall);
+
+        // Two seconds pass in transit.
+        let later = now + Duration::from_secs(10);
+        let restored = snapshot.restore_at(later, wall + Duration::from_secs(2));
+        assert_eq!(restored[0].last_refill, later - Duration::from_secs(5));
+
+        // The wall clock went backwards, so no time is assumed to have passed.
+        let restored = snapshot.restore_at(later, wall - Duration::from_secs(60));
+        assert_eq!(restored[0].last_refill, later - Duration::from_secs(3));
+    }
+}
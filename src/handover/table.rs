@@ -0,0 +1,185 @@
+//! Handover of large `HashMap`-like tables, such as session tables, one entry at a time.
+//!
+//! Serialising a whole table into one buffer before writing it doubles peak memory in the old
+//! process, and reading it back the same way does so in the new one. `send_table` instead writes
+//! each entry as a separate frame, preceded by the number of entries so the receiver can allocate
+//! once, and `TableReceiver` yields entries as they arrive. Entries are encoded as JSON, so `K`
+//! and `V` can evolve using the usual serde attributes such as `#[serde(default)]`.
+//!
+//! ```no_run
+//! # use shellflip::handover::table::{send_table, TableReceiver};
+//! # use std::collections::HashMap;
+//! # async fn example(
+//! #     mut write_pipe: shellflip::lifecycle::PipeWriter,
+//! #     mut read_pipe: shellflip::lifecycle::PipeReader,
+//! # ) -> std::io::Result<()> {
+//! let sessions: HashMap<String, u64> = HashMap::new();
+//! // In the old process:
+//! send_table(&mut write_pipe, &sessions).await?;
+//! // In the new process:
+//! let sessions: HashMap<String, u64> = TableReceiver::new(&mut read_pipe).await?.collect().await?;
+//! # Ok(())
+//! # }
+//! ```
+use bytes::Bytes;
+use futures::SinkExt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::marker::PhantomData;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio_util::codec::{FramedWrite, LengthDelimitedCodec};
+
+/// The largest entry accepted by `TableReceiver`, matching the default of `LengthDelimitedCodec`.
+const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+/// Precedes the entries of a table.
+#[derive(Serialize, Deserialize)]
+struct TableHeader {
+    len: u64,
+}
+
+/// Write the entries of a table to the handover pipe or any other writer. An empty frame marks
+/// the end of the table, so other data can follow it on the same pipe.
+pub async fn send_table<'a, K, V, W, I>(w: &mut W, entries: I) -> io::Result<()>
+where
+    K: Serialize + 'a,
+    V: Serialize + 'a,
+    W: AsyncWrite + Unpin + ?Sized,
+    I: IntoIterator<Item = (&'a K, &'a V)>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let entries = entries.into_iter();
+    let mut framed = FramedWrite::new(w, LengthDelimitedCodec::new());
+    let header = TableHeader {
+        len: entries.len() as u64,
+    };
+    framed
+        .feed(Bytes::from(serde_json::to_vec(&header)?))
+        .await?;
+
+    for entry in entries {
+        framed
+            .feed(Bytes::from(serde_json::to_vec(&entry)?))
+            .await?;
+    }
+    framed.send(Bytes::new()).await
+}
+
+/// Reads a table written by `send_table`, one entry at a time.
+pub struct TableReceiver<'r, K, V, R: ?Sized> {
+    r: &'r mut R,
+    len: u64,
+    done: bool,
+    _entry: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'r, K, V, R> TableReceiver<'r, K, V, R>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    R: AsyncRead + Unpin + ?Sized,
+{
+    /// Read the table header.
+    pub async fn new(r: &'r mut R) -> io::Result<Self> {
+        let header: TableHeader = serde_json::from_slice(&read_frame(r).await?)?;
+        Ok(TableReceiver {
+            r,
+            len: header.len,
+            done: false,
+            _entry: PhantomData,
+        })
+    }
+
+    /// The number of entries written by the old process.
+    pub fn size_hint(&self) -> u64 {
+        self.len
+    }
+
+    /// Receive the next entry, or `None` at the end of the table.
+    pub async fn next(&mut self) -> io::Result<Option<(K, V)>> {
+        if self.done {
+            return Ok(None);
+        }
+        let frame = read_frame(self.r).await?;
+        if frame.is_empty() {
+            self.done = true;
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&frame)?))
+    }
+
+    /// Receive all remaining entries into a map.
+    pub async fn collect(mut self) -> io::Result<HashMap<K, V>>
+    where
+        K: Eq + Hash,
+    {
+        let mut map = HashMap::with_capacity(self.len.try_into().unwrap_or(0));
+        while let Some((k, v)) = self.next().await? {
+            map.insert(k, v);
+        }
+        Ok(map)
+    }
+}
+
+/// Read a single length-delimited frame. Unlike `FramedRead`, this never reads past the end of
+/// the frame, so data following the table is left for the caller.
+async fn read_frame<R: AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = r.read_u32().await? as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("table entry of {len} bytes is too large"),
+        ));
+    }
+    let mut frame = vec![0; len];
+    r.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Session {
+        user: String,
+        #[serde(default)]
+        expires: u64,
+    }
+
+    #[tokio::test]
+    async fn test_table_handover() {
+        let sessions: HashMap<u32, Session> = (0..100)
+            .map(|i| {
+                let session = Session {
+                    user: format!("user{i}"),
+                    expires: i.into(),
+                };
+                (i, session)
+            })
+            .collect();
+
+        let (mut r, mut w) = tokio::io::duplex(256);
+        let sent = sessions.clone();
+        let writer = tokio::spawn(async move {
+            send_table(&mut w, &sent).await.unwrap();
+            w.write_u8(42).await.unwrap();
+        });
+
+        let mut receiver = TableReceiver::<u32, Session, _>::new(&mut r).await.unwrap();
+        assert_eq!(receiver.size_hint(), 100);
+        let (k, v) = receiver.next().await.unwrap().unwrap();
+        assert_eq!(sessions[&k], v);
+        let mut received = receiver.collect().await.unwrap();
+        received.insert(k, v);
+        assert_eq!(received, sessions);
+
+        // Data written after the table is left for the caller.
+        assert_eq!(r.read_u8().await.unwrap(), 42);
+        writer.await.unwrap();
+    }
+}
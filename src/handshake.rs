@@ -0,0 +1,395 @@
+//! Checking that the new binary can read the state this process hands over, before restarting
+//! into it.
+//!
+//! A restart executes whatever binary is installed at the time, which may be much older or newer
+//! than the running one, e.g. when a deployment skips versions or rolls back. With
+//! `RestartConfig::handshake`, before anything is set up for the new process, the restart runs the
+//! binary with only `--shellflip-handshake`. Instead of starting up, it reports its own `Handshake`
+//! on a pipe and exits. The restart is aborted with an `IncompatibleBinary` error if the binary
+//! uses a different handover format, or can't read the schema version that this process writes
+//! for one of its handover schemas. Restarts also fail if the binary does not report a handshake
+//! within `PROBE_TIMEOUT`, so only enable this once every version that may be restarted into
+//! responds to it.
+//!
+//! A binary responds to the handshake when its restart task is created with `handshake` set, which
+//! then fails with `Error::HandshakeReported` so that the process exits without starting.
+//! Anything the application does before that also runs for the handshake, so it should create
+//! the restart task early, or call `respond_if_requested` itself first thing in `main`:
+//!
+//! ```no_run
+//! use shellflip::handshake::{Handshake, SchemaVersions};
+//!
+//! let mut handshake = Handshake {
+//!     build: format!("example {}", env!("CARGO_PKG_VERSION")),
+//!     ..Default::default()
+//! };
+//! // This version writes version 3 of its sessions, and can still read version 2.
+//! handshake.schemas.insert(
+//!     "sessions".into(),
+//!     SchemaVersions {
+//!         version: 3,
+//!         oldest_supported: 2,
+//!     },
+//! );
+//! if shellflip::handshake::respond_if_requested(&handshake)? {
+//!     return Ok(());
+//! }
+//! // Set `RestartConfig::handshake` to `Some(handshake)`.
+//! # Ok::<(), std::io::Error>(())
+//! ```
+use crate::diagnostics;
+use crate::error::ChildSpawnError;
+use crate::handover::{HandoverRecord, FORMAT_VERSION};
+use crate::pipes::{create_paired_pipes, FdStringExt, PipeMode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+/// The argument that makes a binary report its handshake instead of starting.
+pub const HANDSHAKE_FLAG: &str = "--shellflip-handshake";
+/// The fd of the pipe the handshake is reported on.
+const ENV_HANDSHAKE_PIPE: &str = "OXY_HANDSHAKE_PIPE";
+/// How long the binary has to report its handshake.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SCHEMA_VERSION: u16 = 1;
+const TAG_FORMAT_VERSION: u16 = 1;
+const TAG_SHELLFLIP_VERSION: u16 = 2;
+const TAG_BUILD: u16 = 3;
+const TAG_SCHEMAS: u16 = 4;
+const TAG_SCHEMA_NAME: u16 = 1;
+const TAG_SCHEMA_VERSION: u16 = 2;
+const TAG_SCHEMA_OLDEST_SUPPORTED: u16 = 3;
+
+/// What a binary reports about itself in the handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    /// The version of the `handover` record format, which is set by this crate.
+    pub format_version: u8,
+    /// The version of this crate.
+    pub shellflip_version: String,
+    /// A description of the build, e.g. its version and commit, for diagnostics.
+    pub build: String,
+    /// The handover schemas of the application, by name.
+    pub schemas: BTreeMap<String, SchemaVersions>,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Handshake {
+            format_version: FORMAT_VERSION,
+            shellflip_version: env!("CARGO_PKG_VERSION").into(),
+            build: String::new(),
+            schemas: BTreeMap::new(),
+        }
+    }
+}
+
+/// The versions of a handover schema that a binary writes and reads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SchemaVersions {
+    /// The version this binary writes, e.g. `Handover::VERSION`.
+    pub version: u16,
+    /// The oldest version this binary reads. Versions newer than `version` are assumed to be
+    /// readable, as fields that the reader doesn't know are skipped.
+    pub oldest_supported: u16,
+}
+
+/// The new binary can't read what this process would hand over.
+#[derive(Error, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[error("{} ({}) is incompatible: {}", binary.display(), build, problems.join("; "))]
+pub struct IncompatibleBinary {
+    pub binary: PathBuf,
+    /// The build the binary reported, see `Handshake::build`.
+    pub build: String,
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "build: {}", self.build)?;
+        writeln!(f, "shellflip: {}", self.shellflip_version)?;
+        writeln!(f, "handover format: {}", self.format_version)?;
+        for (name, schema) in &self.schemas {
+            writeln!(
+                f,
+                "schema {}: version {}, reads from {}",
+                name, schema.version, schema.oldest_supported
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Handshake {
+    /// The reasons why a binary that reported `new` can't read what this one hands over.
+    pub fn problems(&self, new: &Handshake) -> Vec<String> {
+        let mut problems = Vec::new();
+        if new.format_version != self.format_version {
+            problems.push(format!(
+                "it uses handover format {}, this process uses {}",
+                new.format_version, self.format_version
+            ));
+        }
+        for (name, ours) in &self.schemas {
+            match new.schemas.get(name) {
+                None => problems.push(format!("it does not read schema {name}")),
+                Some(theirs) if theirs.oldest_supported > ours.version => problems.push(format!(
+                    "it reads schema {} from version {}, this process writes version {}",
+                    name, theirs.oldest_supported, ours.version
+                )),
+                Some(_) => {}
+            }
+        }
+        problems
+    }
+
+    fn to_record(&self) -> HandoverRecord {
+        let mut record = HandoverRecord::new(SCHEMA_VERSION);
+        let schemas: Vec<_> = self
+            .schemas
+            .iter()
+            .map(|(name, schema)| {
+                let mut record = HandoverRecord::new(SCHEMA_VERSION);
+                record
+                    .put_str(TAG_SCHEMA_NAME, name)
+                    .put_u64(TAG_SCHEMA_VERSION, schema.version.into())
+                    .put_u64(TAG_SCHEMA_OLDEST_SUPPORTED, schema.oldest_supported.into());
+                record
+            })
+            .collect();
+        record
+            .put_u64(TAG_FORMAT_VERSION, self.format_version.into())
+            .put_str(TAG_SHELLFLIP_VERSION, &self.shellflip_version)
+            .put_str(TAG_BUILD, &self.build)
+            .put_records(TAG_SCHEMAS, &schemas);
+        record
+    }
+
+    fn from_record(record: &HandoverRecord) -> io::Result<Self> {
+        let mut schemas = BTreeMap::new();
+        for schema in record.get_records(TAG_SCHEMAS)? {
+            let version = |tag| -> io::Result<u16> {
+                let version = schema.get_u64(tag)?.unwrap_or(0);
+                u16::try_from(version).map_err(|_| invalid_data("schema version out of range"))
+            };
+            schemas.insert(
+                schema.get_str(TAG_SCHEMA_NAME)?.unwrap_or_default().into(),
+                SchemaVersions {
+                    version: version(TAG_SCHEMA_VERSION)?,
+                    oldest_supported: version(TAG_SCHEMA_OLDEST_SUPPORTED)?,
+                },
+            );
+        }
+        let format_version = record.get_u64(TAG_FORMAT_VERSION)?.unwrap_or(0);
+        Ok(Handshake {
+            format_version: u8::try_from(format_version)
+                .map_err(|_| invalid_data("format version out of range"))?,
+            shellflip_version: record
+                .get_str(TAG_SHELLFLIP_VERSION)?
+                .unwrap_or_default()
+                .into(),
+            build: record.get_str(TAG_BUILD)?.unwrap_or_default().into(),
+            schemas,
+        })
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// If this process was started with `HANDSHAKE_FLAG`, report `handshake`. Returns whether it did,
+/// in which case the process should exit rather than start. The handshake is printed to stdout if
+/// the process was not started by a restart, e.g. by hand.
+pub fn respond_if_requested(handshake: &Handshake) -> io::Result<bool> {
+    if !env::args_os().skip(1).any(|arg| arg == HANDSHAKE_FLAG) {
+        return Ok(false);
+    }
+    match env::var(ENV_HANDSHAKE_PIPE) {
+        Ok(fd) => {
+            unsafe { File::from_fd_string(&fd) }?.write_all(&handshake.to_record().to_bytes())?
+        }
+        Err(_) => io::stdout().write_all(handshake.to_string().as_bytes())?,
+    }
+    Ok(true)
+}
+
+/// Run `program` with `HANDSHAKE_FLAG` and the `environment` the new process would get, and check
+/// that it can read what `ours` hands over.
+pub(crate) async fn probe(
+    program: &OsStr,
+    environment: &[(OsString, OsString)],
+    ours: &Handshake,
+) -> Result<Handshake, ChildSpawnError> {
+    let (pipe_r, pipe_w) = create_paired_pipes(PipeMode::ChildWrites)?;
+    let mut child = Command::new(program)
+        .arg(HANDSHAKE_FLAG)
+        .envs(environment.iter().map(|(k, v)| (k, v)))
+        .env(ENV_HANDSHAKE_PIPE, pipe_w.fd_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    drop(pipe_w);
+
+    let mut report = Vec::new();
+    let mut pipe_r = tokio::fs::File::from_std(pipe_r);
+    let status = timeout(PROBE_TIMEOUT, async {
+        pipe_r.read_to_end(&mut report).await?;
+        child.wait().await
+    })
+    .await
+    .map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "{} did not report a handshake within {:?}",
+                program.to_string_lossy(),
+                PROBE_TIMEOUT
+            ),
+        )
+    })??;
+    if report.is_empty() {
+        return Err(io::Error::other(format!(
+            "{} did not report a handshake, {}",
+            program.to_string_lossy(),
+            status
+        ))
+        .into());
+    }
+    let theirs = Handshake::from_record(&HandoverRecord::from_bytes(&report)?)?;
+    let problems = ours.problems(&theirs);
+    if !problems.is_empty() {
+        return Err(ChildSpawnError::IncompatibleBinary(IncompatibleBinary {
+            binary: program.into(),
+            build: theirs.build,
+            problems,
+        }));
+    }
+    diagnostics::debug!(
+        "{} ({}) is compatible with this process",
+        program.to_string_lossy(),
+        theirs.build
+    );
+    Ok(theirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    fn handshake(schemas: &[(&str, u16, u16)]) -> Handshake {
+        Handshake {
+            build: "test".into(),
+            schemas: schemas
+                .iter()
+                .map(|&(name, version, oldest_supported)| {
+                    let versions = SchemaVersions {
+                        version,
+                        oldest_supported,
+                    };
+                    (name.to_string(), versions)
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_problems() {
+        let ours = handshake(&[("sessions", 3, 2), ("cache", 1, 1)]);
+        assert!(ours.problems(&ours).is_empty());
+        // Newer binaries that still read version 3 are fine, as are older ones that read it.
+        let newer = handshake(&[("sessions", 4, 3), ("cache", 1, 1), ("extra", 1, 1)]);
+        assert!(ours.problems(&newer).is_empty());
+        let older = handshake(&[("sessions", 2, 1), ("cache", 1, 1)]);
+        assert!(ours.problems(&older).is_empty());
+
+        let new = Handshake {
+            format_version: 2,
+            ..handshake(&[("sessions", 5, 4)])
+        };
+        assert_eq!(
+            ours.problems(&new),
+            [
+                "it uses handover format 2, this process uses 1",
+                "it does not read schema cache",
+                "it reads schema sessions from version 4, this process writes version 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let ours = handshake(&[("sessions", 3, 2), ("cache", 1, 1)]);
+        assert_eq!(Handshake::from_record(&ours.to_record()).unwrap(), ours);
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let dir = env::temp_dir().join(format!("shellflip-handshake-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ours = handshake(&[("sessions", 3, 2)]);
+        let script = |name: &str, handshake: Option<&Handshake>| {
+            let report = dir.join(format!("{name}.bin"));
+            let body = match handshake {
+                Some(handshake) => {
+                    fs::write(&report, handshake.to_record().to_bytes()).unwrap();
+                    // The new process's environment applies to the probe as well.
+                    format!(
+                        "[ \"$SHELLFLIP_PROBE\" = yes ] || exit 4\n\
+                         cat {} >/dev/fd/$OXY_HANDSHAKE_PIPE",
+                        report.display()
+                    )
+                }
+                None => "exit 3".into(),
+            };
+            let path = dir.join(name);
+            fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+
+        let environment = [("SHELLFLIP_PROBE".into(), "yes".into())];
+        let compatible = script("compatible", Some(&handshake(&[("sessions", 4, 3)])));
+        let theirs = probe(compatible.as_os_str(), &environment, &ours)
+            .await
+            .unwrap();
+        assert_eq!(theirs.schemas["sessions"].version, 4);
+        let e = probe(compatible.as_os_str(), &[], &ours).await.unwrap_err();
+        assert!(e.to_string().contains("exit status: 4"), "{e}");
+
+        let incompatible = script("incompatible", Some(&handshake(&[("sessions", 5, 4)])));
+        let e = probe(incompatible.as_os_str(), &environment, &ours)
+            .await
+            .unwrap_err();
+        let ChildSpawnError::IncompatibleBinary(e) = e else {
+            panic!("unexpected error {e}");
+        };
+        assert_eq!(e.problems.len(), 1);
+
+        let unsupported = script("unsupported", None);
+        let e = probe(unsupported.as_os_str(), &environment, &ours)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("did not report a handshake"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
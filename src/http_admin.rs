@@ -0,0 +1,462 @@
+//! A small HTTP interface to the restart coordination socket, for environments where HTTP tooling
+//! is easier to use than unix socket clients. Requires the `http-admin` feature.
+//!
+//! Set `RestartConfig::http_admin` to serve it. Requests are handled by the same code as requests
+//! on the restart coordination socket, and the endpoint is passed to the new process on restart.
+//!
+//! - `GET /status` returns the `StatusReport` as JSON.
+//! - `POST /restart` restarts the process, with `RestartOptions` as the optional JSON body, and
+//!   returns the `RestartOutcome` as JSON.
+//! - `POST /drain` sends `AdminCommand::Shutdown` to the application.
+//!
+//! POST requests must carry the configured token as `Authorization: Bearer <token>`, and are
+//! refused if no token is configured. Errors are returned as `{"error": "<message>"}`.
+//!
+//! ```text
+//! curl -X POST -H "Authorization: Bearer $TOKEN" http://127.0.0.1:8081/restart
+//! ```
+use crate::diagnostics;
+use crate::listeners::{self, ListenAddr, Listener};
+use crate::restart_coordination_socket::RestartCoordinationSocket;
+use crate::{AdminCommand, Error, RestartOptions, RestartResult};
+use serde::Serialize;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio::select;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// The name under which the endpoint is passed to the new process.
+const LISTENER_NAME: &str = "shellflip-http-admin";
+/// The number of requests that may wait for the restart task to pick them up.
+const CONNECTION_BACKLOG: usize = 16;
+const MAX_HEAD_LEN: usize = 8 * 1024;
+const MAX_BODY_LEN: usize = 64 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings for the HTTP admin endpoint.
+#[derive(Clone, Debug)]
+pub struct HttpAdminConfig {
+    /// Where to listen. TCP addresses must be loopback addresses.
+    pub listen: ListenAddr,
+    /// The bearer token that POST requests must carry. POST requests are refused if it is not set.
+    pub token: Option<String>,
+}
+
+/// Bind the endpoint and serve it in a new task. Each request is handled over a connection sent
+/// to the restart task through the returned channel. The endpoint stops when the channel closes.
+pub(crate) fn spawn(config: HttpAdminConfig) -> RestartResult<Receiver<UnixStream>> {
+    if let ListenAddr::Tcp(addr) = &config.listen {
+        if !addr.ip().is_loopback() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("HTTP admin endpoint must listen on a loopback address, not {addr}"),
+            )));
+        }
+    }
+    let listener = bind(&config.listen).map_err(|e| {
+        Error::Io(io::Error::new(
+            e.kind(),
+            format!(
+                "failed to bind HTTP admin endpoint {:?}: {}",
+                config.listen, e
+            ),
+        ))
+    })?;
+    let (tx, rx) = channel(CONNECTION_BACKLOG);
+    tokio::spawn(serve(listener, config.token, tx));
+    Ok(rx)
+}
+
+enum AdminListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+impl AdminListener {
+    async fn accept(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(match self {
+            AdminListener::Tcp(l) => Box::new(l.accept().await?.0),
+            AdminListener::Unix(l) => Box::new(l.accept().await?.0),
+        })
+    }
+}
+
+fn bind(addr: &ListenAddr) -> io::Result<AdminListener> {
+    let listener = listeners::listener_group(LISTENER_NAME, std::slice::from_ref(addr))?
+        .pop()
+        .expect("one listener per address");
+    listener.set_nonblocking(true)?;
+    Ok(match listener {
+        Listener::Tcp(l) => AdminListener::Tcp(TcpListener::from_std(l)?),
+        Listener::Unix(l) => AdminListener::Unix(UnixListener::from_std(l)?),
+    })
+}
+
+async fn serve(listener: AdminListener, token: Option<String>, connector: Sender<UnixStream>) {
+    let token = Arc::new(token);
+    loop {
+        let accepted = select! {
+            // The restart task completed, so the new process serves the endpoint from now on.
+            _ = connector.closed() => return,
+            accepted = listener.accept() => accepted,
+        };
+        match accepted {
+            Ok(stream) => {
+                tokio::spawn(handle_connection(
+                    stream,
+                    Arc::clone(&token),
+                    connector.clone(),
+                ));
+            }
+            Err(e) => diagnostics::error!("HTTP admin endpoint accept error: {}", e),
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: &impl Serialize) -> Self {
+        Response {
+            status,
+            body: serde_json::to_string(body).expect("response is serializable"),
+        }
+    }
+
+    fn error(status: u16, message: impl fmt::Display) -> Self {
+        Self::json(status, &serde_json::json!({ "error": message.to_string() }))
+    }
+
+    fn for_error(e: Error) -> Self {
+        let status = match &e {
+            Error::AlreadyRestarting(_)
+            | Error::PreflightRefused { .. }
+            | Error::IncompatibleBinary { .. }
+            | Error::PreviousGenerationRunning { .. } => 409,
+            Error::RestartThreadGone | Error::AcceptorTerminated => 503,
+            Error::Rejected {
+                restart_id: None, ..
+            } => 503,
+            _ => 500,
+        };
+        Self::error(status, e)
+    }
+}
+
+async fn handle_connection(
+    mut stream: Box<dyn Connection>,
+    token: Arc<Option<String>>,
+    connector: Sender<UnixStream>,
+) {
+    let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => respond(request, token.as_deref(), &connector).await,
+        Ok(Err(e)) => Response::error(400, e),
+        Err(_) => Response::error(408, "timed out reading request"),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.body.len()
+    );
+    let res = async {
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(response.body.as_bytes()).await?;
+        stream.shutdown().await
+    };
+    if let Err(e) = res.await {
+        diagnostics::debug!("Failed to send HTTP admin response: {}", e);
+    }
+}
+
+async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Request> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut buf = Vec::new();
+    let head_len = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(invalid("request head too long"));
+        }
+        let mut chunk = [0; 1024];
+        match stream.read(&mut chunk).await? {
+            0 => return Err(invalid("connection closed before end of request head")),
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    };
+
+    let head =
+        std::str::from_utf8(&buf[..head_len]).map_err(|_| invalid("invalid request head"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Err(invalid("invalid request line"));
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        path: target.split('?').next().unwrap_or_default().to_string(),
+        authorization: None,
+        body: Vec::new(),
+    };
+    let mut content_length = 0;
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("invalid header"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| invalid("invalid content length"))?;
+        } else if name.eq_ignore_ascii_case("authorization") {
+            request.authorization = Some(value.to_string());
+        }
+    }
+    if content_length > MAX_BODY_LEN {
+        return Err(invalid("request body too long"));
+    }
+
+    request.body = buf.split_off(head_len);
+    if request.body.len() < content_length {
+        let start = request.body.len();
+        request.body.resize(content_length, 0);
+        stream.read_exact(&mut request.body[start..]).await?;
+    }
+    request.body.truncate(content_length);
+    Ok(request)
+}
+
+async fn respond(
+    request: Request,
+    token: Option<&str>,
+    connector: &Sender<UnixStream>,
+) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/status") => match connect(connector).await {
+            Ok(mut rpc) => match rpc.query_status().await {
+                Ok(status) => Response::json(200, &status),
+                Err(e) => Response::for_error(e),
+            },
+            Err(e) => Response::for_error(e),
+        },
+        ("POST", "/restart") | ("POST", "/drain") if !authorized(&request, token) => match token {
+            Some(_) => Response::error(401, "missing or invalid token"),
+            None => Response::error(403, "no token configured for this endpoint"),
+        },
+        ("POST", "/restart") => {
+            let options: RestartOptions = match request.body.is_empty() {
+                true => Default::default(),
+                false => match serde_json::from_slice(&request.body) {
+                    Ok(options) => options,
+                    Err(e) => return Response::error(400, format!("invalid restart options: {e}")),
+                },
+            };
+            diagnostics::info!("Restart requested through the HTTP admin endpoint");
+            let res = async {
+                connect(connector)
+                    .await?
+                    .send_restart_command_with(options)
+                    .await
+            };
+            match res.await {
+                Ok(outcome) => Response::json(200, &outcome),
+                Err(e) => Response::for_error(e),
+            }
+        }
+        ("POST", "/drain") => {
+            let res = async {
+                connect(connector)
+                    .await?
+                    .send_command(AdminCommand::Shutdown)
+                    .await
+            };
+            match res.await {
+                Ok(()) => Response::json(202, &serde_json::json!({})),
+                Err(e) => Response::for_error(e),
+            }
+        }
+        (_, "/status" | "/restart" | "/drain") => Response::error(405, "method not allowed"),
+        _ => Response::error(404, "not found"),
+    }
+}
+
+fn authorized(request: &Request, token: Option<&str>) -> bool {
+    let (Some(token), Some(authorization)) = (token, &request.authorization) else {
+        return false;
+    };
+    let Some(given) = authorization.strip_prefix("Bearer ") else {
+        return false;
+    };
+    // Compare in constant time, so that the token can't be guessed byte by byte.
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Connect to the restart task as a restart coordination socket client would.
+async fn connect(connector: &Sender<UnixStream>) -> RestartResult<RestartCoordinationSocket> {
+    let (client, server) = UnixStream::pair()?;
+    connector
+        .send(server)
+        .await
+        .map_err(|_| Error::AcceptorTerminated)?;
+    Ok(RestartCoordinationSocket::new(client))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restart_coordination_socket::{RestartMessage, RestartRequest, RestartResponse};
+    use crate::StatusReport;
+    use tokio::net::TcpStream;
+
+    /// Serve the endpoint on a free port, answering requests like a running process would.
+    async fn start(token: Option<&str>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = channel(1);
+        tokio::spawn(serve(
+            AdminListener::Tcp(listener),
+            token.map(Into::into),
+            tx,
+        ));
+        tokio::spawn(async move {
+            while let Some(sock) = rx.recv().await {
+                let mut rpc = RestartCoordinationSocket::new(sock);
+                let response = match rpc.receive_message().await.unwrap() {
+                    RestartMessage::Request(RestartRequest::Status) => {
+                        RestartResponse::Status(StatusReport {
+                            pid: 42,
+                            generation: 0,
+                            uptime: Duration::ZERO,
+                            restart_id: None,
+                            in_progress: None,
+                            active_handles: None,
+                            drain: None,
+                            last_error: None,
+                            ancestry: vec![],
+                        })
+                    }
+                    RestartMessage::Request(RestartRequest::TryRestartWith(options)) => {
+                        let id = options.restart_id.unwrap();
+                        let started = RestartResponse::RestartStarted(id);
+                        rpc.send_message(RestartMessage::Response(started))
+                            .await
+                            .unwrap();
+                        RestartResponse::RestartComplete(43)
+                    }
+                    RestartMessage::Request(RestartRequest::Command(_)) => {
+                        RestartResponse::RestartFailed("not supported".into())
+                    }
+                    m => panic!("unexpected message {m:?}"),
+                };
+                rpc.send_message(RestartMessage::Response(response))
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn request(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let addr = start(None).await;
+        let response = request(addr, "GET /status HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(r#""pid":42"#));
+
+        let response = request(addr, "GET /nope HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 "));
+        let response = request(addr, "POST /status HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 "));
+    }
+
+    #[tokio::test]
+    async fn test_restart() {
+        let addr = start(Some("secret")).await;
+        let body = r#"{"restart_id":"r1"}"#;
+        let restart = |auth: &str| {
+            format!(
+                "POST /restart HTTP/1.1\r\n{auth}Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        };
+
+        let response = request(addr, &restart("")).await;
+        assert!(response.starts_with("HTTP/1.1 401 "));
+        let response = request(addr, &restart("Authorization: Bearer wrong\r\n")).await;
+        assert!(response.starts_with("HTTP/1.1 401 "));
+
+        let response = request(addr, &restart("authorization: Bearer secret\r\n")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with(r#"{"restart_id":"r1","pid":43}"#));
+
+        let drain = "POST /drain HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n";
+        let response = request(addr, drain).await;
+        assert!(response.starts_with("HTTP/1.1 503 "));
+        assert!(response.contains("not supported"));
+    }
+
+    #[tokio::test]
+    async fn test_post_refused_without_token() {
+        let addr = start(None).await;
+        let response = request(addr, "POST /drain HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 403 "));
+    }
+
+    #[tokio::test]
+    async fn test_non_loopback_address_refused() {
+        let config = HttpAdminConfig {
+            listen: ListenAddr::Tcp("0.0.0.0:0".parse().unwrap()),
+            token: None,
+        };
+        assert!(spawn(config).is_err());
+    }
+}
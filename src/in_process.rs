@@ -0,0 +1,168 @@
+//! A restart coordination socket that lives within the process, for hermetic integration tests.
+//!
+//! Set the same `InProcessSocket` as `RestartConfig::in_process` on the config of the restart task
+//! and on the config used to make requests, e.g. `RestartConfig::restart_status`, and connections
+//! are made over socket pairs rather than a path on the filesystem:
+//!
+//! ```no_run
+//! # async fn example() -> shellflip::RestartResult<()> {
+//! use shellflip::in_process::InProcessSocket;
+//! use shellflip::RestartConfig;
+//!
+//! let socket = InProcessSocket::new();
+//! let server = RestartConfig {
+//!     enabled: true,
+//!     in_process: Some(socket.clone()),
+//!     ..Default::default()
+//! };
+//! tokio::spawn(server.try_into_restart_task()?);
+//!
+//! let client = RestartConfig {
+//!     enabled: true,
+//!     in_process: Some(socket),
+//!     ..Default::default()
+//! };
+//! let status = client.restart_status().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A new process spawned by a restart does not inherit an in-process socket.
+use crate::restart_coordination_socket::RestartCoordinationSocket;
+use crate::{Error, RestartResult};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// The number of connections that can wait to be served.
+const BACKLOG: usize = 16;
+
+/// Connects restart coordination socket clients to a restart task in the same process. Clones
+/// connect to the same restart task.
+#[derive(Clone)]
+pub struct InProcessSocket {
+    connector: Sender<UnixStream>,
+    /// Taken by the restart task that serves the socket.
+    connections: Arc<Mutex<Option<Receiver<UnixStream>>>>,
+}
+
+impl InProcessSocket {
+    pub fn new() -> Self {
+        let (connector, connections) = channel(BACKLOG);
+        InProcessSocket {
+            connector,
+            connections: Arc::new(Mutex::new(Some(connections))),
+        }
+    }
+
+    /// Connect to the restart task serving this socket.
+    pub async fn connect(&self) -> RestartResult<RestartCoordinationSocket> {
+        let (client, server) = UnixStream::pair()?;
+        self.connector
+            .send(server)
+            .await
+            .map_err(|_| Error::AcceptorTerminated)?;
+        Ok(RestartCoordinationSocket::new(client))
+    }
+
+    /// The connections to serve, unless another restart task serves them already.
+    pub(crate) fn serve(&self) -> Option<Receiver<UnixStream>> {
+        self.connections.lock().unwrap().take()
+    }
+}
+
+impl Default for InProcessSocket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for InProcessSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InProcessSocket").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RestartConfig, SocketLimits};
+
+    #[tokio::test]
+    async fn test_in_process() {
+        let socket = InProcessSocket::new();
+        let server = RestartConfig {
+            enabled: true,
+            coordination_socket_path: "/nonexistent/shellflip.sock".into(),
+            in_process: Some(socket.clone()),
+            ..Default::default()
+        };
+        let task = tokio::spawn(server.try_into_restart_task().unwrap());
+
+        let client = RestartConfig {
+            enabled: true,
+            in_process: Some(socket.clone()),
+            ..Default::default()
+        };
+        let status = client.restart_status().await.unwrap();
+        assert_eq!(status.pid, std::process::id());
+        assert_eq!(client.cancel_restart(None).await.unwrap(), None);
+
+        // The socket is already served.
+        let other = RestartConfig {
+            enabled: true,
+            in_process: Some(socket),
+            ..Default::default()
+        };
+        assert!(other.try_into_restart_task().is_err());
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let socket = InProcessSocket::new();
+        let server = RestartConfig {
+            enabled: true,
+            coordination_socket_path: "/nonexistent/shellflip.sock".into(),
+            in_process: Some(socket.clone()),
+            socket_limits: SocketLimits {
+                max_connections: 1,
+                max_streams: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let task = tokio::spawn(server.try_into_restart_task().unwrap());
+        let client = RestartConfig {
+            enabled: true,
+            in_process: Some(socket),
+            ..Default::default()
+        };
+
+        // A subscriber doesn't keep other requests from being served.
+        let mut subscriber = client.connect().await.unwrap();
+        subscriber.subscribe().await.unwrap();
+        client.restart_status().await.unwrap();
+        client.restart_status().await.unwrap();
+
+        // But it does keep a second one from subscribing.
+        let mut second = client.connect().await.unwrap();
+        assert!(second.subscribe().await.is_err());
+
+        drop(subscriber);
+        let mut third = client.connect().await.unwrap();
+        let mut subscribed = third.subscribe().await;
+        for _ in 0..100 {
+            if subscribed.is_ok() {
+                break;
+            }
+            // The restart task may not have noticed the subscriber going away yet.
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            third = client.connect().await.unwrap();
+            subscribed = third.subscribe().await;
+        }
+        subscribed.unwrap();
+        task.abort();
+    }
+}
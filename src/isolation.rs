@@ -0,0 +1,169 @@
+//! Namespaces and cgroup of the new process, so that sandboxed services can recreate their
+//! isolation in each generation as part of the restart.
+//!
+//! The new process is set up between fork and exec: it first moves into the cgroup, then joins
+//! the namespaces in `Isolation::join`, then creates the namespaces in `Isolation::unshare`. This
+//! needs code to run in the new process, so it is forked even with `SpawnMethod::PosixSpawn`.
+//!
+//! The standard library spawns processes with fork or `posix_spawn` rather than `clone3`, so
+//! some effects differ from passing the flags to `clone3`:
+//!
+//! - The new process moves into the cgroup right after it is forked, rather than being created
+//!   in it with `CLONE_INTO_CGROUP`, so the fork itself is still accounted to the cgroup of this
+//!   process.
+//! - Joining or creating a PID namespace would only apply to the processes that the new process
+//!   spawns, as with `setns(2)` and `unshare(2)`, leaving the new process itself in the PID
+//!   namespace of this process. As that isn't isolation, `CLONE_NEWPID` is refused. Run the
+//!   service under an init process that creates the PID namespace instead.
+use nix::sched::{setns, unshare};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+pub use nix::sched::CloneFlags;
+
+/// How to isolate the new process. The default leaves it in the namespaces and cgroup of this
+/// process.
+#[derive(Debug)]
+pub struct Isolation {
+    /// Move the new process into this cgroup, given as its directory, e.g.
+    /// `/sys/fs/cgroup/app.slice/app-2`. It moves right after it is forked, see the module
+    /// documentation.
+    pub cgroup: Option<PathBuf>,
+    /// Join these namespaces, each given as an open fd of e.g. `/proc/<pid>/ns/net` or a bind mount
+    /// of one, along with its kind, e.g. `CloneFlags::CLONE_NEWNET`. PID namespaces are refused.
+    pub join: Vec<(OwnedFd, CloneFlags)>,
+    /// Create new namespaces of these kinds, e.g. `CloneFlags::CLONE_NEWNS`. PID namespaces are
+    /// refused.
+    pub unshare: CloneFlags,
+}
+
+impl Default for Isolation {
+    fn default() -> Self {
+        Isolation {
+            cgroup: None,
+            join: Vec::new(),
+            unshare: CloneFlags::empty(),
+        }
+    }
+}
+
+impl Isolation {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.cgroup.is_none() && self.join.is_empty() && self.unshare.is_empty()
+    }
+
+    /// Set up the new process spawned by `cmd` to be isolated. The returned file must stay open
+    /// until it is spawned.
+    pub(crate) fn configure(&self, cmd: &mut Command) -> io::Result<Option<File>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let kinds = self
+            .join
+            .iter()
+            .fold(self.unshare, |kinds, (_, kind)| kinds | *kind);
+        if kinds.contains(CloneFlags::CLONE_NEWPID) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a PID namespace would only apply to the processes the new process spawns",
+            ));
+        }
+        // Opened here, as the new process must not allocate before exec.
+        let procs = match &self.cgroup {
+            Some(dir) => Some(
+                OpenOptions::new()
+                    .write(true)
+                    .open(dir.join("cgroup.procs"))?,
+            ),
+            None => None,
+        };
+        let procs_fd = procs.as_ref().map(File::as_raw_fd);
+        let join: Vec<(RawFd, CloneFlags)> = self
+            .join
+            .iter()
+            .map(|(fd, kind)| (fd.as_raw_fd(), *kind))
+            .collect();
+        let new = self.unshare;
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(fd) = procs_fd {
+                    // Writing 0 moves the writing process.
+                    if libc::write(fd, b"0".as_ptr().cast(), 1) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                for (fd, kind) in &join {
+                    setns(*fd, *kind)?;
+                }
+                if !new.is_empty() {
+                    unshare(new)?;
+                }
+                Ok(())
+            });
+        }
+        Ok(procs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::Capability;
+    use std::fs;
+
+    /// Joining or creating namespaces needs `CAP_SYS_ADMIN`.
+    fn can_isolate() -> bool {
+        Capability::SYS_ADMIN.is_effective().unwrap()
+    }
+
+    #[test]
+    fn test_join() {
+        let ns = File::open("/proc/self/ns/uts").unwrap();
+        let isolation = Isolation {
+            join: vec![(ns.into(), CloneFlags::CLONE_NEWUTS)],
+            ..Default::default()
+        };
+        assert!(!isolation.is_empty());
+        if !can_isolate() {
+            return;
+        }
+        let mut cmd = Command::new("true");
+        let _procs = isolation.configure(&mut cmd).unwrap();
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_unshare() {
+        if !can_isolate() {
+            return;
+        }
+        let isolation = Isolation {
+            unshare: CloneFlags::CLONE_NEWUTS,
+            ..Default::default()
+        };
+        let mut cmd = Command::new("readlink");
+        cmd.arg("/proc/self/ns/uts");
+        let _procs = isolation.configure(&mut cmd).unwrap();
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        let ours = fs::read_link("/proc/self/ns/uts").unwrap();
+        assert_ne!(
+            String::from_utf8(output.stdout).unwrap().trim(),
+            ours.to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pid_namespace_refused() {
+        let isolation = Isolation {
+            unshare: CloneFlags::CLONE_NEWPID,
+            ..Default::default()
+        };
+        let err = isolation.configure(&mut Command::new("true")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
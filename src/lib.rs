@@ -9,14 +9,23 @@
 //! polled. This should be put into a select statement with other futures your app may await on.
 //! The restart task will resolve with `Ok(())` if a restart signal was sent and the new process
 //! spawned successfully. If the task is unable to handle future restart signals for any reason,
-//! it will resolve to an `Err`.
+//! it will resolve to an `Err`. To await it in several places instead, use
+//! `RestartConfig::try_into_shared_restart_task`, which runs the task and returns a cloneable
+//! `RestartWatcher`.
 //!
-//! The process can also be restarted by sending it SIGUSR1. After any kind of restart request, the
-//! old process will terminate if the new process starts up successfully, otherwise it will
-//! continue if possible.
+//! Failures are reported as a `shellflip::Error`, whose variants distinguish e.g. a running
+//! process that rejected the request from one that could not be reached at all.
+//!
+//! The process can also be restarted by sending it SIGUSR1, or by futures of the application
+//! passed to `RestartConfig::restart_on`. After any kind of restart request, the old process will
+//! terminate if the new process starts up successfully, otherwise it will continue if possible.
 //!
 //! For coordinating graceful shutdown of the old process, see `ShutdownCoordinator` in the
-//! `shutdown` module.
+//! `shutdown` module. The `app` module, and the `#[shellflip::main]` attribute of the `macros`
+//! feature, wire all of this together for applications that don't need finer control.
+//!
+//! Messages about restarts are logged with the `log` crate, unless they are passed to another
+//! logging stack with `diagnostics::set_sink`.
 //!
 //! # Restart thread
 //!
@@ -25,7 +34,8 @@
 //! capabilities or using seccomp policies to limit the syscalls that can execute, it is a good
 //! idea to call the aforementioned functions before locking down the main & future child threads.
 //! You likely don't want the restart thread to have the same restrictions and limitations that may
-//! otherwise prevent you from calling execve() or doing certain I/O operations.
+//! otherwise prevent you from calling execve() or doing certain I/O operations. See the `seccomp`
+//! module for what the restart task still needs on the threads that poll it.
 //!
 //! # Transferring state to the new process
 //!
@@ -41,49 +51,179 @@
 //! `LifecycleHandler::new_process_failed` is called and you can undo any changes you made in
 //! preparation for handover. If the new process succeeds, however, the restart task will resolve
 //! and you may terminate the process as usual.
+//!
+//! The restart task resolves with the new `process::Child`. While it drains, the old process can
+//! watch for the new process crashing shortly after cutover with `ChildMonitor`.
+//!
+//! The old and new processes may be different versions of your application. The `handover`
+//! module provides a record format that lets either side skip fields it does not understand.
+//!
+//! A restart requested with `RestartOptions::stateless` skips `send_to_new_process`, and
+//! `receive_from_old_process` returns `None` in the new process, which rebuilds its state as if it
+//! started afresh. Listeners and other fds are passed on as usual. This is useful when only the
+//! configuration or environment changed, and the state is cheap to rebuild.
+//!
+//! The new process always executes the binary again, even if it hasn't changed. A copy forked from
+//! the running process without exec would share its tokio runtimes, whose threads don't exist in
+//! the copy, and any lock held by another thread at the time. To avoid rebuilding expensive state,
+//! send it with `LifecycleHandler::send_to_new_process`, or keep it in a file passed with the
+//! `files` module.
+//!
+//! # Inheriting file descriptors
+//!
+//! Fds listed in `RestartConfig::inherited_fds` are passed to the new process under the same
+//! numbers, as are fds returned by `LifecycleHandler::fds_for_new_process` for state that only
+//! exists at restart time. Listening sockets can instead be passed by name with
+//! `listeners::listener_or_bind` or, for groups of them, `listeners::listener_group`. Unix
+//! listeners need some care around their socket files; see the `listeners` module. Other fds,
+//! such as files holding a lock, can be passed by name with the `files` module. To catch
+//! fds that are inherited by accident, set `RestartConfig::fd_leak_policy`; see the `fds` module
+//! for details.
+// Lets the output of the derive macros refer to `::shellflip` within this crate as well.
+#[cfg(feature = "macros")]
+extern crate self as shellflip;
+
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod app;
+pub mod audit;
+#[cfg(target_os = "linux")]
+pub mod capabilities;
+#[cfg(target_os = "linux")]
+pub mod criu;
+pub mod cutover;
+pub mod daemon;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod diagnostics;
+pub mod discovery;
+pub mod drain;
+pub mod endpoints;
+mod error;
+pub mod fds;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod files;
+pub mod handover;
+pub mod handshake;
+#[cfg(feature = "http-admin")]
+pub mod http_admin;
+#[cfg(any(test, feature = "test-util"))]
+pub mod in_process;
+#[cfg(target_os = "linux")]
+pub mod isolation;
 pub mod lifecycle;
+pub mod lineage;
+pub mod listeners;
+pub mod load_gate;
+pub mod logs;
+pub mod monitor;
+#[cfg(target_os = "linux")]
+pub mod parking;
 mod pipes;
+pub mod preflight;
+pub mod reaper;
+mod relay;
 pub mod restart_coordination_socket;
+mod restart_state;
+#[cfg(target_os = "linux")]
+pub mod scheduling;
+#[cfg(target_os = "linux")]
+pub mod seccomp;
+#[cfg(target_os = "linux")]
+pub mod security_context;
 pub mod shutdown;
+pub mod tableflip;
+#[cfg(all(target_os = "linux", feature = "tcp-repair"))]
+pub mod tcp_repair;
+#[cfg(feature = "self-update")]
+pub mod update;
+#[cfg(target_os = "linux")]
+pub mod vsock;
 
-pub use shutdown::{ShutdownCoordinator, ShutdownHandle, ShutdownSignal};
+pub use error::{ChildSpawnError, Error, RestartResult};
+pub use monitor::{ChildMonitor, PidFd};
+#[allow(deprecated)]
+pub use restart_coordination_socket::RestartStatus;
+pub use restart_coordination_socket::{
+    AdminCommand, ChildIdentity, ChildOutputHook, PeerCredentials, ProcessExited, RestartEvent,
+    RestartId, RestartOptions, RestartOutcome, RestartPhase, StartupFailed, StatusReport,
+};
+#[cfg(feature = "macros")]
+pub use shellflip_macros::main;
+pub use shutdown::{
+    BlockingShutdownSignal, DrainPolicy, DrainReport, DrainStats, DrainSummary, DrainTimeout,
+    HandleDrainTime, PolicySignal, ShutdownCoordinator, ShutdownHandle, ShutdownMessageSender,
+    ShutdownMessages, ShutdownSignal,
+};
 
+use crate::audit::AuditHook;
+use crate::endpoints::EndpointPolicy;
+use crate::fds::{FdKind, FdLeakPolicy, FdManifest};
 use crate::lifecycle::LifecycleHandler;
 use crate::pipes::{
-    completion_pipes, create_paired_pipes, CompletionReceiver, CompletionSender, FdStringExt,
-    PipeMode,
+    completion_pipes, create_paired_pipes, set_pipe_size, CompletionReceiver, CompletionSender,
+    FdStringExt, PipeMode, PipeWriter,
 };
+use crate::relay::{ChildOutput, SavedStdio};
 use crate::restart_coordination_socket::{
-    RestartCoordinationSocket, RestartMessage, RestartRequest, RestartResponse,
+    RestartCoordinationSocket, RestartMessage, RestartProgress, RestartRequest, RestartResponse,
+    RestartTimedOut, MAX_REQUEST_FDS,
 };
-use anyhow::anyhow;
-use futures::stream::{Stream, StreamExt};
+use crate::restart_state::{CancelRequest, CommitRequest, CompletedRestart, SharedRestartState};
+use futures::future::Either;
+use futures::stream::{BoxStream, FuturesUnordered, Stream, StreamExt};
 use std::env;
 use std::ffi::OsString;
-use std::fs::{remove_file, File as StdFile};
+use std::fs::remove_file;
 use std::future::Future;
 use std::io;
+use std::mem;
 use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
 use std::os::unix::net::UnixListener as StdUnixListener;
-use std::os::unix::process::CommandExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::process;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
-use thiserror::Error;
+use std::time::{Duration, Instant};
 use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
 use tokio::net::{UnixListener, UnixStream};
-use tokio::select;
 use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio_stream::wrappers::UnixListenerStream;
-
-pub type RestartResult<T> = anyhow::Result<T>;
+use tokio::sync::{broadcast, oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::{pin, select};
+use tokio_stream::wrappers::{ReceiverStream, UnixListenerStream};
 
 const ENV_NOTIFY_SOCKET: &str = "OXY_NOTIFY_SOCKET";
 const ENV_RESTART_SOCKET: &str = "OXY_RESTART_SOCKET";
 const ENV_HANDOVER_PIPE: &str = "OXY_HANDOVER_PIPE";
+const ENV_STATELESS_RESTART: &str = "OXY_STATELESS_RESTART";
+const ENV_RESTART_ID: &str = "OXY_RESTART_ID";
+const ENV_GENERATION: &str = "OXY_GENERATION";
+const ENV_PREVIOUS_PID: &str = "OXY_PREVIOUS_PID";
+const ENV_PREVIOUS_START_TIME: &str = "OXY_PREVIOUS_START_TIME";
 const ENV_SYSTEMD_PID: &str = "LISTEN_PID";
+/// The number of relayed output lines buffered for the coordination socket client.
+const CHILD_OUTPUT_BUFFER: usize = 64;
+const DEFAULT_HANDOVER_BUFFER_SIZE: usize = 64 * 1024;
 const REBIND_SYSTEMD_PID: &str = "auto";
+/// When this process started, or created its restart task where its start time can't be read.
+static STARTED: OnceLock<Instant> = OnceLock::new();
+/// The start time of this process, see `ChildIdentity::start_time`, read when it created its
+/// restart task.
+static START_TIME: OnceLock<Option<u64>> = OnceLock::new();
+/// The state reported by `status_report`, from the most recently created restart task.
+static STATUS_SOURCE: Mutex<Option<(SharedRestartState, Option<DrainStats>)>> = Mutex::new(None);
+/// How often drain progress is checked for clients subscribed to events.
+const DRAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// How often drain progress is checked for `AfterHandover::DrainThenExit`.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often `LingeringGeneration::Wait` checks whether the previous generation exited.
+const LINGERING_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Settings for graceful restarts
 pub struct RestartConfig {
@@ -91,6 +231,18 @@ pub struct RestartConfig {
     pub enabled: bool,
     /// Socket path
     pub coordination_socket_path: PathBuf,
+    /// Serve the restart coordination socket on this listener instead of binding
+    /// `coordination_socket_path`, e.g. one received through systemd socket activation, or bound
+    /// with custom ownership and permissions. It is passed on to the new process like a socket
+    /// bound by this crate. `coordination_socket_path` is still where clients connect, so it
+    /// should be the path the listener is bound to.
+    pub coordination_listener: Option<StdUnixListener>,
+    /// Which clients and requests the restart coordination socket serves, see the `endpoints`
+    /// module.
+    pub coordination_socket_policy: endpoints::EndpointPolicy,
+    /// Serve the restart coordination socket on these endpoints as well, each with its own policy,
+    /// see the `endpoints` module.
+    pub endpoints: Vec<endpoints::Endpoint>,
     /// Sets environment variables on the newly-started process
     pub environment: Vec<(OsString, OsString)>,
     /// Receive fine-grained events on the lifecycle of the new process and support data transfer.
@@ -99,49 +251,579 @@ pub struct RestartConfig {
     pub exit_on_error: bool,
     /// Sets the signal to listen to on restart. This defaults to SIGUSR1.
     pub restart_signal: SignalKind,
+    /// File descriptors that should be inherited by the new process, keeping the same fd numbers.
+    pub inherited_fds: Vec<RawFd>,
+    /// What to do with other fds that are not marked close-on-exec when the new process is spawned.
+    pub fd_leak_policy: FdLeakPolicy,
+    /// How the new process is created.
+    pub spawn_method: SpawnMethod,
+    /// The namespaces and cgroup of the new process, see the `isolation` module.
+    #[cfg(target_os = "linux")]
+    pub isolation: isolation::Isolation,
+    /// The nice value, scheduling policy and CPU affinity of the new process, which are those of
+    /// the restart thread by default, see the `scheduling` module.
+    #[cfg(target_os = "linux")]
+    pub scheduling: scheduling::Scheduling,
+    /// Which capabilities the new process gets and which it needs, see the `capabilities` module.
+    #[cfg(target_os = "linux")]
+    pub capabilities: capabilities::CapabilityPolicy,
+    /// The SELinux context or AppArmor profile to execute the new process in, see the
+    /// `security_context` module.
+    #[cfg(target_os = "linux")]
+    pub security_context: Option<security_context::SecurityContext>,
+    /// Capture the stdout and stderr of the new process until it signals readiness, and log each
+    /// line. The lines are also sent to restart requesters that set `RestartOptions::relay_output`.
+    pub relay_child_output: bool,
+    /// Receives each line of output of the new process when requesting a restart with
+    /// `RestartOptions::relay_output`. Without it, the lines are logged.
+    pub child_output_hook: Option<ChildOutputHook>,
+    /// Size of the buffer in front of the handover pipe given to
+    /// `LifecycleHandler::send_to_new_process`, so that many small writes are coalesced.
+    pub handover_buffer_size: usize,
+    /// Capacity of the handover pipe itself, if the default of the OS is too small. Only supported
+    /// on Linux, and limited to `/proc/sys/fs/pipe-max-size` for unprivileged processes.
+    pub handover_pipe_size: Option<usize>,
+    /// Receives `AdminCommand`s sent over the restart coordination socket. If this is not set, or
+    /// the channel is full, commands are rejected.
+    pub admin_commands: Option<Sender<AdminCommand>>,
+    /// Also pass the listeners from `listeners::listener_or_bind` and `listeners::listener_group`
+    /// to the new process with `LISTEN_FDS` and `LISTEN_FDNAMES`, as systemd socket activation
+    /// does. This is skipped, with a warning, if an fd that the new process inherits is in the way.
+    pub emit_listen_fds: bool,
+    /// Report the progress of draining this process after a restart to clients that subscribed to
+    /// events on the restart coordination socket, e.g. from `ShutdownCoordinator::drain_stats`.
+    pub drain_stats: Option<DrainStats>,
+    /// Describe the fds the new process inherits in an `fds::FdManifest`, sent ahead of any state
+    /// on the handover pipe. Only enable this if every version this process may be restarted into
+    /// reads it, see the `fds` module.
+    pub send_fd_manifest: bool,
+    /// What to do if another instance already serves the restart coordination socket, e.g. when
+    /// someone runs the binary by hand while the service is running.
+    pub existing_instance: ExistingInstance,
+    /// What to do with a restart request while the process this one was restarted from is still
+    /// running, e.g. draining, so that rapid successive restarts don't pile up generations.
+    pub lingering_generation: LingeringGeneration,
+    /// Delay requested restarts until load drops below these thresholds, see the `load_gate`
+    /// module.
+    pub load_gate: Option<load_gate::LoadGate>,
+    /// Check that the new binary can read what this process hands over before restarting into
+    /// it, and respond to the same check from the old process, see the `handshake` module.
+    pub handshake: Option<handshake::Handshake>,
+    /// Checks that must pass before the new process is spawned, see the `preflight` module.
+    pub preflight_checks: Vec<Box<dyn preflight::PreflightCheck>>,
+    /// How long each pre-flight check may take before it counts as refusing the restart.
+    pub preflight_timeout: Duration,
+    /// Once the new process is ready, keep accepting a decreasing share of new connections over
+    /// this period rather than leaving them all to the new process right away, see the `cutover`
+    /// module. This only shifts connections on listeners that the new process inherits, not on
+    /// those it binds itself with `SO_REUSEPORT`.
+    pub cutover_ramp: Option<Duration>,
+    /// Keep accepting new connections after the new process is ready, until it reports that it is
+    /// accepting them or this period passes, so that no connection waits in a listen backlog that
+    /// neither process accepts from, see the `cutover` module.
+    pub accept_handoff: Option<Duration>,
+    /// Sample the accept queues of the TCP listeners while new connections move over to the new
+    /// process, and report them, see the `cutover` module. This runs a thread that wakes up every
+    /// 10 milliseconds during the cutover.
+    pub watch_backlog: bool,
+    /// Let this process pass the connections queued on its listeners to the new process once it
+    /// stops accepting, rather than resetting them, see the `parking` module.
+    #[cfg(target_os = "linux")]
+    pub park_connections: bool,
+    /// What this process does once a restart has handed over to the new process.
+    pub after_handover: AfterHandover,
+    /// Futures that each trigger a restart when they complete, see `RestartConfig::restart_on`.
+    pub restart_triggers: Vec<RestartTrigger>,
+    /// Receives every request to the restart coordination socket along with the credentials of
+    /// the client, see the `audit` module.
+    pub audit_hook: Option<Arc<dyn AuditHook>>,
+    /// Called by `app::run` if the main function of the application panics, and by the restart
+    /// task if a restart panics, e.g. in the lifecycle handler, see `app::PanicHook`.
+    pub panic_hook: Option<Arc<dyn app::PanicHook>>,
+    /// Limits on restart coordination socket clients.
+    pub socket_limits: SocketLimits,
+    /// Serve a D-Bus interface alongside the restart coordination socket.
+    #[cfg(feature = "dbus")]
+    pub dbus: Option<dbus::DbusConfig>,
+    /// Serve an HTTP admin endpoint alongside the restart coordination socket.
+    #[cfg(feature = "http-admin")]
+    pub http_admin: Option<http_admin::HttpAdminConfig>,
+    /// Serve the restart coordination socket on a vsock port as well, see the `vsock` module. Every
+    /// request is allowed on the port.
+    #[cfg(target_os = "linux")]
+    #[deprecated(
+        note = "add an `Endpoint` with `EndpointAddr::Vsock` to `endpoints` instead, \
+                         which can be given a policy"
+    )]
+    pub vsock: Option<vsock::VsockConfig>,
+    /// The installation slots this binary is managed in, which lets restart requesters roll back
+    /// to the previous slot, see `update::Slots`. The process must be started through
+    /// `Slots::link`, so that the new process is spawned from the slot it points at.
+    #[cfg(feature = "self-update")]
+    pub binary_slots: Option<update::Slots>,
+    /// Serve the restart coordination socket within the process rather than at
+    /// `coordination_socket_path`, and make requests to it that way, for hermetic tests, see the
+    /// `in_process` module.
+    #[cfg(any(test, feature = "test-util"))]
+    pub in_process: Option<in_process::InProcessSocket>,
+}
+
+/// What a starting process does if another instance already serves the restart coordination
+/// socket. A process started by a restart always takes the socket over from the old process.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExistingInstance {
+    /// Bind the socket anyway, so that the running instance can no longer be reached through it.
+    /// This is the historical behaviour.
+    #[default]
+    Replace,
+    /// Fail with `Error::AlreadyRunning`, which includes the pid of the running instance.
+    Refuse,
+    /// Ask the running instance to restart instead. The restart task resolves with
+    /// `Error::RestartedExisting` once the restart completes, or the error if it fails, so this
+    /// process can exit.
+    Restart,
+}
+
+/// What the restart task does with a restart request while the previous generation, the process
+/// this one was restarted from, is still running, see `previous_generation`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LingeringGeneration {
+    /// Restart anyway, leaving three or more generations running until the older ones exit.
+    #[default]
+    Allow,
+    /// Fail the restart with `ChildSpawnError::PreviousGenerationRunning`.
+    Refuse,
+    /// Wait up to this long for the previous generation to exit before restarting, then fail like
+    /// `Refuse`. Other restart requests are turned away meanwhile.
+    Wait(Duration),
+}
+
+/// How the new process is created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpawnMethod {
+    /// Fork, set up the fds of the new process in the child, and exec.
+    #[default]
+    Fork,
+    /// Use `posix_spawn`, which avoids copying the page tables of this process, where the
+    /// standard library supports it, e.g. on Linux. This makes restarts of processes with a large
+    /// RSS faster. The new process is given copies of the fds it inherits, without close-on-exec,
+    /// which are closed once it has been spawned, so processes that other threads spawn meanwhile
+    /// inherit them as well. The fds in `inherited_fds` and from
+    /// `LifecycleHandler::fds_for_new_process` keep their numbers and can't be copied, so if any
+    /// of them is close-on-exec, the new process is forked. Emitting `LISTEN_FDS`,
+    /// `FdLeakPolicy::Close`, `RestartConfig::isolation`, `RestartConfig::scheduling`,
+    /// `RestartConfig::security_context` and `Ambient` capability policies other than
+    /// `Ambient::Keep` need code to run in the new process, so with those, the new process is
+    /// forked anyway.
+    PosixSpawn,
+}
+
+/// Limits on restart coordination socket clients, so that a buggy or malicious client can't keep
+/// others from being served, which could block future restarts. The limits apply to each socket
+/// and endpoint separately, so that the clients of one can't lock out those of another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketLimits {
+    /// The most connections served at once that have yet to send their request. Further
+    /// connections are closed right away.
+    pub max_connections: usize,
+    /// The most connections kept open after their request, waiting for a restart to complete or
+    /// subscribed to events. Further such requests are refused, so that they can't keep restarts
+    /// from being requested.
+    pub max_streams: usize,
+    /// How long a client may take to send its request, and any fds that go with it, before it is
+    /// disconnected.
+    pub idle_timeout: Duration,
+    /// The largest request accepted, in bytes.
+    pub max_request_size: usize,
+}
+
+impl Default for SocketLimits {
+    fn default() -> Self {
+        SocketLimits {
+            max_connections: 64,
+            max_streams: 64,
+            idle_timeout: Duration::from_secs(10),
+            max_request_size: 64 * 1024,
+        }
+    }
+}
+
+/// The result of a shared restart task: the pid of the new process if a restart succeeded, or why
+/// restarts became impossible.
+pub type SharedRestartResult = Result<u32, Arc<Error>>;
+
+/// Waits for the restart task started by `RestartConfig::try_into_shared_restart_task` to
+/// complete. Every clone receives the result.
+#[derive(Clone, Debug)]
+pub struct RestartWatcher {
+    rx: watch::Receiver<Option<SharedRestartResult>>,
+}
+
+impl RestartWatcher {
+    /// Wait until a restart succeeds, or restarts become impossible.
+    pub async fn wait(&self) -> SharedRestartResult {
+        let mut rx = self.rx.clone();
+        loop {
+            if let Some(result) = &*rx.borrow_and_update() {
+                return result.clone();
+            }
+            if rx.changed().await.is_err() {
+                // The restart task panicked.
+                return Err(Arc::new(Error::RestartThreadGone));
+            }
+        }
+    }
+
+    /// The result of the restart task, if it completed.
+    pub fn result(&self) -> Option<SharedRestartResult> {
+        self.rx.borrow().clone()
+    }
+}
+
+/// A future that triggers a restart when it completes.
+pub type RestartTrigger = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// What the old process does once a restart has handed over to the new process.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AfterHandover {
+    /// Resolve the restart task with the new process and leave the rest to the caller, which
+    /// usually drains and exits. This is the historical behaviour.
+    #[default]
+    Return,
+    /// Exit with the given code right away, without draining.
+    Exit(i32),
+    /// Resolve the restart task, so that the caller drains, and exit with `code` once the named
+    /// handles of `RestartConfig::drain_stats` have been dropped, or once `deadline` passes,
+    /// whichever comes first. Without `drain_stats`, this waits for the deadline, unless the
+    /// caller exits earlier.
+    DrainThenExit { deadline: Duration, code: i32 },
+    /// Resolve the restart task, so that the caller drains, and then stay resident without
+    /// serving until terminated with SIGTERM or SIGINT. `app::run` does this by itself; other
+    /// callers await `standby` once they have drained.
+    Standby,
+}
+
+/// Waits until the process is asked to terminate with SIGTERM or SIGINT. For processes that stay
+/// resident after a restart, see `AfterHandover::Standby`.
+pub async fn standby() -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    diagnostics::info!("Standing by until terminated");
+    select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+    Ok(())
+}
+
+/// Carry out `policy` once a restart has handed over to the new process.
+fn after_handover(policy: AfterHandover, drain_stats: Option<DrainStats>) {
+    match policy {
+        AfterHandover::Return | AfterHandover::Standby => {}
+        AfterHandover::Exit(code) => {
+            diagnostics::info!("Handed over to the new process, exiting with code {}", code);
+            process::exit(code);
+        }
+        AfterHandover::DrainThenExit { deadline, code } => {
+            tokio::spawn(async move {
+                let drained = async {
+                    let Some(stats) = drain_stats else {
+                        return futures::future::pending().await;
+                    };
+                    while stats.active_handles() > 0 {
+                        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+                    }
+                };
+                match tokio::time::timeout(deadline, drained).await {
+                    Ok(()) => diagnostics::info!("Drained, exiting with code {}", code),
+                    Err(_) => diagnostics::warn!(
+                        "Not drained after {:?}, exiting with code {}",
+                        deadline,
+                        code
+                    ),
+                }
+                process::exit(code);
+            });
+        }
+    }
 }
 
 impl RestartConfig {
+    /// Restart when `trigger` completes, e.g. on a message from an admin RPC or a queue, in the
+    /// same way as when a restart is requested through the restart coordination socket. Each
+    /// trigger starts at most one restart, and is dropped if another restart is in progress when
+    /// it completes. Triggers are polled by the restart task, also when the socket is disabled.
+    pub fn restart_on(&mut self, trigger: impl Future<Output = ()> + Send + 'static) -> &mut Self {
+        self.restart_triggers.push(Box::pin(trigger));
+        self
+    }
+
     /// Prepare the current process to handle restarts, if enabled.
     pub fn try_into_restart_task(
         self,
-    ) -> io::Result<(impl Future<Output = RestartResult<process::Child>> + Send)> {
+    ) -> RestartResult<impl Future<Output = RestartResult<process::Child>> + Send> {
         fixup_systemd_env();
         spawn_restart_task(self)
     }
 
+    /// Prepare the current process to handle restarts like `try_into_restart_task`, and run the
+    /// restart task on the tokio runtime. The returned watcher can be cloned, so that several
+    /// parts of the application, e.g. an HTTP server and a background scheduler, can each wait
+    /// for the restart to complete. Must be called within a tokio runtime.
+    pub fn try_into_shared_restart_task(self) -> RestartResult<RestartWatcher> {
+        let task = self.try_into_restart_task()?;
+        let (tx, rx) = watch::channel(None);
+        tokio::spawn(async move {
+            let result = task.await.map(|child| child.id()).map_err(Arc::new);
+            let _ = tx.send(Some(result));
+        });
+        Ok(RestartWatcher { rx })
+    }
+
     /// Request an already-running service to restart.
-    pub async fn request_restart(self) -> RestartResult<u32> {
-        if !self.enabled {
-            return Err(anyhow!(
-                "no restart coordination socket socket defined in config"
-            ));
-        }
+    pub async fn request_restart(self) -> RestartResult<RestartOutcome> {
+        self.request_restart_with(RestartOptions::default()).await
+    }
+
+    /// Request an already-running service to restart with the given options.
+    /// A restart ID is generated if the options don't specify one.
+    ///
+    /// If the running service uses an older version of this crate that does not understand
+    /// restart options, the request is retried without them. The restart ID is then only known
+    /// to the caller.
+    pub async fn request_restart_with(
+        self,
+        options: RestartOptions,
+    ) -> RestartResult<RestartOutcome> {
+        self.request_restart_until(options, &[], None).await
+    }
 
-        let socket = UnixStream::connect(self.coordination_socket_path).await?;
-        restart_coordination_socket::RestartCoordinationSocket::new(socket)
-            .send_restart_command()
+    /// Request an already-running service to restart, giving up after `timeout`. If the timeout
+    /// expires, the error is `Error::TimedOut` which describes how far the restart got.
+    ///
+    /// Dropping the returned future closes the connection to the running service. The restart
+    /// itself is not aborted if it already started.
+    pub async fn request_restart_with_timeout(
+        self,
+        options: RestartOptions,
+        timeout: Duration,
+    ) -> RestartResult<RestartOutcome> {
+        self.request_restart_until(options, &[], Some(Instant::now() + timeout))
             .await
     }
 
+    /// Request an already-running service to restart with the given options, passing `fds` to the
+    /// new process under their names, see
+    /// `RestartCoordinationSocket::send_restart_command_with_fds`.
+    /// Unlike the other requests, this is not retried without options if the running service does
+    /// not support them.
+    pub async fn request_restart_with_fds(
+        self,
+        options: RestartOptions,
+        fds: Vec<(String, OwnedFd)>,
+    ) -> RestartResult<RestartOutcome> {
+        self.request_restart_until(options, &fds, None).await
+    }
+
+    async fn request_restart_until(
+        self,
+        mut options: RestartOptions,
+        fds: &[(String, OwnedFd)],
+        deadline: Option<Instant>,
+    ) -> RestartResult<RestartOutcome> {
+        let restart_id = options
+            .restart_id
+            .get_or_insert_with(RestartId::generate)
+            .clone();
+
+        let remaining = || deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        let timed_out = || -> Error {
+            RestartTimedOut {
+                progress: RestartProgress::Requested,
+            }
+            .into()
+        };
+
+        let res = loop {
+            let mut rpc = self.connect().await?;
+            let res = rpc.restart_within(options.clone(), fds, remaining()).await;
+
+            match res {
+                Err(e @ Error::AlreadyRestarting(_)) if options.queue => {
+                    diagnostics::info!("{}, waiting for it to complete", e);
+                    let mut rpc = self.connect().await?;
+                    // Whatever the outcome, our restart runs next, either in this process or in
+                    // the one that replaced it.
+                    let _ = match remaining() {
+                        Some(timeout) => tokio::time::timeout(timeout, rpc.wait_for_restart())
+                            .await
+                            .map_err(|_| timed_out())?,
+                        None => rpc.wait_for_restart().await,
+                    };
+                }
+                res => break res,
+            }
+        };
+
+        match res {
+            // The fds would be lost.
+            Err(Error::Unsupported(_)) if fds.is_empty() => {
+                diagnostics::debug!("Running process does not support restart options, retrying");
+                let mut rpc = self.connect().await?;
+                let pid = match remaining() {
+                    Some(timeout) => tokio::time::timeout(timeout, rpc.send_restart_command())
+                        .await
+                        .unwrap_or_else(|_| Err(timed_out()))?,
+                    None => rpc.send_restart_command().await?,
+                };
+                Ok(RestartOutcome { restart_id, pid })
+            }
+            res => res,
+        }
+    }
+
+    /// Query the restart status of an already-running service.
+    pub async fn restart_status(&self) -> RestartResult<StatusReport> {
+        self.connect().await?.query_status().await
+    }
+
+    /// Wait for a restart that is in progress in an already-running service to complete, without
+    /// requesting a new one. Returns `None` if no restart was in progress.
+    pub async fn wait_for_restart_completion(&self) -> RestartResult<Option<RestartOutcome>> {
+        self.connect().await?.wait_for_restart().await
+    }
+
+    /// Cancel a restart that is in progress in an already-running service, killing the new process
+    /// before it starts serving. If `restart_id` is set, only that restart is cancelled. Returns
+    /// the ID of the cancelled restart, or `None` if there was no matching restart in progress.
+    pub async fn cancel_restart(
+        &self,
+        restart_id: Option<RestartId>,
+    ) -> RestartResult<Option<RestartId>> {
+        self.connect().await?.cancel_restart(restart_id).await
+    }
+
+    /// Commit a restart of an already-running service that awaits a commit, see
+    /// `RestartOptions::await_commit`, so that the old process stops serving. If `restart_id` is
+    /// set, only that restart is committed. Returns the outcome once the restart completes, or
+    /// `None` if there was no matching restart in progress.
+    pub async fn commit_restart(
+        &self,
+        restart_id: Option<RestartId>,
+    ) -> RestartResult<Option<RestartOutcome>> {
+        self.connect().await?.commit_restart(restart_id).await
+    }
+
+    /// Restart an already-running service back into its previous binary slot, see
+    /// `update::Slots`, e.g. when an upgrade turned out to be bad.
+    pub async fn request_rollback(&self, options: RestartOptions) -> RestartResult<RestartOutcome> {
+        self.connect().await?.send_rollback_command(options).await
+    }
+
+    /// Connect to the restart coordination socket. The returned future does not borrow the config,
+    /// which is not `Sync`, so that the futures that await it are `Send`.
+    fn connect(&self) -> impl Future<Output = RestartResult<RestartCoordinationSocket>> + Send {
+        let enabled = self.enabled;
+        let path = self.coordination_socket_path.clone();
+        let child_output_hook = self.child_output_hook.clone();
+        #[cfg(any(test, feature = "test-util"))]
+        let in_process = self.in_process.clone();
+        async move {
+            if !enabled {
+                return Err(Error::NoCoordinationSocket);
+            }
+            #[cfg(any(test, feature = "test-util"))]
+            let socket = match in_process {
+                Some(in_process) => Some(in_process.connect().await?),
+                None => None,
+            };
+            #[cfg(not(any(test, feature = "test-util")))]
+            let socket = None;
+
+            let mut socket = match socket {
+                Some(socket) => socket,
+                None => {
+                    let socket = UnixStream::connect(&path)
+                        .await
+                        .map_err(|source| Error::Connect { path, source })?;
+                    RestartCoordinationSocket::new(socket)
+                }
+            };
+            if let Some(hook) = child_output_hook {
+                socket.on_child_output(hook);
+            }
+            Ok(socket)
+        }
+    }
+
     /// Request an already-running service to restart.
     /// Does not require the tokio runtime to be started yet.
-    pub fn request_restart_sync(self) -> RestartResult<u32> {
+    pub fn request_restart_sync(self) -> RestartResult<RestartOutcome> {
         tokio::runtime::Runtime::new()
             .unwrap()
             .block_on(self.request_restart())
     }
 }
 
+// `vsock` is deprecated, but still has to be initialised.
+#[allow(deprecated)]
 impl Default for RestartConfig {
     fn default() -> Self {
         RestartConfig {
             enabled: false,
             coordination_socket_path: Default::default(),
+            coordination_listener: None,
+            coordination_socket_policy: Default::default(),
+            endpoints: Vec::new(),
             environment: vec![],
             lifecycle_handler: Box::new(lifecycle::NullLifecycleHandler),
             exit_on_error: true,
             restart_signal: SignalKind::user_defined1(),
+            inherited_fds: vec![],
+            fd_leak_policy: FdLeakPolicy::default(),
+            spawn_method: SpawnMethod::default(),
+            #[cfg(target_os = "linux")]
+            isolation: Default::default(),
+            #[cfg(target_os = "linux")]
+            scheduling: Default::default(),
+            #[cfg(target_os = "linux")]
+            capabilities: Default::default(),
+            #[cfg(target_os = "linux")]
+            security_context: None,
+            relay_child_output: false,
+            child_output_hook: None,
+            handover_buffer_size: DEFAULT_HANDOVER_BUFFER_SIZE,
+            handover_pipe_size: None,
+            admin_commands: None,
+            emit_listen_fds: false,
+            drain_stats: None,
+            send_fd_manifest: false,
+            existing_instance: ExistingInstance::default(),
+            lingering_generation: LingeringGeneration::default(),
+            load_gate: None,
+            handshake: None,
+            preflight_checks: Vec::new(),
+            preflight_timeout: Duration::from_secs(30),
+            cutover_ramp: None,
+            accept_handoff: None,
+            watch_backlog: false,
+            #[cfg(target_os = "linux")]
+            park_connections: false,
+            after_handover: AfterHandover::default(),
+            restart_triggers: Vec::new(),
+            audit_hook: None,
+            panic_hook: None,
+            socket_limits: SocketLimits::default(),
+            #[cfg(feature = "dbus")]
+            dbus: None,
+            #[cfg(feature = "http-admin")]
+            http_admin: None,
+            #[cfg(target_os = "linux")]
+            vsock: None,
+            #[cfg(feature = "self-update")]
+            binary_slots: None,
+            #[cfg(any(test, feature = "test-util"))]
+            in_process: None,
         }
     }
 }
@@ -171,8 +853,13 @@ pub fn fixup_systemd_env() {
 /// pass file descriptor numbers were set by something other than shellflip spawning a new instance
 /// of the calling process.
 pub fn startup_complete() -> io::Result<()> {
+    relay::restore_stdio();
+    listeners::release_unclaimed();
     if let Ok(notify_fd) = env::var(ENV_NOTIFY_SOCKET) {
-        pipes::CompletionSender(unsafe { std::fs::File::from_fd_string(&notify_fd)? }).send()?;
+        let mut sender =
+            pipes::CompletionSender(unsafe { std::fs::File::from_fd_string(&notify_fd)? });
+        sender.send()?;
+        cutover::report_accepting(sender);
     }
     // Avoid sending twice on the notification pipe, if this is manually called outside
     // of the restart task.
@@ -182,21 +869,198 @@ pub fn startup_complete() -> io::Result<()> {
     Ok(())
 }
 
+/// If this process has been spawned due to graceful restart, returns the ID of that restart.
+pub fn restart_id() -> Option<RestartId> {
+    env::var(ENV_RESTART_ID).ok().map(RestartId::from)
+}
+
+/// The number of restarts that led to this process, or 0 if it was not started by a restart.
+pub fn generation() -> u32 {
+    env::var(ENV_GENERATION)
+        .ok()
+        .and_then(|g| g.parse().ok())
+        .unwrap_or(0)
+}
+
+/// The pid of the process this one was restarted from, if it is still running. This reads from
+/// `/proc`, so async code should call it with `spawn_blocking`.
+pub fn previous_generation() -> Option<u32> {
+    let pid = env::var(ENV_PREVIOUS_PID).ok()?.parse().ok()?;
+    let start_time = env::var(ENV_PREVIOUS_START_TIME).ok();
+    generation_running(pid, start_time.and_then(|t| t.parse().ok())).then_some(pid)
+}
+
+/// Whether the process `pid`, which started at `start_time`, is still running.
+fn generation_running(pid: u32, start_time: Option<u64>) -> bool {
+    // The start time tells the previous generation apart from a process that reused its pid, also
+    // when this process is not its child, e.g. because it daemonized.
+    match start_time {
+        Some(start_time) => restart_coordination_socket::start_time(pid) == Some(start_time),
+        // Older versions and other platforms don't pass the start time, but the new process is a
+        // child of the old one unless it daemonized.
+        None => std::os::unix::process::parent_id() == pid,
+    }
+}
+
+/// Returns the status of this process, as reported to restart coordination socket clients. Most
+/// fields are only filled in once the restart task has been created.
+pub fn status_report() -> StatusReport {
+    match &*STATUS_SOURCE.lock().unwrap() {
+        Some((state, drain_stats)) => restart_status(state, drain_stats.as_ref()),
+        None => restart_status(&SharedRestartState::default(), None),
+    }
+}
+
+/// The restart state of this process, once the restart task has been created.
+pub(crate) fn restart_state() -> Option<SharedRestartState> {
+    let source = STATUS_SOURCE.lock().unwrap();
+    source.as_ref().map(|(state, _)| state.clone())
+}
+
+fn started() -> Instant {
+    *STARTED.get_or_init(|| {
+        let now = Instant::now();
+        process_uptime()
+            .and_then(|uptime| now.checked_sub(uptime))
+            .unwrap_or(now)
+    })
+}
+
+/// How long ago this process started, from its start time in clock ticks after boot and the time
+/// since boot.
+fn process_uptime() -> Option<Duration> {
+    let start_time =
+        (*START_TIME.get_or_init(|| restart_coordination_socket::start_time(process::id())))?;
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    let since_boot = std::fs::read_to_string("/proc/uptime").ok()?;
+    let since_boot: f64 = since_boot.split_whitespace().next()?.parse().ok()?;
+    let started = start_time as f64 / ticks_per_second as f64;
+    Some(Duration::from_secs_f64((since_boot - started).max(0.0)))
+}
+
+fn restart_status(state: &SharedRestartState, drain_stats: Option<&DrainStats>) -> StatusReport {
+    StatusReport {
+        pid: process::id(),
+        generation: generation(),
+        uptime: started().elapsed(),
+        restart_id: restart_id(),
+        in_progress: state.in_progress(),
+        active_handles: drain_stats.map(DrainStats::active_handles),
+        drain: drain_stats.and_then(DrainStats::summary),
+        last_error: state.last_error(),
+        ancestry: lineage::ancestry(),
+    }
+}
+
 /// Returns the restart completion or error message through the restart coordination socket, if used.
 struct RestartResponder {
     rpc: Option<RestartCoordinationSocket>,
+    /// Options sent by a client that understands `RestartResponse::RestartStarted`.
+    options: Option<RestartOptions>,
+    /// Fds for the new process sent by the client, with their names.
+    fds: Vec<(String, OwnedFd)>,
+    /// Restart into the previous binary slot.
+    rollback: bool,
 }
 
 impl RestartResponder {
-    /// Send success or failure to the restart coordination socket client.
-    async fn respond(self, result: Result<u32, String>) {
-        let response = match result {
-            Ok(pid) => RestartResponse::RestartComplete(pid),
-            Err(e) => RestartResponse::RestartFailed(e),
+    /// The credentials of the client, if the restart was requested through the socket.
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.rpc.as_ref()?.peer_credentials().ok()
+    }
+
+    /// Returns the ID requested by the client, or generates one.
+    fn restart_id(&self) -> RestartId {
+        self.options
+            .as_ref()
+            .and_then(|o| o.restart_id.clone())
+            .unwrap_or_else(RestartId::generate)
+    }
+
+    /// Notify the restart coordination socket client that the restart has started, if it asked
+    /// for restart options.
+    async fn started(&mut self, restart_id: &RestartId) {
+        if let (Some(rpc), Some(_)) = (&mut self.rpc, &self.options) {
+            let response = RestartResponse::RestartStarted(restart_id.clone());
+            if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+                diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
+            }
+        }
+    }
+
+    /// Send success, failure or cancellation to the restart coordination socket client.
+    async fn respond(self, completed: &CompletedRestart) {
+        let response = match (&completed.result, &self.options) {
+            (Ok(pid), _) => RestartResponse::RestartComplete(*pid),
+            (Err(_), Some(_)) if completed.cancelled => {
+                RestartResponse::RestartCancelled(completed.restart_id.clone())
+            }
+            (Err(_), Some(_)) if completed.startup_failure.is_some() => {
+                RestartResponse::StartupFailed(completed.startup_failure.clone().unwrap())
+            }
+            (Err(_), Some(_)) if completed.preflight_refused.is_some() => {
+                RestartResponse::PreflightRefused(completed.preflight_refused.clone().unwrap())
+            }
+            (Err(_), Some(_)) if completed.incompatible_binary.is_some() => {
+                RestartResponse::IncompatibleBinary(completed.incompatible_binary.clone().unwrap())
+            }
+            (Err(_), Some(_)) if completed.previous_generation.is_some() => {
+                RestartResponse::PreviousGenerationRunning(completed.previous_generation.unwrap())
+            }
+            (Err(e), _) => RestartResponse::RestartFailed(e.clone()),
         };
+        let monitor_for = self.options.as_ref().and_then(|o| o.monitor_for);
+        match (monitor_for, &completed.result, self.rpc) {
+            (Some(period), Ok(pid), Some(mut rpc)) => {
+                if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+                    diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
+                    return;
+                }
+                tokio::spawn(report_if_exited(rpc, ChildMonitor::from_pid(*pid), period));
+            }
+            (_, _, rpc) => {
+                let responder = RestartResponder {
+                    rpc,
+                    options: None,
+                    fds: Vec::new(),
+                    rollback: false,
+                };
+                responder.send(response).await
+            }
+        }
+    }
+
+    /// Pass on a line of output from the new process, if the client asked for it.
+    async fn child_output(&mut self, line: String) {
+        if let (
+            Some(rpc),
+            Some(RestartOptions {
+                relay_output: true, ..
+            }),
+        ) = (&mut self.rpc, &self.options)
+        {
+            let response = RestartResponse::ChildOutput(line);
+            if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+                diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
+            }
+        }
+    }
+
+    /// Tell the restart coordination socket client that another restart is in progress.
+    async fn already_restarting(self, restart_id: &RestartId) {
+        let response = match self.options {
+            Some(_) => RestartResponse::AlreadyRestarting(restart_id.clone()),
+            None => {
+                RestartResponse::RestartFailed(format!("restart {restart_id} already in progress"))
+            }
+        };
+        self.send(response).await;
+    }
+
+    async fn send(self, response: RestartResponse) {
         if let Some(mut rpc) = self.rpc {
             if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
-                log::warn!("Failed to respond to restart coordinator: {}", e);
+                diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
             }
         }
     }
@@ -208,83 +1072,502 @@ impl RestartResponder {
 /// The child spawner thread needs to be created before seccomp locks down fork/exec.
 pub fn spawn_restart_task(
     settings: RestartConfig,
-) -> io::Result<impl Future<Output = RestartResult<process::Child>> + Send> {
+) -> RestartResult<impl Future<Output = RestartResult<process::Child>> + Send> {
+    if let Some(handshake) = &settings.handshake {
+        if handshake::respond_if_requested(handshake)? {
+            return Err(Error::HandshakeReported);
+        }
+    }
+    if let Some(pid) = existing_instance(&settings)? {
+        let path = settings.coordination_socket_path.clone();
+        diagnostics::info!(
+            "Restarting the instance{} that serves {} instead of starting",
+            pid.map(|pid| format!(" {pid}")).unwrap_or_default(),
+            path.display()
+        );
+        let config = RestartConfig {
+            enabled: true,
+            coordination_socket_path: path,
+            ..Default::default()
+        };
+        return Ok(Either::Left(async move {
+            Err(Error::RestartedExisting(config.request_restart().await?))
+        }));
+    }
+    start_restart_task(settings).map(Either::Right)
+}
+
+/// Checks for another instance serving the restart coordination socket. Returns its pid, if known,
+/// if it should be restarted instead of starting this process.
+fn existing_instance(settings: &RestartConfig) -> RestartResult<Option<Option<u32>>> {
+    let path = &settings.coordination_socket_path;
+    // A process started by a restart inherits the socket of the old process, which is running.
+    // A listener that was handed in is ours to serve.
+    #[cfg(any(test, feature = "test-util"))]
+    if settings.in_process.is_some() {
+        return Ok(None);
+    }
+    if !settings.enabled
+        || settings.coordination_listener.is_some()
+        || env::var_os(ENV_RESTART_SOCKET).is_some()
+    {
+        return Ok(None);
+    }
+    let Ok(pid) = restart_coordination_socket::running_instance(path) else {
+        return Ok(None);
+    };
+    match settings.existing_instance {
+        ExistingInstance::Replace => {
+            diagnostics::warn!(
+                "Another instance{} serves {}, replacing it",
+                pid.map(|pid| format!(" ({pid})")).unwrap_or_default(),
+                path.display()
+            );
+            Ok(None)
+        }
+        ExistingInstance::Refuse => Err(Error::AlreadyRunning {
+            path: path.clone(),
+            pid,
+        }),
+        ExistingInstance::Restart => Ok(Some(pid)),
+    }
+}
+
+fn start_restart_task(
+    settings: RestartConfig,
+) -> RestartResult<impl Future<Output = RestartResult<process::Child>> + Send> {
     let socket = match settings.enabled {
-        true => Some(settings.coordination_socket_path.as_ref()),
+        true => Some((
+            settings.coordination_socket_path.as_ref(),
+            settings.coordination_listener,
+            settings.coordination_socket_policy,
+        )),
         false => None,
     };
+    #[cfg(any(test, feature = "test-util"))]
+    let (socket, in_process) = match (&settings.in_process, socket) {
+        (Some(in_process), Some(_)) => {
+            let connections = in_process.serve().ok_or_else(|| Error::Bind {
+                path: settings.coordination_socket_path.clone(),
+                source: io::ErrorKind::AddrInUse.into(),
+            })?;
+            (None, Some(connections))
+        }
+        (_, socket) => (socket, None),
+    };
+    #[cfg(not(any(test, feature = "test-util")))]
+    let in_process = None;
 
+    START_TIME.get_or_init(|| restart_coordination_socket::start_time(process::id()));
+    started();
+    lineage::started();
+    let state = SharedRestartState::default();
+    *STATUS_SOURCE.lock().unwrap() = Some((state.clone(), settings.drain_stats.clone()));
+    let drain_stats = settings.drain_stats.clone();
     let mut signal_stream = signal(settings.restart_signal)?;
-    let (restart_fd, mut socket_stream) = new_restart_coordination_socket_stream(socket)?;
-    let mut child_spawner =
-        ChildSpawner::new(restart_fd, settings.environment, settings.lifecycle_handler);
+    #[cfg(feature = "http-admin")]
+    let http_admin = match settings.http_admin {
+        Some(config) => Some(http_admin::spawn(config)?),
+        None => None,
+    };
+    #[cfg(not(feature = "http-admin"))]
+    let http_admin = None;
+    #[cfg(feature = "dbus")]
+    let dbus = match settings.dbus {
+        Some(config) => {
+            let policy = Arc::new(config.policy.clone());
+            let calls = ReceiverStream::new(dbus::spawn(config)?);
+            Some((calls.map(|(sock, peer)| (sock, Some(peer))).boxed(), policy))
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "dbus"))]
+    let dbus = None;
+    #[allow(unused_mut)]
+    let mut endpoints = settings.endpoints;
+    #[cfg(target_os = "linux")]
+    #[allow(deprecated)]
+    endpoints.extend(settings.vsock.map(|config| endpoints::Endpoint {
+        addr: endpoints::EndpointAddr::Vsock(config),
+        policy: endpoints::EndpointPolicy::default(),
+    }));
+    let unrestricted = Arc::new(endpoints::EndpointPolicy::default());
+    let mut internal: Vec<_> = in_process
+        .into_iter()
+        .chain(http_admin)
+        .map(|connections| (direct_connections(connections), Arc::clone(&unrestricted)))
+        .chain(dbus)
+        .collect();
+    internal.extend(
+        endpoints::spawn(endpoints)?
+            .into_iter()
+            .map(|(connections, policy)| (direct_connections(connections), policy)),
+    );
+    let (restart_fd, socket_stream) = new_restart_coordination_socket_stream(
+        socket,
+        internal,
+        SocketContext {
+            state: state.clone(),
+            admin_commands: settings.admin_commands,
+            drain_stats: settings.drain_stats,
+            audit_hook: settings.audit_hook,
+            limits: settings.socket_limits,
+        },
+    )?;
+    let triggers = settings
+        .restart_triggers
+        .into_iter()
+        .collect::<FuturesUnordered<_>>()
+        .map(|()| {
+            diagnostics::info!("Restart triggered by the application");
+            RestartResponder {
+                rpc: None,
+                options: None,
+                fds: Vec::new(),
+                rollback: false,
+            }
+        });
+    let mut socket_stream = futures::stream::select(socket_stream, triggers);
+    let child_options = ChildOptions {
+        environment: settings.environment,
+        inherited_fds: settings.inherited_fds,
+        fd_leak_policy: settings.fd_leak_policy,
+        relay_output: settings.relay_child_output,
+        handover_buffer_size: settings.handover_buffer_size,
+        handover_pipe_size: settings.handover_pipe_size,
+        emit_listen_fds: settings.emit_listen_fds,
+        spawn_method: settings.spawn_method,
+        #[cfg(target_os = "linux")]
+        isolation: settings.isolation,
+        #[cfg(target_os = "linux")]
+        scheduling: settings.scheduling,
+        #[cfg(target_os = "linux")]
+        capabilities: settings.capabilities,
+        #[cfg(target_os = "linux")]
+        security_context: settings.security_context,
+        send_fd_manifest: settings.send_fd_manifest,
+        handshake: settings.handshake,
+        cutover_ramp: settings.cutover_ramp,
+        accept_handoff: settings.accept_handoff,
+        watch_backlog: settings.watch_backlog,
+        #[cfg(target_os = "linux")]
+        park_connections: settings.park_connections,
+    };
+    let (output_tx, mut output_rx) = channel(CHILD_OUTPUT_BUFFER);
+    let mut child_spawner = ChildSpawner::new(
+        restart_fd,
+        child_options,
+        settings.lifecycle_handler,
+        state.clone(),
+        output_tx,
+    )?;
+
+    #[cfg(feature = "self-update")]
+    let binary_slots = settings.binary_slots;
+    #[cfg(not(feature = "self-update"))]
+    let binary_slots = ();
+
+    let panic_hook = settings.panic_hook;
+    let lingering_generation = settings.lingering_generation;
+    let load_gate = settings.load_gate;
+    let preflight_checks = settings.preflight_checks;
+    let preflight_timeout = settings.preflight_timeout;
 
     Ok(async move {
         startup_complete()?;
         loop {
-            let responder = next_restart_request(&mut signal_stream, &mut socket_stream).await?;
+            let mut responder =
+                next_restart_request(&mut signal_stream, &mut socket_stream).await?;
+            let rollback = responder.rollback;
+            if rollback {
+                match switch_binary_slot(&binary_slots) {
+                    Ok(slot) => diagnostics::info!("Rolling back to {}", slot.display()),
+                    Err(reason) => {
+                        diagnostics::warn!("Rejecting rollback request: {}", reason);
+                        responder.send(RestartResponse::RestartFailed(reason)).await;
+                        continue;
+                    }
+                }
+            }
+            let restart_id = responder.restart_id();
+            responder.started(&restart_id).await;
+
+            state.begin(&restart_id, responder.peer_credentials());
+
+            diagnostics::debug!(
+                restart_id = restart_id;
+                "Spawning new process for restart {}",
+                restart_id
+            );
+            let request = SpawnRequest {
+                restart_id: restart_id.clone(),
+                await_commit: responder.options.as_ref().and_then(|o| o.await_commit),
+                stateless: responder.options.as_ref().is_some_and(|o| o.stateless),
+                fds: mem::take(&mut responder.fds),
+            };
+            let spawn = async {
+                await_previous_generation(lingering_generation).await?;
+                if let Some(gate) = &load_gate {
+                    gate.wait(|| state.cancel_requested()).await;
+                }
+                preflight::run(&preflight_checks, &request.restart_id, preflight_timeout)
+                    .await
+                    .map_err(ChildSpawnError::PreflightRefused)?;
+                child_spawner.spawn_new_process(request).await
+            };
+            pin!(spawn);
+
+            // Keep serving the coordination socket while the restart is in progress. Only one
+            // restart may run at a time, so further restart requests are turned away.
+            let res = loop {
+                select! {
+                    res = &mut spawn => break res,
+                    Some(r) = socket_stream.next() => {
+                        diagnostics::info!(
+                            restart_id = restart_id;
+                            "Restart {} already in progress, rejecting request",
+                            restart_id
+                        );
+                        r.already_restarting(&restart_id).await;
+                    }
+                    Some(output) = output_rx.recv() => {
+                        // Output of the new process from an earlier restart may still arrive.
+                        if output.restart_id == restart_id {
+                            responder.child_output(output.line).await;
+                        }
+                    }
+                }
+            };
 
-            log::debug!("Spawning new process");
-            let res = child_spawner.spawn_new_process().await;
+            let cancelled = res.is_err() && state.cancel_requested();
+            let completed = CompletedRestart {
+                restart_id: restart_id.clone(),
+                result: res.as_ref().map(|p| p.id()).map_err(|e| match cancelled {
+                    true => format!("restart {restart_id} cancelled"),
+                    false => e.to_string(),
+                }),
+                cancelled,
+                startup_failure: match &res {
+                    Err(ChildSpawnError::StartupFailed(failure)) => Some(failure.clone()),
+                    _ => None,
+                },
+                preflight_refused: match &res {
+                    Err(ChildSpawnError::PreflightRefused(refused)) => Some(refused.clone()),
+                    _ => None,
+                },
+                incompatible_binary: match &res {
+                    Err(ChildSpawnError::IncompatibleBinary(incompatible)) => {
+                        Some(incompatible.clone())
+                    }
+                    _ => None,
+                },
+                previous_generation: match &res {
+                    Err(ChildSpawnError::PreviousGenerationRunning(pid)) => Some(*pid),
+                    _ => None,
+                },
+            };
+            state.complete(completed.clone());
+            responder.respond(&completed).await;
 
-            responder
-                .respond(res.as_ref().map(|p| p.id()).map_err(|e| e.to_string()))
-                .await;
+            if rollback && res.is_err() {
+                // Switch back to the slot this process runs from.
+                if let Err(reason) = switch_binary_slot(&binary_slots) {
+                    diagnostics::error!(restart_id = restart_id; "{}", reason);
+                }
+            }
 
             match res {
                 Ok(child) => {
-                    log::debug!("New process spawned with pid {}", child.id());
+                    diagnostics::debug!(
+                        restart_id = restart_id, pid = child.id();
+                        "New process spawned with pid {} for restart {}",
+                        child.id(),
+                        restart_id
+                    );
 
                     if let Err(e) =
                         sd_notify::notify(true, &[sd_notify::NotifyState::MainPid(child.id())])
                     {
-                        log::error!("Failed to notify systemd: {}", e);
+                        diagnostics::error!("Failed to notify systemd: {}", e);
                     }
 
+                    listeners::handed_over();
+                    cutover::handed_over();
+                    daemon::handed_over(child.id());
+                    lineage::handed_over(drain_stats.clone());
+                    after_handover(settings.after_handover, drain_stats);
                     return Ok(child);
                 }
-                Err(ChildSpawnError::ChildError(e)) => {
+                Err(ChildSpawnError::RestartThreadGone) => return Err(Error::RestartThreadGone),
+                Err(ChildSpawnError::Panicked(message)) => {
+                    if let Some(hook) = &panic_hook {
+                        hook.main_panicked(&app::MainPanic {
+                            message: message.clone(),
+                            aborted_restart: Some(restart_id.clone()),
+                        });
+                    }
+                    let e = ChildSpawnError::Panicked(message);
                     if settings.exit_on_error {
-                        return Err(anyhow!("Restart failed: {}", e));
-                    } else {
-                        log::error!("Restart failed: {}", e);
+                        return Err(Error::restart_failed(restart_id, e));
                     }
+                    diagnostics::error!(
+                        restart_id = restart_id;
+                        "Restart {} failed: {}",
+                        restart_id,
+                        e
+                    );
                 }
-                Err(ChildSpawnError::RestartThreadGone) => {
-                    res?;
+                Err(_) if cancelled => {
+                    diagnostics::info!(restart_id = restart_id; "Restart {} cancelled", restart_id);
+                }
+                // Refusing a restart is not a failure of this process.
+                Err(
+                    e @ (ChildSpawnError::PreviousGenerationRunning(_)
+                    | ChildSpawnError::PreflightRefused(_)
+                    | ChildSpawnError::IncompatibleBinary(_)),
+                ) => {
+                    diagnostics::warn!(
+                        restart_id = restart_id;
+                        "Restart {} refused: {}",
+                        restart_id,
+                        e
+                    );
+                }
+                Err(e) => {
+                    if settings.exit_on_error {
+                        return Err(Error::restart_failed(restart_id, e));
+                    } else {
+                        diagnostics::error!(
+                            restart_id = restart_id;
+                            "Restart {} failed: {}",
+                            restart_id,
+                            e
+                        );
+                    }
                 }
             }
         }
     })
 }
 
+/// Switch to the previous binary slot for a rollback request. Returns the slot that is now current.
+#[cfg(feature = "self-update")]
+fn switch_binary_slot(slots: &Option<update::Slots>) -> Result<PathBuf, String> {
+    let slots = slots.as_ref().ok_or("binary slots are not configured")?;
+    slots
+        .roll_back()
+        .map_err(|e| format!("failed to switch to the previous binary slot: {e}"))
+}
+
+#[cfg(not(feature = "self-update"))]
+fn switch_binary_slot(_slots: &()) -> Result<PathBuf, String> {
+    Err("binary slots are not supported without the self-update feature".into())
+}
+
+/// Settings that control how the new process is spawned.
+struct ChildOptions {
+    environment: Vec<(OsString, OsString)>,
+    inherited_fds: Vec<RawFd>,
+    fd_leak_policy: FdLeakPolicy,
+    relay_output: bool,
+    handover_buffer_size: usize,
+    handover_pipe_size: Option<usize>,
+    emit_listen_fds: bool,
+    spawn_method: SpawnMethod,
+    #[cfg(target_os = "linux")]
+    isolation: isolation::Isolation,
+    #[cfg(target_os = "linux")]
+    scheduling: scheduling::Scheduling,
+    #[cfg(target_os = "linux")]
+    capabilities: capabilities::CapabilityPolicy,
+    #[cfg(target_os = "linux")]
+    security_context: Option<security_context::SecurityContext>,
+    send_fd_manifest: bool,
+    handshake: Option<handshake::Handshake>,
+    cutover_ramp: Option<Duration>,
+    accept_handoff: Option<Duration>,
+    watch_backlog: bool,
+    #[cfg(target_os = "linux")]
+    park_connections: bool,
+}
+
+/// What the restart thread needs to know about a restart.
+struct SpawnRequest {
+    restart_id: RestartId,
+    await_commit: Option<Duration>,
+    /// Don't send state to the new process, see `RestartOptions::stateless`.
+    stateless: bool,
+    /// Fds for the new process sent by the restart requester, with their names.
+    fds: Vec<(String, OwnedFd)>,
+}
+
 /// Handles forking a new client in a more privileged thread.
 struct ChildSpawner {
-    signal_sender: Sender<()>,
-    pid_receiver: Receiver<io::Result<process::Child>>,
+    signal_sender: Sender<SpawnRequest>,
+    pid_receiver: Receiver<Result<process::Child, ChildSpawnError>>,
 }
 
 impl ChildSpawner {
     /// Create a ChildSpawner that will pass restart_fd to child processes.
     fn new(
         restart_fd: Option<OwnedFd>,
-        environment: Vec<(OsString, OsString)>,
+        options: ChildOptions,
         mut lifecycle_handler: Box<dyn LifecycleHandler>,
-    ) -> Self {
+        state: SharedRestartState,
+        output_tx: Sender<ChildOutput>,
+    ) -> io::Result<Self> {
         let (signal_sender, mut signal_receiver) = channel(1);
         let (pid_sender, pid_receiver) = channel(1);
+        // Created here, so that its threads are not subject to seccomp filters installed later.
+        let runtime = tokio::runtime::Runtime::new()?;
 
         thread::spawn(move || {
             let restart_fd = restart_fd.as_ref().map(OwnedFd::as_fd);
+            #[cfg(target_os = "linux")]
+            let filters = seccomp::ThreadFilters::current().ok();
 
-            while let Some(()) = signal_receiver.blocking_recv() {
-                let child = tokio::runtime::Runtime::new()
-                    .unwrap()
-                    .block_on(spawn_child(
+            while let Some(request) = signal_receiver.blocking_recv() {
+                #[cfg(target_os = "linux")]
+                if let (Some(before), Ok(now)) = (&filters, seccomp::ThreadFilters::current()) {
+                    if now.added_since(before) {
+                        diagnostics::warn!(
+                            "A seccomp filter was applied to the restart thread after it started, \
+                             which may break the restart"
+                        );
+                    }
+                }
+                // Whether the restart got as far as `quiesce_writes`, which failed restarts undo.
+                let mut quiesced = false;
+                // A panic, e.g. in the lifecycle handler, fails the restart rather than taking
+                // down the restart thread. The new process has been killed by then.
+                let child = panic::catch_unwind(AssertUnwindSafe(|| {
+                    runtime.block_on(spawn_child(
                         restart_fd,
-                        &environment,
+                        request,
+                        &options,
                         &mut *lifecycle_handler,
-                    ));
+                        &state,
+                        &output_tx,
+                        &mut quiesced,
+                    ))
+                }))
+                .unwrap_or_else(|panic| {
+                    let message = app::panic_message(&*panic);
+                    diagnostics::error!("The restart panicked: {}", message);
+                    Err(ChildSpawnError::Panicked(message))
+                });
+                if child.is_err() {
+                    runtime.block_on(async {
+                        cutover::reset();
+                        #[cfg(target_os = "linux")]
+                        parking::reset();
+                        #[cfg(target_os = "linux")]
+                        listeners::deferred::reset();
+                        if quiesced {
+                            lifecycle_handler.resume_writes().await;
+                        }
+                    });
+                }
 
                 pid_sender
                     .blocking_send(child)
@@ -292,34 +1575,59 @@ impl ChildSpawner {
             }
         });
 
-        ChildSpawner {
+        Ok(ChildSpawner {
             signal_sender,
             pid_receiver,
-        }
+        })
     }
 
     /// Spawn a process via IPC to the privileged thread.
     /// Returns the child pid on success.
-    async fn spawn_new_process(&mut self) -> Result<process::Child, ChildSpawnError> {
+    async fn spawn_new_process(
+        &mut self,
+        request: SpawnRequest,
+    ) -> Result<process::Child, ChildSpawnError> {
         self.signal_sender
-            .send(())
+            .send(request)
             .await
             .map_err(|_| ChildSpawnError::RestartThreadGone)?;
-        match self.pid_receiver.recv().await {
-            Some(Ok(child)) => Ok(child),
-            Some(Err(e)) => Err(ChildSpawnError::ChildError(e)),
-            None => Err(ChildSpawnError::RestartThreadGone),
-        }
+        self.pid_receiver
+            .recv()
+            .await
+            .unwrap_or(Err(ChildSpawnError::RestartThreadGone))
     }
 }
 
-/// Indicates an error that happened during child forking.
-#[derive(Error, Debug)]
-pub enum ChildSpawnError {
-    #[error("Restart thread exited")]
-    RestartThreadGone,
-    #[error("Child failed to start: {0}")]
-    ChildError(io::Error),
+/// Apply `policy` if the previous generation is still running.
+async fn await_previous_generation(policy: LingeringGeneration) -> Result<(), ChildSpawnError> {
+    let timeout = match policy {
+        LingeringGeneration::Allow => return Ok(()),
+        LingeringGeneration::Refuse => Duration::ZERO,
+        LingeringGeneration::Wait(timeout) => timeout,
+    };
+    let running = || async {
+        tokio::task::spawn_blocking(previous_generation)
+            .await
+            .expect("checking the previous generation panicked")
+    };
+    let Some(pid) = running().await else {
+        return Ok(());
+    };
+    if !timeout.is_zero() {
+        diagnostics::info!(
+            "Waiting up to {:?} for the previous generation (pid {}) to exit before restarting",
+            timeout,
+            pid
+        );
+    }
+    let exited = async {
+        while running().await.is_some() {
+            tokio::time::sleep(LINGERING_POLL_INTERVAL).await;
+        }
+    };
+    tokio::time::timeout(timeout, exited)
+        .await
+        .map_err(|_| ChildSpawnError::PreviousGenerationRunning(pid))
 }
 
 /// Await the next request to gracefully restart the process.
@@ -329,76 +1637,494 @@ async fn next_restart_request(
     mut socket_stream: impl Stream<Item = RestartResponder> + Unpin,
 ) -> RestartResult<RestartResponder> {
     select! {
-        _ = signal_stream.recv() => Ok(RestartResponder{ rpc: None }),
+        _ = signal_stream.recv() => Ok(RestartResponder {
+            rpc: None,
+            options: None,
+            fds: Vec::new(),
+            rollback: false,
+        }),
         r = socket_stream.next() => match r {
             Some(r) => Ok(r),
             None => {
                 // Technically we can still support signal restart! However if you have the restart coordination
                 // socket enabled you probably don't want to use signals, and need to recover the process such
                 // that you can use the restart coordinator socket again.
-                Err(anyhow!("Restart coordinator socket acceptor terminated"))
+                Err(Error::AcceptorTerminated)
             }
         }
     }
 }
 
+/// Connections to the restart task made by another part of this process, each with the client it
+/// acts for if that isn't the process at the other end of the connection.
+type InternalConnections = BoxStream<'static, (UnixStream, Option<PeerCredentials>)>;
+
+/// Connections whose clients are the processes at the other end, e.g. those accepted on an
+/// endpoint.
+fn direct_connections(connections: Receiver<UnixStream>) -> InternalConnections {
+    ReceiverStream::new(connections)
+        .map(|sock| (sock, None))
+        .boxed()
+}
+
+/// Serve the restart coordination socket, if enabled, and connections made by other parts of this
+/// process through `internal`, such as the HTTP admin endpoint, along with the policy to apply to
+/// each.
 fn new_restart_coordination_socket_stream(
-    restart_coordination_socket: Option<&Path>,
-) -> io::Result<(Option<OwnedFd>, impl Stream<Item = RestartResponder>)> {
-    if let Some(path) = restart_coordination_socket {
-        let listener = bind_restart_coordination_socket(path)?;
+    restart_coordination_socket: Option<(&Path, Option<StdUnixListener>, EndpointPolicy)>,
+    internal: Vec<(InternalConnections, Arc<EndpointPolicy>)>,
+    ctx: SocketContext,
+) -> RestartResult<(Option<OwnedFd>, impl Stream<Item = RestartResponder>)> {
+    let limits = ctx.limits;
+    let sources = internal.len() + usize::from(restart_coordination_socket.is_some());
+    let internal =
+        futures::stream::select_all(internal.into_iter().map(|(connections, policy)| {
+            let source = Arc::new(ConnectionSource::new(policy, limits));
+            connections.map(move |(sock, peer)| Ok((sock, peer, Arc::clone(&source))))
+        }));
+    if let Some((path, listener, policy)) = restart_coordination_socket {
+        let listener =
+            bind_restart_coordination_socket(path, listener).map_err(|source| Error::Bind {
+                path: path.to_path_buf(),
+                source,
+            })?;
         listener.set_nonblocking(true)?;
         let inherit_socket = OwnedFd::from(listener.try_clone()?);
         let listener = UnixListener::from_std(listener)?;
-        let st = listen_for_restart_events(listener);
+        let source = Arc::new(ConnectionSource::new(Arc::new(policy), limits));
+        let connections = UnixListenerStream::new(listener)
+            .map(move |r| r.map(|sock| (sock, None, Arc::clone(&source))));
+        let connections = futures::stream::select(connections, internal);
+        let st = listen_for_restart_events(connections, ctx, sources);
         Ok((Some(inherit_socket), st.boxed()))
+    } else if sources > 0 {
+        let st = listen_for_restart_events(internal, ctx, sources);
+        Ok((None, st.boxed()))
     } else {
         Ok((None, futures::stream::pending().boxed()))
     }
 }
 
-fn bind_restart_coordination_socket(path: &Path) -> io::Result<StdUnixListener> {
-    match env::var(ENV_RESTART_SOCKET) {
-        Err(_) => {
+fn bind_restart_coordination_socket(
+    path: &Path,
+    listener: Option<StdUnixListener>,
+) -> io::Result<StdUnixListener> {
+    match (env::var(ENV_RESTART_SOCKET), listener) {
+        (Err(_), Some(listener)) => Ok(listener),
+        (Err(_), None) => {
             // This may fail but binding will succeed despite that. If binding fails,
             // that's the error we really care about.
             let _ = remove_file(path);
             StdUnixListener::bind(path)
         }
-        Ok(maybe_sock_fd) => unsafe { StdUnixListener::from_fd_string(&maybe_sock_fd) },
+        // The old process passed on the socket it served, which replaces the one handed in.
+        (Ok(maybe_sock_fd), _) => unsafe { StdUnixListener::from_fd_string(&maybe_sock_fd) },
+    }
+}
+
+/// What serving a connection to the restart coordination socket needs.
+#[derive(Clone)]
+struct SocketContext {
+    state: SharedRestartState,
+    admin_commands: Option<Sender<AdminCommand>>,
+    drain_stats: Option<DrainStats>,
+    audit_hook: Option<Arc<dyn AuditHook>>,
+    limits: SocketLimits,
+}
+
+/// A socket or endpoint that restart coordination socket connections are accepted on, or another
+/// part of this process that makes them, with its own policy and limits.
+struct ConnectionSource {
+    policy: Arc<EndpointPolicy>,
+    /// Held by connections until their request is read.
+    requests: Arc<Semaphore>,
+    /// Held by connections that stay open after their request.
+    streams: Arc<Semaphore>,
+}
+
+impl ConnectionSource {
+    fn new(policy: Arc<EndpointPolicy>, limits: SocketLimits) -> Self {
+        ConnectionSource {
+            policy,
+            requests: Arc::new(Semaphore::new(limits.max_connections)),
+            streams: Arc::new(Semaphore::new(limits.max_streams)),
+        }
     }
 }
 
 fn listen_for_restart_events(
-    restart_coordination_socket: UnixListener,
+    connections: impl Stream<
+        Item = io::Result<(UnixStream, Option<PeerCredentials>, Arc<ConnectionSource>)>,
+    >,
+    ctx: SocketContext,
+    sources: usize,
 ) -> impl Stream<Item = RestartResponder> {
-    UnixListenerStream::new(restart_coordination_socket).filter_map(move |r| async move {
-        let sock = match r {
-            Ok(sock) => sock,
-            Err(e) => {
-                log::error!("Restart coordination socket accept error: {}", e);
-                return None;
-            }
-        };
+    let max_connections = ctx.limits.max_connections;
+    let concurrency = max_connections.max(1) * sources.max(1);
+    connections
+        .filter_map(move |r| {
+            let accepted = match r {
+                Ok((sock, peer, source)) => {
+                    match Arc::clone(&source.requests).try_acquire_owned() {
+                        Ok(permit) => Some((sock, peer, source, permit)),
+                        Err(_) => {
+                            diagnostics::warn!(
+                                "Closing restart coordination socket connection, as {} are open",
+                                max_connections
+                            );
+                            None
+                        }
+                    }
+                }
+                Err(e) => {
+                    diagnostics::error!("Restart coordination socket accept error: {}", e);
+                    None
+                }
+            };
+            futures::future::ready(accepted)
+        })
+        .map(move |(sock, peer, source, permit)| {
+            handle_connection(ctx.clone(), sock, peer, source, permit)
+        })
+        // Serve connections concurrently, so that a client that is slow to send its request
+        // doesn't hold up the others.
+        .buffer_unordered(concurrency)
+        .filter_map(futures::future::ready)
+}
 
-        let mut rpc = RestartCoordinationSocket::new(sock);
-        match rpc.receive_message().await {
-            Ok(RestartMessage::Request(RestartRequest::TryRestart)) => {
-                Some(RestartResponder { rpc: Some(rpc) })
+/// Serve a connection to the restart coordination socket, made for `peer` if given. Restart
+/// requests are returned, to be carried out by the restart task.
+async fn handle_connection(
+    ctx: SocketContext,
+    sock: UnixStream,
+    peer: Option<PeerCredentials>,
+    source: Arc<ConnectionSource>,
+    permit: OwnedSemaphorePermit,
+) -> Option<RestartResponder> {
+    let SocketContext {
+        state,
+        admin_commands,
+        drain_stats,
+        audit_hook,
+        limits,
+    } = ctx;
+    let mut rpc = RestartCoordinationSocket::new(sock);
+    rpc.limit(limits.max_request_size, permit);
+    if let Some(peer) = peer {
+        rpc.act_for(peer);
+    }
+    let Ok(message) = tokio::time::timeout(limits.idle_timeout, rpc.receive_message()).await else {
+        diagnostics::warn!(
+            "Closing restart coordination socket connection that sent no request within {:?}",
+            limits.idle_timeout
+        );
+        return None;
+    };
+    if let Ok(RestartMessage::Request(request)) = &message {
+        let peer = rpc.peer_credentials().ok();
+        audit::record(audit_hook.as_deref(), peer, request);
+        if let Err(reason) = source.policy.check(request, peer.as_ref()) {
+            diagnostics::warn!("Refusing control request: {}", reason);
+            let response = RestartResponse::RestartFailed(format!("refused: {reason}"));
+            if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+                diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
             }
-            Ok(m) => {
-                log::warn!(
-                    "Restart coordination socket received unexpected message: {:?}",
-                    m
-                );
-                None
+            return None;
+        }
+        // Connections that stay open count against their own limit from now on, so that clients
+        // waiting for restarts or subscribed to events can't keep restarts from being requested.
+        match request {
+            RestartRequest::WaitForRestart
+            | RestartRequest::CancelRestart(_)
+            | RestartRequest::CommitRestart(_)
+            | RestartRequest::Subscribe => match Arc::clone(&source.streams).try_acquire_owned() {
+                Ok(permit) => rpc.replace_permit(Some(permit)),
+                Err(_) => {
+                    diagnostics::warn!(
+                        "Refusing control request, as {} clients are waiting for restarts or \
+                         subscribed to events",
+                        limits.max_streams
+                    );
+                    let response = RestartResponse::RestartFailed(
+                        "too many clients are waiting for restarts or subscribed to events".into(),
+                    );
+                    if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+                        diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
+                    }
+                    return None;
+                }
+            },
+            // The permit is held until the fds that go with the request are received.
+            RestartRequest::TryRestartWithFds { .. } => {}
+            _ => rpc.replace_permit(None),
+        }
+    }
+    match message {
+        Ok(RestartMessage::Request(RestartRequest::TryRestart)) => Some(RestartResponder {
+            rpc: Some(rpc),
+            options: None,
+            fds: Vec::new(),
+            rollback: false,
+        }),
+        Ok(RestartMessage::Request(RestartRequest::TryRestartWith(options))) => {
+            Some(RestartResponder {
+                rpc: Some(rpc),
+                options: Some(options),
+                fds: Vec::new(),
+                rollback: false,
+            })
+        }
+        Ok(RestartMessage::Request(RestartRequest::TryRestartWithFds { options, fd_names })) => {
+            match receive_requester_fds(&mut rpc, &fd_names, limits.idle_timeout).await {
+                Ok(fds) => {
+                    rpc.replace_permit(None);
+                    Some(RestartResponder {
+                        rpc: Some(rpc),
+                        options: Some(options),
+                        fds: fd_names.into_iter().zip(fds).collect(),
+                        rollback: false,
+                    })
+                }
+                Err(e) => {
+                    diagnostics::warn!("Failed to receive fds from restart requester: {}", e);
+                    let response =
+                        RestartResponse::RestartFailed(format!("failed to receive fds: {e}"));
+                    if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+                        diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
+                    }
+                    None
+                }
             }
-            Err(e) => {
-                log::warn!("Restart coordination socket connection error: {}", e);
-                None
+        }
+        Ok(RestartMessage::Request(RestartRequest::Rollback(options))) => Some(RestartResponder {
+            rpc: Some(rpc),
+            options: Some(options),
+            fds: Vec::new(),
+            rollback: true,
+        }),
+        Ok(RestartMessage::Request(RestartRequest::Status)) => {
+            let response = RestartResponse::Status(restart_status(&state, drain_stats.as_ref()));
+            if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+                diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
             }
+            None
         }
-    })
+        Ok(RestartMessage::Request(RestartRequest::WaitForRestart)) => {
+            let restart_id = state.in_progress().map(|r| r.restart_id);
+            tokio::spawn(respond_when_restarted(rpc, state, restart_id));
+            None
+        }
+        Ok(RestartMessage::Request(RestartRequest::CancelRestart(restart_id))) => {
+            let restart_id = match state.cancel(restart_id.as_ref()) {
+                CancelRequest::Cancelling(id) => {
+                    diagnostics::info!(restart_id = id; "Cancelling restart {}", id);
+                    Some(id)
+                }
+                CancelRequest::TooLate(id) => {
+                    let response = RestartResponse::RestartFailed(format!(
+                        "restart {id} can no longer be cancelled"
+                    ));
+                    if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+                        diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
+                    }
+                    return None;
+                }
+                CancelRequest::NotInProgress => None,
+            };
+            tokio::spawn(respond_when_restarted(rpc, state, restart_id));
+            None
+        }
+        Ok(RestartMessage::Request(RestartRequest::CommitRestart(restart_id))) => {
+            let restart_id = match state.request_commit(restart_id.as_ref()) {
+                CommitRequest::Committing(id) => {
+                    diagnostics::info!(restart_id = id; "Committing restart {}", id);
+                    Some(id)
+                }
+                CommitRequest::NotAwaitingCommit(id) => {
+                    let response = RestartResponse::RestartFailed(format!(
+                        "restart {id} is not awaiting a commit"
+                    ));
+                    if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+                        diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
+                    }
+                    return None;
+                }
+                CommitRequest::NotInProgress => None,
+            };
+            tokio::spawn(respond_when_restarted(rpc, state, restart_id));
+            None
+        }
+        Ok(RestartMessage::Request(RestartRequest::Command(command))) => {
+            diagnostics::info!("Received {} command", command);
+            let reopened = match command == AdminCommand::ReopenLogs {
+                true => Some(
+                    tokio::task::spawn_blocking(logs::reopen)
+                        .await
+                        .expect("reopening log files panicked"),
+                ),
+                false => None,
+            };
+            let response = match (reopened, admin_commands.map(|tx| tx.try_send(command))) {
+                (Some(Err(e)), _) => {
+                    RestartResponse::RestartFailed(format!("failed to reopen log files: {e}"))
+                }
+                (_, Some(Ok(()))) | (Some(Ok(_)), None) => RestartResponse::CommandAccepted,
+                (_, Some(Err(e))) => {
+                    RestartResponse::RestartFailed(format!("{command} command not accepted: {e}"))
+                }
+                (None, None) => RestartResponse::RestartFailed(format!(
+                    "{command} command not supported by this process"
+                )),
+            };
+            if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+                diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
+            }
+            None
+        }
+        Ok(RestartMessage::Request(RestartRequest::Subscribe)) => {
+            tokio::spawn(send_events(rpc, state, drain_stats));
+            None
+        }
+        Ok(m) => {
+            diagnostics::warn!(
+                "Restart coordination socket received unexpected message: {:?}",
+                m
+            );
+            None
+        }
+        Err(e) => {
+            diagnostics::warn!("Restart coordination socket connection error: {}", e);
+            None
+        }
+    }
+}
+
+/// Ask a coordination socket client for the fds it attached to its restart request.
+async fn receive_requester_fds(
+    rpc: &mut RestartCoordinationSocket,
+    names: &[String],
+    timeout: Duration,
+) -> RestartResult<Vec<OwnedFd>> {
+    if names.len() > MAX_REQUEST_FDS {
+        return Err(Error::Protocol(format!("too many fds: {}", names.len())));
+    }
+    if let Some(name) = names.iter().find(|name| name.contains([',', '='])) {
+        return Err(Error::Protocol(format!("invalid fd name {name:?}")));
+    }
+    rpc.send_message(RestartMessage::Response(RestartResponse::SendFds))
+        .await?;
+    match tokio::time::timeout(timeout, rpc.receive_requester_fds(names.len())).await {
+        Ok(fds) => Ok(fds?),
+        Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+    }
+}
+
+/// Send lifecycle events to a coordination socket client until either side goes away.
+async fn send_events(
+    mut rpc: RestartCoordinationSocket,
+    state: SharedRestartState,
+    drain_stats: Option<DrainStats>,
+) {
+    // Subscribe first, so that no event is missed between the status and the events.
+    let mut events = state.subscribe_events();
+    let response = RestartResponse::Status(restart_status(&state, drain_stats.as_ref()));
+    if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+        diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
+        return;
+    }
+
+    let mut drain_interval = tokio::time::interval(DRAIN_PROGRESS_INTERVAL);
+    let mut last_drain = None;
+    loop {
+        let event = select! {
+            event = events.recv() => match event {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(n)) => RestartEvent::Lagged(n),
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            _ = drain_interval.tick(), if drain_stats.is_some() => {
+                let drain = drain_stats
+                    .as_ref()
+                    .and_then(DrainStats::report)
+                    .map(|r| (r.handles, r.still_running));
+                match drain {
+                    Some((handles, still_running)) if drain != last_drain => {
+                        last_drain = drain;
+                        RestartEvent::DrainProgress { handles, still_running }
+                    }
+                    _ => continue,
+                }
+            },
+            // Subscribers don't send anything else, so this is the client going away.
+            _ = rpc.receive_message() => return,
+        };
+        if let Err(e) = rpc
+            .send_message(RestartMessage::Response(RestartResponse::Event(event)))
+            .await
+        {
+            diagnostics::debug!("Failed to send event to restart coordinator: {}", e);
+            return;
+        }
+    }
+}
+
+/// Tell a coordination socket client whether the new process exited within `period`.
+async fn report_if_exited(
+    mut rpc: RestartCoordinationSocket,
+    monitor: ChildMonitor,
+    period: Duration,
+) {
+    let response = match monitor.exited_within(period).await {
+        Some(exited) => {
+            diagnostics::error!(pid = exited.pid; "Restarted process exited: {}", exited);
+            RestartResponse::ProcessExited(exited)
+        }
+        None => RestartResponse::ProcessSurvived,
+    };
+    if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+        diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
+    }
+}
+
+/// Respond to a coordination socket client once the given restart completes.
+async fn respond_when_restarted(
+    mut rpc: RestartCoordinationSocket,
+    state: SharedRestartState,
+    restart_id: Option<RestartId>,
+) {
+    let restart_id = match restart_id {
+        Some(id) => id,
+        None => {
+            let response = RestartResponse::NoRestartInProgress;
+            if let Err(e) = rpc.send_message(RestartMessage::Response(response)).await {
+                diagnostics::warn!("Failed to respond to restart coordinator: {}", e);
+            }
+            return;
+        }
+    };
+
+    let mut state = state.subscribe();
+    // Waiting clients expect `RestartStarted`, like clients that send restart options.
+    let mut responder = RestartResponder {
+        rpc: Some(rpc),
+        options: Some(RestartOptions::default()),
+        fds: Vec::new(),
+        rollback: false,
+    };
+    responder.started(&restart_id).await;
+
+    let completed = restart_state::wait_for_completion(&mut state, &restart_id)
+        .await
+        .unwrap_or_else(|| CompletedRestart {
+            restart_id,
+            result: Err("restart task exited".into()),
+            cancelled: false,
+            startup_failure: None,
+            preflight_refused: None,
+            incompatible_binary: None,
+            previous_generation: None,
+        });
+    responder.respond(&completed).await;
 }
 
 /// Clears the FD_CLOEXEC flag on a fd so it can be inherited by a child process.
@@ -410,13 +2136,63 @@ fn clear_cloexec(fd: RawFd) -> nix::Result<()> {
     Ok(())
 }
 
-/// Attempt to start a new instance of this proxy.
+/// Attempt to start a new instance of this proxy. `quiesced` is set once `quiesce_writes` is
+/// called.
 async fn spawn_child(
     restart_fd: Option<BorrowedFd<'_>>,
-    user_envs: &[(OsString, OsString)],
+    request: SpawnRequest,
+    options: &ChildOptions,
     lifecycle_handler: &mut dyn LifecycleHandler,
-) -> io::Result<process::Child> {
+    state: &SharedRestartState,
+    output_tx: &Sender<ChildOutput>,
+    quiesced: &mut bool,
+) -> Result<process::Child, ChildSpawnError> {
+    let SpawnRequest {
+        restart_id,
+        await_commit,
+        stateless,
+        fds,
+    } = request;
+    let restart_id = &restart_id;
+    // Refuse before anything is set up if the new binary would lack capabilities it needs.
+    #[cfg(target_os = "linux")]
+    options
+        .capabilities
+        .prepare(&env::args_os().next().unwrap(), &options.environment)?;
+    if let Some(handshake) = &options.handshake {
+        let program = env::args_os().next().unwrap();
+        handshake::probe(&program, &options.environment, handshake).await?;
+    }
+    lifecycle_handler.restart_started(restart_id).await;
     lifecycle_handler.pre_new_process().await;
+    *quiesced = true;
+    lifecycle_handler.quiesce_writes().await;
+    let mut inherited_fds = options.inherited_fds.clone();
+    inherited_fds.extend(lifecycle_handler.fds_for_new_process().await);
+    // The rest are copies made for the new process, which are closed once it has been spawned.
+    let application_fds = inherited_fds.clone();
+    // These copies must stay open until the new process is spawned.
+    let listeners = listeners::for_new_process()?;
+    let mut files = files::for_new_process()?;
+    for (name, fd) in fds {
+        diagnostics::info!(
+            "Passing fd {} from the restart requester to the new process",
+            name
+        );
+        files.insert(name, fd);
+    }
+    let mut manifest = FdManifest::default();
+    for fd in &inherited_fds {
+        manifest.push(FdKind::Inherited, None, *fd);
+    }
+    for (name, fd) in files.entries() {
+        manifest.push(FdKind::File, Some(name), fd);
+    }
+    #[cfg(target_os = "linux")]
+    manifest.describe_packet_sockets();
+    inherited_fds.extend(files.fds());
+    let lineage = lineage::for_new_process()?;
+    inherited_fds.push(lineage.fd());
 
     let mut args = env::args();
     let process_name = args.next().unwrap();
@@ -426,56 +2202,477 @@ async fn spawn_child(
 
     // And another pair of pipes to hand over data to the child process.
     let (handover_r, handover_w) = create_paired_pipes(PipeMode::ParentWrites)?;
+    if let Some(size) = options.handover_pipe_size {
+        if let Err(e) = set_pipe_size(&handover_w, size) {
+            diagnostics::warn!("Failed to set handover pipe size to {}: {}", size, e);
+        }
+    }
 
     let mut cmd = process::Command::new(process_name);
     cmd.args(args)
-        .envs(user_envs.iter().map(|(k, v)| (k, v)))
+        .envs(options.environment.iter().map(|(k, v)| (k, v)))
         .env(ENV_SYSTEMD_PID, REBIND_SYSTEMD_PID)
         .env(ENV_HANDOVER_PIPE, handover_r.fd_string())
+        .env(ENV_RESTART_ID, restart_id.as_str())
+        .env(ENV_GENERATION, (generation() + 1).to_string())
+        .env(ENV_PREVIOUS_PID, process::id().to_string())
+        .envs(lineage.env())
         .env(ENV_NOTIFY_SOCKET, notif_w.0.fd_string());
+    match START_TIME.get().copied().flatten() {
+        Some(start_time) => cmd.env(ENV_PREVIOUS_START_TIME, start_time.to_string()),
+        None => cmd.env_remove(ENV_PREVIOUS_START_TIME),
+    };
+    match stateless {
+        true => cmd.env(ENV_STATELESS_RESTART, "1"),
+        false => cmd.env_remove(ENV_STATELESS_RESTART),
+    };
+    match options.accept_handoff {
+        Some(_) => cmd.env(cutover::ENV_ACCEPT_HANDOFF, "1"),
+        None => cmd.env_remove(cutover::ENV_ACCEPT_HANDOFF),
+    };
 
-    if let Some(fd) = restart_fd {
+    // Fds that the child inherits under the same numbers.
+    let mut keep_open = Vec::new();
+    // Pass a copy of the restart coordination socket, as the original is kept for later restarts.
+    let restart_fd = restart_fd.map(|fd| fd.try_clone_to_owned()).transpose()?;
+    if let Some(fd) = &restart_fd {
         // Let the child inherit the restart coordination socket
         let fd = fd.as_raw_fd();
-        unsafe {
-            cmd.env(ENV_RESTART_SOCKET, fd.to_string())
-                .pre_exec(move || {
-                    clear_cloexec(fd)?;
+        cmd.env(ENV_RESTART_SOCKET, fd.to_string());
+        keep_open.push(fd);
+    }
+
+    #[cfg(target_os = "linux")]
+    let parking = match options.park_connections {
+        true => Some(pipes::seqpacket_pair()?),
+        false => None,
+    };
+    #[cfg(target_os = "linux")]
+    match &parking {
+        Some((_, theirs)) => {
+            cmd.env(parking::ENV_PARKING_SOCKET, theirs.as_raw_fd().to_string());
+            keep_open.push(theirs.as_raw_fd());
+        }
+        None => {
+            cmd.env_remove(parking::ENV_PARKING_SOCKET);
+        }
+    }
+    #[cfg(target_os = "linux")]
+    let deferred = listeners::deferred::for_new_process()?;
+    #[cfg(target_os = "linux")]
+    match &deferred {
+        Some(deferred) => {
+            cmd.env(listeners::deferred::ENV_DEFERRED_LISTENERS, deferred.env());
+            keep_open.push(deferred.fd());
+            for name in deferred.names() {
+                manifest.push(FdKind::DeferredListener, Some(name), deferred.fd());
+            }
+        }
+        None => {
+            cmd.env_remove(listeners::deferred::ENV_DEFERRED_LISTENERS);
+        }
+    }
+
+    let saved_stdio = match options.relay_output {
+        true => Some(SavedStdio::new()?),
+        false => None,
+    };
+    if let Some(saved) = &saved_stdio {
+        saved.configure(&mut cmd);
+        keep_open.extend(saved.fds());
+    }
+
+    let mut listen_fds = None;
+    if options.emit_listen_fds && !listeners.is_empty() {
+        let mut kept = inherited_fds.clone();
+        kept.extend([handover_r.as_raw_fd(), notif_w.0.as_raw_fd()]);
+        kept.extend(restart_fd.as_ref().map(|fd| fd.as_raw_fd()));
+        kept.extend(saved_stdio.iter().flat_map(SavedStdio::fds));
+        #[cfg(target_os = "linux")]
+        kept.extend(parking.iter().map(|(_, theirs)| theirs.as_raw_fd()));
+        #[cfg(target_os = "linux")]
+        kept.extend(deferred.iter().map(|deferred| deferred.fd()));
+        listen_fds = listeners.listen_fds(&kept);
+    }
+    match files.is_empty() {
+        true => cmd.env_remove(files::ENV_FILES),
+        false => cmd.env(files::ENV_FILES, files.env()),
+    };
+    match reaper::for_new_process() {
+        Some(children) => cmd.env(reaper::ENV_CHILDREN, children),
+        None => cmd.env_remove(reaper::ENV_CHILDREN),
+    };
+    for (name, fd) in listeners.child_fds(listen_fds.as_ref()) {
+        manifest.push(FdKind::Listener, Some(name), fd);
+    }
+    let manifest = options.send_fd_manifest.then_some(manifest);
+    match manifest {
+        Some(_) => cmd.env(fds::ENV_FD_MANIFEST, "1"),
+        None => cmd.env_remove(fds::ENV_FD_MANIFEST),
+    };
+    match (&listen_fds, listeners.is_empty()) {
+        (_, true) => cmd.env_remove(listeners::ENV_LISTENERS),
+        (Some(listen_fds), false) => cmd
+            .env(listeners::ENV_LISTENERS, listeners.env(Some(listen_fds)))
+            .env(listeners::ENV_LISTEN_FDS, listen_fds.count().to_string())
+            .env(listeners::ENV_LISTEN_FDNAMES, listeners.listen_fdnames()),
+        (None, false) => {
+            inherited_fds.extend(listeners.fds());
+            cmd.env(listeners::ENV_LISTENERS, listeners.env(None))
+        }
+    };
+
+    let mut allowed_fds = inherited_fds.clone();
+    allowed_fds.extend(saved_stdio.iter().flat_map(SavedStdio::fds));
+    keep_open.extend(&inherited_fds);
+
+    let mut close_leaked = Vec::new();
+    if options.fd_leak_policy != FdLeakPolicy::Ignore {
+        let mut allowed = vec![handover_r.as_raw_fd(), notif_w.0.as_raw_fd()];
+        allowed.extend(restart_fd.as_ref().map(|fd| fd.as_raw_fd()));
+        allowed.extend(&allowed_fds);
+        #[cfg(target_os = "linux")]
+        allowed.extend(parking.iter().map(|(_, theirs)| theirs.as_raw_fd()));
+        #[cfg(target_os = "linux")]
+        allowed.extend(deferred.iter().map(|deferred| deferred.fd()));
+
+        let leaked = fds::find_leaked_fds(&allowed)?;
+        for l in &leaked {
+            diagnostics::warn!(
+                "File descriptor {} ({}) is not registered for inheritance{}",
+                l.fd,
+                l.target
+                    .as_ref()
+                    .map_or("unknown".into(), |t| t.display().to_string()),
+                match options.fd_leak_policy {
+                    FdLeakPolicy::Close => ", closing it in the new process",
+                    _ => " but will be inherited by the new process",
+                }
+            );
+        }
+
+        if options.fd_leak_policy == FdLeakPolicy::Close {
+            close_leaked = leaked.iter().map(|l| l.fd).collect();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    let isolated = !options.isolation.is_empty()
+        || !options.scheduling.is_empty()
+        || options.security_context.is_some()
+        || options.capabilities.changes_ambient();
+    #[cfg(not(target_os = "linux"))]
+    let isolated = false;
+    let spawn_method = match options.spawn_method {
+        SpawnMethod::PosixSpawn if listen_fds.is_some() || !close_leaked.is_empty() || isolated => {
+            diagnostics::warn!(
+                "Emitting LISTEN_FDS, closing leaked fds, isolation, scheduling, security \
+                 contexts and ambient capabilities need code to run in the new process, forking \
+                 instead of using posix_spawn"
+            );
+            SpawnMethod::Fork
+        }
+        SpawnMethod::PosixSpawn if fds::any_cloexec(&application_fds)? => {
+            diagnostics::warn!(
+                "Inherited fds of the application are close-on-exec, which only the new process \
+                 may clear, forking instead of using posix_spawn"
+            );
+            SpawnMethod::Fork
+        }
+        method => method,
+    };
+    #[cfg(target_os = "linux")]
+    let scheduling = match options.scheduling.is_empty() {
+        true => None,
+        false => Some(options.scheduling.resolve()?),
+    };
+    #[cfg(target_os = "linux")]
+    let exec_attr = options.security_context.as_ref().map(|c| c.exec_attr());
+
+    if state.cancel_requested() {
+        return Err(restart_cancelled().into());
+    }
+    let mut child = match spawn_method {
+        SpawnMethod::Fork => {
+            #[cfg(target_os = "linux")]
+            let _cgroup_procs = options.isolation.configure(&mut cmd)?;
+            unsafe {
+                cmd.pre_exec(move || {
+                    for fd in &keep_open {
+                        clear_cloexec(*fd)?;
+                    }
+                    for fd in &close_leaked {
+                        libc::close(*fd);
+                    }
                     Ok(())
                 });
+            }
+            if let Some(mut listen_fds) = listen_fds {
+                // This must come after closing leaked fds, which may be in the way.
+                unsafe { cmd.pre_exec(move || listen_fds.apply()) };
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(scheduling) = scheduling {
+                unsafe { cmd.pre_exec(move || scheduling.apply()) };
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(exec_attr) = exec_attr {
+                unsafe { cmd.pre_exec(move || exec_attr.apply()) };
+            }
+            #[cfg(target_os = "linux")]
+            if options.capabilities.changes_ambient() {
+                let capabilities = options.capabilities.clone();
+                unsafe { cmd.pre_exec(move || capabilities.apply_ambient()) };
+            }
+            cmd.spawn()?
+        }
+        SpawnMethod::PosixSpawn => {
+            // Without code to run in the new process, std can use posix_spawn. The copies made for
+            // the new process are made inheritable here instead, and closed once it has been
+            // spawned. The fds of the application are already inheritable.
+            let _inheritable = fds::Inheritable::new(&keep_open)?;
+            cmd.spawn()?
         }
+    };
+    let _kill_on_panic = KillOnPanic(child.id());
+    drop(restart_fd);
+    drop(listeners);
+    drop(files);
+    #[cfg(target_os = "linux")]
+    if let Some((ours, _)) = parking {
+        parking::new_process_spawned(ours);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(deferred) = deferred {
+        deferred.spawned();
+    }
+    let pid = child.id();
+    let identity = tokio::task::spawn_blocking(move || ChildIdentity::of(pid))
+        .await
+        .expect("reading the identity of the new process panicked");
+    let pidfd = PidFd::open(child.id()).ok().map(Arc::new);
+    if !state.child_spawned(&identity, pidfd.clone()) {
+        let _ = child.kill();
+        return Err(restart_cancelled().into());
     }
-    let mut child = cmd.spawn()?;
+    lifecycle_handler
+        .new_process_spawned(&identity, pidfd.as_deref())
+        .await;
+    let relayed = saved_stdio
+        .is_some()
+        .then(|| relay::relay_output(&mut child, restart_id, output_tx));
+    // Only the child needs the copies of our stdio.
+    drop(saved_stdio);
 
-    if let Err(e) = send_parent_state(lifecycle_handler, notif_r, notif_w, handover_w).await {
+    let (handover_w, unflushed) = PipeWriter::new(handover_w, options.handover_buffer_size);
+    let res = async {
+        send_parent_state(
+            lifecycle_handler,
+            notif_w,
+            handover_w,
+            unflushed,
+            manifest,
+            stateless,
+            state,
+        )
+        .await?;
+        await_readiness(
+            lifecycle_handler,
+            restart_id,
+            notif_r,
+            await_commit,
+            options,
+            state,
+        )
+        .await
+    };
+    if let Err(e) = res.await {
+        if !state.cancel_requested() {
+            if let Some(status) = exited_status(&mut child).await {
+                let failure = StartupFailed {
+                    exit_code: status.code(),
+                    signal: status.signal(),
+                    stderr_tail: match relayed {
+                        Some(relayed) => relayed.stderr_tail().await,
+                        None => vec![],
+                    },
+                };
+                diagnostics::error!("Failed to send parent state: {e:?}; {failure}");
+                return Err(ChildSpawnError::StartupFailed(failure));
+            }
+        }
         if child.kill().is_err() {
-            log::error!("Child process has already exited. Failed to send parent state: {e:?}");
+            diagnostics::error!(
+                "Child process has already exited. Failed to send parent state: {e:?}"
+            );
         } else {
-            log::error!("Killed child process because failed to send parent state: {e:?}");
+            diagnostics::error!("Killed child process because failed to send parent state: {e:?}");
         }
         return Err(e);
     }
 
+    lineage.spawned();
     Ok(child)
 }
 
 async fn send_parent_state(
     lifecycle_handler: &mut dyn LifecycleHandler,
-    mut notif_r: CompletionReceiver,
     notif_w: CompletionSender,
-    handover_w: StdFile,
-) -> io::Result<()> {
-    lifecycle_handler
-        .send_to_new_process(Box::pin(File::from(handover_w)))
-        .await?;
+    mut handover_w: PipeWriter,
+    mut unflushed: oneshot::Receiver<BufWriter<File>>,
+    manifest: Option<FdManifest>,
+    stateless: bool,
+    state: &SharedRestartState,
+) -> Result<(), ChildSpawnError> {
+    state.set_phase(RestartPhase::HandingOver);
+    if let Some(manifest) = manifest {
+        handover_w
+            .write_all(&manifest.encode())
+            .await
+            .map_err(ChildSpawnError::HandoverError)?;
+    }
+    if stateless {
+        diagnostics::debug!("Stateless restart, not sending state to the new process");
+        drop(handover_w);
+    } else {
+        lifecycle_handler
+            .send_to_new_process(handover_w)
+            .await
+            .map_err(ChildSpawnError::HandoverError)?;
+    }
+    // Write out whatever is left in the buffer, unless the handler is still holding on to the pipe.
+    if let Ok(mut unflushed) = unflushed.try_recv() {
+        unflushed
+            .flush()
+            .await
+            .map_err(ChildSpawnError::HandoverError)?;
+    }
 
     // only the child needs the write end
     drop(notif_w);
-    match notif_r.recv() {
-        Ok(_) => Ok(()),
+    Ok(())
+}
+
+/// Wait for the new process to signal readiness, and then for the restart to be committed if it
+/// awaits a commit.
+async fn await_readiness(
+    lifecycle_handler: &mut dyn LifecycleHandler,
+    restart_id: &RestartId,
+    mut notif_r: CompletionReceiver,
+    await_commit: Option<Duration>,
+    options: &ChildOptions,
+    state: &SharedRestartState,
+) -> Result<(), ChildSpawnError> {
+    state.set_phase(RestartPhase::AwaitingReadiness);
+    let ready = notif_r.recv();
+    if ready.is_ok() {
+        cutover::new_process_ready(
+            restart_id,
+            options.cutover_ramp,
+            options.accept_handoff,
+            options.watch_backlog,
+        );
+        if options.accept_handoff.is_some() {
+            // The new process reports that it is accepting on the same pipe.
+            let restart_id = restart_id.clone();
+            thread::spawn(move || {
+                if notif_r.recv().is_err() {
+                    diagnostics::debug!("New process closed the pipe without reporting accepting");
+                }
+                cutover::new_process_accepting(&restart_id);
+            });
+        }
+    }
+    if let (Ok(_), Some(timeout)) = (&ready, await_commit) {
+        // Both processes serve until the restart is committed or cancelled.
+        state.set_phase(RestartPhase::AwaitingCommit);
+        diagnostics::info!(
+            "New process is ready, waiting {:?} for the restart to be committed",
+            timeout
+        );
+        if tokio::time::timeout(timeout, state.wait_for_commit())
+            .await
+            .is_err()
+        {
+            lifecycle_handler.new_process_failed().await;
+            return Err(ChildSpawnError::NotCommitted(timeout));
+        }
+    }
+    match ready {
+        Ok(_) if state.commit() => Ok(()),
+        Ok(_) => {
+            lifecycle_handler.new_process_failed().await;
+            Err(restart_cancelled().into())
+        }
         Err(e) => {
             lifecycle_handler.new_process_failed().await;
-            Err(e)
+            Err(e.into())
+        }
+    }
+}
+
+/// Kills and reaps the new process if the restart thread panics before the restart completes,
+/// so that the new process isn't left running without state or anyone waiting for it.
+struct KillOnPanic(u32);
+
+impl Drop for KillOnPanic {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let pid = self.0 as libc::pid_t;
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+                libc::waitpid(pid, std::ptr::null_mut(), 0);
+            }
         }
     }
 }
+
+/// Returns the exit status of the new process if it exited by itself. When the new process dies,
+/// closing its end of the notification pipe may be seen slightly before it can be reaped.
+async fn exited_status(child: &mut process::Child) -> Option<process::ExitStatus> {
+    for _ in 0..10 {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Some(status);
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    None
+}
+
+fn restart_cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "restart cancelled")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_generation_running() {
+        let pid = process::id();
+        let start_time = restart_coordination_socket::start_time(pid).unwrap();
+        assert!(generation_running(pid, Some(start_time)));
+        // Another process that reused the pid.
+        assert!(!generation_running(pid, Some(start_time + 1)));
+
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let child_start_time = restart_coordination_socket::start_time(child.id());
+        child.wait().unwrap();
+        assert!(!generation_running(child.id(), child_start_time));
+        // Without a start time, only the parent counts as the previous generation.
+        assert!(!generation_running(child.id(), None));
+        assert!(generation_running(
+            std::os::unix::process::parent_id(),
+            None
+        ));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_process_uptime() {
+        // This process started just before the test.
+        assert!(process_uptime().unwrap() < Duration::from_secs(60));
+        assert!(started().elapsed() < Duration::from_secs(60));
+    }
+}
@@ -1,27 +1,60 @@
-use super::ENV_HANDOVER_PIPE;
+use super::{ENV_HANDOVER_PIPE, ENV_STATELESS_RESTART};
+use crate::monitor::PidFd;
 use crate::pipes::FdStringExt;
+pub use crate::pipes::PipeWriter;
+use crate::restart_coordination_socket::ChildIdentity;
+use crate::RestartId;
 use async_trait::async_trait;
 use std::env;
 use std::io;
+use std::os::fd::RawFd;
 use std::pin::Pin;
 use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::AsyncRead;
 
 pub type PipeReader = Pin<Box<dyn AsyncRead + Send>>;
-pub type PipeWriter = Pin<Box<dyn AsyncWrite + Send>>;
 
 #[async_trait]
 pub trait LifecycleHandler: Send {
     /// Called after the child process has been spawned, allowing the current process to send state
     /// to the child process. The child process can receive this data by calling
     /// `receive_from_old_process`.
+    ///
+    /// Writes to the pipe are buffered, see `RestartConfig::handover_buffer_size`. Anything still
+    /// buffered when the pipe is dropped is written out after this returns, but flushing the pipe
+    /// sooner lets the child process start reading while more state is being prepared.
     async fn send_to_new_process(&mut self, _write_pipe: PipeWriter) -> io::Result<()> {
         Ok(())
     }
 
+    /// Called when a restart begins, before `pre_new_process`. The restart ID is also available to
+    /// the child process by calling `restart_id`, and is returned to the restart requester.
+    async fn restart_started(&mut self, _restart_id: &RestartId) {}
+
     /// Called before the child process has been spawned.
     async fn pre_new_process(&mut self) {}
 
+    /// Called after `pre_new_process`, before the child process is spawned. Stop writing to files
+    /// passed to the child process, such as database files opened with `files::open_file`, so
+    /// that it can take them over.
+    async fn quiesce_writes(&mut self) {}
+
+    /// Called if the restart fails after `quiesce_writes`, once the child process is gone, so that
+    /// this process can resume writing.
+    async fn resume_writes(&mut self) {}
+
+    /// Called after `quiesce_writes`. The returned fds are inherited by the child process under
+    /// the same numbers, in addition to `RestartConfig::inherited_fds`. They must stay open until
+    /// `send_to_new_process` is called, which is a good place to tell the child about them.
+    async fn fds_for_new_process(&mut self) -> Vec<RawFd> {
+        Vec::new()
+    }
+
+    /// Called once the child process has been spawned, before `send_to_new_process`, so that it
+    /// can be e.g. placed into monitoring before it serves. `pidfd` refers to the child process
+    /// where pidfds are supported. Duplicate it to keep it beyond the call.
+    async fn new_process_spawned(&mut self, _child: &ChildIdentity, _pidfd: Option<&PidFd>) {}
+
     /// Called after `send_to_new_process` if the child process fails to start successfully.
     /// This gives you an opportunity to undo any state changes made in `send_to_new_process`.
     async fn new_process_failed(&mut self) {}
@@ -33,17 +66,45 @@ pub struct NullLifecycleHandler;
 impl LifecycleHandler for NullLifecycleHandler {}
 
 /// If this process has been spawned due to graceful restart, returns a `PipeReader` used to receive
-/// data from the parent process's implementation of `LifecycleHandler::send_to_new_process`.
+/// data from the parent process's implementation of `LifecycleHandler::send_to_new_process`. If the
+/// parent sent an fd manifest, it is read first and available from `fds::fd_manifest`. Returns
+/// `None` if the restart was requested with `RestartOptions::stateless`.
 ///
 /// The behaviour of this function is undefined if the environment variables used by this crate to
 /// pass file descriptor numbers were set by something other than shellflip spawning a new instance
 /// of the calling process.
 pub fn receive_from_old_process() -> Option<PipeReader> {
-    if let Ok(handover_fd) = env::var(ENV_HANDOVER_PIPE) {
-        unsafe { File::from_fd_string(&handover_fd) }
-            .ok()
-            .map(|x| Box::pin(x) as PipeReader)
-    } else {
-        None
+    // The fd manifest, if any, comes before the state.
+    crate::fds::fd_manifest();
+    let stateless = env::var_os(ENV_STATELESS_RESTART).is_some();
+    unsafe { handover_pipe(stateless, env::var(ENV_HANDOVER_PIPE).ok()) }
+}
+
+/// The handover pipe passed by the old process as `handover_fd`, unless the restart is stateless.
+///
+/// # Safety
+///
+/// `handover_fd` must be the number of an open fd that nothing else owns.
+unsafe fn handover_pipe(stateless: bool, handover_fd: Option<String>) -> Option<PipeReader> {
+    if stateless {
+        return None;
+    }
+    File::from_fd_string(&handover_fd?)
+        .ok()
+        .map(|x| Box::pin(x) as PipeReader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::IntoRawFd;
+
+    #[test]
+    fn test_handover_pipe() {
+        let fd = std::fs::File::open("/dev/null").unwrap().into_raw_fd();
+        // A stateless restart leaves the pipe alone.
+        assert!(unsafe { handover_pipe(true, Some(fd.to_string())) }.is_none());
+        assert!(unsafe { handover_pipe(false, Some(fd.to_string())) }.is_some());
+        assert!(unsafe { handover_pipe(false, None) }.is_none());
     }
 }
@@ -0,0 +1,241 @@
+//! The earlier generations of processes that led to this one, and whether they are still running.
+//!
+//! Each restart passes the list of earlier generations on to the new process, so that
+//! `StatusReport::ancestry` shows e.g. that generation 3 serves while generation 2 still drains 4
+//! handles. The old process reports the number of handles it has open while it drains, from
+//! `RestartConfig::drain_stats`, on a pipe to the new process. Only the previous generation
+//! reports its drain progress; older ones are only known to be running or not.
+use crate::diagnostics;
+use crate::pipes::{create_paired_pipes, set_cloexec, PipeMode};
+use crate::shutdown::DrainStats;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::process;
+use std::sync::{Mutex, Once, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const ENV_ANCESTRY: &str = "OXY_ANCESTRY";
+const ENV_DRAIN_REPORT: &str = "OXY_DRAIN_REPORT";
+/// The number of generations passed on, so that the environment doesn't grow without bound.
+const MAX_ANCESTORS: usize = 16;
+/// How often the old process reports its drain progress to the new process.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When this process started, in seconds since the Unix epoch and in clock ticks since boot.
+static STARTED: OnceLock<(u64, Option<u64>)> = OnceLock::new();
+/// The number of handles the previous generation last reported.
+static PREVIOUS_ACTIVE_HANDLES: Mutex<Option<usize>> = Mutex::new(None);
+/// The pipe to report drain progress to the most recently spawned new process.
+static REPORT_PIPE: Mutex<Option<File>> = Mutex::new(None);
+static WATCH: Once = Once::new();
+
+/// An earlier generation of this process.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ancestor {
+    pub generation: u32,
+    pub pid: u32,
+    /// When it created its restart task, in seconds since the Unix epoch.
+    pub started_at: u64,
+    pub running: bool,
+    /// The number of handles it has open while it drains, if it is the previous generation and
+    /// reports them.
+    #[serde(default)]
+    pub active_handles: Option<usize>,
+}
+
+/// An earlier generation, as passed on to the new process.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Record {
+    generation: u32,
+    pid: u32,
+    started_at: u64,
+    /// Tells the process apart from a later one that reuses its pid.
+    start_ticks: Option<u64>,
+}
+
+/// The earlier generations that led to this process, oldest first.
+pub fn ancestry() -> Vec<Ancestor> {
+    let records = records();
+    let previous = records.len().checked_sub(1);
+    records
+        .into_iter()
+        .enumerate()
+        .map(|(i, record)| {
+            let running = is_running(&record);
+            Ancestor {
+                generation: record.generation,
+                pid: record.pid,
+                started_at: record.started_at,
+                running,
+                active_handles: match Some(i) == previous && running {
+                    true => *PREVIOUS_ACTIVE_HANDLES.lock().unwrap(),
+                    false => None,
+                },
+            }
+        })
+        .collect()
+}
+
+/// Record when this process started, and follow the drain progress of the previous generation.
+pub(crate) fn started() {
+    STARTED.get_or_init(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        (now.as_secs(), start_ticks(process::id()))
+    });
+    WATCH.call_once(|| {
+        let Some(fd) = env::var(ENV_DRAIN_REPORT)
+            .ok()
+            .and_then(|fd| fd.parse().ok())
+        else {
+            return;
+        };
+        if let Err(e) = watch_reports(fd) {
+            diagnostics::warn!(
+                "Failed to follow the drain progress of the old process: {}",
+                e
+            );
+        }
+    });
+}
+
+/// The environment and the drain report pipe for a new process.
+pub(crate) struct ForNewProcess {
+    reader: File,
+    writer: File,
+}
+
+pub(crate) fn for_new_process() -> io::Result<ForNewProcess> {
+    let (reader, writer) = create_paired_pipes(PipeMode::ParentWrites)?;
+    Ok(ForNewProcess { reader, writer })
+}
+
+impl ForNewProcess {
+    /// The fd the new process inherits.
+    pub(crate) fn fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+
+    pub(crate) fn env(&self) -> [(&'static str, String); 2] {
+        let mut records = records();
+        let (started_at, start_ticks) = *STARTED.get_or_init(|| (0, None));
+        records.push(Record {
+            generation: crate::generation(),
+            pid: process::id(),
+            started_at,
+            start_ticks,
+        });
+        let skip = records.len().saturating_sub(MAX_ANCESTORS);
+        [
+            (
+                ENV_ANCESTRY,
+                serde_json::to_string(&records[skip..]).unwrap(),
+            ),
+            (ENV_DRAIN_REPORT, self.fd().to_string()),
+        ]
+    }
+
+    /// Keep the pipe to report drain progress to the new process, once it was spawned.
+    pub(crate) fn spawned(self) {
+        *REPORT_PIPE.lock().unwrap() = Some(self.writer);
+    }
+}
+
+/// Report drain progress to the new process until all handles are closed. Without drain stats, the
+/// new process only learns when this process exits.
+pub(crate) fn handed_over(drain_stats: Option<DrainStats>) {
+    let Some(mut pipe) = REPORT_PIPE.lock().unwrap().take() else {
+        return;
+    };
+    let Some(drain_stats) = drain_stats else {
+        return;
+    };
+    thread::spawn(move || loop {
+        let active = drain_stats.active_handles();
+        if writeln!(pipe, "{active}").is_err() || active == 0 {
+            break;
+        }
+        thread::sleep(REPORT_INTERVAL);
+    });
+}
+
+fn records() -> Vec<Record> {
+    env::var(ENV_ANCESTRY)
+        .ok()
+        .and_then(|records| serde_json::from_str(&records).ok())
+        .unwrap_or_default()
+}
+
+fn watch_reports(fd: RawFd) -> io::Result<()> {
+    // Make sure the fd is the pipe from the old process, rather than one opened since.
+    let stat = nix::sys::stat::fstat(fd)?;
+    if stat.st_mode & libc::S_IFMT != libc::S_IFIFO {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a pipe"));
+    }
+    set_cloexec(fd)?;
+    let pipe = unsafe { File::from_raw_fd(fd) };
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).lines() {
+            match line.ok().and_then(|l| l.parse().ok()) {
+                Some(active) => *PREVIOUS_ACTIVE_HANDLES.lock().unwrap() = Some(active),
+                None => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+fn is_running(record: &Record) -> bool {
+    match record.start_ticks {
+        Some(ticks) => start_ticks(record.pid) == Some(ticks),
+        None => {
+            let pid = nix::unistd::Pid::from_raw(record.pid as i32);
+            !matches!(nix::sys::signal::kill(pid, None), Err(nix::Error::ESRCH))
+        }
+    }
+}
+
+/// When the process started, in clock ticks since boot.
+fn start_ticks(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    parse_start_ticks(&stat)
+}
+
+fn parse_start_ticks(stat: &str) -> Option<u64> {
+    // The command name may contain spaces and parentheses; the fields after it don't. The start
+    // time is field 22, the 20th after the command name.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_running() {
+        let stat = "42 (a (weird) name) S 1 42 42 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 \
+                    12345 1000 10 18446744073709551615";
+        assert_eq!(parse_start_ticks(stat), Some(12345));
+
+        let this = Record {
+            generation: 0,
+            pid: process::id(),
+            started_at: 0,
+            start_ticks: start_ticks(process::id()),
+        };
+        assert!(this.start_ticks.is_some());
+        assert!(is_running(&this));
+        // A different process with the same pid.
+        let reused = Record {
+            start_ticks: this.start_ticks.map(|t| t + 1),
+            ..this
+        };
+        assert!(!is_running(&reused));
+    }
+}
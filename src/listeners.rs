@@ -0,0 +1,1115 @@
+//! Listening sockets that are passed to the new process by name.
+//!
+//! Without this, every application ends up writing the same code: check whether a listener was
+//! inherited from the old process, bind a new one otherwise, and remember to pass it on at the next
+//! restart. `listener_or_bind` does all three. A copy of each listener it returns is kept, and is
+//! inherited by the new process along with its name, so the new process picks it up by calling
+//! `listener_or_bind` with the same name. Listeners it inherited but did not pick up by the time it
+//! reports that it started, with `startup_complete`, are closed with a warning.
+//!
+//! ```no_run
+//! # fn main() -> std::io::Result<()> {
+//! let listener = shellflip::listeners::listener_or_bind("http", "0.0.0.0:8080")?;
+//! listener.set_nonblocking(true)?;
+//! # let _rt = tokio::runtime::Runtime::new()?;
+//! # let _guard = _rt.enter();
+//! let listener = tokio::net::TcpListener::from_std(listener)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Services that accept the same traffic on several addresses, e.g. IPv4, IPv6 and a unix socket,
+//! can pass them as a single group with `listener_group`.
+//!
+//! Names may not contain `,`, `=` or `:`.
+//!
+//! Sockets passed with the `LISTEN_FDS` convention of systemd socket activation, or of tools such
+//! as `systemfd` during development, are picked up as well. Those named in `LISTEN_FDNAMES` join
+//! the group of that name, and unnamed ones are claimed by the first call that asks for the address
+//! they are bound to. Set `RestartConfig::emit_listen_fds` to pass the listeners to the new process
+//! in the same way, for processes that use crates such as `listenfd` to pick them up.
+//!
+//! # Unix sockets
+//!
+//! A unix listener is passed to the new process like any other, so it keeps accepting on the same
+//! socket file and clients, e.g. a sidecar proxy in front of a gRPC service, never see the path
+//! disappear. This only works if the old process leaves the socket file alone as it shuts down:
+//! call `remove_unix_sockets` instead of removing it, which only does so if no new process took
+//! the listeners over and the file was not replaced since.
+//!
+//! To give the new process a socket of its own instead, call `replace_unix_listener`. It binds a
+//! new socket next to the path and renames it into place, so clients that reconnect reach the new
+//! process while the old one drains the connections already queued on its socket.
+//!
+//! # Pausing
+//!
+//! A `ListenerGuard`, returned by `guard` for a listener name, lets the application stop taking
+//! new work from the listener without closing it, earlier than and independently of shutting down,
+//! e.g. while a dependency is unavailable or before draining ahead of maintenance. Accept loops
+//! await `ListenerGuard::accept_turn` before each accept, which waits while the guard is paused,
+//! and returns false once it is stopped for good, e.g. when shutdown starts if it was tied to a
+//! `ShutdownSignal` with `ListenerGuard::stop_on`. It also waits for `cutover::accept_turn`, so
+//! accept loops only await one of them:
+//!
+//! ```no_run
+//! # async fn example(
+//! #     coordinator: &shellflip::ShutdownCoordinator,
+//! #     listener: tokio::net::TcpListener,
+//! # ) -> std::io::Result<()> {
+//! let guard = shellflip::listeners::guard("http");
+//! guard.stop_on(shellflip::ShutdownSignal::from(&*coordinator.handle()));
+//! while guard.accept_turn().await {
+//!     let (stream, _) = listener.accept().await?;
+//!     // ...
+//! #   drop(stream);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The listener stays open while paused, so connections queue up in its backlog until it resumes,
+//! or the new process accepts them after a restart.
+//!
+//! # Staged handover
+//!
+//! The `deferred` module lets the old process keep some listener groups past a restart, and hand
+//! them over to the new process later.
+use crate::pipes::set_cloexec;
+use crate::shutdown::ShutdownSignal;
+use crate::{cutover, diagnostics};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem::{self, MaybeUninit};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once, OnceLock};
+use tokio::sync::watch;
+
+#[cfg(target_os = "linux")]
+pub mod deferred;
+
+pub(crate) const ENV_LISTENERS: &str = "OXY_LISTENERS";
+pub(crate) const ENV_LISTEN_FDS: &str = "LISTEN_FDS";
+pub(crate) const ENV_LISTEN_FDNAMES: &str = "LISTEN_FDNAMES";
+/// The first fd passed with `LISTEN_FDS`.
+const LISTEN_FDS_START: RawFd = 3;
+/// The name systemd gives to sockets without a configured name.
+const UNNAMED: &str = "unknown";
+
+/// Listeners inherited from the old process that have not been claimed yet.
+static INHERITED: OnceLock<Mutex<Inherited>> = OnceLock::new();
+/// Copies of the listener groups to pass to the next process.
+static REGISTERED: Mutex<BTreeMap<String, Vec<OwnedFd>>> = Mutex::new(BTreeMap::new());
+/// The socket files of the registered unix listeners, by group.
+static SOCKET_FILES: Mutex<BTreeMap<String, Vec<SocketFile>>> = Mutex::new(BTreeMap::new());
+/// Set once a new process has taken over the registered listeners.
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+/// The guards returned by `guard`, by listener name.
+static GUARDS: Mutex<BTreeMap<String, ListenerGuard>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Default)]
+struct Inherited {
+    groups: BTreeMap<String, Vec<RawFd>>,
+    /// Sockets passed with `LISTEN_FDS` without a name, which are claimed by address.
+    unnamed: Vec<RawFd>,
+}
+
+/// The path of a unix listener, along with the identity of the file found there when the
+/// listener was bound or inherited.
+#[derive(Debug)]
+struct SocketFile {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+impl SocketFile {
+    fn new(path: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        Ok(SocketFile {
+            path: path.to_path_buf(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
+    }
+
+    /// Whether the file at the path is still the one this listener is bound to.
+    fn is_current(&self) -> bool {
+        fs::symlink_metadata(&self.path)
+            .map(|m| m.dev() == self.dev && m.ino() == self.ino)
+            .unwrap_or(false)
+    }
+}
+
+/// An address to listen on as part of a listener group.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+impl From<&Path> for ListenAddr {
+    fn from(path: &Path) -> Self {
+        ListenAddr::Unix(path.into())
+    }
+}
+
+/// Removes the unix socket left behind at `path` by an earlier process, so that it can be bound
+/// again. Anything else at the path is left alone, and binding fails.
+pub(crate) fn remove_stale_socket(path: &Path) {
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// A member of a listener group.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => TcpListener::bind(addr).map(Listener::Tcp),
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path);
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+        }
+    }
+
+    /// Take ownership of an inherited listening socket.
+    unsafe fn from_inherited(fd: RawFd) -> io::Result<Self> {
+        // Don't leak the inherited fd itself into the next process, only the registered copy.
+        set_cloexec(fd)?;
+        Self::from_fd(OwnedFd::from_raw_fd(fd))
+    }
+
+    fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        match socket_family(fd.as_fd())? {
+            libc::AF_UNIX => Ok(Listener::Unix(fd.into())),
+            libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(fd.into())),
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("inherited socket has unsupported address family {family}"),
+            )),
+        }
+    }
+
+    /// The address this listener is bound to.
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(l) => l.local_addr().map(ListenAddr::Tcp),
+            Listener::Unix(l) => {
+                let path = l.local_addr()?.as_pathname().map(Path::to_path_buf);
+                Ok(ListenAddr::Unix(path.unwrap_or_default()))
+            }
+        }
+    }
+
+    /// Whether this listener is bound to `addr`, treating TCP port 0 as any port.
+    fn is_bound_to(&self, addr: &ListenAddr) -> bool {
+        match (self.local_addr(), addr) {
+            (Ok(ListenAddr::Tcp(local)), ListenAddr::Tcp(addr)) => {
+                addr.ip() == local.ip() && (addr.port() == 0 || addr.port() == local.port())
+            }
+            (Ok(ListenAddr::Unix(local)), ListenAddr::Unix(path)) => {
+                local == *path || is_temporary_path(&local, path)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(l) => l.set_nonblocking(nonblocking),
+            Listener::Unix(l) => l.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl AsFd for Listener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Listener::Tcp(l) => l.as_fd(),
+            Listener::Unix(l) => l.as_fd(),
+        }
+    }
+}
+
+/// Returns the TCP listener called `name` inherited from the old process, or binds a new one to
+/// `addr` if there is none. Either way, the listener is passed to the next process under the same
+/// name.
+///
+/// An inherited listener that is not bound to one of the addresses `addr` resolves to is closed
+/// and replaced, so configuration changes take effect across restarts. New listeners are bound
+/// with `SO_REUSEADDR`, so binding succeeds even while connections to an earlier process on the
+/// same address are in `TIME_WAIT`.
+///
+/// The listener can be bound before the tokio runtime is started, which is useful when binding
+/// privileged ports before dropping privileges.
+pub fn listener_or_bind(name: &str, addr: impl ToSocketAddrs) -> io::Result<TcpListener> {
+    let addrs: Vec<ListenAddr> = addr.to_socket_addrs()?.map(ListenAddr::Tcp).collect();
+    let mut inherited = take_inherited(name, &addrs)?;
+
+    let position = inherited
+        .iter()
+        .position(|l| addrs.iter().any(|a| l.is_bound_to(a)));
+    let listener = match position.map(|i| inherited.swap_remove(i)) {
+        Some(Listener::Tcp(listener)) => {
+            log_inherited(name, listener.local_addr());
+            listener
+        }
+        _ => {
+            let addrs: Vec<SocketAddr> = addrs
+                .iter()
+                .filter_map(|a| match a {
+                    ListenAddr::Tcp(a) => Some(*a),
+                    ListenAddr::Unix(_) => None,
+                })
+                .collect();
+            let listener = TcpListener::bind(&addrs[..])?;
+            log_bound(name, listener.local_addr());
+            listener
+        }
+    };
+    close_unclaimed(name, inherited);
+
+    register(name, [listener.as_fd()])?;
+    Ok(listener)
+}
+
+/// Returns one listener for each of `addrs`, in the same order, all of which are passed to the
+/// next process under `name`. Members of the group inherited from the old process are reused if
+/// they are bound to one of `addrs`, the others are bound afresh. Inherited members that are no
+/// longer part of the group are closed.
+pub fn listener_group(name: &str, addrs: &[ListenAddr]) -> io::Result<Vec<Listener>> {
+    let mut inherited = take_inherited(name, addrs)?;
+    let mut listeners = Vec::with_capacity(addrs.len());
+
+    for addr in addrs {
+        let listener = match inherited.iter().position(|l| l.is_bound_to(addr)) {
+            Some(i) => {
+                let listener = inherited.swap_remove(i);
+                log_inherited(name, listener.local_addr());
+                listener
+            }
+            None => {
+                let listener = Listener::bind(addr)?;
+                log_bound(name, listener.local_addr());
+                listener
+            }
+        };
+        listeners.push(listener);
+    }
+    close_unclaimed(name, inherited);
+
+    register(name, listeners.iter().map(AsFd::as_fd))?;
+    track_socket_files(
+        name,
+        addrs.iter().filter_map(|addr| match addr {
+            ListenAddr::Unix(path) => Some(path.as_path()),
+            ListenAddr::Tcp(_) => None,
+        }),
+    );
+    Ok(listeners)
+}
+
+/// Returns the unix listener called `name` inherited from the old process, or binds a new one to
+/// `path` if there is none, like `listener_or_bind` does for TCP.
+pub fn unix_listener_or_bind(name: &str, path: impl AsRef<Path>) -> io::Result<UnixListener> {
+    let addr = ListenAddr::Unix(path.as_ref().to_path_buf());
+    match listener_group(name, &[addr])?.pop() {
+        Some(Listener::Unix(listener)) => Ok(listener),
+        _ => unreachable!("a unix address yields a unix listener"),
+    }
+}
+
+/// Binds a new unix listener called `name` and atomically renames it to `path`, replacing the
+/// socket file there. Inherited members of the group are closed, but the old process keeps its
+/// copies and can drain the connections already queued on them, while clients that connect to
+/// `path` from now on reach this process. There is no moment at which `path` does not exist.
+///
+/// The new socket is first bound to a temporary path in the same directory, which must be short
+/// enough for a unix socket address.
+pub fn replace_unix_listener(name: &str, path: impl AsRef<Path>) -> io::Result<UnixListener> {
+    let path = path.as_ref();
+    let inherited = take_inherited(name, &[ListenAddr::Unix(path.to_path_buf())])?;
+
+    let listener = bind_and_rename(path)?;
+    log_bound(name, listener.local_addr());
+    for listener in inherited {
+        diagnostics::info!(
+            "Closing inherited listener {} on {:?}, as it was replaced",
+            name,
+            listener.local_addr()
+        );
+    }
+
+    register(name, [listener.as_fd()])?;
+    track_socket_files(name, [path]);
+    Ok(listener)
+}
+
+/// The path a socket is bound to before it is renamed to `path`, which remains its address.
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.tmp", process::id()));
+    temporary.into()
+}
+
+/// Whether `local` is the temporary path of a socket that any process renamed to `path`.
+fn is_temporary_path(local: &Path, path: &Path) -> bool {
+    let (Some(local), Some(path)) = (local.to_str(), path.to_str()) else {
+        return false;
+    };
+    local
+        .strip_prefix(path)
+        .and_then(|rest| rest.strip_prefix('.'))
+        .and_then(|rest| rest.strip_suffix(".tmp"))
+        .is_some_and(|pid| pid.parse::<u32>().is_ok())
+}
+
+fn bind_and_rename(path: &Path) -> io::Result<UnixListener> {
+    let temporary = temporary_path(path);
+
+    let _ = fs::remove_file(&temporary);
+    let listener = UnixListener::bind(&temporary)?;
+    if let Err(e) = fs::rename(&temporary, path) {
+        let _ = fs::remove_file(&temporary);
+        return Err(e);
+    }
+    Ok(listener)
+}
+
+/// Removes the socket files of the unix listeners registered by this process, as it shuts down.
+///
+/// Nothing is removed once a restart succeeded, as the new process accepts on the same sockets,
+/// nor are files that were replaced since the listener was bound, e.g. by
+/// `replace_unix_listener` in a new process.
+pub fn remove_unix_sockets() {
+    if HANDED_OVER.load(Ordering::SeqCst) {
+        diagnostics::debug!("Leaving unix sockets in place for the new process");
+        return;
+    }
+    for files in SOCKET_FILES.lock().unwrap().values() {
+        remove_socket_files(files);
+    }
+}
+
+/// Whether the application accepts connections from a listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcceptState {
+    Accepting,
+    Paused,
+    /// Accepting stopped for good, e.g. as the process shuts down.
+    Stopped,
+}
+
+/// Controls whether the application accepts connections from the listener of the same name, see
+/// the module documentation. Clones control the same listener.
+#[derive(Clone, Debug)]
+pub struct ListenerGuard {
+    name: Arc<str>,
+    state: Arc<watch::Sender<AcceptState>>,
+}
+
+impl ListenerGuard {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> AcceptState {
+        *self.state.borrow()
+    }
+
+    /// Stop accepting until `resume` is called. Does nothing once the guard is stopped.
+    pub fn pause(&self) {
+        if self.transition(AcceptState::Accepting, AcceptState::Paused) {
+            diagnostics::info!("Pausing accepting on listener {}", self.name);
+        }
+    }
+
+    /// Accept again after `pause`. Does nothing once the guard is stopped.
+    pub fn resume(&self) {
+        if self.transition(AcceptState::Paused, AcceptState::Accepting) {
+            diagnostics::info!("Resuming accepting on listener {}", self.name);
+        }
+    }
+
+    /// Stop accepting for good.
+    pub fn stop(&self) {
+        let previous = self.state.send_replace(AcceptState::Stopped);
+        if previous != AcceptState::Stopped {
+            diagnostics::info!("Stopped accepting on listener {}", self.name);
+        }
+    }
+
+    /// Stop accepting for good once `signal` is received, e.g. the signal of a handle of a
+    /// `ShutdownCoordinator`, or of a group handle to stop when the group is drained.
+    pub fn stop_on(&self, mut signal: ShutdownSignal) {
+        let guard = self.clone();
+        tokio::spawn(async move {
+            signal.on_shutdown().await;
+            guard.stop();
+        });
+    }
+
+    /// Waits while the guard is paused, and then for `cutover::accept_turn`. Returns true when the
+    /// application should accept the next connection, or false once the guard is stopped or the
+    /// new process has taken over.
+    pub async fn accept_turn(&self) -> bool {
+        let mut state = self.state.subscribe();
+        loop {
+            let accepting = state
+                .wait_for(|state| *state != AcceptState::Paused)
+                .await
+                .map(|state| *state == AcceptState::Accepting);
+            if !accepting.unwrap_or(false) || !cutover::accept_turn().await {
+                return false;
+            }
+            // The guard may have been paused while waiting for the cutover.
+            if *state.borrow_and_update() == AcceptState::Accepting {
+                return true;
+            }
+        }
+    }
+
+    fn transition(&self, from: AcceptState, to: AcceptState) -> bool {
+        self.state.send_if_modified(|state| match *state == from {
+            true => {
+                *state = to;
+                true
+            }
+            false => false,
+        })
+    }
+}
+
+/// The guard of the listener called `name`. Every call with the same name returns a guard that
+/// controls the same listener, which starts out accepting.
+pub fn guard(name: &str) -> ListenerGuard {
+    GUARDS
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_insert_with(|| ListenerGuard {
+            name: name.into(),
+            state: Arc::new(watch::channel(AcceptState::Accepting).0),
+        })
+        .clone()
+}
+
+fn remove_socket_files(files: &[SocketFile]) {
+    for file in files {
+        if !file.is_current() {
+            diagnostics::debug!("Leaving {:?} in place, as it was replaced", file.path);
+            continue;
+        }
+        match fs::remove_file(&file.path) {
+            Ok(()) => diagnostics::debug!("Removed unix socket {:?}", file.path),
+            Err(e) => diagnostics::warn!("Failed to remove unix socket {:?}: {}", file.path, e),
+        }
+    }
+}
+
+/// Record that a new process has taken over the registered listeners.
+pub(crate) fn handed_over() {
+    HANDED_OVER.store(true, Ordering::SeqCst);
+}
+
+fn track_socket_files<'a>(name: &str, paths: impl IntoIterator<Item = &'a Path>) {
+    let files = paths
+        .into_iter()
+        .filter_map(|path| match SocketFile::new(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                diagnostics::warn!("Failed to stat unix socket {:?}: {}", path, e);
+                None
+            }
+        })
+        .collect();
+    SOCKET_FILES.lock().unwrap().insert(name.to_string(), files);
+}
+
+fn log_inherited(name: &str, addr: io::Result<impl fmt::Debug>) {
+    match addr {
+        Ok(addr) => {
+            diagnostics::info!("Using listener {name} on {addr:?} inherited from the old process")
+        }
+        Err(e) => diagnostics::info!("Using listener {name} inherited from the old process: {e}"),
+    }
+}
+
+fn log_bound(name: &str, addr: io::Result<impl fmt::Debug>) {
+    match addr {
+        Ok(addr) => diagnostics::info!("Bound listener {name} to {addr:?}"),
+        Err(e) => diagnostics::info!("Bound listener {name}: {e}"),
+    }
+}
+
+fn close_unclaimed(name: &str, unclaimed: Vec<Listener>) {
+    for listener in unclaimed {
+        diagnostics::info!(
+            "Closing inherited listener {} on {:?}, as it is not bound to a configured address",
+            name,
+            listener.local_addr()
+        );
+    }
+}
+
+fn inherited() -> &'static Mutex<Inherited> {
+    INHERITED.get_or_init(|| {
+        // A shellflip parent that sets both describes the same listeners in each.
+        let inherited = match env::var(ENV_LISTENERS) {
+            Ok(value) => Inherited {
+                groups: parse_listeners(&value),
+                unnamed: Vec::new(),
+            },
+            Err(_) => parse_listen_fds(
+                env::var(ENV_LISTEN_FDS).ok().as_deref(),
+                env::var(ENV_LISTEN_FDNAMES).ok().as_deref(),
+                env::var(crate::ENV_SYSTEMD_PID).ok().as_deref(),
+            ),
+        };
+        Mutex::new(inherited)
+    })
+}
+
+/// Take the inherited members of the group called `name`, along with any unnamed inherited
+/// listeners bound to one of `addrs`.
+fn take_inherited(name: &str, addrs: &[ListenAddr]) -> io::Result<Vec<Listener>> {
+    let mut inherited = inherited().lock().unwrap();
+    let mut fds = inherited.groups.remove(name).unwrap_or_default();
+    let (claimed, unclaimed) = mem::take(&mut inherited.unnamed)
+        .into_iter()
+        .partition(|fd| is_listener_bound_to_any(*fd, addrs));
+    inherited.unnamed = unclaimed;
+    fds.extend::<Vec<_>>(claimed);
+    drop(inherited);
+
+    fds.into_iter()
+        .map(|fd| unsafe { Listener::from_inherited(fd) })
+        .collect()
+}
+
+fn is_listener_bound_to_any(fd: RawFd, addrs: &[ListenAddr]) -> bool {
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    // Sockets passed with LISTEN_FDS may also be datagram sockets.
+    if socket_type(fd).ok() != Some(libc::SOCK_STREAM) {
+        return false;
+    }
+    match fd.try_clone_to_owned().and_then(Listener::from_fd) {
+        Ok(listener) => addrs.iter().any(|addr| listener.is_bound_to(addr)),
+        Err(_) => false,
+    }
+}
+
+/// Close the listeners inherited from the old process that nothing claimed by the time this
+/// process reported that it started, so that they don't keep their ports from being released.
+/// Listeners passed with `LISTEN_FDS` may be taken by other means than this module, so they are
+/// only logged.
+pub(crate) fn release_unclaimed() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        let close = env::var_os(ENV_LISTENERS).is_some() && env::var_os(ENV_LISTEN_FDS).is_none();
+        release_unclaimed_in(&mut inherited().lock().unwrap(), close);
+    });
+}
+
+/// Log the unclaimed listeners in `inherited`, and close them if `close` is set. Returns how many
+/// there were.
+fn release_unclaimed_in(inherited: &mut Inherited, close: bool) -> usize {
+    let unnamed = inherited.unnamed.iter().map(|fd| (UNNAMED, *fd));
+    let groups = inherited
+        .groups
+        .iter()
+        .flat_map(|(name, fds)| fds.iter().map(move |fd| (name.as_str(), *fd)));
+    let unclaimed: Vec<_> = groups.chain(unnamed).collect();
+    for &(name, fd) in &unclaimed {
+        match close {
+            true => {
+                diagnostics::warn!("Closing inherited listener {} (fd {}), unclaimed", name, fd)
+            }
+            false => diagnostics::warn!("Inherited listener {} (fd {}) is unclaimed", name, fd),
+        }
+    }
+    let count = unclaimed.len();
+    if close {
+        for (_, fd) in unclaimed {
+            drop(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+        *inherited = Inherited::default();
+    }
+    count
+}
+
+/// Add a listener inherited by other means than `ENV_LISTENERS` to the group called `name`.
+pub(crate) fn add_inherited(name: &str, fd: OwnedFd) {
+    inherited()
+        .lock()
+        .unwrap()
+        .groups
+        .entry(name.to_string())
+        .or_default()
+        .push(fd.into_raw_fd());
+}
+
+fn socket_family(fd: BorrowedFd<'_>) -> io::Result<libc::c_int> {
+    let mut addr = MaybeUninit::<libc::sockaddr_storage>::zeroed();
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let res = unsafe { libc::getsockname(fd.as_raw_fd(), addr.as_mut_ptr().cast(), &mut len) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { addr.assume_init() }.ss_family.into())
+}
+
+fn socket_type(fd: BorrowedFd<'_>) -> io::Result<libc::c_int> {
+    let mut ty: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            (&mut ty as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ty)
+}
+
+/// Parse the `LISTEN_FDS`, `LISTEN_FDNAMES` and `LISTEN_PID` variables. The sockets are ignored
+/// if they were passed to another process, but `systemfd --no-pid` does not set `LISTEN_PID`.
+fn parse_listen_fds(fds: Option<&str>, names: Option<&str>, pid: Option<&str>) -> Inherited {
+    let mut inherited = Inherited::default();
+    let Some(count) = fds.and_then(|fds| fds.parse::<RawFd>().ok()) else {
+        return inherited;
+    };
+    if let Some(pid) = pid {
+        if pid != process::id().to_string() && pid != crate::REBIND_SYSTEMD_PID {
+            return inherited;
+        }
+    }
+
+    let mut names = names.unwrap_or_default().split(':');
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count) {
+        match names.next() {
+            Some(name) if !name.is_empty() && name != UNNAMED => inherited
+                .groups
+                .entry(name.to_string())
+                .or_default()
+                .push(fd),
+            _ => inherited.unnamed.push(fd),
+        }
+    }
+    inherited
+}
+
+fn parse_listeners(value: &str) -> BTreeMap<String, Vec<RawFd>> {
+    value
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let (name, fds) = entry.split_once('=')?;
+            match fds.split(':').map(str::parse).collect() {
+                Ok(fds) => Some((name.to_string(), fds)),
+                Err(_) => {
+                    diagnostics::warn!("Ignoring malformed inherited listener {:?}", entry);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn register<'a>(name: &str, fds: impl IntoIterator<Item = BorrowedFd<'a>>) -> io::Result<()> {
+    let fds = fds
+        .into_iter()
+        .map(|fd| fd.try_clone_to_owned())
+        .collect::<io::Result<_>>()?;
+    REGISTERED.lock().unwrap().insert(name.to_string(), fds);
+    Ok(())
+}
+
+/// Copies of the registered listeners for a new process, which must be kept open until it is
+/// spawned.
+pub(crate) struct NewProcessListeners {
+    names: Vec<String>,
+    fds: Vec<OwnedFd>,
+}
+
+pub(crate) fn for_new_process() -> io::Result<NewProcessListeners> {
+    let registered = REGISTERED.lock().unwrap();
+    let mut listeners = NewProcessListeners {
+        names: Vec::new(),
+        fds: Vec::new(),
+    };
+    for (name, group) in registered.iter() {
+        #[cfg(target_os = "linux")]
+        if deferred::is_deferred(name) {
+            continue;
+        }
+        for fd in group {
+            listeners.names.push(name.clone());
+            listeners.fds.push(fd.try_clone()?);
+        }
+    }
+    Ok(listeners)
+}
+
+impl NewProcessListeners {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    pub(crate) fn fds(&self) -> impl Iterator<Item = RawFd> + '_ {
+        self.fds.iter().map(AsRawFd::as_raw_fd)
+    }
+
+    /// The value of `ENV_LISTENERS`. If `listen_fds` is set, the listeners are passed that way,
+    /// otherwise they keep their fd numbers.
+    pub(crate) fn env(&self, listen_fds: Option<&ListenFds>) -> String {
+        let numbers: Vec<RawFd> = self.child_fds(listen_fds).map(|(_, fd)| fd).collect();
+        let mut entries: Vec<String> = Vec::new();
+        for (i, number) in numbers.iter().enumerate() {
+            match i > 0 && self.names[i] == self.names[i - 1] {
+                true => entries.last_mut().unwrap().push_str(&format!(":{number}")),
+                false => entries.push(format!("{}={}", self.names[i], number)),
+            }
+        }
+        entries.join(",")
+    }
+
+    /// The name of each listener and the fd number it has in the new process.
+    pub(crate) fn child_fds<'a>(
+        &'a self,
+        listen_fds: Option<&ListenFds>,
+    ) -> impl Iterator<Item = (&'a str, RawFd)> + 'a {
+        let moved = listen_fds.is_some();
+        self.names
+            .iter()
+            .zip(&self.fds)
+            .enumerate()
+            .map(move |(i, (name, fd))| match moved {
+                true => (name.as_str(), LISTEN_FDS_START + i as RawFd),
+                false => (name.as_str(), fd.as_raw_fd()),
+            })
+    }
+
+    /// The name of each listener along with the copy of it.
+    pub(crate) fn into_entries(self) -> impl Iterator<Item = (String, OwnedFd)> {
+        self.names.into_iter().zip(self.fds)
+    }
+
+    /// The value of `ENV_LISTEN_FDNAMES`.
+    pub(crate) fn listen_fdnames(&self) -> String {
+        self.names.join(":")
+    }
+
+    /// Prepare to move the listeners to the fds that `LISTEN_FDS` refers to in the new process.
+    /// Returns `None` if one of the `kept` fds that the new process inherits is in the way.
+    pub(crate) fn listen_fds(&self, kept: &[RawFd]) -> Option<ListenFds> {
+        let end = LISTEN_FDS_START + self.fds.len() as RawFd;
+        if let Some(fd) = kept.iter().find(|fd| (LISTEN_FDS_START..end).contains(fd)) {
+            diagnostics::warn!(
+                "Not passing listeners with {} to the new process, as fd {} is inherited as well",
+                ENV_LISTEN_FDS,
+                fd
+            );
+            return None;
+        }
+        let sources: Vec<RawFd> = self.fds().collect();
+        let highest = kept.iter().chain(&sources).copied().max().unwrap_or(0);
+        Some(ListenFds {
+            temporary: vec![-1; sources.len()],
+            sources,
+            floor: highest.max(end) + 1,
+        })
+    }
+}
+
+/// Moves listeners to the start of the fd table of the new process, after it is forked.
+pub(crate) struct ListenFds {
+    sources: Vec<RawFd>,
+    /// Allocated up front, as the new process must not allocate before exec.
+    temporary: Vec<RawFd>,
+    /// Lowest fd for temporary copies, so that they don't overwrite anything.
+    floor: RawFd,
+}
+
+impl ListenFds {
+    pub(crate) fn count(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Move the listeners into place. Only call this in the new process before exec.
+    pub(crate) fn apply(&mut self) -> io::Result<()> {
+        // Copy the sources out of the way first, since they may be in the target range.
+        for (source, temporary) in self.sources.iter().zip(&mut self.temporary) {
+            *temporary = unsafe { libc::fcntl(*source, libc::F_DUPFD_CLOEXEC, self.floor) };
+            if *temporary < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        for (i, temporary) in self.temporary.iter().enumerate() {
+            // The copy made by dup2 is not close-on-exec.
+            if unsafe { libc::dup2(*temporary, LISTEN_FDS_START + i as RawFd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pretend the registered copies of a group were inherited from the old process.
+    fn inherit(name: &str) {
+        let fds = REGISTERED.lock().unwrap().remove(name).unwrap();
+        INHERITED
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap()
+            .groups
+            .insert(
+                name.into(),
+                fds.into_iter().map(|fd| fd.into_raw_fd()).collect(),
+            );
+    }
+
+    #[test]
+    fn test_listener_or_bind() {
+        let listener = listener_or_bind("test", "127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let listeners = for_new_process().unwrap();
+        assert!(listeners
+            .env(None)
+            .split(',')
+            .any(|e| e.starts_with("test=")));
+        assert!(!listeners.is_empty());
+
+        inherit("test");
+        let listener = listener_or_bind("test", "127.0.0.1:0").unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn test_release_unclaimed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = OwnedFd::from(listener).into_raw_fd();
+        let mut inherited = Inherited {
+            groups: BTreeMap::from([("a".to_string(), vec![fd])]),
+            unnamed: Vec::new(),
+        };
+        assert_eq!(release_unclaimed_in(&mut inherited, false), 1);
+        assert_eq!(inherited.groups["a"], [fd]);
+        assert!(socket_type(unsafe { BorrowedFd::borrow_raw(fd) }).is_ok());
+
+        assert_eq!(release_unclaimed_in(&mut inherited, true), 1);
+        assert!(inherited.groups.is_empty());
+        assert!(socket_type(unsafe { BorrowedFd::borrow_raw(fd) }).is_err());
+    }
+
+    #[test]
+    fn test_listener_group() {
+        let path = env::temp_dir().join(format!("shellflip-group-{}.sock", std::process::id()));
+        let addrs = [
+            ListenAddr::Tcp("127.0.0.1:0".parse().unwrap()),
+            ListenAddr::Unix(path.clone()),
+        ];
+        let group = listener_group("group", &addrs).unwrap();
+        let tcp_addr = group[0].local_addr().unwrap();
+        assert_eq!(group[1].local_addr().unwrap(), addrs[1]);
+
+        inherit("group");
+        drop(group);
+        // The TCP member is dropped from the group, and a new one takes its place.
+        let addrs = [
+            ListenAddr::Unix(path.clone()),
+            ListenAddr::Tcp("127.0.0.2:0".parse().unwrap()),
+        ];
+        let group = listener_group("group", &addrs).unwrap();
+        assert!(matches!(group[0], Listener::Unix(_)));
+        assert_eq!(group[0].local_addr().unwrap(), addrs[0]);
+        assert_ne!(group[1].local_addr().unwrap(), tcp_addr);
+        assert!(group[1].is_bound_to(&addrs[1]));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_bind_keeps_other_files() {
+        let path = env::temp_dir().join(format!("shellflip-not-a-socket-{}", process::id()));
+        fs::write(&path, b"data").unwrap();
+        assert!(Listener::bind(&ListenAddr::Unix(path.clone())).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+        fs::remove_file(&path).unwrap();
+
+        // A socket left behind is replaced.
+        drop(UnixListener::bind(&path).unwrap());
+        Listener::bind(&ListenAddr::Unix(path.clone())).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_unnamed_listener_claimed_by_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = OwnedFd::from(listener).into_raw_fd();
+        inherited().lock().unwrap().unnamed.push(fd);
+
+        // Not bound to the requested address, so it stays unclaimed.
+        let other = listener_or_bind("unnamed-other", "127.0.0.3:0").unwrap();
+        assert_ne!(other.local_addr().unwrap(), addr);
+        assert!(inherited().lock().unwrap().unnamed.contains(&fd));
+
+        let listener = listener_or_bind("unnamed", addr).unwrap();
+        assert_eq!(listener.as_raw_fd(), fd);
+        assert!(!inherited().lock().unwrap().unnamed.contains(&fd));
+    }
+
+    #[test]
+    fn test_replace_unix_listener() {
+        let path = env::temp_dir().join(format!("shellflip-replace-{}.sock", process::id()));
+        let old = unix_listener_or_bind("replace", &path).unwrap();
+        let old_file = SocketFile::new(&path).unwrap();
+
+        inherit("replace");
+        let new = replace_unix_listener("replace", &path).unwrap();
+        assert_ne!(new.as_raw_fd(), old.as_raw_fd());
+        assert!(!old_file.is_current());
+
+        // Clients reach the new listener, and the old one is left alone.
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+        new.set_nonblocking(true).unwrap();
+        old.set_nonblocking(true).unwrap();
+        assert!(new.accept().is_ok());
+        assert!(old.accept().is_err());
+
+        // The new listener is still found under its path by the next process.
+        let new_file = SocketFile::new(&path).unwrap();
+        inherit("replace");
+        unix_listener_or_bind("replace", &path).unwrap();
+        assert!(new_file.is_current());
+
+        remove_socket_files(&[old_file]);
+        assert!(path.exists());
+        remove_socket_files(&[SocketFile::new(&path).unwrap()]);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_parse_listen_fds() {
+        let pid = process::id().to_string();
+        let parsed = parse_listen_fds(Some("4"), Some("http:unknown::http"), Some(&pid));
+        assert_eq!(parsed.groups["http"], [3, 6]);
+        assert_eq!(parsed.unnamed, [4, 5]);
+
+        // systemfd --no-pid
+        let parsed = parse_listen_fds(Some("2"), None, None);
+        assert!(parsed.groups.is_empty());
+        assert_eq!(parsed.unnamed, [3, 4]);
+
+        let parsed = parse_listen_fds(Some("2"), None, Some("1"));
+        assert!(parsed.unnamed.is_empty());
+        assert!(parse_listen_fds(None, None, None).unnamed.is_empty());
+    }
+
+    #[test]
+    fn test_new_process_env() {
+        let fds: Vec<OwnedFd> = (0..3)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap().into())
+            .collect();
+        let numbers: Vec<RawFd> = fds.iter().map(AsRawFd::as_raw_fd).collect();
+        let listeners = NewProcessListeners {
+            names: vec!["a".into(), "a".into(), "b".into()],
+            fds,
+        };
+        assert_eq!(
+            listeners.env(None),
+            format!("a={}:{},b={}", numbers[0], numbers[1], numbers[2])
+        );
+        assert_eq!(listeners.listen_fdnames(), "a:a:b");
+
+        assert!(listeners.listen_fds(&[4]).is_none());
+        let listen_fds = listeners.listen_fds(&[2, 100]).unwrap();
+        assert_eq!(listen_fds.floor, 101.max(numbers.iter().max().unwrap() + 1));
+        assert_eq!(listeners.env(Some(&listen_fds)), "a=3:4,b=5");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_listen_fds_apply() {
+        use std::os::unix::process::CommandExt;
+        use std::process::Command;
+
+        let listeners = NewProcessListeners {
+            names: vec!["a".into(), "b".into()],
+            fds: (0..2)
+                .map(|_| TcpListener::bind("127.0.0.1:0").unwrap().into())
+                .collect(),
+        };
+        let mut listen_fds = listeners.listen_fds(&[]).unwrap();
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "test -S /proc/self/fd/3 && test -S /proc/self/fd/4"]);
+        unsafe { cmd.pre_exec(move || listen_fds.apply()) };
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_parse_listeners() {
+        let parsed = parse_listeners("https=3:4:5,http=6,bad=x");
+        assert_eq!(parsed["https"], [3, 4, 5]);
+        assert_eq!(parsed["http"], [6]);
+        assert!(!parsed.contains_key("bad"));
+    }
+
+    #[tokio::test]
+    async fn test_guard() {
+        let guard = super::guard("test-guard");
+        assert_eq!(guard.state(), AcceptState::Accepting);
+        assert!(guard.accept_turn().await);
+
+        super::guard("test-guard").pause();
+        let turn = tokio::spawn({
+            let guard = guard.clone();
+            async move { guard.accept_turn().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!turn.is_finished());
+        guard.resume();
+        assert!(turn.await.unwrap());
+
+        // Stopping is for good.
+        let coordinator = crate::ShutdownCoordinator::new();
+        guard.stop_on(ShutdownSignal::from(&*coordinator.handle()));
+        guard.pause();
+        coordinator.shutdown().await;
+        assert!(!guard.accept_turn().await);
+        guard.resume();
+        assert_eq!(guard.state(), AcceptState::Stopped);
+    }
+}
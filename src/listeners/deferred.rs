@@ -0,0 +1,358 @@
+//! Handing some listener groups over later than the rest, for staged migrations of daemons that
+//! serve several protocols, e.g. moving port 443 to the new process at the restart while the old
+//! process keeps serving the admin port until it exits.
+//!
+//! The old process calls `defer` for each group to keep. Deferred groups are not passed to the new
+//! process when it is spawned. Instead, the new process inherits a socket that the old process
+//! passes them on later with `hand_over`, once the restart completed. Handing a group over stops
+//! its `ListenerGuard`, so the old process stops accepting on it. `hand_over_all` hands over the
+//! groups that are left, e.g. as the old process exits.
+//!
+//! The new process lists the groups it is waiting for with `pending`. They are also described in
+//! the fd manifest, as entries of kind `FdKind::DeferredListener` with the number of the socket
+//! they arrive on. Before calling `listener_or_bind` or `listener_group` for a deferred group, the
+//! new process awaits `wait_for`, after which they use the listeners that were handed over. If the
+//! old process exits without handing the group over, `wait_for` returns false, and the new process
+//! binds the group itself.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use shellflip::listeners::{self, deferred};
+//!
+//! // In every version, keep the admin port until the end of the next restart.
+//! deferred::defer("admin");
+//!
+//! // In the new process:
+//! deferred::wait_for("admin").await;
+//! let admin = listeners::listener_or_bind("admin", "127.0.0.1:9000")?;
+//!
+//! // In the old process, once it has drained:
+//! deferred::hand_over_all().await?;
+//! # Ok(())
+//! # }
+//! ```
+use super::{add_inherited, guard, HANDED_OVER, REGISTERED};
+use crate::diagnostics;
+use crate::pipes::{seqpacket_pair, set_cloexec};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use std::collections::BTreeSet;
+use std::env;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// The socket the new process receives deferred groups on, and their names.
+pub(crate) const ENV_DEFERRED_LISTENERS: &str = "OXY_DEFERRED_LISTENERS";
+/// The most listeners a group handed over may have.
+const MAX_GROUP_LEN: usize = 64;
+/// The longest group name that can be handed over.
+const MAX_NAME_LEN: usize = 256;
+/// How long `hand_over` waits for room in the socket.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The groups this process defers at the next restart.
+static DEFERRED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+/// The socket to the new process, once it has been spawned with deferred groups.
+static SOCKET: Mutex<Option<OwnedFd>> = Mutex::new(None);
+/// The deferred groups of the old process.
+static INCOMING: OnceLock<watch::Sender<Incoming>> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct Incoming {
+    /// Groups the old process deferred and has not handed over yet.
+    pending: BTreeSet<String>,
+    /// Groups the old process handed over.
+    received: BTreeSet<String>,
+}
+
+/// Keep the listener group `name` in this process at the next restart, rather than passing it to
+/// the new process when it is spawned, until it is handed over with `hand_over`.
+pub fn defer(name: &str) {
+    DEFERRED.lock().unwrap().insert(name.to_string());
+}
+
+/// Pass the listener group `name`, deferred with `defer`, to the new process, and stop accepting
+/// on it by stopping its `ListenerGuard`. Fails if the restart has not completed, as the group
+/// can't be handed back if it fails.
+pub async fn hand_over(name: &str) -> io::Result<()> {
+    if !HANDED_OVER.load(Ordering::SeqCst) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "no restart has completed",
+        ));
+    }
+    if !DEFERRED.lock().unwrap().contains(name) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("listener group {name} is not deferred"),
+        ));
+    }
+    let fds = match REGISTERED.lock().unwrap().get(name) {
+        Some(group) => group
+            .iter()
+            .map(OwnedFd::try_clone)
+            .collect::<io::Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    let socket = match &*SOCKET.lock().unwrap() {
+        Some(socket) => socket.try_clone()?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the new process does not expect deferred listeners",
+            ))
+        }
+    };
+    let group = name.to_string();
+    tokio::task::spawn_blocking(move || send_group(socket, &group, &fds)).await??;
+    DEFERRED.lock().unwrap().remove(name);
+    diagnostics::info!("Handed listener group {} over to the new process", name);
+    guard(name).stop();
+    Ok(())
+}
+
+/// Hand over every deferred group that has not been handed over yet, see `hand_over`.
+pub async fn hand_over_all() -> io::Result<()> {
+    let names: Vec<String> = DEFERRED.lock().unwrap().iter().cloned().collect();
+    for name in names {
+        if REGISTERED.lock().unwrap().contains_key(&name) {
+            hand_over(&name).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The groups the old process deferred and has not handed over yet.
+pub fn pending() -> Vec<String> {
+    incoming().borrow().pending.iter().cloned().collect()
+}
+
+/// Wait until the old process hands over the group `name`. Returns false right away if the old
+/// process did not defer it, or once it exits without handing it over.
+pub async fn wait_for(name: &str) -> bool {
+    let mut incoming = incoming().subscribe();
+    let incoming = incoming.wait_for(|i| !i.pending.contains(name)).await;
+    incoming.is_ok_and(|i| i.received.contains(name))
+}
+
+fn incoming() -> &'static watch::Sender<Incoming> {
+    INCOMING.get_or_init(|| {
+        let (tx, _) = watch::channel(Incoming::default());
+        let Some(value) = env::var_os(ENV_DEFERRED_LISTENERS) else {
+            return tx;
+        };
+        env::remove_var(ENV_DEFERRED_LISTENERS);
+        let Some((fd, names)) = value.to_str().and_then(parse_env) else {
+            diagnostics::warn!("Ignoring malformed deferred listeners {:?}", value);
+            return tx;
+        };
+        // Don't leak the socket into the next process.
+        if let Err(e) = set_cloexec(fd) {
+            diagnostics::warn!("Failed to set close-on-exec on the deferred listeners socket: {e}");
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        tx.send_modify(|i| i.pending = names);
+        thread::spawn(move || receive_all(socket));
+        tx
+    })
+}
+
+/// Receive the groups handed over by the old process until it closes the socket.
+fn receive_all(socket: OwnedFd) {
+    loop {
+        match receive_group(&socket) {
+            Ok(Some((name, fds))) => {
+                diagnostics::info!("Received listener group {} from the old process", name);
+                for fd in fds {
+                    add_inherited(&name, fd);
+                }
+                incoming().send_modify(|i| {
+                    i.pending.remove(&name);
+                    i.received.insert(name);
+                });
+            }
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                diagnostics::warn!("Failed to receive deferred listeners: {}", e);
+                break;
+            }
+        }
+    }
+    incoming().send_modify(|i| {
+        for name in &i.pending {
+            diagnostics::info!("The old process did not hand over listener group {}", name);
+        }
+        i.pending.clear();
+    });
+}
+
+fn send_group(socket: OwnedFd, name: &str, fds: &[OwnedFd]) -> io::Result<()> {
+    if name.len() > MAX_NAME_LEN || fds.len() > MAX_GROUP_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "listener group too large to hand over",
+        ));
+    }
+    let socket = UnixStream::from(socket);
+    socket.set_write_timeout(Some(SEND_TIMEOUT))?;
+    let fds: Vec<RawFd> = fds.iter().map(AsRawFd::as_raw_fd).collect();
+    sendmsg::<()>(
+        socket.as_raw_fd(),
+        &[IoSlice::new(name.as_bytes())],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    Ok(())
+}
+
+/// Receive a group, or `None` once the old process closed the socket.
+fn receive_group(socket: &OwnedFd) -> io::Result<Option<(String, Vec<OwnedFd>)>> {
+    let mut name = [0u8; MAX_NAME_LEN];
+    let mut iov = [IoSliceMut::new(&mut name)];
+    let mut cmsg = nix::cmsg_space!([RawFd; MAX_GROUP_LEN]);
+    let msg = recvmsg::<()>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    let mut fds = Vec::new();
+    for c in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(received) = c {
+            fds.extend(
+                received
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+    let len = msg.bytes;
+    if len == 0 {
+        return Ok(None);
+    }
+    Ok(Some((
+        String::from_utf8_lossy(&name[..len]).into_owned(),
+        fds,
+    )))
+}
+
+fn parse_env(value: &str) -> Option<(RawFd, BTreeSet<String>)> {
+    let (fd, names) = value.split_once(':')?;
+    let names = names
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(Into::into)
+        .collect();
+    Some((fd.parse().ok()?, names))
+}
+
+/// Whether the group `name` is kept from the new process at the next restart.
+pub(crate) fn is_deferred(name: &str) -> bool {
+    DEFERRED.lock().unwrap().contains(name)
+}
+
+/// The socket to pass the deferred groups on, for a new process that is about to be spawned.
+pub(crate) struct NewProcessDeferred {
+    ours: OwnedFd,
+    theirs: OwnedFd,
+    names: Vec<String>,
+}
+
+/// Returns the socket for the new process, unless no registered group is deferred.
+pub(crate) fn for_new_process() -> io::Result<Option<NewProcessDeferred>> {
+    let registered = REGISTERED.lock().unwrap();
+    let names: Vec<String> = DEFERRED
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|name| registered.contains_key(*name))
+        .cloned()
+        .collect();
+    if names.is_empty() {
+        return Ok(None);
+    }
+    let (ours, theirs) = seqpacket_pair()?;
+    Ok(Some(NewProcessDeferred {
+        ours,
+        theirs,
+        names,
+    }))
+}
+
+impl NewProcessDeferred {
+    /// The socket the new process inherits.
+    pub(crate) fn fd(&self) -> RawFd {
+        self.theirs.as_raw_fd()
+    }
+
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(String::as_str)
+    }
+
+    /// The value of `ENV_DEFERRED_LISTENERS`.
+    pub(crate) fn env(&self) -> String {
+        format!("{}:{}", self.fd(), self.names.join(","))
+    }
+
+    /// The new process has been spawned, so keep our end of the socket to hand groups over on.
+    pub(crate) fn spawned(self) {
+        *SOCKET.lock().unwrap() = Some(self.ours);
+    }
+}
+
+/// The restart failed, so there is no new process to hand groups over to.
+pub(crate) fn reset() {
+    SOCKET.lock().unwrap().take();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_for_new_process() {
+        let listener = super::super::listener_or_bind("deferred-test", "127.0.0.1:0").unwrap();
+        defer("deferred-test");
+        defer("deferred-unregistered");
+        let deferred = for_new_process().unwrap().unwrap();
+        assert_eq!(deferred.names().collect::<Vec<_>>(), ["deferred-test"]);
+        let (fd, names) = parse_env(&deferred.env()).unwrap();
+        assert_eq!(fd, deferred.fd());
+        assert!(names.contains("deferred-test"));
+        // The group is not passed to the new process with the others.
+        let listeners = super::super::for_new_process().unwrap();
+        assert!(!listeners
+            .into_entries()
+            .any(|(name, _)| name == "deferred-test"));
+
+        DEFERRED.lock().unwrap().clear();
+        REGISTERED.lock().unwrap().remove("deferred-test");
+        drop(listener);
+    }
+
+    #[test]
+    fn test_send_group() {
+        let (ours, theirs) = seqpacket_pair().unwrap();
+        let listeners: Vec<OwnedFd> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap().into())
+            .collect();
+        send_group(ours, "admin", &listeners).unwrap();
+        let (name, fds) = receive_group(&theirs).unwrap().unwrap();
+        assert_eq!(name, "admin");
+        for (sent, received) in listeners.into_iter().zip(fds) {
+            let sent = TcpListener::from(sent).local_addr().unwrap();
+            let received = TcpListener::from(received).local_addr().unwrap();
+            assert_eq!(sent, received);
+        }
+        // Our end was closed.
+        assert!(receive_group(&theirs).unwrap().is_none());
+    }
+}
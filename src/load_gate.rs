@@ -0,0 +1,320 @@
+//! Delaying requested restarts until load drops, so that deploy automation doesn't upgrade a
+//! machine at peak traffic.
+//!
+//! With `RestartConfig::load_gate`, a requested restart waits until the active connections and
+//! in-flight requests of the application, and the CPU usage of the machine, are all at or below
+//! their thresholds, checking every `poll_interval`. If load is still above them after
+//! `max_wait`, the restart goes ahead anyway. Meanwhile the restart is in progress as far as
+//! restart requesters are concerned: further requests are turned away, and it can be cancelled.
+//!
+//! Active connections are the live handles from `ShutdownCoordinator::named_handle`, which the
+//! application already holds for each connection to drain it, as counted by `DrainStats`. Requests
+//! are counted with a `LoadCounter`, holding a guard for each request:
+//!
+//! ```no_run
+//! # async fn example(
+//! #     coordinator: &shellflip::ShutdownCoordinator,
+//! #     listener: tokio::net::TcpListener,
+//! # ) -> std::io::Result<()> {
+//! use shellflip::load_gate::{LoadCounter, LoadGate};
+//! use std::time::Duration;
+//!
+//! let requests = LoadCounter::new();
+//! let gate = LoadGate {
+//!     connections: Some(coordinator.drain_stats()),
+//!     max_connections: Some(1000),
+//!     in_flight: requests.clone(),
+//!     max_in_flight: Some(100),
+//!     max_wait: Duration::from_secs(600),
+//!     ..Default::default()
+//! };
+//! // Set `RestartConfig::load_gate` to `Some(gate)`.
+//!
+//! loop {
+//!     let (stream, peer) = listener.accept().await?;
+//!     let handle = coordinator.named_handle(peer.to_string());
+//!     let requests = requests.clone();
+//!     tokio::spawn(async move {
+//!         // For each request on the connection...
+//!         let guard = requests.enter();
+//! #       drop(stream);
+//!         drop(guard);
+//!         drop(handle);
+//!     });
+//! }
+//! # }
+//! ```
+use crate::diagnostics;
+use crate::shutdown::DrainStats;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+/// A count of units of load, e.g. requests, reported by the application.
+#[derive(Clone, Debug, Default)]
+pub struct LoadCounter(Arc<AtomicUsize>);
+
+impl LoadCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one unit of load until the returned guard is dropped.
+    pub fn enter(&self) -> LoadGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        LoadGuard(self.0.clone())
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts one unit of load of a `LoadCounter` while it is alive.
+#[derive(Debug)]
+pub struct LoadGuard(Arc<AtomicUsize>);
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Thresholds that a requested restart waits for load to drop below. Thresholds that are `None`
+/// are not checked.
+#[derive(Clone, Debug)]
+pub struct LoadGate {
+    /// The active connections of the application, as the handles from
+    /// `ShutdownCoordinator::named_handle` that are alive. Without this, `max_connections` is not
+    /// checked.
+    pub connections: Option<DrainStats>,
+    pub max_connections: Option<usize>,
+    /// The requests the application is processing.
+    pub in_flight: LoadCounter,
+    pub max_in_flight: Option<usize>,
+    /// The share of the time of all CPUs of the machine that is spent busy, from 0 to 1, over the
+    /// last `poll_interval`. This is measured from `/proc/stat`, and ignored where that can't be
+    /// read. Checking it delays every restart by at least one `poll_interval`.
+    pub max_cpu: Option<f64>,
+    /// How long to wait for load to drop before restarting anyway.
+    pub max_wait: Duration,
+    /// How often load is checked.
+    pub poll_interval: Duration,
+}
+
+impl Default for LoadGate {
+    fn default() -> Self {
+        LoadGate {
+            connections: None,
+            max_connections: None,
+            in_flight: LoadCounter::new(),
+            max_in_flight: None,
+            max_cpu: None,
+            max_wait: Duration::from_secs(300),
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl LoadGate {
+    /// Wait until load is at or below the thresholds, `max_wait` passes or `cancelled` returns
+    /// true.
+    pub(crate) async fn wait(&self, cancelled: impl Fn() -> bool) {
+        let start = Instant::now();
+        let mut cpu = self.max_cpu.and_then(|_| match CpuTimes::read() {
+            Ok(times) => Some(times),
+            Err(e) => {
+                diagnostics::warn!("Not gating the restart on CPU usage: {}", e);
+                None
+            }
+        });
+        if cpu.is_some() {
+            sleep(self.poll_interval).await;
+        }
+        let mut delayed = false;
+        loop {
+            let usage = cpu.as_mut().and_then(CpuTimes::usage);
+            let Some(load) = self.exceeded(usage) else {
+                if delayed {
+                    diagnostics::info!("Load dropped, restarting after {:?}", start.elapsed());
+                }
+                return;
+            };
+            if start.elapsed() >= self.max_wait {
+                diagnostics::warn!(
+                    "Restarting despite {}, after waiting {:?} for load to drop",
+                    load,
+                    self.max_wait
+                );
+                return;
+            }
+            if cancelled() {
+                return;
+            }
+            if !delayed {
+                diagnostics::info!(
+                    "Delaying the restart for up to {:?} while {}",
+                    self.max_wait,
+                    load
+                );
+                delayed = true;
+            }
+            sleep(self.poll_interval).await;
+        }
+    }
+
+    /// The first threshold that load is above, if any.
+    fn exceeded(&self, cpu: Option<f64>) -> Option<Load> {
+        let connections = self.connections.as_ref().map(DrainStats::active_handles);
+        if let (Some(connections), Some(max)) = (connections, self.max_connections) {
+            if connections > max {
+                return Some(Load::Connections(connections));
+            }
+        }
+        let in_flight = self.in_flight.get();
+        if self.max_in_flight.is_some_and(|max| in_flight > max) {
+            return Some(Load::InFlight(in_flight));
+        }
+        match (cpu, self.max_cpu) {
+            (Some(cpu), Some(max)) if cpu > max => Some(Load::Cpu(cpu)),
+            _ => None,
+        }
+    }
+}
+
+/// A kind of load that is above its threshold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Load {
+    Connections(usize),
+    InFlight(usize),
+    Cpu(f64),
+}
+
+impl fmt::Display for Load {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Load::Connections(n) => write!(f, "{n} connections are active"),
+            Load::InFlight(n) => write!(f, "{n} requests are in flight"),
+            Load::Cpu(usage) => write!(f, "CPU usage is {:.0}%", usage * 100.0),
+        }
+    }
+}
+
+/// The busy and total time of all CPUs, as of the last sample.
+#[derive(Debug)]
+struct CpuTimes {
+    busy: u64,
+    total: u64,
+}
+
+impl CpuTimes {
+    fn read() -> io::Result<Self> {
+        Self::parse(&fs::read_to_string("/proc/stat")?)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid /proc/stat"))
+    }
+
+    /// Parse the `cpu` line of `/proc/stat`, which has the time spent in user, nice, system, idle,
+    /// iowait, irq, softirq and steal, followed by guest times that are already included in user
+    /// and nice.
+    fn parse(stat: &str) -> Option<Self> {
+        let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+        let times: Vec<u64> = line
+            .split_whitespace()
+            .skip(1)
+            .take(8)
+            .map(|t| t.parse().ok())
+            .collect::<Option<_>>()?;
+        if times.len() < 5 {
+            return None;
+        }
+        let total = times.iter().sum();
+        Some(CpuTimes {
+            busy: total - times[3] - times[4],
+            total,
+        })
+    }
+
+    /// Take a new sample, and return the share of the time since the last one that was busy.
+    fn usage(&mut self) -> Option<f64> {
+        let now = Self::read().ok()?;
+        let total = now.total.saturating_sub(self.total);
+        let busy = now.busy.saturating_sub(self.busy);
+        *self = now;
+        (total > 0).then(|| busy as f64 / total as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter() {
+        let counter = LoadCounter::new();
+        let a = counter.enter();
+        let b = counter.clone().enter();
+        assert_eq!(counter.get(), 2);
+        drop(a);
+        drop(b);
+        assert_eq!(counter.get(), 0);
+    }
+
+    #[test]
+    fn test_parse_cpu_times() {
+        let stat = "cpu  100 5 50 800 20 3 2 0 10 0\ncpu0 50 2 25 400 10 1 1 0 5 0\n";
+        let times = CpuTimes::parse(stat).unwrap();
+        assert_eq!(times.total, 980);
+        assert_eq!(times.busy, 160);
+        assert!(CpuTimes::parse("cpu0 1 2 3 4 5\n").is_none());
+    }
+
+    #[test]
+    fn test_exceeded() {
+        let coordinator = crate::ShutdownCoordinator::new();
+        let gate = LoadGate {
+            connections: Some(coordinator.drain_stats()),
+            max_connections: Some(1),
+            max_cpu: Some(0.5),
+            ..Default::default()
+        };
+        let _a = coordinator.named_handle("a");
+        let _unnamed = coordinator.handle();
+        let _requests = gate.in_flight.enter();
+        assert_eq!(gate.exceeded(Some(0.5)), None);
+        assert_eq!(gate.exceeded(Some(0.6)), Some(Load::Cpu(0.6)));
+        let _b = coordinator.named_handle("b");
+        assert_eq!(gate.exceeded(None), Some(Load::Connections(2)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait() {
+        let gate = LoadGate {
+            max_in_flight: Some(0),
+            max_wait: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        gate.wait(|| false).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // Waits until the request completes.
+        let request = gate.in_flight.enter();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(2500)).await;
+            drop(request);
+        });
+        gate.wait(|| false).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+
+        // Gives up after `max_wait`, or when cancelled.
+        let _request = gate.in_flight.enter();
+        gate.wait(|| false).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(13));
+        gate.wait(|| true).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(13));
+    }
+}
@@ -0,0 +1,169 @@
+//! Log files that are reopened after rotation and passed on across restarts.
+//!
+//! Rotating a log file by renaming it only works if the process then opens a new file at the
+//! original path, which is what `AdminCommand::ReopenLogs` asks for, e.g. from a logrotate
+//! `postrotate` script running `shellflip-admin --socket ... reopen-logs`. This avoids
+//! `copytruncate`, which loses whatever is written between copying and truncating.
+//!
+//! Files opened with `open` are reopened by shellflip itself when the command arrives, before it is
+//! passed on to the application through `RestartConfig::admin_commands`, if set, for log files
+//! that it manages itself.
+//!
+//! Restarts and rotation can overlap, so log files are passed to the new process deliberately,
+//! with the `files` module. When the new process calls `open`, it gets the same open file if it is
+//! still the file at the path, and otherwise opens the path again, so that it never keeps writing
+//! to a file that has been rotated away while it started.
+use crate::diagnostics;
+use crate::files;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+/// Log files opened with `open` that are still in use.
+static LOGS: Mutex<Vec<Weak<Inner>>> = Mutex::new(Vec::new());
+
+/// A log file opened for appending, which follows its path when logs are reopened. Write to it
+/// through `&LogFile`, or clone it, to share it between writers.
+#[derive(Clone, Debug)]
+pub struct LogFile {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// The name the file is passed to the new process under.
+    name: String,
+    path: PathBuf,
+    file: RwLock<File>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        files::unregister(&self.name);
+    }
+}
+
+/// Open the log file at `path` for appending, creating it if needed, or reuse the one the old
+/// process opened under `name` if it is still the file at `path`. Names may not contain `,` or `=`.
+pub fn open(name: &str, path: impl AsRef<Path>) -> io::Result<LogFile> {
+    if name.contains([',', '=']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid log file name {name:?}"),
+        ));
+    }
+    let name = format!("log.{name}");
+    let path = path.as_ref().to_path_buf();
+    let file = files::open_file(&name, &path, &options())?;
+    let inner = Arc::new(Inner {
+        name,
+        path,
+        file: RwLock::new(file),
+    });
+
+    let mut logs = LOGS.lock().unwrap();
+    logs.retain(|log| log.strong_count() > 0);
+    logs.push(Arc::downgrade(&inner));
+    Ok(LogFile { inner })
+}
+
+/// Reopen every log file opened with `open` at its path. Returns how many were reopened, or the
+/// first error, after trying all of them. This blocks on file I/O, so async code should call it
+/// with `spawn_blocking`.
+pub fn reopen() -> io::Result<usize> {
+    let logs: Vec<_> = LOGS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    let mut result = Ok(0);
+    for log in logs {
+        match log.reopen() {
+            Ok(()) => {
+                if let Ok(count) = &mut result {
+                    *count += 1;
+                }
+            }
+            Err(e) => {
+                diagnostics::error!("Failed to reopen log file {}: {}", log.path.display(), e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+    result
+}
+
+impl LogFile {
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+}
+
+impl Inner {
+    fn reopen(&self) -> io::Result<()> {
+        let file = options().open(&self.path)?;
+        files::register(&self.name, &file)?;
+        let mut current = self.file.write().unwrap();
+        let _ = current.flush();
+        *current = file;
+        Ok(())
+    }
+}
+
+impl Write for &LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.inner.file.read().unwrap()).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.inner.file.read().unwrap()).flush()
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+fn options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_reopen() {
+        let dir = std::env::temp_dir().join(format!("shellflip-logs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        let mut log = open("app", &path).unwrap();
+        writeln!(log, "one").unwrap();
+
+        // Rotate the file, then reopen.
+        let rotated = dir.join("app.log.1");
+        fs::rename(&path, &rotated).unwrap();
+        writeln!(log, "two").unwrap();
+        assert!(reopen().unwrap() >= 1);
+        writeln!(&log, "three").unwrap();
+
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), "one\ntwo\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "three\n");
+        assert!(open("bad,name", &path).is_err());
+        drop(log);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
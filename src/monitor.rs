@@ -0,0 +1,265 @@
+//! Monitoring of the new process after a successful restart.
+//!
+//! A new version may pass its readiness check and then crash shortly after it starts taking
+//! traffic. The old process usually keeps running while it drains, so it can watch the new process
+//! with a `ChildMonitor` and raise an alert if it dies. Restart requesters can ask for the same by
+//! setting `RestartOptions::monitor_for`.
+//!
+//! On Linux 5.3 and later, the process is tracked with a `PidFd`, so that it can't be confused with
+//! a process that later gets the same pid, and its exit is awaited without polling.
+use crate::restart_coordination_socket::ProcessExited;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often to check whether the monitored process exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A file descriptor that refers to a single process, even once it exited and its pid was reused.
+/// Only supported on Linux 5.3 and later.
+#[derive(Debug)]
+pub struct PidFd(OwnedFd);
+
+impl PidFd {
+    /// Open a pidfd for the process `pid`. To be sure that it refers to the intended process,
+    /// `pid` must be a child that has not been reaped yet.
+    pub fn open(pid: u32) -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::FromRawFd;
+
+            let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(PidFd(unsafe { OwnedFd::from_raw_fd(fd as RawFd) }))
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = pid;
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "pidfds are only supported on Linux",
+            ))
+        }
+    }
+
+    /// Send `signal` to the process, unless it has exited.
+    pub fn send_signal(&self, signal: i32) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_pidfd_send_signal,
+                    self.0.as_raw_fd(),
+                    signal,
+                    std::ptr::null::<libc::siginfo_t>(),
+                    0,
+                )
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = signal;
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+    }
+
+    /// Wait until the process exits, which makes the pidfd readable.
+    pub async fn exited(&self) -> io::Result<()> {
+        // Each waiter registers its own copy, as an fd can only be registered with tokio once.
+        let fd = tokio::io::unix::AsyncFd::with_interest(
+            self.0.try_clone()?,
+            tokio::io::Interest::READABLE,
+        )?;
+        let _ = fd.readable().await?;
+        Ok(())
+    }
+}
+
+impl AsFd for PidFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl From<PidFd> for OwnedFd {
+    fn from(pidfd: PidFd) -> Self {
+        pidfd.0
+    }
+}
+
+/// Watches a child process for exit without reaping it, so `Child::wait` can still be used.
+/// Clones share the pidfd the process is tracked with, which is why this is not `Copy`.
+#[derive(Clone, Debug)]
+pub struct ChildMonitor {
+    pid: u32,
+    pidfd: Option<Arc<PidFd>>,
+}
+
+impl ChildMonitor {
+    /// Monitor the process returned by the restart task.
+    pub fn new(child: &process::Child) -> Self {
+        Self::from_pid(child.id())
+    }
+
+    pub(crate) fn from_pid(pid: u32) -> Self {
+        ChildMonitor {
+            pid,
+            pidfd: PidFd::open(pid).ok().map(Arc::new),
+        }
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// The pidfd the process is tracked with, if pidfds are supported.
+    pub fn pidfd(&self) -> Option<&PidFd> {
+        self.pidfd.as_deref()
+    }
+
+    /// Returns how the process exited, or `None` if it is still running. If the process was
+    /// already reaped by `Child::wait`, its exit status is unknown.
+    pub fn try_exited(&self) -> Option<ProcessExited> {
+        let mut info = MaybeUninit::<libc::siginfo_t>::zeroed();
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        let mut res = -1;
+        #[cfg(target_os = "linux")]
+        if let Some(pidfd) = &self.pidfd {
+            let id = pidfd.as_raw_fd() as libc::id_t;
+            res = waitid(libc::P_PIDFD, id, &mut info, flags);
+            // Waiting on pidfds needs Linux 5.4, one version later than opening them.
+            if res < 0 && io::Error::last_os_error().raw_os_error() != Some(libc::EINVAL) {
+                return Some(ProcessExited {
+                    pid: self.pid,
+                    exit_code: None,
+                    signal: None,
+                });
+            }
+        }
+        if res < 0 {
+            res = waitid(libc::P_PID, self.pid as libc::id_t, &mut info, flags);
+        }
+        let mut exited = ProcessExited {
+            pid: self.pid,
+            exit_code: None,
+            signal: None,
+        };
+        if res < 0 {
+            return Some(exited);
+        }
+
+        let info = unsafe { info.assume_init() };
+        if unsafe { info.si_pid() } == 0 {
+            return None;
+        }
+        let status = unsafe { info.si_status() };
+        match info.si_code {
+            libc::CLD_EXITED => exited.exit_code = Some(status),
+            _ => exited.signal = Some(status),
+        }
+        Some(exited)
+    }
+
+    /// Wait for the process to exit.
+    pub async fn exited(&self) -> ProcessExited {
+        if let Some(pidfd) = &self.pidfd {
+            if pidfd.exited().await.is_ok() {
+                if let Some(exited) = self.try_exited() {
+                    return exited;
+                }
+            }
+        }
+        loop {
+            if let Some(exited) = self.try_exited() {
+                return exited;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Wait for up to `period` for the process to exit. Returns `None` if it is still running.
+    pub async fn exited_within(&self, period: Duration) -> Option<ProcessExited> {
+        tokio::time::timeout(period, self.exited()).await.ok()
+    }
+}
+
+/// `waitid(2)`, retried if it is interrupted by a signal.
+fn waitid(
+    idtype: libc::idtype_t,
+    id: libc::id_t,
+    info: &mut MaybeUninit<libc::siginfo_t>,
+    flags: libc::c_int,
+) -> libc::c_int {
+    loop {
+        let res = unsafe { libc::waitid(idtype, id, info.as_mut_ptr(), flags) };
+        if res < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        return res;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[tokio::test]
+    async fn test_child_exit() {
+        let mut child = process::Command::new("sh")
+            .args(["-c", "exit 3"])
+            .spawn()
+            .unwrap();
+        let monitor = ChildMonitor::new(&child);
+        assert_eq!(monitor.exited().await.exit_code, Some(3));
+        // The child has not been reaped.
+        assert_eq!(child.wait().unwrap().code(), Some(3));
+        assert_eq!(monitor.try_exited().unwrap().exit_code, None);
+    }
+
+    #[tokio::test]
+    async fn test_child_killed() {
+        let mut child = process::Command::new("sleep").arg("10").spawn().unwrap();
+        let monitor = ChildMonitor::new(&child);
+        assert_eq!(monitor.exited_within(Duration::from_millis(50)).await, None);
+        child.kill().unwrap();
+        let exited = monitor.exited().await;
+        assert_eq!(exited.signal, Some(libc::SIGKILL));
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_pidfd() {
+        let mut child = process::Command::new("sleep").arg("10").spawn().unwrap();
+        let Ok(pidfd) = PidFd::open(child.id()) else {
+            // Older kernels don't support pidfds.
+            child.kill().unwrap();
+            child.wait().unwrap();
+            return;
+        };
+        pidfd.send_signal(libc::SIGTERM).unwrap();
+        pidfd.exited().await.unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+        // The process is gone, and its pid may be reused, but the pidfd doesn't refer to that.
+        assert_eq!(
+            pidfd.send_signal(libc::SIGTERM).unwrap_err().raw_os_error(),
+            Some(libc::ESRCH)
+        );
+    }
+}
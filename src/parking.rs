@@ -0,0 +1,276 @@
+//! Passing connections left in the accept queue of a listener to the new process, rather than
+//! resetting them when the listener is closed.
+//!
+//! Listeners that the new process inherits keep their accept queue across a restart. Listeners
+//! that it binds itself with `SO_REUSEPORT` do not: the kernel spreads new connections over the
+//! listeners of both processes, and connections that were queued on a listener of the old process
+//! are reset when it closes the listener, unless `net.ipv4.tcp_migrate_req` is enabled.
+//!
+//! With `RestartConfig::park_connections`, once the old process has stopped accepting, e.g. when
+//! `cutover::accept_turn` returns false, it hands the listener to `park` instead of closing it.
+//! `park` accepts the connections that are queued, without reading from them, closes the listener
+//! and passes them to the new process with `SCM_RIGHTS`. Connections that arrive once the listener
+//! is closed are queued on the listener of the new process instead. The new process receives them
+//! with `receive`, and serves them like connections it accepted itself:
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! if let Some(mut parked) = shellflip::parking::receive()? {
+//!     tokio::spawn(async move {
+//!         while let Ok(Some(connection)) = parked.next().await {
+//!             let stream = std::net::TcpStream::from(connection.stream);
+//!             // Serve the connection accepted from `connection.listener`...
+//! #           drop(stream);
+//!         }
+//!     });
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Connections are held, with their data unread, in the socket they are passed over until the new
+//! process receives them. The new process should call `receive` even if it has no use for parked
+//! connections, so that the socket is not leaked into the process after it.
+use crate::diagnostics;
+#[cfg(test)]
+use crate::pipes::seqpacket_pair;
+use crate::pipes::set_cloexec;
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use std::env;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::Interest;
+use tokio::net::UnixStream;
+
+/// The fd of the socket that the new process receives parked connections on.
+pub(crate) const ENV_PARKING_SOCKET: &str = "OXY_PARKING_SOCKET";
+/// The longest listener name that can be passed along with a connection.
+const MAX_NAME_LEN: usize = 256;
+/// How long `park` waits for the new process to make room for more connections in the socket.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The socket that connections are passed to the new process on, once it has been spawned.
+static SOCKET: Mutex<Option<OwnedFd>> = Mutex::new(None);
+/// Whether `receive` took the socket passed by the old process. The environment variable is left
+/// as it is, as changing the environment of a multithreaded process is unsound, and the next new
+/// process gets its own.
+static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+/// A connection accepted by the old process and passed on to this one.
+#[derive(Debug)]
+pub struct ParkedConnection {
+    /// The name the old process gave to `park`.
+    pub listener: String,
+    pub stream: OwnedFd,
+}
+
+/// Receives the connections parked by the old process.
+#[derive(Debug)]
+pub struct ParkedConnections {
+    socket: UnixStream,
+}
+
+impl ParkedConnections {
+    /// Receive the next parked connection. Returns `None` once the old process exited.
+    pub async fn next(&mut self) -> io::Result<Option<ParkedConnection>> {
+        let socket = &self.socket;
+        socket
+            .async_io(Interest::READABLE, || {
+                let mut name = [0u8; MAX_NAME_LEN];
+                let mut iov = [IoSliceMut::new(&mut name)];
+                let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+                let msg = recvmsg::<()>(
+                    socket.as_raw_fd(),
+                    &mut iov,
+                    Some(&mut cmsg),
+                    MsgFlags::MSG_CMSG_CLOEXEC,
+                )?;
+                let mut stream = None;
+                for c in msg.cmsgs() {
+                    if let ControlMessageOwned::ScmRights(received) = c {
+                        for fd in received {
+                            stream = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+                        }
+                    }
+                }
+                let len = msg.bytes;
+                let Some(stream) = stream else {
+                    return match len {
+                        0 => Ok(None),
+                        _ => Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "parked connection without an fd",
+                        )),
+                    };
+                };
+                Ok(Some(ParkedConnection {
+                    listener: String::from_utf8_lossy(&name[..len]).into_owned(),
+                    stream,
+                }))
+            })
+            .await
+    }
+}
+
+/// Takes the socket that the old process passes parked connections on, if it was started with
+/// `RestartConfig::park_connections`. Returns `None` after the first call.
+pub fn receive() -> io::Result<Option<ParkedConnections>> {
+    let Some(fd) = env::var_os(ENV_PARKING_SOCKET) else {
+        return Ok(None);
+    };
+    if RECEIVED.swap(true, Ordering::Relaxed) {
+        return Ok(None);
+    }
+    let fd: RawFd = fd
+        .to_str()
+        .and_then(|fd| fd.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid parking socket"))?;
+    // Don't leak the socket into the next process.
+    set_cloexec(fd)?;
+    let socket = StdUnixStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    socket.set_nonblocking(true)?;
+    Ok(Some(ParkedConnections {
+        socket: UnixStream::from_std(socket)?,
+    }))
+}
+
+/// Accepts the connections queued on `listener`, closes it, and passes the connections to the new
+/// process under `name`. Call this once the restart completed and the new process accepts
+/// connections itself. Returns the number of connections passed. If there is no new process to
+/// pass them to, the listener is closed and this fails.
+pub async fn park(name: &str, listener: impl Into<OwnedFd>) -> io::Result<usize> {
+    let listener = listener.into();
+    if name.len() > MAX_NAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "listener name too long",
+        ));
+    }
+    let socket = match &*SOCKET.lock().unwrap() {
+        Some(socket) => socket.try_clone()?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no new process to pass connections to",
+            ))
+        }
+    };
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || {
+        let connections = accept_queued(listener)?;
+        let count = connections.len();
+        let socket = StdUnixStream::from(socket);
+        socket.set_write_timeout(Some(SEND_TIMEOUT))?;
+        for (i, connection) in connections.iter().enumerate() {
+            let sent = sendmsg::<()>(
+                socket.as_raw_fd(),
+                &[IoSlice::new(name.as_bytes())],
+                &[ControlMessage::ScmRights(&[connection.as_raw_fd()])],
+                MsgFlags::empty(),
+                None,
+            );
+            if let Err(e) = sent {
+                diagnostics::warn!(
+                    "Closing {} connections from {} that could not be parked: {}",
+                    count - i,
+                    name,
+                    e
+                );
+                return Ok(i);
+            }
+        }
+        if count > 0 {
+            diagnostics::info!("Parked {} connections from {}", count, name);
+        }
+        Ok(count)
+    })
+    .await?
+}
+
+/// Accept every connection that is queued on `listener`, and close it.
+fn accept_queued(listener: OwnedFd) -> io::Result<Vec<OwnedFd>> {
+    let fd = listener.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut connections = Vec::new();
+    loop {
+        let conn = unsafe {
+            libc::accept4(
+                fd,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        if conn >= 0 {
+            connections.push(unsafe { OwnedFd::from_raw_fd(conn) });
+            continue;
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::WouldBlock => return Ok(connections),
+            io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted => {}
+            _ => return Err(err),
+        }
+    }
+}
+
+/// A new process was spawned that receives parked connections on the other end of `socket`.
+pub(crate) fn new_process_spawned(socket: OwnedFd) {
+    *SOCKET.lock().unwrap() = Some(socket);
+}
+
+/// The restart failed, so there is no new process to pass connections to.
+pub(crate) fn reset() {
+    SOCKET.lock().unwrap().take();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_park() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut clients: Vec<TcpStream> =
+            (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for (i, client) in clients.iter_mut().enumerate() {
+            client.write_all(&[i as u8]).unwrap();
+        }
+
+        // Without a new process, the listener is just closed.
+        let other = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(park("other", other).await.is_err());
+
+        let (ours, theirs) = seqpacket_pair().unwrap();
+        new_process_spawned(ours);
+        assert_eq!(park("http", listener).await.unwrap(), 3);
+        reset();
+
+        let theirs = StdUnixStream::from(theirs);
+        theirs.set_nonblocking(true).unwrap();
+        let mut parked = ParkedConnections {
+            socket: UnixStream::from_std(theirs).unwrap(),
+        };
+        for i in 0..3 {
+            let connection = parked.next().await.unwrap().unwrap();
+            assert_eq!(connection.listener, "http");
+            // The data sent before the connection was parked is still there.
+            let mut stream = TcpStream::from(connection.stream);
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], i);
+        }
+        // The old process closed its end.
+        assert!(parked.next().await.unwrap().is_none());
+    }
+}
@@ -1,7 +1,14 @@
 use libc::c_int;
 use std::fs::File;
-use std::io::{self, Read, Write};
-use std::os::fd::{AsRawFd, FromRawFd};
+use std::io::{self, IoSlice, Read, Write};
+#[cfg(target_os = "linux")]
+use std::os::fd::OwnedFd;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd};
+use std::pin::Pin;
+use std::ptr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::oneshot;
 
 pub(crate) enum PipeMode {
     ParentWrites,
@@ -33,7 +40,42 @@ pub(crate) fn create_paired_pipes(mode: PipeMode) -> io::Result<(File, File)> {
     Ok((reader, writer))
 }
 
-fn set_cloexec(fd: c_int) -> io::Result<()> {
+/// Set the capacity of a pipe. Unprivileged processes are limited to `/proc/sys/fs/pipe-max-size`.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_pipe_size(pipe: &File, size: usize) -> io::Result<()> {
+    let size = c_int::try_from(size).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let res = unsafe { libc::fcntl(pipe.as_raw_fd(), libc::F_SETPIPE_SZ, size) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Create a pair of connected `SOCK_SEQPACKET` sockets, e.g. to pass fds to a new process after it
+/// has been spawned. Both are close-on-exec.
+#[cfg(target_os = "linux")]
+pub(crate) fn seqpacket_pair() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds: [c_int; 2] = [0; 2];
+    let res = unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_pipe_size(_pipe: &File, _size: usize) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+pub(crate) fn set_cloexec(fd: c_int) -> io::Result<()> {
     let res = unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
     if res != 0 {
         return Err(io::Error::last_os_error());
@@ -57,10 +99,7 @@ impl CompletionReceiver {
         let mut buf = [0u8; 1];
 
         if self.0.read(&mut buf)? != 1 {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "child failed to notify parent",
-            ))
+            Err(io::Error::other("child failed to notify parent"))
         } else {
             Ok(())
         }
@@ -72,16 +111,151 @@ pub(crate) struct CompletionSender(pub(crate) File);
 impl CompletionSender {
     pub(crate) fn send(&mut self) -> io::Result<()> {
         if self.0.write(b"1")? != 1 {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to signal parent to close",
-            ))
+            Err(io::Error::other("failed to signal parent to close"))
         } else {
             Ok(())
         }
     }
 }
 
+/// The write end of the handover pipe, given to `LifecycleHandler::send_to_new_process`.
+///
+/// Writes are buffered, and buffered data is written out whenever the pipe is flushed. Whatever is
+/// still buffered when the pipe is dropped is written out once `send_to_new_process` returns.
+pub struct PipeWriter {
+    inner: Option<BufWriter<tokio::fs::File>>,
+    unflushed_tx: Option<oneshot::Sender<BufWriter<tokio::fs::File>>>,
+}
+
+impl PipeWriter {
+    pub(crate) fn new(
+        pipe: File,
+        capacity: usize,
+    ) -> (Self, oneshot::Receiver<BufWriter<tokio::fs::File>>) {
+        let (unflushed_tx, unflushed_rx) = oneshot::channel();
+        let writer = PipeWriter {
+            inner: Some(BufWriter::with_capacity(capacity, pipe.into())),
+            unflushed_tx: Some(unflushed_tx),
+        };
+        (writer, unflushed_rx)
+    }
+
+    fn inner(&mut self) -> Pin<&mut BufWriter<tokio::fs::File>> {
+        Pin::new(self.inner.as_mut().expect("writer is only taken on drop"))
+    }
+
+    /// Move up to `len` bytes from `fd` into the pipe, starting at the current offset of `fd`,
+    /// which is advanced. This is useful for state that already sits in a file or socket. On Linux
+    /// the data is moved within the kernel using `splice`, instead of being copied through this
+    /// process. The pipe is flushed first, so the data follows anything written before.
+    ///
+    /// Returns the number of bytes moved, which is less than `len` if `fd` reached end of file.
+    pub async fn splice_from(&mut self, fd: BorrowedFd<'_>, len: u64) -> io::Result<u64> {
+        self.flush().await?;
+        let src = File::from(fd.try_clone_to_owned()?);
+        let dst = File::from(self.inner().get_ref().as_fd().try_clone_to_owned()?);
+        // The fds are duplicated, so they stay valid even if this future is dropped.
+        tokio::task::spawn_blocking(move || splice_all(&src, &dst, len)).await?
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn splice_all(src: &File, dst: &File, len: u64) -> io::Result<u64> {
+    let mut moved = 0;
+    while moved < len {
+        let chunk = usize::try_from(len - moved).unwrap_or(usize::MAX);
+        let res = unsafe {
+            libc::splice(
+                src.as_raw_fd(),
+                ptr::null_mut(),
+                dst.as_raw_fd(),
+                ptr::null_mut(),
+                chunk,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_MORE,
+            )
+        };
+        match res {
+            0 => break,
+            n if n > 0 => moved += n as u64,
+            _ => {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EINTR) => {}
+                    Some(libc::EAGAIN) => wait_readable(src)?,
+                    // The source does not support splicing.
+                    Some(libc::EINVAL) if moved == 0 => return copy_all(src, dst, len),
+                    _ => return Err(e),
+                }
+            }
+        }
+    }
+    Ok(moved)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn splice_all(src: &File, dst: &File, len: u64) -> io::Result<u64> {
+    copy_all(src, dst, len)
+}
+
+fn copy_all(src: &File, mut dst: &File, len: u64) -> io::Result<u64> {
+    io::copy(&mut src.take(len), &mut dst)
+}
+
+/// Wait until a non-blocking fd has data to read.
+#[cfg(target_os = "linux")]
+fn wait_readable(fd: &File) -> io::Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+impl AsyncWrite for PipeWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.inner().poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.inner().poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_shutdown(cx)
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        if let (Some(inner), Some(tx)) = (self.inner.take(), self.unflushed_tx.take()) {
+            let _ = tx.send(inner);
+        }
+    }
+}
+
 pub(crate) trait FdStringExt {
     fn fd_string(&self) -> String;
     unsafe fn from_fd_string(fd_str: &str) -> io::Result<Self>
@@ -104,3 +278,70 @@ impl<T: AsRawFd + FromRawFd> FdStringExt for T {
         self.as_raw_fd().to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom};
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_handover_writer_flushes_on_drop() {
+        let (r, w) = create_paired_pipes(PipeMode::ParentWrites).unwrap();
+        let (mut writer, unflushed) = PipeWriter::new(w, 1024);
+        for i in 0..10 {
+            writer.write_u32(i).await.unwrap();
+        }
+        drop(writer);
+
+        let mut unflushed = unflushed.await.unwrap();
+        assert_eq!(unflushed.buffer().len(), 40);
+        unflushed.flush().await.unwrap();
+        drop(unflushed);
+
+        let mut r = tokio::fs::File::from(r);
+        for i in 0..10 {
+            assert_eq!(r.read_u32().await.unwrap(), i);
+        }
+        assert_eq!(
+            r.read_u8().await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[tokio::test]
+    async fn test_splice_from() {
+        let path = std::env::temp_dir().join(format!("shellflip-splice-{}", std::process::id()));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.write_all(b"skip:spliced").unwrap();
+        file.seek(SeekFrom::Start(5)).unwrap();
+
+        let (r, w) = create_paired_pipes(PipeMode::ParentWrites).unwrap();
+        let (mut writer, unflushed) = PipeWriter::new(w, 1024);
+        writer.write_all(b"buffered:").await.unwrap();
+        assert_eq!(writer.splice_from(file.as_fd(), 100).await.unwrap(), 7);
+        drop((writer, unflushed));
+
+        let mut received = String::new();
+        let mut r = tokio::fs::File::from(r);
+        r.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, "buffered:spliced");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_set_pipe_size() {
+        let (_r, w) = create_paired_pipes(PipeMode::ParentWrites).unwrap();
+        set_pipe_size(&w, 1024 * 1024).unwrap();
+        let size = unsafe { libc::fcntl(w.as_raw_fd(), libc::F_GETPIPE_SZ) };
+        assert_eq!(size, 1024 * 1024);
+    }
+}
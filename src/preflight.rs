@@ -0,0 +1,145 @@
+//! Checks that must pass before a restart spawns the new process, e.g. asking the load balancer
+//! in front of the machine whether it has been drained upstream.
+//!
+//! The checks in `RestartConfig::preflight_checks` run in order once a restart request has been
+//! accepted, after any wait for the previous generation or for load to drop, and before any
+//! `LifecycleHandler` method is called for the restart. The first check that fails refuses the
+//! restart: nothing has been changed at that point, so this process carries on as if it had not
+//! been requested, and the requester gets `Error::PreflightRefused` with the name of the check and
+//! its reason. A check that takes longer than `RestartConfig::preflight_timeout` refuses the
+//! restart as well.
+//!
+//! ```no_run
+//! use shellflip::preflight::PreflightCheck;
+//! use shellflip::RestartId;
+//!
+//! struct DrainedUpstream;
+//!
+//! #[async_trait::async_trait]
+//! impl PreflightCheck for DrainedUpstream {
+//!     fn name(&self) -> &str {
+//!         "drained-upstream"
+//!     }
+//!
+//!     async fn check(&self, _restart_id: &RestartId) -> Result<(), String> {
+//!         // Ask the load balancer...
+//!         Err("node still receives 20% of traffic".into())
+//!     }
+//! }
+//! // Add `Box::new(DrainedUpstream)` to `RestartConfig::preflight_checks`.
+//! ```
+use crate::{diagnostics, RestartId};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::timeout;
+
+/// A check that must pass before the new process is spawned.
+#[async_trait]
+pub trait PreflightCheck: Send + Sync {
+    /// The name of the check, reported along with the reason when it refuses a restart.
+    fn name(&self) -> &str;
+
+    /// Returns the reason to refuse the restart with, if it should not go ahead.
+    async fn check(&self, restart_id: &RestartId) -> Result<(), String>;
+}
+
+/// A pre-flight check refused the restart.
+#[derive(Error, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[error("pre-flight check {check} refused the restart: {reason}")]
+pub struct PreflightRefused {
+    /// The name of the check, see `PreflightCheck::name`.
+    pub check: String,
+    pub reason: String,
+}
+
+/// Run `checks` in order, stopping at the first that refuses the restart.
+pub(crate) async fn run(
+    checks: &[Box<dyn PreflightCheck>],
+    restart_id: &RestartId,
+    limit: Duration,
+) -> Result<(), PreflightRefused> {
+    for check in checks {
+        let res = match timeout(limit, check.check(restart_id)).await {
+            Ok(res) => res,
+            Err(_) => Err(format!("timed out after {limit:?}")),
+        };
+        if let Err(reason) = res {
+            return Err(PreflightRefused {
+                check: check.name().to_string(),
+                reason,
+            });
+        }
+        diagnostics::debug!(
+            restart_id = restart_id;
+            "Pre-flight check {} passed for restart {}",
+            check.name(),
+            restart_id
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct Check {
+        name: &'static str,
+        result: Result<(), &'static str>,
+        delay: Duration,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl PreflightCheck for Check {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn check(&self, _restart_id: &RestartId) -> Result<(), String> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            self.result.map_err(String::from)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let check = |name, result, delay| -> Box<dyn PreflightCheck> {
+            Box::new(Check {
+                name,
+                result,
+                delay: Duration::from_secs(delay),
+                calls: calls.clone(),
+            })
+        };
+        let restart_id = RestartId::from("deploy-1");
+        let limit = Duration::from_secs(10);
+
+        let checks = [check("health", Ok(()), 1), check("upstream", Ok(()), 1)];
+        assert_eq!(run(&checks, &restart_id, limit).await, Ok(()));
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 2);
+
+        // The first refusal stops the checks.
+        let checks = [
+            check("upstream", Err("not drained"), 1),
+            check("health", Ok(()), 1),
+        ];
+        let refused = run(&checks, &restart_id, limit).await.unwrap_err();
+        assert_eq!(refused.check, "upstream");
+        assert_eq!(
+            refused.to_string(),
+            "pre-flight check upstream refused the restart: not drained"
+        );
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 1);
+
+        let checks = [check("slow", Ok(()), 60)];
+        let refused = run(&checks, &restart_id, limit).await.unwrap_err();
+        assert_eq!(refused.reason, "timed out after 10s");
+    }
+}
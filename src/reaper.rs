@@ -0,0 +1,434 @@
+//! Bookkeeping for helper processes that the service spawns itself.
+//!
+//! Helpers that outlive the process that spawned them are a problem during restarts: once the old
+//! process exits, they are reparented to init, and nothing stops them or knows they exist. Helpers
+//! that exit while the old process is still draining stay zombies until it exits.
+//!
+//! Register helpers with `track` or `track_child`, and call `spawn_reaper` once to reap them as
+//! they exit. `terminate_all` forwards shutdown to the tracked helpers, e.g. once the old process
+//! has drained, and waits for them to exit.
+//!
+//! The new process is told which helpers the old one tracked when it was spawned. It cannot reap
+//! them, as they are not its children, but it can take them over with `adopt`, so that they are
+//! tracked and terminated like its own helpers, or stop whatever it doesn't want with
+//! `terminate_inherited`. On Linux, a process is only recognised as the same helper if its start
+//! time matches, so that a reused pid is never signalled. Other platforms have no `/proc` to read
+//! the start time from, and only check that the pid exists.
+use crate::diagnostics;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::process;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+
+pub(crate) const ENV_CHILDREN: &str = "OXY_CHILDREN";
+
+/// How often helpers are checked for exit besides on SIGCHLD, which adopted helpers don't raise in
+/// this process.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tracked helpers by pid.
+static TRACKED: Mutex<BTreeMap<u32, Helper>> = Mutex::new(BTreeMap::new());
+/// Helpers tracked by the old process that have not been adopted yet.
+static INHERITED: OnceLock<Mutex<BTreeMap<u32, Helper>>> = OnceLock::new();
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Helper {
+    name: String,
+    /// The start time of the process in clock ticks after boot, if known.
+    start_time: Option<u64>,
+    /// Whether the process is a child of another process, so that it can't be reaped here.
+    adopted: bool,
+}
+
+/// A tracked helper process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrackedProcess {
+    pub pid: u32,
+    pub name: String,
+    /// Whether the process was adopted from the old process.
+    pub adopted: bool,
+}
+
+/// A tracked helper process that exited.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExitedHelper {
+    pub pid: u32,
+    pub name: String,
+    /// The exit code, if the process exited normally and its status is known. The status of
+    /// adopted processes is never known.
+    pub exit_code: Option<i32>,
+    /// The signal that terminated the process, if any.
+    pub signal: Option<i32>,
+}
+
+impl fmt::Display for ExitedHelper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "helper {} ({}) ", self.name, self.pid)?;
+        match (self.exit_code, self.signal) {
+            (Some(code), _) => write!(f, "exited with code {code}"),
+            (None, Some(signal)) => write!(f, "was killed by signal {signal}"),
+            (None, None) => write!(f, "exited"),
+        }
+    }
+}
+
+/// Track the child process `pid` under `name`. Tracking a pid again replaces its name.
+pub fn track(name: &str, pid: u32) {
+    let helper = Helper {
+        name: name.to_string(),
+        start_time: start_time(pid),
+        adopted: false,
+    };
+    TRACKED.lock().unwrap().insert(pid, helper);
+}
+
+/// Track a child spawned with `std::process::Command` under `name`. Don't also wait for it with
+/// `Child::wait`, as only one of them gets its exit status.
+pub fn track_child(name: &str, child: &process::Child) {
+    track(name, child.id());
+}
+
+/// Stop tracking `pid`, e.g. when the application waits for it itself.
+pub fn untrack(pid: u32) {
+    TRACKED.lock().unwrap().remove(&pid);
+}
+
+/// The tracked helpers.
+pub fn tracked() -> Vec<TrackedProcess> {
+    list(&TRACKED.lock().unwrap())
+}
+
+/// The helpers the old process tracked that are still running and have not been adopted.
+pub fn inherited() -> Vec<TrackedProcess> {
+    let mut inherited = inherited_helpers().lock().unwrap();
+    inherited.retain(|pid, helper| is_running(*pid, helper));
+    list(&inherited)
+}
+
+/// Track the helper `pid` inherited from the old process. Returns false if there is no such
+/// helper, or it is no longer running.
+pub fn adopt(pid: u32) -> bool {
+    let Some(helper) = inherited_helpers().lock().unwrap().remove(&pid) else {
+        return false;
+    };
+    if !is_running(pid, &helper) {
+        return false;
+    }
+    diagnostics::info!("Adopting helper {} ({})", helper.name, pid);
+    TRACKED.lock().unwrap().insert(pid, helper);
+    true
+}
+
+/// Send `signal` to the helpers inherited from the old process that have not been adopted, and
+/// stop tracking them. Returns how many were signalled.
+pub fn terminate_inherited(signal: i32) -> usize {
+    let inherited = std::mem::take(&mut *inherited_helpers().lock().unwrap());
+    inherited
+        .into_iter()
+        .filter(|(pid, helper)| {
+            let sent = send_signal(*pid, helper, signal);
+            if sent {
+                diagnostics::info!(
+                    "Sent signal {} to inherited helper {} ({})",
+                    signal,
+                    helper.name,
+                    pid
+                );
+            }
+            sent
+        })
+        .count()
+}
+
+/// Reap the tracked helpers that exited, and stop tracking them.
+pub fn reap() -> Vec<ExitedHelper> {
+    let mut tracked = TRACKED.lock().unwrap();
+    let mut exited = Vec::new();
+    tracked.retain(|&pid, helper| {
+        let status = match helper.adopted {
+            false => try_wait(pid),
+            true => (!is_running(pid, helper)).then_some((None, None)),
+        };
+        match status {
+            Some((exit_code, signal)) => {
+                exited.push(ExitedHelper {
+                    pid,
+                    name: helper.name.clone(),
+                    exit_code,
+                    signal,
+                });
+                false
+            }
+            None => true,
+        }
+    });
+    exited
+}
+
+/// Reap tracked helpers in the background as they exit, logging each exit.
+pub fn spawn_reaper() -> io::Result<JoinHandle<()>> {
+    let mut sigchld = signal(SignalKind::child())?;
+    Ok(tokio::spawn(async move {
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = sigchld.recv() => {}
+                _ = poll.tick() => {}
+            }
+            for exited in reap() {
+                diagnostics::info!(pid = exited.pid; "Reaped {}", exited);
+            }
+        }
+    }))
+}
+
+/// Send SIGTERM to the tracked helpers, and SIGKILL to those that are still running after `grace`.
+/// Returns the helpers that exited.
+pub async fn terminate_all(grace: Duration) -> Vec<ExitedHelper> {
+    signal_all(libc::SIGTERM);
+    let mut exited = reap();
+    let deadline = tokio::time::Instant::now() + grace;
+    while !TRACKED.lock().unwrap().is_empty() && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(POLL_INTERVAL.min(grace)).await;
+        exited.extend(reap());
+    }
+    if signal_all(libc::SIGKILL) > 0 {
+        diagnostics::warn!("Killed helpers still running after {:?}", grace);
+        // Killed children exit right away, but may not have been reaped by the kernel yet.
+        for _ in 0..10 {
+            exited.extend(reap());
+            if TRACKED.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    exited
+}
+
+/// Send `signal` to the tracked helpers. Returns how many were signalled.
+pub fn signal_all(signal: i32) -> usize {
+    let tracked = TRACKED.lock().unwrap();
+    tracked
+        .iter()
+        .filter(|(pid, helper)| send_signal(**pid, helper, signal))
+        .count()
+}
+
+/// The value of `ENV_CHILDREN` for a new process, or `None` if no helpers are tracked.
+pub(crate) fn for_new_process() -> Option<String> {
+    let tracked = TRACKED.lock().unwrap();
+    if tracked.is_empty() {
+        return None;
+    }
+    let entries = tracked.iter().map(|(pid, helper)| {
+        let start_time = helper.start_time.map(|t| t.to_string()).unwrap_or_default();
+        format!("{}:{}:{}", pid, start_time, escape(&helper.name))
+    });
+    Some(entries.collect::<Vec<_>>().join(","))
+}
+
+fn list(helpers: &BTreeMap<u32, Helper>) -> Vec<TrackedProcess> {
+    helpers
+        .iter()
+        .map(|(pid, helper)| TrackedProcess {
+            pid: *pid,
+            name: helper.name.clone(),
+            adopted: helper.adopted,
+        })
+        .collect()
+}
+
+fn inherited_helpers() -> &'static Mutex<BTreeMap<u32, Helper>> {
+    INHERITED
+        .get_or_init(|| Mutex::new(parse_children(&env::var(ENV_CHILDREN).unwrap_or_default())))
+}
+
+fn parse_children(value: &str) -> BTreeMap<u32, Helper> {
+    value
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let parsed = parse_child(entry);
+            if parsed.is_none() {
+                diagnostics::warn!("Ignoring malformed inherited helper {:?}", entry);
+            }
+            parsed
+        })
+        .collect()
+}
+
+fn parse_child(entry: &str) -> Option<(u32, Helper)> {
+    let mut parts = entry.splitn(3, ':');
+    let pid = parts.next()?.parse().ok()?;
+    let start_time = match parts.next()? {
+        "" => None,
+        t => Some(t.parse().ok()?),
+    };
+    let helper = Helper {
+        name: unescape(parts.next()?)?,
+        start_time,
+        adopted: true,
+    };
+    Some((pid, helper))
+}
+
+/// Escape `,`, which separates helpers in `ENV_CHILDREN`, and `%` itself as `%XX`.
+fn escape(name: &str) -> String {
+    name.replace('%', "%25").replace(',', "%2C")
+}
+
+fn unescape(name: &str) -> Option<String> {
+    let mut unescaped = Vec::with_capacity(name.len());
+    let mut bytes = name.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                unescaped.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b => unescaped.push(b),
+        }
+    }
+    String::from_utf8(unescaped).ok()
+}
+
+/// Returns the exit code or signal of a child that exited, reaping it.
+fn try_wait(pid: u32) -> Option<(Option<i32>, Option<i32>)> {
+    let mut info = MaybeUninit::<libc::siginfo_t>::zeroed();
+    loop {
+        let res = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                info.as_mut_ptr(),
+                libc::WEXITED | libc::WNOHANG,
+            )
+        };
+        if res == 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            // Not a child of this process, or already reaped.
+            diagnostics::debug!("Failed to wait for helper {}: {}", pid, e);
+            return Some((None, None));
+        }
+    }
+    let info = unsafe { info.assume_init() };
+    if unsafe { info.si_pid() } == 0 {
+        return None;
+    }
+    let status = unsafe { info.si_status() };
+    match info.si_code {
+        libc::CLD_EXITED => Some((Some(status), None)),
+        _ => Some((None, Some(status))),
+    }
+}
+
+/// Whether `pid` is still the process that was tracked, and has not exited.
+fn is_running(pid: u32, helper: &Helper) -> bool {
+    match (start_time(pid), helper.start_time) {
+        (Some(now), Some(then)) => now == then && !is_zombie(pid),
+        (Some(_), None) => !is_zombie(pid),
+        // Without `/proc`, all that can be checked is that the pid exists.
+        (None, _) if !cfg!(target_os = "linux") => {
+            let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+            res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+        }
+        (None, _) => false,
+    }
+}
+
+fn send_signal(pid: u32, helper: &Helper, signal: i32) -> bool {
+    // Only adopted helpers can have been reaped and their pid reused, as this process reaps its
+    // own children.
+    if helper.adopted && !is_running(pid, helper) {
+        return false;
+    }
+    unsafe { libc::kill(pid as libc::pid_t, signal) == 0 }
+}
+
+/// The fields of `/proc/<pid>/stat` after the command name, starting with the state.
+#[cfg(target_os = "linux")]
+fn stat_fields(pid: u32) -> Option<Vec<String>> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name may contain spaces and parentheses, so skip to the last parenthesis.
+    let (_, rest) = stat.rsplit_once(')')?;
+    Some(rest.split_whitespace().map(String::from).collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn stat_fields(_pid: u32) -> Option<Vec<String>> {
+    None
+}
+
+fn start_time(pid: u32) -> Option<u64> {
+    // Field 22, `starttime`, counting from 1 with the pid and command name first.
+    stat_fields(pid)?.get(19)?.parse().ok()
+}
+
+fn is_zombie(pid: u32) -> bool {
+    stat_fields(pid).is_some_and(|fields| fields.first().is_some_and(|state| state == "Z"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The child is reaped by `terminate_all`.
+    #[allow(clippy::zombie_processes)]
+    #[tokio::test]
+    async fn test_terminate_all() {
+        let child = process::Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = child.id();
+        track_child("sleeper", &child);
+        assert!(tracked().iter().any(|t| t.pid == pid && !t.adopted));
+        assert!(for_new_process().unwrap().contains(&format!("{pid}:")));
+
+        let exited = terminate_all(Duration::from_secs(5)).await;
+        let exited = exited.iter().find(|e| e.pid == pid).unwrap();
+        assert_eq!(exited.signal, Some(libc::SIGTERM));
+        assert!(!tracked().iter().any(|t| t.pid == pid));
+    }
+
+    #[test]
+    fn test_escape() {
+        for name in ["plain", "a,b", "100%", "%2C,", "ünï"] {
+            assert_eq!(unescape(&escape(name)).as_deref(), Some(name));
+        }
+        assert!(!escape("a,b").contains(','));
+        assert_eq!(unescape("%2"), None);
+        assert_eq!(unescape("%zz"), None);
+
+        let children = parse_children(&format!("1:2:{},3:4:b", escape("a,b")));
+        assert_eq!(children[&1].name, "a,b");
+        assert_eq!(children[&3].name, "b");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_children() {
+        let pid = process::id();
+        let start = start_time(pid).unwrap();
+        let children = parse_children(&format!("{pid}:{start}:self,{pid}1:1:other:name,bad"));
+        assert_eq!(children.len(), 2);
+        assert!(is_running(pid, &children[&pid]));
+        assert_eq!(children[&(pid * 10 + 1)].name, "other:name");
+
+        // A reused pid is not the same helper.
+        let reused = Helper {
+            start_time: Some(start + 1),
+            ..children[&pid].clone()
+        };
+        assert!(!is_running(pid, &reused));
+        assert!(!send_signal(pid, &reused, 0));
+    }
+}
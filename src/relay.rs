@@ -0,0 +1,160 @@
+//! Relays the output of the new process through this process until it signals readiness.
+//!
+//! Panics and errors printed by a new binary before it is ready would otherwise go wherever this
+//! process's output goes, far away from the operator who requested the restart. With relaying, the
+//! new process starts with its stdout and stderr connected to pipes that are read by this process,
+//! and is given copies of the original fds. Once it signals readiness, it restores the originals
+//! and the pipes are closed.
+use crate::{diagnostics, RestartId};
+use std::collections::VecDeque;
+use std::env;
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+
+const ENV_RELAY_STDIO: &str = "OXY_RELAY_STDIO";
+/// The number of stderr lines kept for reporting startup failures.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// A line of output from the new process.
+pub(crate) struct ChildOutput {
+    pub(crate) restart_id: RestartId,
+    pub(crate) line: String,
+}
+
+/// Copies of this process's stdout and stderr, for the new process to restore once it is ready.
+pub(crate) struct SavedStdio {
+    stdout: OwnedFd,
+    stderr: OwnedFd,
+}
+
+impl SavedStdio {
+    pub(crate) fn new() -> io::Result<Self> {
+        Ok(SavedStdio {
+            stdout: io::stdout().as_fd().try_clone_to_owned()?,
+            stderr: io::stderr().as_fd().try_clone_to_owned()?,
+        })
+    }
+
+    pub(crate) fn fds(&self) -> [RawFd; 2] {
+        [self.stdout.as_raw_fd(), self.stderr.as_raw_fd()]
+    }
+
+    /// Connect the output of the new process to pipes. The new process must also inherit the
+    /// saved fds.
+    pub(crate) fn configure(&self, cmd: &mut Command) {
+        let [stdout, stderr] = self.fds();
+        cmd.stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .env(ENV_RELAY_STDIO, format!("{stdout},{stderr}"));
+    }
+}
+
+/// The output being relayed from the new process.
+pub(crate) struct RelayedOutput {
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    stderr_thread: Option<JoinHandle<()>>,
+}
+
+impl RelayedOutput {
+    /// The last lines written to stderr. If the new process exited, this waits briefly for the
+    /// rest of its output to be read.
+    pub(crate) async fn stderr_tail(self) -> Vec<String> {
+        if let Some(thread) = self.stderr_thread {
+            for _ in 0..10 {
+                if thread.is_finished() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        let tail = self.stderr_tail.lock().unwrap();
+        tail.iter().cloned().collect()
+    }
+}
+
+/// Log each line of output from the new process, and pass it on to `output_tx` if there is room.
+/// Relaying stops when the new process restores its own output or exits.
+pub(crate) fn relay_output(
+    child: &mut Child,
+    restart_id: &RestartId,
+    output_tx: &Sender<ChildOutput>,
+) -> RelayedOutput {
+    let pid = child.id();
+    let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
+    if let Some(stdout) = child.stdout.take() {
+        let output_tx = output_tx.clone();
+        relay_stream(stdout, pid, "stdout", restart_id.clone(), output_tx, None);
+    }
+    let stderr_thread = child.stderr.take().map(|stderr| {
+        let tail = Some(Arc::clone(&stderr_tail));
+        relay_stream(
+            stderr,
+            pid,
+            "stderr",
+            restart_id.clone(),
+            output_tx.clone(),
+            tail,
+        )
+    });
+    RelayedOutput {
+        stderr_tail,
+        stderr_thread,
+    }
+}
+
+fn relay_stream(
+    stream: impl Read + Send + 'static,
+    pid: u32,
+    name: &'static str,
+    restart_id: RestartId,
+    output_tx: Sender<ChildOutput>,
+    tail: Option<Arc<Mutex<VecDeque<String>>>>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut buf = Vec::new();
+        while let Ok(n) = reader.read_until(b'\n', &mut buf) {
+            if n == 0 {
+                break;
+            }
+            let line = String::from_utf8_lossy(&buf).trim_end().to_string();
+            buf.clear();
+            diagnostics::info!("New process {} {}: {}", pid, name, line);
+            if let Some(tail) = &tail {
+                let mut tail = tail.lock().unwrap();
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(line.clone());
+            }
+            let _ = output_tx.try_send(ChildOutput {
+                restart_id: restart_id.clone(),
+                line,
+            });
+        }
+    })
+}
+
+/// If the parent process is relaying our output, restore the original stdout and stderr.
+pub(crate) fn restore_stdio() {
+    let saved = match env::var(ENV_RELAY_STDIO) {
+        Ok(saved) => saved,
+        Err(_) => return,
+    };
+    env::remove_var(ENV_RELAY_STDIO);
+
+    let fds = saved.split(',').map(str::parse::<RawFd>);
+    for (saved, target) in fds.zip([libc::STDOUT_FILENO, libc::STDERR_FILENO]) {
+        if let Ok(saved) = saved {
+            unsafe {
+                libc::dup2(saved, target);
+                libc::close(saved);
+            }
+        }
+    }
+}
@@ -1,17 +1,48 @@
 //! Communication with a running process over a unix domain socket.
-use crate::RestartResult;
-use anyhow::{anyhow, Context};
+use crate::handshake::IncompatibleBinary;
+use crate::lineage::Ancestor;
+use crate::preflight::PreflightRefused;
+use crate::shutdown::DrainSummary;
+use crate::{diagnostics, Error, RestartResult};
+use bytes::Bytes;
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::io::Interest;
 use tokio::net::UnixStream;
+use tokio::sync::OwnedSemaphorePermit;
 use tokio_util::codec::length_delimited::LengthDelimitedCodec;
 use tokio_util::codec::{Decoder, Framed};
 
+/// Receives the lines of output of the new process relayed during a restart, see
+/// `RestartOptions::relay_output`.
+pub type ChildOutputHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// The most fds that can be passed with a restart request.
+pub const MAX_REQUEST_FDS: usize = 32;
+
 /// Represents the restart coordination socket, used for communicating with a running oxy process.
 /// This is used to trigger a restart and receive notification of its completion or failure.
 pub struct RestartCoordinationSocket {
     codec: Framed<UnixStream, LengthDelimitedCodec>,
+    /// Counts the connection against the limit of the serving side until it is closed.
+    permit: Option<OwnedSemaphorePermit>,
+    /// Receives the output of the new process relayed during a restart.
+    child_output: Option<ChildOutputHook>,
+    /// The client this connection was made for by another part of this process, e.g. the D-Bus
+    /// interface, whose credentials the socket doesn't carry.
+    peer: Option<PeerCredentials>,
 }
 
 impl RestartCoordinationSocket {
@@ -19,9 +50,52 @@ impl RestartCoordinationSocket {
     pub fn new(socket: UnixStream) -> Self {
         RestartCoordinationSocket {
             codec: LengthDelimitedCodec::new().framed(socket),
+            permit: None,
+            child_output: None,
+            peer: None,
         }
     }
 
+    /// Apply the limits of the serving side: the largest message accepted, and a permit that
+    /// counts the connection against the connection limit.
+    pub(crate) fn limit(&mut self, max_message_size: usize, permit: OwnedSemaphorePermit) {
+        self.codec
+            .codec_mut()
+            .set_max_frame_length(max_message_size);
+        self.permit = Some(permit);
+    }
+
+    /// Count the connection against `permit` instead of the permit it held, or against no limit,
+    /// e.g. once its request has been read.
+    pub(crate) fn replace_permit(&mut self, permit: Option<OwnedSemaphorePermit>) {
+        self.permit = permit;
+    }
+
+    /// Pass each line of output of the new process to `f` when restarting with
+    /// `RestartOptions::relay_output`, instead of logging it.
+    pub fn on_child_output(&mut self, hook: ChildOutputHook) {
+        self.child_output = Some(hook);
+    }
+
+    /// Report `peer` as the client, rather than the process at the other end of the socket.
+    pub(crate) fn act_for(&mut self, peer: PeerCredentials) {
+        self.peer = Some(peer);
+    }
+
+    /// The credentials of the process at the other end of the socket, or of the client it acts
+    /// for.
+    pub fn peer_credentials(&self) -> io::Result<PeerCredentials> {
+        if let Some(peer) = self.peer {
+            return Ok(peer);
+        }
+        let cred = self.codec.get_ref().peer_cred()?;
+        Ok(PeerCredentials {
+            pid: cred.pid().map(|pid| pid as u32),
+            uid: cred.uid(),
+            gid: cred.gid(),
+        })
+    }
+
     /// Sends a restart command through the socket. Returns Ok(child_pid) on success or an error
     /// if the restart failed for any reason.
     pub async fn send_restart_command(&mut self) -> RestartResult<u32> {
@@ -30,16 +104,418 @@ impl RestartCoordinationSocket {
         match self.receive_message().await? {
             RestartMessage::Response(RestartResponse::RestartComplete(pid)) => Ok(pid),
             RestartMessage::Response(RestartResponse::RestartFailed(reason)) => {
-                Err(anyhow!(reason))
+                Err(Error::Rejected {
+                    restart_id: None,
+                    reason,
+                })
+            }
+            _ => Err(Error::unexpected_message()),
+        }
+    }
+
+    /// Sends a restart command with options through the socket. Returns the restart ID and child
+    /// pid on success or an error if the restart failed for any reason.
+    ///
+    /// If the running process predates `RestartRequest::TryRestartWith`, it closes the connection
+    /// without doing anything and `Error::Unsupported` is returned. The caller may then reconnect
+    /// and fall back to `send_restart_command`.
+    pub async fn send_restart_command_with(
+        &mut self,
+        options: RestartOptions,
+    ) -> RestartResult<RestartOutcome> {
+        self.restart_within(options, &[], None).await
+    }
+
+    /// Like `send_restart_command_with`, but gives up after `timeout`. On timeout,
+    /// `Error::TimedOut` is returned describing how far the restart got.
+    ///
+    /// Dropping the returned future closes the connection. The running process is not told to
+    /// abort the restart, which carries on regardless.
+    pub async fn send_restart_command_with_timeout(
+        &mut self,
+        options: RestartOptions,
+        timeout: Duration,
+    ) -> RestartResult<RestartOutcome> {
+        self.restart_within(options, &[], Some(timeout)).await
+    }
+
+    /// Like `send_restart_command_with`, but also passes `fds` to the new process, which picks
+    /// each one up by calling `files::take_inherited` with its name. Names may not contain `,` or
+    /// `=`, and at most `MAX_REQUEST_FDS` fds can be passed. The fds are only duplicated, so the
+    /// caller may close them once this returns.
+    ///
+    /// This lets a deploy tool hand over what the running process can't get itself, such as a
+    /// socket bound to a privileged port or a config file it can't read. Running processes that
+    /// predate this fail with `Error::Unsupported`, without restarting.
+    pub async fn send_restart_command_with_fds(
+        &mut self,
+        options: RestartOptions,
+        fds: &[(String, OwnedFd)],
+    ) -> RestartResult<RestartOutcome> {
+        self.restart_within(options, fds, None).await
+    }
+
+    /// Request a restart, giving up after `timeout` if set.
+    pub(crate) async fn restart_within(
+        &mut self,
+        options: RestartOptions,
+        fds: &[(String, OwnedFd)],
+        timeout: Option<Duration>,
+    ) -> RestartResult<RestartOutcome> {
+        let mut progress = RestartProgress::Requested;
+        let request = self.restart_command(options, fds, &mut progress);
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, request)
+                .await
+                .unwrap_or_else(|_| Err(RestartTimedOut { progress }.into())),
+            None => request.await,
+        }
+    }
+
+    async fn restart_command(
+        &mut self,
+        options: RestartOptions,
+        fds: &[(String, OwnedFd)],
+        progress: &mut RestartProgress,
+    ) -> RestartResult<RestartOutcome> {
+        let monitor_for = options.monitor_for;
+        if fds.is_empty() {
+            self.send_message(RestartMessage::Request(RestartRequest::TryRestartWith(
+                options,
+            )))
+            .await?;
+        } else {
+            self.send_requester_fds(options, fds).await?;
+        }
+        self.receive_restart(monitor_for, progress).await
+    }
+
+    /// Restarts the running process back into its previous binary slot, see `update::Slots`.
+    /// Returns the restart ID and child pid on success, like `send_restart_command_with`. The
+    /// running process rejects the request if it does not manage binary slots, or there is no
+    /// previous slot.
+    pub async fn send_rollback_command(
+        &mut self,
+        options: RestartOptions,
+    ) -> RestartResult<RestartOutcome> {
+        let monitor_for = options.monitor_for;
+        self.send_message(RestartMessage::Request(RestartRequest::Rollback(options)))
+            .await?;
+        self.receive_restart(monitor_for, &mut RestartProgress::Requested)
+            .await
+    }
+
+    /// Receive the responses to a restart request, up to the outcome of monitoring the new process
+    /// if the client asked for it.
+    async fn receive_restart(
+        &mut self,
+        monitor_for: Option<Duration>,
+        progress: &mut RestartProgress,
+    ) -> RestartResult<RestartOutcome> {
+        let restart_id = self
+            .receive_started()
+            .await?
+            .ok_or_else(Error::unexpected_message)?;
+        *progress = RestartProgress::Started(restart_id.clone());
+        let outcome = self.receive_outcome(restart_id).await?;
+        if monitor_for.is_some() {
+            self.receive_monitoring_result().await?;
+        }
+        Ok(outcome)
+    }
+
+    /// Send a restart request with fds attached, once the running process asks for them.
+    async fn send_requester_fds(
+        &mut self,
+        options: RestartOptions,
+        fds: &[(String, OwnedFd)],
+    ) -> RestartResult<()> {
+        if fds.len() > MAX_REQUEST_FDS {
+            return Err(Error::Protocol(format!(
+                "at most {MAX_REQUEST_FDS} fds can be passed with a restart request"
+            )));
+        }
+        let fd_names = fds.iter().map(|(name, _)| name.clone()).collect();
+        self.send_message(RestartMessage::Request(RestartRequest::TryRestartWithFds {
+            options,
+            fd_names,
+        }))
+        .await?;
+        match self.codec.next().await {
+            None => return Err(UnsupportedRequest.into()),
+            Some(message) => match serde_json::from_slice(&message?)? {
+                RestartMessage::Response(RestartResponse::SendFds) => {}
+                RestartMessage::Response(RestartResponse::RestartFailed(reason)) => {
+                    return Err(Error::Rejected {
+                        restart_id: None,
+                        reason,
+                    })
+                }
+                _ => return Err(Error::unexpected_message()),
+            },
+        }
+
+        let raw_fds: Vec<RawFd> = fds.iter().map(|(_, fd)| fd.as_raw_fd()).collect();
+        let socket = self.codec.get_ref();
+        socket
+            .async_io(Interest::WRITABLE, || {
+                sendmsg::<()>(
+                    socket.as_raw_fd(),
+                    &[IoSlice::new(b"F")],
+                    &[ControlMessage::ScmRights(&raw_fds)],
+                    MsgFlags::empty(),
+                    None,
+                )
+                .map_err(io::Error::from)
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Receive the fds attached to a restart request, once the requester has been asked for them.
+    pub(crate) async fn receive_requester_fds(&mut self, count: usize) -> io::Result<Vec<OwnedFd>> {
+        let socket = self.codec.get_ref();
+        let fds = socket
+            .async_io(Interest::READABLE, || {
+                let mut byte = [0u8; 1];
+                let mut iov = [IoSliceMut::new(&mut byte)];
+                let mut cmsg = nix::cmsg_space!([RawFd; MAX_REQUEST_FDS]);
+                let msg = recvmsg::<()>(
+                    socket.as_raw_fd(),
+                    &mut iov,
+                    Some(&mut cmsg),
+                    MsgFlags::MSG_CMSG_CLOEXEC,
+                )?;
+                let mut fds = Vec::new();
+                for c in msg.cmsgs() {
+                    if let ControlMessageOwned::ScmRights(received) = c {
+                        fds.extend(
+                            received
+                                .into_iter()
+                                .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                        );
+                    }
+                }
+                if msg.bytes == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                if msg.flags.contains(MsgFlags::MSG_CTRUNC) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "too many fds"));
+                }
+                Ok(fds)
+            })
+            .await?;
+        if fds.len() != count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected {count} fds, received {}", fds.len()),
+            ));
+        }
+        Ok(fds)
+    }
+
+    /// Receive the result of monitoring the new process after a restart.
+    async fn receive_monitoring_result(&mut self) -> RestartResult<()> {
+        match self.codec.next().await {
+            None => {
+                diagnostics::debug!("Old process exited while monitoring the new process");
+                Ok(())
+            }
+            Some(message) => match serde_json::from_slice(&message?)? {
+                RestartMessage::Response(RestartResponse::ProcessSurvived) => Ok(()),
+                RestartMessage::Response(RestartResponse::ProcessExited(exited)) => {
+                    Err(exited.into())
+                }
+                _ => Err(Error::unexpected_message()),
+            },
+        }
+    }
+
+    /// Passes a command to the application in the running process. Returns once the application
+    /// has been given the command, not once it has been carried out.
+    pub async fn send_command(&mut self, command: AdminCommand) -> RestartResult<()> {
+        self.send_message(RestartMessage::Request(RestartRequest::Command(command)))
+            .await?;
+        match self.codec.next().await {
+            None => Err(UnsupportedRequest.into()),
+            Some(message) => match serde_json::from_slice(&message?)? {
+                RestartMessage::Response(RestartResponse::CommandAccepted) => Ok(()),
+                RestartMessage::Response(RestartResponse::RestartFailed(reason)) => {
+                    Err(Error::Rejected {
+                        restart_id: None,
+                        reason,
+                    })
+                }
+                _ => Err(Error::unexpected_message()),
+            },
+        }
+    }
+
+    /// Asks the running process about its restart status.
+    pub async fn query_status(&mut self) -> RestartResult<StatusReport> {
+        self.send_message(RestartMessage::Request(RestartRequest::Status))
+            .await?;
+        match self.codec.next().await {
+            None => Err(UnsupportedRequest.into()),
+            Some(message) => match serde_json::from_slice(&message?)? {
+                RestartMessage::Response(RestartResponse::Status(status)) => Ok(status),
+                _ => Err(Error::unexpected_message()),
+            },
+        }
+    }
+
+    /// Subscribes to the lifecycle events of the running process, and returns its current status.
+    /// Call `next_event` to receive the events that follow.
+    pub async fn subscribe(&mut self) -> RestartResult<StatusReport> {
+        self.send_message(RestartMessage::Request(RestartRequest::Subscribe))
+            .await?;
+        match self.codec.next().await {
+            None => Err(UnsupportedRequest.into()),
+            Some(message) => match serde_json::from_slice(&message?)? {
+                RestartMessage::Response(RestartResponse::Status(status)) => Ok(status),
+                _ => Err(Error::unexpected_message()),
+            },
+        }
+    }
+
+    /// Receives the next event after `subscribe`. Returns `None` once the running process closes
+    /// the connection, which it does when it exits after draining.
+    pub async fn next_event(&mut self) -> RestartResult<Option<RestartEvent>> {
+        match self.codec.next().await {
+            None => Ok(None),
+            Some(message) => match serde_json::from_slice(&message?)? {
+                RestartMessage::Response(RestartResponse::Event(event)) => Ok(Some(event)),
+                _ => Err(Error::unexpected_message()),
+            },
+        }
+    }
+
+    /// Waits for the restart currently in progress in the running process to complete, without
+    /// starting a new one. Returns `None` if no restart was in progress.
+    pub async fn wait_for_restart(&mut self) -> RestartResult<Option<RestartOutcome>> {
+        self.send_message(RestartMessage::Request(RestartRequest::WaitForRestart))
+            .await?;
+        match self.receive_started().await? {
+            Some(restart_id) => self.receive_outcome(restart_id).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Cancels the restart in progress in the running process, or only the restart with the given
+    /// ID if specified. Returns the ID of the cancelled restart once it has been undone, or `None`
+    /// if there was no matching restart in progress. Fails if the restart already went past the
+    /// point where it can be cancelled.
+    pub async fn cancel_restart(
+        &mut self,
+        restart_id: Option<RestartId>,
+    ) -> RestartResult<Option<RestartId>> {
+        self.send_message(RestartMessage::Request(RestartRequest::CancelRestart(
+            restart_id,
+        )))
+        .await?;
+        let restart_id = match self.receive_started().await? {
+            Some(restart_id) => restart_id,
+            None => return Ok(None),
+        };
+        match self.receive_outcome(restart_id.clone()).await {
+            Err(Error::Cancelled(_)) => Ok(Some(restart_id)),
+            Err(e) => Err(e),
+            Ok(_) => Err(Error::AlreadyCompleted(restart_id)),
+        }
+    }
+
+    /// Commits the restart in progress in the running process, or only the restart with the given
+    /// ID if specified, once its new process is ready and it awaits a commit, see
+    /// `RestartOptions::await_commit`. Returns the outcome once the restart completes, or `None` if
+    /// there was no matching restart in progress. Fails if the restart is not awaiting a commit.
+    pub async fn commit_restart(
+        &mut self,
+        restart_id: Option<RestartId>,
+    ) -> RestartResult<Option<RestartOutcome>> {
+        self.send_message(RestartMessage::Request(RestartRequest::CommitRestart(
+            restart_id,
+        )))
+        .await?;
+        match self.receive_started().await? {
+            Some(restart_id) => self.receive_outcome(restart_id).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Receive the acknowledgement of a restart request. Returns `None` if no restart is in
+    /// progress.
+    async fn receive_started(&mut self) -> RestartResult<Option<RestartId>> {
+        match self.codec.next().await {
+            None => Err(UnsupportedRequest.into()),
+            Some(message) => match serde_json::from_slice(&message?)? {
+                RestartMessage::Response(RestartResponse::RestartStarted(id)) => Ok(Some(id)),
+                RestartMessage::Response(RestartResponse::NoRestartInProgress) => Ok(None),
+                RestartMessage::Response(RestartResponse::AlreadyRestarting(restart_id)) => {
+                    Err(AlreadyRestarting { restart_id }.into())
+                }
+                RestartMessage::Response(RestartResponse::RestartFailed(reason)) => {
+                    Err(Error::Rejected {
+                        restart_id: None,
+                        reason,
+                    })
+                }
+                _ => Err(Error::unexpected_message()),
+            },
+        }
+    }
+
+    /// Receive the result of an acknowledged restart.
+    async fn receive_outcome(&mut self, restart_id: RestartId) -> RestartResult<RestartOutcome> {
+        let mut message = self.receive_message().await?;
+        while let RestartMessage::Response(RestartResponse::ChildOutput(line)) = message {
+            match &self.child_output {
+                Some(hook) => hook(&line),
+                None => diagnostics::info!(restart_id = restart_id; "New process: {}", line),
+            }
+            message = self.receive_message().await?;
+        }
+        match message {
+            RestartMessage::Response(RestartResponse::RestartComplete(pid)) => {
+                Ok(RestartOutcome { restart_id, pid })
+            }
+            RestartMessage::Response(RestartResponse::RestartFailed(reason)) => {
+                Err(Error::Rejected {
+                    restart_id: Some(restart_id),
+                    reason,
+                })
             }
-            _ => Err(anyhow!("unexpected message received")),
+            RestartMessage::Response(RestartResponse::RestartCancelled(restart_id)) => {
+                Err(RestartCancelled { restart_id }.into())
+            }
+            RestartMessage::Response(RestartResponse::StartupFailed(failure)) => {
+                Err(Error::StartupFailed {
+                    restart_id,
+                    failure,
+                })
+            }
+            RestartMessage::Response(RestartResponse::PreflightRefused(refused)) => {
+                Err(Error::PreflightRefused {
+                    restart_id,
+                    refused,
+                })
+            }
+            RestartMessage::Response(RestartResponse::IncompatibleBinary(incompatible)) => {
+                Err(Error::IncompatibleBinary {
+                    restart_id,
+                    incompatible,
+                })
+            }
+            RestartMessage::Response(RestartResponse::PreviousGenerationRunning(pid)) => {
+                Err(Error::PreviousGenerationRunning { restart_id, pid })
+            }
+            _ => Err(Error::unexpected_message()),
         }
     }
 
     /// Send a message over the socket
     pub async fn send_message(&mut self, msg: RestartMessage) -> RestartResult<()> {
         self.codec
-            .send(serde_json::to_string(&msg).unwrap().into())
+            .send(Bytes::from(serde_json::to_string(&msg).unwrap()))
             .await?;
 
         Ok(())
@@ -47,11 +523,9 @@ impl RestartCoordinationSocket {
 
     /// Receive a message from the socket.
     pub async fn receive_message(&mut self) -> RestartResult<RestartMessage> {
-        let message = self
-            .codec
-            .next()
-            .await
-            .context("connection closed while awaiting a message")??;
+        let message = self.codec.next().await.ok_or_else(|| {
+            Error::Protocol("connection closed while awaiting a message".into())
+        })??;
 
         Ok(serde_json::from_slice(&message)?)
     }
@@ -67,22 +541,520 @@ pub enum RestartMessage {
 /// A request message that expects a response.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RestartRequest {
+    /// Restart with default options. Answered with `RestartComplete` or `RestartFailed`.
     TryRestart,
+    /// Restart with the given options. Answered with `RestartStarted` once the request has been
+    /// accepted, followed by `RestartComplete` or `RestartFailed`. If another restart is in
+    /// progress, answered with `AlreadyRestarting` instead.
+    TryRestartWith(RestartOptions),
+    /// Restart with the given options, passing fds supplied by the requester to the new process
+    /// under the given names. Answered with `SendFds`, after which the requester sends the fds in
+    /// a single message with `SCM_RIGHTS`, and then like `TryRestartWith`.
+    TryRestartWithFds {
+        options: RestartOptions,
+        fd_names: Vec<String>,
+    },
+    /// Query the restart status. Answered with `Status`.
+    Status,
+    /// Wait for the restart in progress to complete. Answered with `NoRestartInProgress`, or with
+    /// `RestartStarted` followed by `RestartComplete` or `RestartFailed`.
+    WaitForRestart,
+    /// Cancel the restart in progress, if it matches the given ID. Answered like `WaitForRestart`,
+    /// with `RestartCancelled` once the restart has been cancelled.
+    CancelRestart(Option<RestartId>),
+    /// Let the restart in progress complete, if it matches the given ID and awaits a commit.
+    /// Answered like `WaitForRestart`, or with `RestartFailed` if the restart does not await a
+    /// commit (yet).
+    CommitRestart(Option<RestartId>),
+    /// Pass a command to the application. Answered with `CommandAccepted` or `RestartFailed`.
+    Command(AdminCommand),
+    /// Restart into the previous binary slot, see `update::Slots`. Answered like `TryRestartWith`,
+    /// or with `RestartFailed` if there is no slot to roll back to.
+    Rollback(RestartOptions),
+    /// Subscribe to lifecycle events. Answered with `Status`, followed by an `Event` for each
+    /// event until either side closes the connection.
+    Subscribe,
 }
 
 /// A response to a request message.
+// Responses are sent once and dropped, so their size doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RestartResponse {
     // Restart completed. The child PID is provided.
     RestartComplete(u32),
     // Restart failed. The error message is attached.
     RestartFailed(String),
+    // Restart request accepted. The ID used for this restart is provided.
+    RestartStarted(RestartId),
+    // The current restart status.
+    Status(StatusReport),
+    // There is no restart in progress to wait for.
+    NoRestartInProgress,
+    // The request was rejected because another restart is in progress. Its ID is attached.
+    AlreadyRestarting(RestartId),
+    // The restart was cancelled and the new process killed. The restart ID is attached.
+    RestartCancelled(RestartId),
+    // The new process exited before it became ready. Sent instead of `RestartFailed` to clients
+    // that understand `RestartStarted`.
+    StartupFailed(StartupFailed),
+    // A pre-flight check refused the restart. Sent instead of `RestartFailed` to clients that
+    // understand `RestartStarted`.
+    PreflightRefused(PreflightRefused),
+    // The new binary can't read what the old process hands over, see `RestartConfig::handshake`.
+    // Sent instead of `RestartFailed` to clients that understand `RestartStarted`.
+    IncompatibleBinary(IncompatibleBinary),
+    // The process this one was restarted from is still running, see
+    // `RestartConfig::lingering_generation`. Its pid is attached. Sent instead of `RestartFailed`
+    // to clients that understand `RestartStarted`.
+    PreviousGenerationRunning(u32),
+    // The new process exited within the period given by `RestartOptions::monitor_for`.
+    ProcessExited(ProcessExited),
+    // The new process was still running at the end of the period given by
+    // `RestartOptions::monitor_for`.
+    ProcessSurvived,
+    // The command was passed to the application.
+    CommandAccepted,
+    // A line of output from the new process before it became ready. Only sent to clients that
+    // set `RestartOptions::relay_output`.
+    ChildOutput(String),
+    // A lifecycle event. Only sent to clients that subscribed.
+    Event(RestartEvent),
+    // Send the fds for `RestartRequest::TryRestartWithFds`.
+    SendFds,
+}
+
+/// The status of a running process, as returned by `shellflip::status_report` in the process
+/// itself and by the restart coordination socket to other processes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusReport {
+    /// The pid of the running process.
+    pub pid: u32,
+    /// The number of restarts that led to the running process, or 0 if it was not started by a
+    /// restart.
+    #[serde(default)]
+    pub generation: u32,
+    /// How long the running process has been up, counted from when it started.
+    #[serde(default)]
+    pub uptime: Duration,
+    /// The ID of the restart that started the running process, if it was started by a restart.
+    #[serde(default)]
+    pub restart_id: Option<RestartId>,
+    /// The restart currently in progress, if any.
+    #[serde(default)]
+    pub in_progress: Option<RestartInProgress>,
+    /// The number of live named handles of the `ShutdownCoordinator` given in
+    /// `RestartConfig::drain_stats`, if one was given.
+    #[serde(default)]
+    pub active_handles: Option<usize>,
+    /// How long the named handles of that `ShutdownCoordinator` took to close, once it started
+    /// shutting down.
+    #[serde(default)]
+    pub drain: Option<DrainSummary>,
+    /// The error message of the most recent restart, if it failed.
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// The earlier generations that led to the running process, oldest first, and whether they
+    /// are still running.
+    #[serde(default)]
+    pub ancestry: Vec<Ancestor>,
+}
+
+#[deprecated(note = "renamed to `StatusReport`")]
+pub type RestartStatus = StatusReport;
+
+/// A restart that has started but not yet completed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestartInProgress {
+    pub restart_id: RestartId,
+    pub phase: RestartPhase,
+    /// The client that requested the restart, if it was requested through the restart
+    /// coordination socket.
+    #[serde(default)]
+    pub requested_by: Option<PeerCredentials>,
+    /// The new process, once it has been spawned.
+    #[serde(default)]
+    pub child: Option<ChildIdentity>,
+}
+
+/// Identifies the new process of a restart, so that tooling can e.g. attach to it or start
+/// monitoring it before it serves.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChildIdentity {
+    pub pid: u32,
+    /// When the process started, in clock ticks since boot, from `/proc/<pid>/stat`. Unlike the
+    /// pid, the pair of both is not reused once the process exits. Only known on Linux.
+    #[serde(default)]
+    pub start_time: Option<u64>,
+    /// The inode numbers of the namespaces of the process by type, e.g. `net` or `pid`, from
+    /// `/proc/<pid>/ns`. Only known on Linux.
+    #[serde(default)]
+    pub namespaces: BTreeMap<String, u64>,
+}
+
+impl ChildIdentity {
+    /// The identity of the running process `pid`, with whatever could be read about it. This
+    /// reads from `/proc`, so async code should call it with `spawn_blocking`.
+    pub fn of(pid: u32) -> Self {
+        let proc = Path::new("/proc").join(pid.to_string());
+        let start_time = start_time(pid);
+        let namespaces = std::fs::read_dir(proc.join("ns"))
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let link = std::fs::read_link(entry.path()).ok()?;
+                let inode = parse_namespace_link(link.to_str()?)?;
+                Some((entry.file_name().into_string().ok()?, inode))
+            })
+            .collect();
+        ChildIdentity {
+            pid,
+            start_time,
+            namespaces,
+        }
+    }
+}
+
+/// The start time of the process `pid`, see `ChildIdentity::start_time`.
+pub(crate) fn start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    parse_start_time(&stat)
+}
+
+/// The start time in `/proc/<pid>/stat`, the 22nd field. The second field is the command name in
+/// parentheses, which may itself contain spaces and parentheses.
+fn parse_start_time(stat: &str) -> Option<u64> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(19)?.parse().ok()
 }
 
+/// The inode in a namespace link such as `net:[4026531840]`.
+fn parse_namespace_link(link: &str) -> Option<u64> {
+    let (_, inode) = link.split_once(":[")?;
+    inode.strip_suffix(']')?.parse().ok()
+}
+
+/// The credentials of a restart coordination socket client, as reported by the kernel when it
+/// connected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerCredentials {
+    /// Not known on all platforms.
+    pub pid: Option<u32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl fmt::Display for PeerCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(pid) = self.pid {
+            write!(f, "pid {pid} ")?;
+        }
+        write!(f, "uid {} gid {}", self.uid, self.gid)
+    }
+}
+
+/// The stages of a restart, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestartPhase {
+    /// Running `LifecycleHandler::pre_new_process` and spawning the new process.
+    Spawning,
+    /// Sending state to the new process with `LifecycleHandler::send_to_new_process`.
+    HandingOver,
+    /// Waiting for the new process to signal that it started successfully.
+    AwaitingReadiness,
+    /// The new process is ready, and both processes serve until the restart is committed with
+    /// `RestartRequest::CommitRestart`, see `RestartOptions::await_commit`.
+    AwaitingCommit,
+}
+
+/// A lifecycle event of the running process, sent to clients that subscribed with
+/// `RestartCoordinationSocket::subscribe`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestartEvent {
+    RestartStarted(RestartId),
+    /// The restart in progress entered a new phase.
+    PhaseChanged {
+        restart_id: RestartId,
+        phase: RestartPhase,
+    },
+    /// The new process was spawned, but is not ready yet.
+    ProcessSpawned {
+        restart_id: RestartId,
+        pid: u32,
+        /// Not sent by running processes that predate it.
+        #[serde(default)]
+        identity: Option<ChildIdentity>,
+    },
+    /// The new process is ready and has taken over.
+    RestartCompleted {
+        restart_id: RestartId,
+        pid: u32,
+    },
+    RestartFailed {
+        restart_id: RestartId,
+        reason: String,
+    },
+    RestartCancelled(RestartId),
+    /// How many of the named handles of the `ShutdownCoordinator` given in
+    /// `RestartConfig::drain_stats` are still running, once shutdown has started. Sent when the
+    /// numbers change.
+    DrainProgress {
+        handles: usize,
+        still_running: usize,
+    },
+    /// The given number of events were dropped, as the client did not keep up.
+    Lagged(u64),
+}
+
+/// A command for the application other than a restart, e.g. from an operator using
+/// `shellflip-admin`. The application receives these through `RestartConfig::admin_commands`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminCommand {
+    /// Shut down gracefully without starting a new process.
+    Shutdown,
+    /// Reload configuration without restarting.
+    Reload,
+    /// Reopen log files, e.g. after they were rotated. Files opened with `logs::open` are reopened
+    /// before the application receives this.
+    ReopenLogs,
+}
+
+impl fmt::Display for AdminCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdminCommand::Shutdown => f.write_str("shutdown"),
+            AdminCommand::Reload => f.write_str("reload"),
+            AdminCommand::ReopenLogs => f.write_str("reopen-logs"),
+        }
+    }
+}
+
+/// Options sent along with a restart request.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RestartOptions {
+    /// Correlation ID for the restart. If not set, the running process generates one.
+    #[serde(default)]
+    pub restart_id: Option<RestartId>,
+    /// If another restart is already in progress, wait for it to complete and then restart again,
+    /// instead of failing with `AlreadyRestarting`. This is handled by the client.
+    #[serde(skip)]
+    pub queue: bool,
+    /// Receive the output of the new process until it is ready, if the running process is
+    /// configured with `RestartConfig::relay_child_output`. Each line is passed to
+    /// `RestartConfig::child_output_hook` as it arrives, or logged if there is none.
+    #[serde(default)]
+    pub relay_output: bool,
+    /// After the restart completes, keep watching the new process for this long, and fail with
+    /// `ProcessExited` if it dies in the meantime. If the old process exits before the period
+    /// ends, the restart is assumed to have succeeded.
+    #[serde(default)]
+    pub monitor_for: Option<Duration>,
+    /// Once the new process is ready, keep this process serving as well until the restart is
+    /// committed, e.g. by an operator or a canary analysis, with `commit_restart`. If it is not
+    /// committed within this period, or it is cancelled instead, the new process is killed and
+    /// this process carries on alone. Running processes that predate this option ignore it, and
+    /// complete the restart right away.
+    #[serde(default)]
+    pub await_commit: Option<Duration>,
+    /// Don't send state to the new process with `LifecycleHandler::send_to_new_process`, so that
+    /// it starts with fresh state, but still pass it the listeners and other fds. Running
+    /// processes that predate this option ignore it, and hand over state as usual.
+    #[serde(default)]
+    pub stateless: bool,
+}
+
+/// The result of a successful restart request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestartOutcome {
+    /// The ID of the restart, which is also given to the new process.
+    pub restart_id: RestartId,
+    /// The pid of the new process.
+    pub pid: u32,
+}
+
+/// Identifies a single restart, so that it can be traced through the logs of both the old and new
+/// processes.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RestartId(String);
+
+impl RestartId {
+    /// Generate a new ID that is unique on this host.
+    pub fn generate() -> Self {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        RestartId(format!(
+            "{:x}{:08x}-{:x}-{:x}",
+            now.as_secs(),
+            now.subsec_nanos(),
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for RestartId {
+    fn from(id: String) -> Self {
+        RestartId(id)
+    }
+}
+
+impl From<&str> for RestartId {
+    fn from(id: &str) -> Self {
+        RestartId(id.into())
+    }
+}
+
+impl fmt::Display for RestartId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// How far a restart request got before the client stopped waiting for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RestartProgress {
+    /// The request was sent but the running process did not acknowledge it.
+    Requested,
+    /// The running process started the restart with the given ID, but it did not complete.
+    Started(RestartId),
+}
+
+impl fmt::Display for RestartProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestartProgress::Requested => f.write_str("request not acknowledged"),
+            RestartProgress::Started(id) => write!(f, "restart {id} still in progress"),
+        }
+    }
+}
+
+/// The restart request did not complete in time.
+#[derive(Error, Debug)]
+#[error("restart request timed out: {progress}")]
+pub struct RestartTimedOut {
+    pub progress: RestartProgress,
+}
+
+/// The restart request was rejected because another restart is in progress.
+#[derive(Error, Debug)]
+#[error("restart {restart_id} already in progress")]
+pub struct AlreadyRestarting {
+    pub restart_id: RestartId,
+}
+
+/// The restart was cancelled before the new process was ready.
+#[derive(Error, Debug)]
+#[error("restart {restart_id} cancelled")]
+pub struct RestartCancelled {
+    pub restart_id: RestartId,
+}
+
+/// The new process exited before it signalled readiness.
+#[derive(Error, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StartupFailed {
+    /// The exit code, if the new process exited normally.
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    /// The signal that terminated the new process, if any.
+    #[serde(default)]
+    pub signal: Option<i32>,
+    /// The last lines the new process wrote to stderr. Only captured if the running process is
+    /// configured with `RestartConfig::relay_child_output`.
+    #[serde(default)]
+    pub stderr_tail: Vec<String>,
+}
+
+impl fmt::Display for StartupFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.exit_code, self.signal) {
+            (Some(code), _) => write!(f, "new process exited with code {code}")?,
+            (None, Some(signal)) => write!(f, "new process was killed by signal {signal}")?,
+            (None, None) => write!(f, "new process exited")?,
+        }
+        write!(f, " before it was ready")?;
+        if !self.stderr_tail.is_empty() {
+            write!(f, ", last output:\n{}", self.stderr_tail.join("\n"))?;
+        }
+        Ok(())
+    }
+}
+
+/// The new process exited after the restart completed.
+#[derive(Error, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessExited {
+    pub pid: u32,
+    /// The exit code, if the process exited normally and its status is known.
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    /// The signal that terminated the process, if any.
+    #[serde(default)]
+    pub signal: Option<i32>,
+}
+
+impl fmt::Display for ProcessExited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "new process {} ", self.pid)?;
+        match (self.exit_code, self.signal) {
+            (Some(code), _) => write!(f, "exited with code {code}"),
+            (None, Some(signal)) => write!(f, "was killed by signal {signal}"),
+            (None, None) => write!(f, "exited"),
+        }
+    }
+}
+
+/// Returns the pid of the process that accepts connections on the restart coordination socket at
+/// `path`, or `Ok(None)` if it can't be determined. Fails if nothing accepts connections there,
+/// e.g. because the socket was left behind by a process that exited.
+pub(crate) fn running_instance(path: &Path) -> io::Result<Option<u32>> {
+    let socket = std::os::unix::net::UnixStream::connect(path)?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+        use std::os::fd::AsRawFd;
+        Ok(getsockopt(socket.as_raw_fd(), PeerCredentials)
+            .ok()
+            .map(|creds| creds.pid() as u32))
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        drop(socket);
+        Ok(None)
+    }
+}
+
+/// The running process closed the connection without acknowledging the request.
+#[derive(Error, Debug)]
+#[error("request not supported by the running process")]
+pub struct UnsupportedRequest;
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_running_instance() {
+        let path = std::env::temp_dir().join(format!("shellflip-running-{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let pid = running_instance(&path).unwrap();
+        if cfg!(target_os = "linux") {
+            assert_eq!(pid, Some(process::id()));
+        }
+
+        // A socket left behind by a process that exited.
+        drop(listener);
+        assert!(running_instance(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
     #[tokio::test]
     async fn test_restart_complete() {
         let (client, server) = UnixStream::pair().unwrap();
@@ -103,6 +1075,28 @@ mod tests {
         assert_eq!(client.send_restart_command().await.unwrap(), child_pid);
     }
 
+    #[tokio::test]
+    async fn test_limit() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+        let permits = std::sync::Arc::new(tokio::sync::Semaphore::new(1));
+        server.limit(16, permits.clone().try_acquire_owned().unwrap());
+        assert_eq!(permits.available_permits(), 0);
+
+        let request = RestartRequest::TryRestartWithFds {
+            options: RestartOptions::default(),
+            fd_names: vec!["x".repeat(32)],
+        };
+        client
+            .send_message(RestartMessage::Request(request))
+            .await
+            .unwrap();
+        assert!(server.receive_message().await.is_err());
+        drop(server);
+        assert_eq!(permits.available_permits(), 1);
+    }
+
     #[tokio::test]
     async fn test_restart_failed() {
         let (client, server) = UnixStream::pair().unwrap();
@@ -122,6 +1116,626 @@ mod tests {
         });
 
         let r = client.send_restart_command().await;
-        assert_eq!(r.err().map(|e| e.to_string()), Some(error_message.into()));
+        match r {
+            Err(Error::Rejected { restart_id, reason }) => {
+                assert_eq!(restart_id, None);
+                assert_eq!(reason, error_message);
+            }
+            r => panic!("unexpected result {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restart_with_id() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+        let restart_id = RestartId::from("deploy-1");
+        let child_pid = 42;
+
+        tokio::spawn(async move {
+            let message = server.receive_message().await.unwrap();
+            let id = match message {
+                RestartMessage::Request(RestartRequest::TryRestartWith(options)) => {
+                    options.restart_id.unwrap()
+                }
+                m => panic!("unexpected message {m:?}"),
+            };
+            let response = RestartMessage::Response(RestartResponse::RestartStarted(id));
+            server.send_message(response).await.unwrap();
+            let response = RestartMessage::Response(RestartResponse::RestartComplete(child_pid));
+            server.send_message(response).await.unwrap();
+        });
+
+        let options = RestartOptions {
+            restart_id: Some(restart_id.clone()),
+            ..Default::default()
+        };
+        assert_eq!(
+            client.send_restart_command_with(options).await.unwrap(),
+            RestartOutcome {
+                restart_id,
+                pid: child_pid
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restart_with_child_output() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+        let restart_id = RestartId::from("deploy-1");
+
+        let id = restart_id.clone();
+        tokio::spawn(async move {
+            match server.receive_message().await.unwrap() {
+                RestartMessage::Request(RestartRequest::TryRestartWith(options)) => {
+                    assert!(options.relay_output)
+                }
+                m => panic!("unexpected message {m:?}"),
+            };
+            for response in [
+                RestartResponse::RestartStarted(id),
+                RestartResponse::ChildOutput("starting up".into()),
+                RestartResponse::RestartComplete(42),
+            ] {
+                server
+                    .send_message(RestartMessage::Response(response))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let output = Arc::clone(&lines);
+        client.on_child_output(Arc::new(move |line: &str| {
+            output.lock().unwrap().push(line.to_string())
+        }));
+        let options = RestartOptions {
+            restart_id: Some(restart_id.clone()),
+            relay_output: true,
+            ..Default::default()
+        };
+        let outcome = client.send_restart_command_with(options).await.unwrap();
+        assert_eq!(
+            outcome,
+            RestartOutcome {
+                restart_id,
+                pid: 42
+            }
+        );
+        assert_eq!(*lines.lock().unwrap(), ["starting up"]);
+    }
+
+    #[tokio::test]
+    async fn test_restart_startup_failed() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+        let failure = StartupFailed {
+            exit_code: Some(101),
+            signal: None,
+            stderr_tail: vec!["thread 'main' panicked".into()],
+        };
+
+        let response = failure.clone();
+        tokio::spawn(async move {
+            server.receive_message().await.unwrap();
+            for response in [
+                RestartResponse::RestartStarted("deploy-1".into()),
+                RestartResponse::StartupFailed(response),
+            ] {
+                server
+                    .send_message(RestartMessage::Response(response))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let e = client
+            .send_restart_command_with(RestartOptions::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(&e, Error::StartupFailed { failure: f, .. } if *f == failure));
+        assert_eq!(
+            e.to_string(),
+            "restart deploy-1 failed: new process exited with code 101 before it was ready, \
+             last output:\nthread 'main' panicked"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restart_preflight_refused() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+        let refused = PreflightRefused {
+            check: "upstream".into(),
+            reason: "not drained".into(),
+        };
+
+        let response = refused.clone();
+        tokio::spawn(async move {
+            server.receive_message().await.unwrap();
+            for response in [
+                RestartResponse::RestartStarted("deploy-1".into()),
+                RestartResponse::PreflightRefused(response),
+            ] {
+                server
+                    .send_message(RestartMessage::Response(response))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let e = client
+            .send_restart_command_with(RestartOptions::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(&e, Error::PreflightRefused { refused: r, .. } if *r == refused));
+        assert_eq!(
+            e.to_string(),
+            "restart deploy-1 refused: pre-flight check upstream refused the restart: not drained"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restart_incompatible_binary() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+        let incompatible = IncompatibleBinary {
+            binary: "/usr/bin/example".into(),
+            build: "example 2.0".into(),
+            problems: vec!["it does not read schema sessions".into()],
+        };
+
+        let response = incompatible.clone();
+        tokio::spawn(async move {
+            server.receive_message().await.unwrap();
+            for response in [
+                RestartResponse::RestartStarted("deploy-1".into()),
+                RestartResponse::IncompatibleBinary(response),
+            ] {
+                server
+                    .send_message(RestartMessage::Response(response))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let e = client
+            .send_restart_command_with(RestartOptions::default())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&e, Error::IncompatibleBinary { incompatible: i, .. } if *i == incompatible)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restart_previous_generation_running() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+
+        tokio::spawn(async move {
+            server.receive_message().await.unwrap();
+            for response in [
+                RestartResponse::RestartStarted("deploy-1".into()),
+                RestartResponse::PreviousGenerationRunning(1234),
+            ] {
+                server
+                    .send_message(RestartMessage::Response(response))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let e = client
+            .send_restart_command_with(RestartOptions::default())
+            .await
+            .unwrap_err();
+        let Error::PreviousGenerationRunning { restart_id, pid } = e else {
+            panic!("unexpected error {e:?}");
+        };
+        assert_eq!((restart_id.as_str(), pid), ("deploy-1", 1234));
+    }
+
+    #[tokio::test]
+    async fn test_restart_with_monitoring() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+        let exited = ProcessExited {
+            pid: 42,
+            exit_code: None,
+            signal: Some(11),
+        };
+
+        let response = exited.clone();
+        tokio::spawn(async move {
+            match server.receive_message().await.unwrap() {
+                RestartMessage::Request(RestartRequest::TryRestartWith(options)) => {
+                    assert_eq!(options.monitor_for, Some(Duration::from_secs(10)))
+                }
+                m => panic!("unexpected message {m:?}"),
+            };
+            for response in [
+                RestartResponse::RestartStarted("deploy-1".into()),
+                RestartResponse::RestartComplete(42),
+                RestartResponse::ProcessExited(response),
+            ] {
+                server
+                    .send_message(RestartMessage::Response(response))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let options = RestartOptions {
+            monitor_for: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let e = client.send_restart_command_with(options).await.unwrap_err();
+        assert!(matches!(e, Error::ProcessExited(e) if e == exited));
+    }
+
+    #[tokio::test]
+    async fn test_command() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+
+        tokio::spawn(async move {
+            let message = server.receive_message().await.unwrap();
+            assert!(matches!(
+                message,
+                RestartMessage::Request(RestartRequest::Command(AdminCommand::Reload))
+            ));
+            let response = RestartMessage::Response(RestartResponse::CommandAccepted);
+            server.send_message(response).await.unwrap();
+        });
+
+        client.send_command(AdminCommand::Reload).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restart_with_unsupported() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+
+        tokio::spawn(async move {
+            // Older versions fail to parse the request and drop the connection.
+            let _ = server.receive_message().await;
+        });
+
+        let r = client
+            .send_restart_command_with(RestartOptions::default())
+            .await;
+        assert!(matches!(r, Err(Error::Unsupported(_))));
+    }
+
+    #[tokio::test]
+    async fn test_restart_already_in_progress() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+
+        tokio::spawn(async move {
+            let _ = server.receive_message().await.unwrap();
+            let response =
+                RestartMessage::Response(RestartResponse::AlreadyRestarting("first".into()));
+            server.send_message(response).await.unwrap();
+        });
+
+        let r = client
+            .send_restart_command_with(RestartOptions::default())
+            .await;
+        match r {
+            Err(Error::AlreadyRestarting(e)) => assert_eq!(e.restart_id, "first".into()),
+            r => panic!("unexpected result {r:?}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restart_with_timeout() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+
+        tokio::spawn(async move {
+            let _ = server.receive_message().await.unwrap();
+            let response = RestartMessage::Response(RestartResponse::RestartStarted("x".into()));
+            server.send_message(response).await.unwrap();
+            // Wedge mid-restart.
+            futures::future::pending::<()>().await;
+        });
+
+        let r = client
+            .send_restart_command_with_timeout(RestartOptions::default(), Duration::from_secs(5))
+            .await;
+        match r {
+            Err(Error::TimedOut(e)) => assert_eq!(e.progress, RestartProgress::Started("x".into())),
+            r => panic!("unexpected result {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_status() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+        let status = StatusReport {
+            pid: 42,
+            generation: 3,
+            uptime: Duration::from_secs(60),
+            restart_id: None,
+            in_progress: Some(RestartInProgress {
+                restart_id: "x".into(),
+                phase: RestartPhase::HandingOver,
+                requested_by: Some(PeerCredentials {
+                    pid: Some(1),
+                    uid: 0,
+                    gid: 0,
+                }),
+                child: Some(ChildIdentity {
+                    pid: 43,
+                    start_time: Some(1000),
+                    namespaces: [("net".to_string(), 4026531840)].into(),
+                }),
+            }),
+            active_handles: Some(2),
+            drain: Some(DrainSummary {
+                count: 3,
+                still_running: 2,
+                p99: Duration::from_millis(1500),
+                ..Default::default()
+            }),
+            last_error: Some("restart y failed".into()),
+            ancestry: vec![],
+        };
+
+        let server_status = status.clone();
+        tokio::spawn(async move {
+            let message = server.receive_message().await.unwrap();
+            assert!(matches!(
+                message,
+                RestartMessage::Request(RestartRequest::Status)
+            ));
+            let response = RestartMessage::Response(RestartResponse::Status(server_status));
+            server.send_message(response).await.unwrap();
+        });
+
+        assert_eq!(client.query_status().await.unwrap(), status);
+    }
+
+    #[test]
+    fn test_status_report_from_older_process() {
+        let status: StatusReport =
+            serde_json::from_str(r#"{"pid":42,"restart_id":"x","in_progress":null}"#).unwrap();
+        assert_eq!(status.restart_id, Some("x".into()));
+        assert_eq!(status.generation, 0);
+        assert_eq!(status.active_handles, None);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+        let status = StatusReport {
+            pid: 42,
+            generation: 0,
+            uptime: Duration::ZERO,
+            restart_id: None,
+            in_progress: None,
+            active_handles: None,
+            drain: None,
+            last_error: None,
+            ancestry: vec![],
+        };
+        let events = vec![
+            RestartEvent::RestartStarted("x".into()),
+            RestartEvent::RestartCompleted {
+                restart_id: "x".into(),
+                pid: 43,
+            },
+        ];
+
+        let (server_status, server_events) = (status.clone(), events.clone());
+        tokio::spawn(async move {
+            let message = server.receive_message().await.unwrap();
+            assert!(matches!(
+                message,
+                RestartMessage::Request(RestartRequest::Subscribe)
+            ));
+            let response = RestartMessage::Response(RestartResponse::Status(server_status));
+            server.send_message(response).await.unwrap();
+            for event in server_events {
+                let response = RestartMessage::Response(RestartResponse::Event(event));
+                server.send_message(response).await.unwrap();
+            }
+        });
+
+        assert_eq!(client.subscribe().await.unwrap(), status);
+        for event in events {
+            assert_eq!(client.next_event().await.unwrap(), Some(event));
+        }
+        assert_eq!(client.next_event().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_restart() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+
+        tokio::spawn(async move {
+            let message = server.receive_message().await.unwrap();
+            assert!(matches!(
+                message,
+                RestartMessage::Request(RestartRequest::WaitForRestart)
+            ));
+            let response = RestartMessage::Response(RestartResponse::RestartStarted("x".into()));
+            server.send_message(response).await.unwrap();
+            let response = RestartMessage::Response(RestartResponse::RestartComplete(42));
+            server.send_message(response).await.unwrap();
+        });
+
+        assert_eq!(
+            client.wait_for_restart().await.unwrap(),
+            Some(RestartOutcome {
+                restart_id: "x".into(),
+                pid: 42
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_restart_not_in_progress() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+
+        tokio::spawn(async move {
+            let _ = server.receive_message().await.unwrap();
+            let response = RestartMessage::Response(RestartResponse::NoRestartInProgress);
+            server.send_message(response).await.unwrap();
+        });
+
+        assert_eq!(client.wait_for_restart().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_restart() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+
+        tokio::spawn(async move {
+            let message = server.receive_message().await.unwrap();
+            assert!(matches!(
+                message,
+                RestartMessage::Request(RestartRequest::CancelRestart(None))
+            ));
+            let response = RestartMessage::Response(RestartResponse::RestartStarted("x".into()));
+            server.send_message(response).await.unwrap();
+            let response = RestartMessage::Response(RestartResponse::RestartCancelled("x".into()));
+            server.send_message(response).await.unwrap();
+        });
+
+        assert_eq!(client.cancel_restart(None).await.unwrap(), Some("x".into()));
+    }
+
+    #[tokio::test]
+    async fn test_restart_with_fds() {
+        use std::os::unix::fs::MetadataExt;
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let ino = file.metadata().unwrap().ino();
+
+        let server = tokio::spawn(async move {
+            match server.receive_message().await.unwrap() {
+                RestartMessage::Request(RestartRequest::TryRestartWithFds { fd_names, .. }) => {
+                    assert_eq!(fd_names, ["config"])
+                }
+                m => panic!("unexpected message {m:?}"),
+            };
+            let response = RestartMessage::Response(RestartResponse::SendFds);
+            server.send_message(response).await.unwrap();
+            let fds = server.receive_requester_fds(1).await.unwrap();
+            let response = RestartMessage::Response(RestartResponse::RestartStarted("x".into()));
+            server.send_message(response).await.unwrap();
+            let response = RestartMessage::Response(RestartResponse::RestartComplete(42));
+            server.send_message(response).await.unwrap();
+            fds
+        });
+
+        let fds = vec![("config".to_string(), OwnedFd::from(file))];
+        let outcome = client
+            .send_restart_command_with_fds(Default::default(), &fds)
+            .await
+            .unwrap();
+        assert_eq!(outcome.pid, 42);
+        let received = std::fs::File::from(server.await.unwrap().remove(0));
+        assert_eq!(received.metadata().unwrap().ino(), ino);
+    }
+
+    #[tokio::test]
+    async fn test_commit_restart() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+
+        tokio::spawn(async move {
+            let message = server.receive_message().await.unwrap();
+            assert!(matches!(
+                message,
+                RestartMessage::Request(RestartRequest::CommitRestart(Some(_)))
+            ));
+            let response = RestartMessage::Response(RestartResponse::RestartStarted("x".into()));
+            server.send_message(response).await.unwrap();
+            let response = RestartMessage::Response(RestartResponse::RestartComplete(42));
+            server.send_message(response).await.unwrap();
+        });
+
+        assert_eq!(
+            client.commit_restart(Some("x".into())).await.unwrap(),
+            Some(RestartOutcome {
+                restart_id: "x".into(),
+                pid: 42
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rollback() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = RestartCoordinationSocket::new(client);
+        let mut server = RestartCoordinationSocket::new(server);
+
+        tokio::spawn(async move {
+            let message = server.receive_message().await.unwrap();
+            assert!(matches!(
+                message,
+                RestartMessage::Request(RestartRequest::Rollback(_))
+            ));
+            let response = RestartResponse::RestartFailed("there is no previous slot".into());
+            server
+                .send_message(RestartMessage::Response(response))
+                .await
+                .unwrap();
+        });
+
+        match client.send_rollback_command(Default::default()).await {
+            Err(Error::Rejected { reason, .. }) => assert_eq!(reason, "there is no previous slot"),
+            r => panic!("unexpected result {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_child_identity() {
+        let stat = "42 (a (weird) name) S 1 42 42 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 \
+                    123456 1000 10 18446744073709551615";
+        assert_eq!(parse_start_time(stat), Some(123456));
+        assert_eq!(parse_namespace_link("net:[4026531840]"), Some(4026531840));
+        assert_eq!(parse_namespace_link("net"), None);
+
+        let identity = ChildIdentity::of(process::id());
+        if cfg!(target_os = "linux") {
+            assert!(identity.start_time.is_some());
+            assert!(identity.namespaces.contains_key("pid"));
+        }
+    }
+
+    #[test]
+    fn test_generated_restart_ids_are_unique() {
+        assert_ne!(RestartId::generate(), RestartId::generate());
     }
 }
@@ -0,0 +1,322 @@
+//! Tracks restarts of this process. This is shared between the restart task, the restart thread
+//! and coordination socket connections that wait for a restart to complete or subscribe to its
+//! events.
+use crate::handshake::IncompatibleBinary;
+use crate::monitor::PidFd;
+use crate::preflight::PreflightRefused;
+use crate::restart_coordination_socket::{
+    ChildIdentity, PeerCredentials, RestartEvent, RestartInProgress, RestartPhase, StartupFailed,
+};
+use crate::RestartId;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+
+/// The number of events buffered for each subscriber.
+const EVENT_CAPACITY: usize = 64;
+
+#[derive(Default)]
+pub(crate) struct RestartState {
+    pub(crate) in_progress: Option<RestartInProgress>,
+    /// The pid of the new process for the restart in progress, once it has been spawned.
+    child_pid: Option<u32>,
+    /// A pidfd for the new process, if pidfds are supported.
+    child_pidfd: Option<Arc<PidFd>>,
+    cancellation: Cancellation,
+    /// Whether the restart in progress was committed, if it awaits a commit.
+    commit_requested: bool,
+    /// The most recently completed restart.
+    pub(crate) last_result: Option<CompletedRestart>,
+}
+
+/// Whether the restart in progress may still be cancelled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Cancellation {
+    #[default]
+    Allowed,
+    Requested,
+    /// The new process signalled readiness, so the restart can no longer be undone.
+    TooLate,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CompletedRestart {
+    pub(crate) restart_id: RestartId,
+    /// The new process pid on success, or the error message.
+    pub(crate) result: Result<u32, String>,
+    pub(crate) cancelled: bool,
+    /// Details of the failure, if the new process exited before it was ready.
+    pub(crate) startup_failure: Option<StartupFailed>,
+    /// The refusal, if a pre-flight check refused the restart.
+    pub(crate) preflight_refused: Option<PreflightRefused>,
+    /// The problems, if the new binary can't read what this process hands over.
+    pub(crate) incompatible_binary: Option<IncompatibleBinary>,
+    /// The pid of the previous generation, if the restart was refused because it is still running.
+    pub(crate) previous_generation: Option<u32>,
+}
+
+/// The outcome of asking to cancel the restart in progress.
+pub(crate) enum CancelRequest {
+    NotInProgress,
+    Cancelling(RestartId),
+    TooLate(RestartId),
+}
+
+/// The outcome of asking to commit the restart in progress.
+pub(crate) enum CommitRequest {
+    NotInProgress,
+    Committing(RestartId),
+    NotAwaitingCommit(RestartId),
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedRestartState {
+    state: Arc<watch::Sender<RestartState>>,
+    events: broadcast::Sender<RestartEvent>,
+}
+
+impl Default for SharedRestartState {
+    fn default() -> Self {
+        SharedRestartState {
+            state: Default::default(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+}
+
+impl SharedRestartState {
+    pub(crate) fn subscribe(&self) -> watch::Receiver<RestartState> {
+        self.state.subscribe()
+    }
+
+    pub(crate) fn subscribe_events(&self) -> broadcast::Receiver<RestartEvent> {
+        self.events.subscribe()
+    }
+
+    fn send_event(&self, event: RestartEvent) {
+        // There may be no subscribers.
+        let _ = self.events.send(event);
+    }
+
+    pub(crate) fn in_progress(&self) -> Option<RestartInProgress> {
+        self.state.borrow().in_progress.clone()
+    }
+
+    /// The error message of the most recently completed restart, if it failed.
+    pub(crate) fn last_error(&self) -> Option<String> {
+        let state = self.state.borrow();
+        state.last_result.as_ref()?.result.clone().err()
+    }
+
+    pub(crate) fn begin(&self, restart_id: &RestartId, requested_by: Option<PeerCredentials>) {
+        self.state.send_modify(|s| {
+            s.in_progress = Some(RestartInProgress {
+                restart_id: restart_id.clone(),
+                phase: RestartPhase::Spawning,
+                requested_by,
+                child: None,
+            });
+            s.child_pid = None;
+            s.child_pidfd = None;
+            s.cancellation = Cancellation::Allowed;
+            s.commit_requested = false;
+        });
+        self.send_event(RestartEvent::RestartStarted(restart_id.clone()));
+    }
+
+    pub(crate) fn set_phase(&self, phase: RestartPhase) {
+        let mut restart_id = None;
+        self.state.send_modify(|s| {
+            if let Some(r) = &mut s.in_progress {
+                r.phase = phase;
+                restart_id = Some(r.restart_id.clone());
+            }
+        });
+        if let Some(restart_id) = restart_id {
+            self.send_event(RestartEvent::PhaseChanged { restart_id, phase });
+        }
+    }
+
+    pub(crate) fn cancel_requested(&self) -> bool {
+        self.state.borrow().cancellation == Cancellation::Requested
+    }
+
+    /// Record the pid of the new process so that it can be killed if the restart is cancelled.
+    /// Returns false if the restart was cancelled in the meantime, in which case the caller must
+    /// kill the new process itself.
+    pub(crate) fn child_spawned(
+        &self,
+        identity: &ChildIdentity,
+        pidfd: Option<Arc<PidFd>>,
+    ) -> bool {
+        let mut cancelled = false;
+        let mut restart_id = None;
+        self.state.send_modify(|s| {
+            s.child_pid = Some(identity.pid);
+            s.child_pidfd = pidfd;
+            cancelled = s.cancellation == Cancellation::Requested;
+            if let Some(r) = &mut s.in_progress {
+                r.child = Some(identity.clone());
+                restart_id = Some(r.restart_id.clone());
+            }
+        });
+        if let (Some(restart_id), false) = (restart_id, cancelled) {
+            self.send_event(RestartEvent::ProcessSpawned {
+                restart_id,
+                pid: identity.pid,
+                identity: Some(identity.clone()),
+            });
+        }
+        !cancelled
+    }
+
+    /// Mark the restart as past the point of no return, unless it was already cancelled. Returns
+    /// false if the restart was cancelled.
+    pub(crate) fn commit(&self) -> bool {
+        let mut committed = false;
+        self.state.send_modify(|s| {
+            if s.cancellation == Cancellation::Allowed {
+                s.cancellation = Cancellation::TooLate;
+                committed = true;
+            }
+        });
+        committed
+    }
+
+    /// Cancel the restart in progress if its ID matches, killing the new process if it was
+    /// already spawned.
+    pub(crate) fn cancel(&self, restart_id: Option<&RestartId>) -> CancelRequest {
+        let mut request = CancelRequest::NotInProgress;
+        self.state.send_modify(|s| {
+            let current = match &s.in_progress {
+                Some(r) if restart_id.is_none_or(|id| *id == r.restart_id) => r.restart_id.clone(),
+                _ => return,
+            };
+            request = match s.cancellation {
+                Cancellation::TooLate => CancelRequest::TooLate(current),
+                _ => {
+                    s.cancellation = Cancellation::Requested;
+                    if let Some(pidfd) = &s.child_pidfd {
+                        let _ = pidfd.send_signal(libc::SIGKILL);
+                    } else if let Some(pid) = s.child_pid {
+                        // The child hasn't been reaped yet, so its pid can't have been reused.
+                        let _ = kill(Pid::from_raw(pid as i32), Signal::SIGKILL);
+                    }
+                    CancelRequest::Cancelling(current)
+                }
+            };
+        });
+        request
+    }
+
+    /// Commit the restart in progress if its ID matches and it awaits a commit.
+    pub(crate) fn request_commit(&self, restart_id: Option<&RestartId>) -> CommitRequest {
+        let mut request = CommitRequest::NotInProgress;
+        self.state.send_modify(|s| {
+            let r = match &s.in_progress {
+                Some(r) if restart_id.is_none_or(|id| *id == r.restart_id) => r,
+                _ => return,
+            };
+            request = match (r.phase, s.cancellation) {
+                (RestartPhase::AwaitingCommit, Cancellation::Allowed) => {
+                    s.commit_requested = true;
+                    CommitRequest::Committing(r.restart_id.clone())
+                }
+                _ => CommitRequest::NotAwaitingCommit(r.restart_id.clone()),
+            };
+        });
+        request
+    }
+
+    /// Wait until the restart in progress is either committed or cancelled. Returns true if it was
+    /// committed.
+    pub(crate) async fn wait_for_commit(&self) -> bool {
+        let mut state = self.state.subscribe();
+        let state = state
+            .wait_for(|s| s.commit_requested || s.cancellation == Cancellation::Requested)
+            .await
+            .expect("the sender is not dropped while borrowed");
+        state.commit_requested
+    }
+
+    pub(crate) fn complete(&self, completed: CompletedRestart) {
+        let restart_id = completed.restart_id.clone();
+        let event = match &completed.result {
+            _ if completed.cancelled => RestartEvent::RestartCancelled(restart_id),
+            Ok(pid) => RestartEvent::RestartCompleted {
+                restart_id,
+                pid: *pid,
+            },
+            Err(reason) => RestartEvent::RestartFailed {
+                restart_id,
+                reason: reason.clone(),
+            },
+        };
+        self.state.send_modify(|s| {
+            s.in_progress = None;
+            s.child_pid = None;
+            s.child_pidfd = None;
+            s.last_result = Some(completed);
+        });
+        self.send_event(event);
+    }
+}
+
+/// Wait for the restart with the given ID to complete. Returns `None` if the restart task went
+/// away before that happened.
+pub(crate) async fn wait_for_completion(
+    state: &mut watch::Receiver<RestartState>,
+    restart_id: &RestartId,
+) -> Option<CompletedRestart> {
+    loop {
+        if let Some(completed) = &state.borrow_and_update().last_result {
+            if completed.restart_id == *restart_id {
+                return Some(completed.clone());
+            }
+        }
+        state.changed().await.ok()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_commit() {
+        let state = SharedRestartState::default();
+        let id = RestartId::from("x");
+        state.begin(&id, None);
+        assert!(matches!(
+            state.request_commit(None),
+            CommitRequest::NotAwaitingCommit(_)
+        ));
+
+        state.set_phase(RestartPhase::AwaitingCommit);
+        let waiting = tokio::spawn({
+            let state = state.clone();
+            async move { state.wait_for_commit().await }
+        });
+        assert!(matches!(
+            state.request_commit(Some(&"y".into())),
+            CommitRequest::NotInProgress
+        ));
+        assert!(matches!(
+            state.request_commit(Some(&id)),
+            CommitRequest::Committing(_)
+        ));
+        assert!(waiting.await.unwrap());
+        assert!(state.commit());
+
+        // A cancelled restart can't be committed.
+        state.begin(&id, None);
+        state.set_phase(RestartPhase::AwaitingCommit);
+        assert!(matches!(state.cancel(None), CancelRequest::Cancelling(_)));
+        assert!(!state.wait_for_commit().await);
+        assert!(matches!(
+            state.request_commit(None),
+            CommitRequest::NotAwaitingCommit(_)
+        ));
+    }
+}
@@ -0,0 +1,270 @@
+//! The nice value, scheduling policy and CPU affinity of the new process.
+//!
+//! On Linux these are attributes of each thread, and a new process takes them from the thread
+//! that spawns it, which is the restart thread rather than the main thread that a latency-tuned
+//! service would have pinned or prioritised. With `Scheduling::inherit`, the new process takes on
+//! the attributes of the main thread instead, and those configured in `Scheduling` override them.
+//! They are set in the new process before it executes the binary, so the new process is forked
+//! even with `SpawnMethod::PosixSpawn`, and the restart thread keeps its own attributes.
+//!
+//! A policy inherited from the main thread keeps its `SCHED_RESET_ON_FORK` flag, so processes that
+//! the new process spawns don't inherit a real-time policy either.
+use crate::diagnostics;
+use nix::errno::Errno;
+use std::io;
+use std::mem;
+
+/// A scheduling policy, with its priority for real-time policies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    Other,
+    Batch,
+    Idle,
+    Fifo(i32),
+    RoundRobin(i32),
+}
+
+/// The scheduling attributes of the new process. By default, nothing is changed, and the new
+/// process keeps those of the restart thread.
+#[derive(Clone, Debug, Default)]
+pub struct Scheduling {
+    /// Copy the attributes that are not set below from the main thread. If this fails, the
+    /// restart goes ahead with a warning.
+    pub inherit: bool,
+    /// The nice value, which applies to `Policy::Other` and `Policy::Batch`.
+    pub nice: Option<i32>,
+    pub policy: Option<Policy>,
+    /// The CPUs the new process may run on.
+    pub cpus: Option<Vec<usize>>,
+}
+
+impl Scheduling {
+    /// Whether the new process keeps the attributes of the restart thread.
+    pub(crate) fn is_empty(&self) -> bool {
+        !self.inherit && self.nice.is_none() && self.policy.is_none() && self.cpus.is_none()
+    }
+
+    /// The attributes to set in the new process, with those to inherit read from the main thread.
+    /// Fails if the configured CPUs are out of range.
+    pub(crate) fn resolve(&self) -> io::Result<ResolvedScheduling> {
+        // The main thread has the pid as its thread ID.
+        let main = std::process::id() as libc::pid_t;
+        Ok(ResolvedScheduling {
+            cpus: match &self.cpus {
+                Some(cpus) => Some(Attribute::configured(cpu_set(cpus)?)),
+                None if self.inherit => Attribute::inherited("CPU affinity", affinity(main)),
+                None => None,
+            },
+            policy: match self.policy {
+                Some(policy) => Some(Attribute::configured((policy, false))),
+                None if self.inherit => Attribute::inherited("scheduling policy", policy(main)),
+                None => None,
+            },
+            nice: match self.nice {
+                Some(nice) => Some(Attribute::configured(nice)),
+                None if self.inherit => Attribute::inherited("nice value", nice(main)),
+                None => None,
+            },
+        })
+    }
+}
+
+/// An attribute to set in the new process.
+#[derive(Clone, Copy)]
+struct Attribute<T> {
+    value: T,
+    /// Whether the new process fails to start if the attribute can't be set, which is the case for
+    /// configured attributes. Inherited ones are passed on on a best effort basis.
+    required: bool,
+}
+
+impl<T> Attribute<T> {
+    fn configured(value: T) -> Self {
+        Attribute {
+            value,
+            required: true,
+        }
+    }
+
+    fn inherited(what: &str, value: io::Result<T>) -> Option<Self> {
+        match value {
+            Ok(value) => Some(Attribute {
+                value,
+                required: false,
+            }),
+            Err(e) => {
+                diagnostics::warn!("Failed to pass on the {} of the main thread: {}", what, e);
+                None
+            }
+        }
+    }
+
+    fn apply(&self, set: impl FnOnce(&T) -> io::Result<()>) -> io::Result<()> {
+        match set(&self.value) {
+            Err(e) if self.required => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The scheduling attributes of the new process, as resolved by `Scheduling::resolve`.
+#[derive(Clone, Copy)]
+pub(crate) struct ResolvedScheduling {
+    cpus: Option<Attribute<libc::cpu_set_t>>,
+    /// The policy, and whether it has `SCHED_RESET_ON_FORK` set.
+    policy: Option<Attribute<(Policy, bool)>>,
+    nice: Option<Attribute<i32>>,
+}
+
+impl ResolvedScheduling {
+    /// Set the attributes of the calling thread. This runs in the new process between fork and
+    /// exec, so it only makes system calls.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        if let Some(cpus) = &self.cpus {
+            cpus.apply(set_affinity)?;
+        }
+        if let Some(policy) = &self.policy {
+            policy.apply(|&(policy, reset_on_fork)| set_policy(policy, reset_on_fork))?;
+        }
+        if let Some(nice) = &self.nice {
+            nice.apply(|&nice| set_nice(nice))?;
+        }
+        Ok(())
+    }
+}
+
+fn cpu_set(cpus: &[usize]) -> io::Result<libc::cpu_set_t> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for &cpu in cpus {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("CPU {cpu} is out of range"),
+            ));
+        }
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    Ok(set)
+}
+
+fn affinity(tid: libc::pid_t) -> io::Result<libc::cpu_set_t> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    let size = mem::size_of::<libc::cpu_set_t>();
+    match unsafe { libc::sched_getaffinity(tid, size, &mut set) } {
+        0 => Ok(set),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn set_affinity(set: &libc::cpu_set_t) -> io::Result<()> {
+    let size = mem::size_of::<libc::cpu_set_t>();
+    // 0 is the calling thread.
+    match unsafe { libc::sched_setaffinity(0, size, set) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The policy of a thread, and whether it has `SCHED_RESET_ON_FORK` set.
+fn policy(tid: libc::pid_t) -> io::Result<(Policy, bool)> {
+    let policy = unsafe { libc::sched_getscheduler(tid) };
+    let mut param: libc::sched_param = unsafe { mem::zeroed() };
+    if policy < 0 || unsafe { libc::sched_getparam(tid, &mut param) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let reset_on_fork = policy & libc::SCHED_RESET_ON_FORK != 0;
+    let policy = match policy & !libc::SCHED_RESET_ON_FORK {
+        libc::SCHED_BATCH => Policy::Batch,
+        libc::SCHED_IDLE => Policy::Idle,
+        libc::SCHED_FIFO => Policy::Fifo(param.sched_priority),
+        libc::SCHED_RR => Policy::RoundRobin(param.sched_priority),
+        _ => Policy::Other,
+    };
+    Ok((policy, reset_on_fork))
+}
+
+fn set_policy(policy: Policy, reset_on_fork: bool) -> io::Result<()> {
+    let (policy, priority) = match policy {
+        Policy::Other => (libc::SCHED_OTHER, 0),
+        Policy::Batch => (libc::SCHED_BATCH, 0),
+        Policy::Idle => (libc::SCHED_IDLE, 0),
+        Policy::Fifo(priority) => (libc::SCHED_FIFO, priority),
+        Policy::RoundRobin(priority) => (libc::SCHED_RR, priority),
+    };
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    let policy = match reset_on_fork {
+        true => policy | libc::SCHED_RESET_ON_FORK,
+        false => policy,
+    };
+    match unsafe { libc::sched_setscheduler(0, policy, &param) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn nice(tid: libc::pid_t) -> io::Result<i32> {
+    // -1 is a valid nice value, so errors are told apart by errno.
+    Errno::clear();
+    let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, tid as libc::id_t) };
+    match (nice, Errno::last()) {
+        (-1, errno) if errno != Errno::UnknownErrno => Err(errno.into()),
+        (nice, _) => Ok(nice),
+    }
+}
+
+fn set_nice(nice: i32) -> io::Result<()> {
+    // On Linux, this applies to the calling thread only.
+    match unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    #[test]
+    fn test_apply_in_new_process() {
+        assert!(Scheduling::default().is_empty());
+        let main = std::process::id() as libc::pid_t;
+        let main_nice = nice(main).unwrap();
+        let (ours, our_policy, our_nice) = (affinity(0).unwrap(), policy(0).unwrap(), nice(0));
+        let cpu = (0..libc::CPU_SETSIZE as usize)
+            .find(|&cpu| unsafe { libc::CPU_ISSET(cpu, &ours) })
+            .unwrap();
+        // Lowering the priority needs no privileges.
+        let scheduling = Scheduling {
+            inherit: true,
+            nice: Some(main_nice + 1),
+            cpus: Some(vec![cpu]),
+            ..Default::default()
+        };
+        let resolved = scheduling.resolve().unwrap();
+        let mut cmd = Command::new("cat");
+        cmd.arg("/proc/self/stat");
+        unsafe { cmd.pre_exec(move || resolved.apply()) };
+        let output = cmd.output().unwrap();
+        let stat = String::from_utf8(output.stdout).unwrap();
+        // The fields after the command name, starting with the state.
+        let fields: Vec<_> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+        assert_eq!(fields[16], (main_nice + 1).to_string());
+        // The CPU the new process last ran on.
+        assert_eq!(fields[36], cpu.to_string());
+
+        // This thread is unaffected.
+        assert!(unsafe { libc::CPU_EQUAL(&affinity(0).unwrap(), &ours) });
+        assert_eq!(policy(0).unwrap(), our_policy);
+        assert_eq!(nice(0).unwrap(), our_nice.unwrap());
+
+        let out_of_range = Scheduling {
+            cpus: Some(vec![libc::CPU_SETSIZE as usize]),
+            ..Default::default()
+        };
+        assert!(out_of_range.resolve().is_err());
+    }
+}
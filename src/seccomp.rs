@@ -0,0 +1,95 @@
+//! Restarting services that install seccomp filters after they start.
+//!
+//! A seccomp filter applies to the thread that installs it and to the threads and processes it
+//! creates later. The restart thread and its tokio runtime are created by
+//! `RestartConfig::try_into_restart_task`, so once it returns, filters installed by other threads
+//! don't apply to it. Everything the new process needs is done on the restart thread, including
+//! the lifecycle hooks, writing to the handover pipe, and forking and executing the new binary.
+//!
+//! Filters must be installed after `try_into_restart_task` returns, and without
+//! `SECCOMP_FILTER_FLAG_TSYNC`, which would apply them to the restart thread too. If the restart
+//! thread was filtered after it was created, a warning is logged when a restart starts.
+//!
+//! The restart task itself runs on the runtime that polls it, so filters on the threads of that
+//! runtime must permit the syscalls in `RESTART_TASK_SYSCALLS` for the restart coordination socket
+//! and the restart signal to work.
+use std::fs;
+use std::io;
+
+/// The syscalls that the restart task makes, besides those of the tokio runtime that polls it.
+/// Architectures that lack some of them use others, e.g. `accept` rather than `accept4`.
+pub const RESTART_TASK_SYSCALLS: &[&str] = &[
+    // The restart coordination socket.
+    "accept4",
+    "close",
+    "fcntl",
+    "getsockopt",
+    "read",
+    "recvmsg",
+    "sendmsg",
+    "write",
+    // Cancelling a restart and monitoring the new process.
+    "kill",
+    "waitid",
+    // Reading the state of fds and threads in /proc.
+    "openat",
+    "readlinkat",
+    "getdents64",
+];
+
+/// The seccomp mode of the calling thread and the number of filters that apply to it, which
+/// kernels before 5.9 don't report.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ThreadFilters {
+    mode: u32,
+    count: u32,
+}
+
+impl ThreadFilters {
+    pub(crate) fn current() -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(
+            "/proc/thread-self/status",
+        )?))
+    }
+
+    fn parse(status: &str) -> Self {
+        let mut filters = ThreadFilters::default();
+        for line in status.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim().parse().unwrap_or(0);
+            match key {
+                "Seccomp" => filters.mode = value,
+                "Seccomp_filters" => filters.count = value,
+                _ => {}
+            }
+        }
+        filters
+    }
+
+    /// Whether filters were installed since `earlier`.
+    pub(crate) fn added_since(&self, earlier: &ThreadFilters) -> bool {
+        self.mode > earlier.mode || self.count > earlier.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_added_since() {
+        let none = ThreadFilters::parse("Name:\tapp\nSeccomp:\t0\nSeccomp_filters:\t0\n");
+        let one = ThreadFilters::parse("Seccomp:\t2\nSeccomp_filters:\t1\n");
+        let two = ThreadFilters::parse("Seccomp:\t2\nSeccomp_filters:\t2\n");
+        assert_eq!(one, ThreadFilters { mode: 2, count: 1 });
+        assert!(one.added_since(&none));
+        assert!(two.added_since(&one));
+        assert!(!one.added_since(&one));
+        // Kernels before 5.9 only report the mode.
+        let old = ThreadFilters::parse("Seccomp:\t2\n");
+        assert!(old.added_since(&ThreadFilters::default()));
+        assert!(ThreadFilters::current().is_ok());
+    }
+}
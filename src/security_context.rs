@@ -0,0 +1,132 @@
+//! The SELinux context or AppArmor profile of the new process.
+//!
+//! By default, the new process runs in the domain it transitions to on exec according to the
+//! policy, which is often the one this process runs in. A confined service can instead have each
+//! new version start in a given domain, e.g. one added by the policy shipped with the upgrade, like
+//! `setexeccon(3)` and `aa_change_onexec(2)` do.
+//!
+//! The exec context is an attribute of the thread that executes the new binary, so it is set in
+//! the new process before it does, and the new process is forked even with
+//! `SpawnMethod::PosixSpawn`. This process, and anything else it executes, keeps its own context.
+//! The policy must allow the transition, otherwise spawning the new process fails.
+use std::ffi::CString;
+use std::io;
+
+/// The security context to execute the new process in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SecurityContext {
+    /// A full SELinux context, e.g. `system_u:system_r:app_t:s0`.
+    SeLinux(String),
+    /// The name of an AppArmor profile.
+    AppArmor(String),
+}
+
+impl SecurityContext {
+    /// The attribute to set in the new process.
+    pub(crate) fn exec_attr(&self) -> ExecAttr {
+        let (paths, value) = self.attr();
+        let paths = paths
+            .iter()
+            .map(|path| CString::new(*path).unwrap())
+            .collect();
+        ExecAttr { paths, value }
+    }
+
+    /// The files to try writing the attribute to, and its value.
+    fn attr(&self) -> (&'static [&'static str], Vec<u8>) {
+        match self {
+            // libselinux includes the terminating NUL.
+            SecurityContext::SeLinux(context) => {
+                let mut attr = context.as_bytes().to_vec();
+                attr.push(0);
+                (&["/proc/thread-self/attr/exec"], attr)
+            }
+            // Newer kernels have an AppArmor specific directory, for stacking with other modules.
+            SecurityContext::AppArmor(profile) => (
+                &[
+                    "/proc/thread-self/attr/apparmor/exec",
+                    "/proc/thread-self/attr/exec",
+                ],
+                format!("exec {profile}").into_bytes(),
+            ),
+        }
+    }
+}
+
+/// The exec context attribute of a `SecurityContext`, prepared to be written in the new process.
+pub(crate) struct ExecAttr {
+    paths: Vec<CString>,
+    value: Vec<u8>,
+}
+
+impl ExecAttr {
+    /// Set the context that the calling thread executes new processes in. This runs in the new
+    /// process between fork and exec, so it only makes system calls.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        let mut res = Err(io::ErrorKind::NotFound.into());
+        for path in &self.paths {
+            res = write_attr(path, &self.value);
+            if !matches!(&res, Err(e) if e.kind() == io::ErrorKind::NotFound) {
+                break;
+            }
+        }
+        res
+    }
+}
+
+fn write_attr(path: &CString, attr: &[u8]) -> io::Result<()> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The attribute must be written in a single write.
+    let res = match unsafe { libc::write(fd, attr.as_ptr().cast(), attr.len()) } {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        n if n as usize == attr.len() => Ok(()),
+        _ => Err(io::ErrorKind::WriteZero.into()),
+    };
+    unsafe { libc::close(fd) };
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exec_attr() {
+        let selinux = SecurityContext::SeLinux("system_u:system_r:app_t:s0".into());
+        let (paths, attr) = selinux.attr();
+        assert_eq!(paths, ["/proc/thread-self/attr/exec"]);
+        assert_eq!(attr, b"system_u:system_r:app_t:s0\0");
+
+        let apparmor = SecurityContext::AppArmor("app-v2".into());
+        let (paths, attr) = apparmor.attr();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(attr, b"exec app-v2");
+    }
+
+    #[test]
+    fn test_apply() {
+        let file = std::env::temp_dir().join(format!("shellflip-exec-attr-{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        // Missing files are skipped, like the AppArmor specific one on older kernels.
+        let attr = ExecAttr {
+            paths: vec![
+                CString::new("/nonexistent/attr/exec").unwrap(),
+                CString::new(file.to_str().unwrap()).unwrap(),
+            ],
+            value: b"exec app-v2".to_vec(),
+        };
+        attr.apply().unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"exec app-v2");
+        std::fs::remove_file(&file).unwrap();
+
+        let missing = ExecAttr {
+            paths: vec![CString::new("/nonexistent/attr/exec").unwrap()],
+            value: Vec::new(),
+        };
+        let err = missing.apply().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
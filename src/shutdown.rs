@@ -1,7 +1,24 @@
+use crate::diagnostics;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::HashMap;
 use std::default::Default;
 use std::fmt;
-use std::sync::{Arc, Weak};
-use tokio::sync::{mpsc, watch};
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// The number of messages that are buffered for each `ShutdownMessages` receiver.
+const MESSAGE_CAPACITY: usize = 16;
+/// The number of handles listed in `DrainReport::longest`.
+const LONGEST_HANDLES: usize = 5;
+
+type Message = Arc<dyn Any + Send + Sync>;
 
 /// A handle held by an active task, which can be used to receive
 /// shutdown notifications and to delay program termination until the
@@ -11,14 +28,34 @@ pub struct ShutdownHandle {
     cancellation_rx: ShutdownSignal,
     /// Signals connection completion (when dropped)
     _shutdown_tx: mpsc::Sender<()>,
+    /// Used to subscribe to messages broadcast by the coordinator.
+    message_tx: broadcast::Sender<Message>,
+    /// Records drain statistics for named handles.
+    _tracker: Option<DrainTracker>,
+    /// Signals completion of the handle's group (when dropped)
+    _group_tx: Option<mpsc::Sender<()>>,
+}
+
+impl ShutdownHandle {
+    /// Subscribe to messages broadcast with `ShutdownCoordinator::broadcast`. Only messages sent
+    /// after subscribing are received.
+    pub fn messages(&self) -> ShutdownMessages {
+        ShutdownMessages {
+            message_rx: self.message_tx.subscribe(),
+        }
+    }
 }
 
 impl Default for ShutdownHandle {
     fn default() -> Self {
         let (_shutdown_tx, _) = mpsc::channel(1);
+        let (message_tx, _) = broadcast::channel(1);
         ShutdownHandle {
             cancellation_rx: ShutdownSignal::default(),
             _shutdown_tx,
+            message_tx,
+            _tracker: None,
+            _group_tx: None,
         }
     }
 }
@@ -33,7 +70,10 @@ impl fmt::Debug for ShutdownHandle {
 #[derive(Clone)]
 pub enum ShutdownSignal {
     WaitingForSignal(watch::Receiver<bool>),
+    /// `on_shutdown` has resolved.
     Signalled,
+    /// Not connected to a coordinator, e.g. the signal of a default `ShutdownHandle`.
+    Never,
 }
 
 impl ShutdownSignal {
@@ -46,17 +86,229 @@ impl ShutdownSignal {
                 let _ = r.changed().await;
                 *self = ShutdownSignal::Signalled;
             }
-            ShutdownSignal::Signalled => {
+            ShutdownSignal::Signalled | ShutdownSignal::Never => {
                 futures::future::pending::<()>().await;
             }
         }
     }
 }
 
+impl ShutdownSignal {
+    /// Drain a long-lived response, such as a long poll or a server-sent event stream, according
+    /// to `policy`, so that different kinds of endpoints in the same server can drain differently.
+    /// A signal that has already been signalled counts as shutting down from when this is called.
+    pub fn with_policy(self, policy: DrainPolicy) -> PolicySignal {
+        let shutdown_at = match self {
+            ShutdownSignal::WaitingForSignal(_) | ShutdownSignal::Never => None,
+            ShutdownSignal::Signalled => Some(Instant::now()),
+        };
+        PolicySignal {
+            signal: self,
+            policy,
+            shutdown_at,
+        }
+    }
+}
+
+/// How a long-lived response drains once shutdown starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrainPolicy {
+    /// End the response right away, even in the middle of an event.
+    Immediate,
+    /// Finish sending the current event, if any, then end the response.
+    FinishEvent,
+    /// Keep sending events until the client disconnects, for at most the given time after shutdown
+    /// starts.
+    HoldUntilDisconnect(Duration),
+}
+
+/// A shutdown signal that follows a `DrainPolicy`. Unlike `ShutdownSignal`, its methods keep
+/// resolving once they have resolved, so they can be used in a loop:
+///
+/// ```no_run
+/// # use shellflip::shutdown::{DrainPolicy, ShutdownHandle, ShutdownSignal};
+/// # async fn example(handle: &ShutdownHandle, mut events: tokio::sync::mpsc::Receiver<String>) {
+/// # async fn send(event: String) {}
+/// let mut signal = ShutdownSignal::from(handle).with_policy(DrainPolicy::FinishEvent);
+/// loop {
+///     let event = tokio::select! {
+///         Some(event) = events.recv() => event,
+///         _ = signal.on_drain() => break,
+///     };
+///     tokio::select! {
+///         _ = send(event) => {}
+///         _ = signal.on_abort() => break,
+///     }
+/// }
+/// # }
+/// ```
+pub struct PolicySignal {
+    signal: ShutdownSignal,
+    policy: DrainPolicy,
+    /// When this signal observed the start of shutdown.
+    shutdown_at: Option<Instant>,
+}
+
+impl PolicySignal {
+    pub fn policy(&self) -> DrainPolicy {
+        self.policy
+    }
+
+    /// Wait until no further events should be started, after which the response should end.
+    pub async fn on_drain(&mut self) {
+        let shutdown_at = self.shutdown_started().await;
+        if let DrainPolicy::HoldUntilDisconnect(cap) = self.policy {
+            tokio::time::sleep_until(shutdown_at + cap).await;
+        }
+    }
+
+    /// Wait until the response must end, even in the middle of an event. This never happens with
+    /// `DrainPolicy::FinishEvent`.
+    pub async fn on_abort(&mut self) {
+        match self.policy {
+            DrainPolicy::Immediate | DrainPolicy::HoldUntilDisconnect(_) => self.on_drain().await,
+            DrainPolicy::FinishEvent => futures::future::pending().await,
+        }
+    }
+
+    async fn shutdown_started(&mut self) -> Instant {
+        if let Some(shutdown_at) = self.shutdown_at {
+            return shutdown_at;
+        }
+        match &mut self.signal {
+            // Dropping the coordinator counts as a shutdown too.
+            ShutdownSignal::WaitingForSignal(r) => {
+                let _ = r.wait_for(|shutdown| *shutdown).await;
+            }
+            ShutdownSignal::Signalled => {}
+            ShutdownSignal::Never => futures::future::pending().await,
+        }
+        *self.shutdown_at.insert(Instant::now())
+    }
+}
+
+/// Receives a shutdown signal in a thread that is not running an async runtime, e.g. a worker
+/// thread spawned with `std::thread::spawn`. Unlike `ShutdownSignal`, this keeps reporting the
+/// shutdown once it has happened.
+///
+/// The thread is parked while it waits, so don't use this on a thread that runs async tasks.
+#[derive(Clone, Default)]
+pub struct BlockingShutdownSignal {
+    /// Not set for signals that are never signalled.
+    cancellation_rx: Option<watch::Receiver<bool>>,
+}
+
+impl BlockingShutdownSignal {
+    /// Returns whether shutdown has started, without blocking.
+    pub fn is_shutdown(&self) -> bool {
+        match &self.cancellation_rx {
+            Some(r) => *r.borrow() || r.has_changed().is_err(),
+            None => false,
+        }
+    }
+
+    /// Block until shutdown starts. Returns immediately if it already has.
+    pub fn wait(&mut self) {
+        self.wait_until(None);
+    }
+
+    /// Block until shutdown starts, or until `timeout` has passed. Returns whether shutdown
+    /// started, so this can be used to sleep between units of work.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> bool {
+        self.wait_until(Some(std::time::Instant::now() + timeout))
+    }
+
+    fn wait_until(&mut self, deadline: Option<std::time::Instant>) -> bool {
+        if self.is_shutdown() {
+            return true;
+        }
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        // Only a shutdown changes the value, or dropping the coordinator, which counts as one too.
+        let mut changed = self.cancellation_rx.as_mut().map(|r| Box::pin(r.changed()));
+        loop {
+            if let Some(changed) = &mut changed {
+                if changed.as_mut().poll(&mut cx).is_ready() {
+                    return true;
+                }
+            }
+            match deadline {
+                Some(deadline) => {
+                    let now = std::time::Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+                None => thread::park(),
+            }
+        }
+    }
+}
+
+impl From<&ShutdownHandle> for BlockingShutdownSignal {
+    fn from(handle: &ShutdownHandle) -> Self {
+        match &handle.cancellation_rx {
+            ShutdownSignal::WaitingForSignal(r) => BlockingShutdownSignal {
+                cancellation_rx: Some(r.clone()),
+            },
+            ShutdownSignal::Signalled | ShutdownSignal::Never => BlockingShutdownSignal::default(),
+        }
+    }
+}
+
+/// Unparks the waiting thread.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Receives messages broadcast to all shutdown handles, which can be used to adjust the behaviour
+/// of tasks while they drain, e.g. to shorten keepalive timeouts.
+pub struct ShutdownMessages {
+    message_rx: broadcast::Receiver<Message>,
+}
+
+impl ShutdownMessages {
+    /// Wait for the next message of type `M`. Messages of other types are skipped. If this receiver
+    /// fell behind, the oldest messages are skipped too.
+    /// Returns `None` once no more messages can be sent.
+    pub async fn recv<M: Any + Send + Sync>(&mut self) -> Option<Arc<M>> {
+        loop {
+            match self.message_rx.recv().await {
+                Ok(message) => {
+                    if let Ok(message) = message.downcast::<M>() {
+                        return Some(message);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Broadcasts messages to all shutdown handles of a `ShutdownCoordinator`. Unlike the coordinator
+/// itself, this remains usable after shutdown has started.
+#[derive(Clone)]
+pub struct ShutdownMessageSender {
+    message_tx: broadcast::Sender<Message>,
+}
+
+impl ShutdownMessageSender {
+    /// Send a message to every `ShutdownMessages` receiver. Returns the number of receivers.
+    pub fn broadcast<M: Any + Send + Sync>(&self, message: M) -> usize {
+        self.message_tx.send(Arc::new(message)).unwrap_or(0)
+    }
+}
+
 /// The default implementation of ShutdownSignal will never be signalled.
 impl Default for ShutdownSignal {
     fn default() -> Self {
-        ShutdownSignal::Signalled
+        ShutdownSignal::Never
     }
 }
 
@@ -66,6 +318,213 @@ impl From<&ShutdownHandle> for ShutdownSignal {
     }
 }
 
+/// How long a named handle took to drain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandleDrainTime {
+    pub name: String,
+    /// The time between creating the handle and dropping it, or now if it is still alive.
+    pub lifetime: Duration,
+    /// The time between requesting shutdown and dropping the handle, or now if it is still alive.
+    pub drain_time: Duration,
+    /// Whether the handle was dropped.
+    pub completed: bool,
+}
+
+/// Aggregate drain statistics for the named handles that were alive when shutdown was requested.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DrainReport {
+    /// The number of named handles that were alive when shutdown was requested.
+    pub handles: usize,
+    /// The number of those handles that are still alive.
+    pub still_running: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    /// The handles that took longest to drain, longest first.
+    pub longest: Vec<HandleDrainTime>,
+}
+
+impl DrainReport {
+    pub fn summary(&self) -> DrainSummary {
+        DrainSummary {
+            count: self.handles,
+            still_running: self.still_running,
+            p50: self.p50,
+            p95: self.p95,
+            p99: self.p99,
+            max: self.max,
+        }
+    }
+}
+
+/// The distribution of the time the named handles took to close after shutdown was requested,
+/// for tuning client keepalive settings against restart speed. Handles that are still alive count
+/// with the time so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DrainSummary {
+    /// The number of named handles that were alive when shutdown was requested.
+    pub count: usize,
+    /// The number of those handles that were still alive at the end of the drain.
+    pub still_running: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl fmt::Display for DrainSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} handles ({} still running), p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+            self.count, self.still_running, self.p50, self.p95, self.p99, self.max
+        )
+    }
+}
+
+/// Returned by `ShutdownCoordinator::shutdown_within` when handles were still alive at the
+/// deadline.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("shutdown timed out with {} named handles still alive", .still_running.len())]
+pub struct DrainTimeout {
+    /// The named handles that were still alive, oldest first. Handles that are not named, e.g.
+    /// those from `handle`, may also have been alive, but can't be listed.
+    pub still_running: Vec<HandleDrainTime>,
+}
+
+/// Produces drain reports for a `ShutdownCoordinator`. This remains usable after shutdown has
+/// started, so a report can be logged once shutdown completes or times out.
+#[derive(Clone, Debug)]
+pub struct DrainStats {
+    recorder: Arc<Mutex<DrainRecorder>>,
+}
+
+impl DrainStats {
+    /// Returns a report of the drain so far, or `None` if shutdown has not been requested.
+    pub fn report(&self) -> Option<DrainReport> {
+        self.recorder.lock().unwrap().report(Instant::now())
+    }
+
+    /// Returns a summary of the drain, or `None` if shutdown has not been requested. Once the drain
+    /// ended, by completing or timing out, this is the summary at that point.
+    pub fn summary(&self) -> Option<DrainSummary> {
+        let recorder = self.recorder.lock().unwrap();
+        recorder
+            .summary
+            .or_else(|| Some(recorder.report(Instant::now())?.summary()))
+    }
+
+    /// The number of handles created with `named_handle` that are still alive.
+    pub fn active_handles(&self) -> usize {
+        self.recorder.lock().unwrap().live.len()
+    }
+}
+
+#[derive(Debug, Default)]
+struct DrainRecorder {
+    shutdown_requested: Option<Instant>,
+    next_id: u64,
+    /// Name and creation time of each live named handle.
+    live: HashMap<u64, (String, Instant)>,
+    /// Handles that were dropped after shutdown was requested.
+    drained: Vec<HandleDrainTime>,
+    /// The summary at the end of the drain.
+    summary: Option<DrainSummary>,
+}
+
+impl DrainRecorder {
+    fn drain_time(&self, name: &str, created: Instant, now: Instant) -> Option<HandleDrainTime> {
+        let requested = self.shutdown_requested?;
+        Some(HandleDrainTime {
+            name: name.to_string(),
+            lifetime: now - created,
+            drain_time: now - requested.max(created),
+            completed: true,
+        })
+    }
+
+    /// Record and log the summary at the end of the drain.
+    fn finish(&mut self) {
+        let Some(summary) = self.report(Instant::now()).map(|r| r.summary()) else {
+            return;
+        };
+        if summary.count > 0 {
+            diagnostics::info!("Drain finished: {}", summary);
+        }
+        self.summary = Some(summary);
+    }
+
+    /// The live handles, if shutdown has been requested.
+    fn still_running(&self, now: Instant) -> impl Iterator<Item = HandleDrainTime> + '_ {
+        self.live.values().filter_map(move |(name, created)| {
+            let mut t = self.drain_time(name, *created, now)?;
+            t.completed = false;
+            Some(t)
+        })
+    }
+
+    fn report(&self, now: Instant) -> Option<DrainReport> {
+        self.shutdown_requested?;
+        let still_running = self.still_running(now);
+        let mut handles: Vec<_> = self.drained.iter().cloned().chain(still_running).collect();
+
+        handles.sort_by_key(|h| std::cmp::Reverse(h.drain_time));
+        let percentile = |q: f64| {
+            // Nearest-rank percentile; handles are sorted longest first.
+            let rank = (q * handles.len() as f64).ceil() as usize;
+            handles[handles.len() - rank.max(1)].drain_time
+        };
+        let (p50, p95, p99, max) = if handles.is_empty() {
+            Default::default()
+        } else {
+            (
+                percentile(0.5),
+                percentile(0.95),
+                percentile(0.99),
+                handles[0].drain_time,
+            )
+        };
+
+        Some(DrainReport {
+            handles: handles.len(),
+            still_running: handles.iter().filter(|h| !h.completed).count(),
+            p50,
+            p95,
+            p99,
+            max,
+            longest: handles.into_iter().take(LONGEST_HANDLES).collect(),
+        })
+    }
+}
+
+/// Removes a named handle from the set of live handles when it is dropped.
+struct DrainTracker {
+    id: u64,
+    recorder: Arc<Mutex<DrainRecorder>>,
+}
+
+impl Drop for DrainTracker {
+    fn drop(&mut self) {
+        let mut recorder = self.recorder.lock().unwrap();
+        if let Some((name, created)) = recorder.live.remove(&self.id) {
+            if let Some(t) = recorder.drain_time(&name, created, Instant::now()) {
+                recorder.drained.push(t);
+            }
+        }
+    }
+}
+
+/// A labelled group of tasks that can be drained independently of the rest of the process.
+struct ShutdownGroup {
+    /// Holds onto the group's ShutdownHandle until the group is drained.
+    shutdown_handle: Arc<ShutdownHandle>,
+    /// Used to notify the group's tasks to start shutdown
+    cancellation_tx: watch::Sender<bool>,
+    /// Used to wait for the group's tasks to shutdown successfully.
+    shutdown_rx: mpsc::Receiver<()>,
+}
+
 /// Coordinates the shutdown process for a group of tasks.
 /// This allows the tasks to get notified when a shutdown is requested,
 /// and allows the main thread to defer termination until all of the tasks have successfully
@@ -77,6 +536,12 @@ pub struct ShutdownCoordinator {
     cancellation_tx: watch::Sender<bool>,
     /// Used to wait for all connections to shutdown successfully.
     shutdown_rx: mpsc::Receiver<()>,
+    /// Used to send messages to all handles.
+    message_tx: broadcast::Sender<Message>,
+    /// Records how long named handles take to drain.
+    drain_recorder: Arc<Mutex<DrainRecorder>>,
+    /// Groups of handles that can be drained separately, by label.
+    groups: Mutex<HashMap<String, ShutdownGroup>>,
 }
 
 impl ShutdownCoordinator {
@@ -84,14 +549,21 @@ impl ShutdownCoordinator {
     pub fn new() -> Self {
         let (cancellation_tx, cancellation_rx) = watch::channel(false);
         let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+        let (message_tx, _) = broadcast::channel(MESSAGE_CAPACITY);
         let shutdown_handle = Arc::new(ShutdownHandle {
             cancellation_rx: ShutdownSignal::WaitingForSignal(cancellation_rx),
             _shutdown_tx: shutdown_tx,
+            message_tx: message_tx.clone(),
+            _tracker: None,
+            _group_tx: None,
         });
         ShutdownCoordinator {
             shutdown_handle,
             cancellation_tx,
             shutdown_rx,
+            message_tx,
+            drain_recorder: Default::default(),
+            groups: Default::default(),
         }
     }
 
@@ -100,6 +572,40 @@ impl ShutdownCoordinator {
         Arc::clone(&self.shutdown_handle)
     }
 
+    /// Spawn a task on the tokio runtime that holds a ShutdownHandle until it completes, so that
+    /// shutdown waits for it without having to move a handle into the task. Use `handle` within the
+    /// task as well if it needs to react to the shutdown signal.
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let handle = self.handle();
+        tokio::spawn(async move {
+            let output = task.await;
+            drop(handle);
+            output
+        })
+    }
+
+    /// Run the future returned by `f` while holding a ShutdownHandle, so that shutdown waits for
+    /// it. `f` receives the shutdown signal, e.g. to stop accepting requests on a connection once
+    /// shutdown starts. The handle is taken right away, and released when the future completes or
+    /// is dropped.
+    pub fn scope<F, Fut>(&self, f: F) -> impl Future<Output = Fut::Output>
+    where
+        F: FnOnce(ShutdownSignal) -> Fut,
+        Fut: Future,
+    {
+        let handle = self.handle();
+        let task = f(ShutdownSignal::from(&*handle));
+        async move {
+            let output = task.await;
+            drop(handle);
+            output
+        }
+    }
+
     /// Get a ShutdownHandle that can be held by a task that does not need to be waited on, but may
     /// spawn tasks that should be waited on. If the task can upgrade the handle with Arc::upgrade,
     /// then shutdown has not yet started.
@@ -107,18 +613,116 @@ impl ShutdownCoordinator {
         Arc::downgrade(&self.shutdown_handle)
     }
 
+    /// Get a ShutdownHandle like `handle`, whose lifetime is recorded in the drain statistics under
+    /// the given name, e.g. the peer address of a connection.
+    pub fn named_handle(&self, name: impl Into<String>) -> Arc<ShutdownHandle> {
+        let mut recorder = self.drain_recorder.lock().unwrap();
+        let id = recorder.next_id;
+        recorder.next_id += 1;
+        recorder.live.insert(id, (name.into(), Instant::now()));
+
+        Arc::new(ShutdownHandle {
+            cancellation_rx: self.shutdown_handle.cancellation_rx.clone(),
+            _shutdown_tx: self.shutdown_handle._shutdown_tx.clone(),
+            message_tx: self.message_tx.clone(),
+            _tracker: Some(DrainTracker {
+                id,
+                recorder: Arc::clone(&self.drain_recorder),
+            }),
+            _group_tx: None,
+        })
+    }
+
+    /// Get a ShutdownHandle for a task in the group with the given label, e.g. "port-8443".
+    /// The handle is signalled either when the group is drained with `drain_group` or when the
+    /// whole process shuts down, and delays both until the task has completed.
+    pub fn group_handle(&self, group: &str) -> Arc<ShutdownHandle> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups.entry(group.to_string()).or_insert_with(|| {
+            let (cancellation_tx, cancellation_rx) = watch::channel(false);
+            let (group_tx, shutdown_rx) = mpsc::channel(1);
+            ShutdownGroup {
+                shutdown_handle: Arc::new(ShutdownHandle {
+                    cancellation_rx: ShutdownSignal::WaitingForSignal(cancellation_rx),
+                    _shutdown_tx: self.shutdown_handle._shutdown_tx.clone(),
+                    message_tx: self.message_tx.clone(),
+                    _tracker: None,
+                    _group_tx: Some(group_tx),
+                }),
+                cancellation_tx,
+                shutdown_rx,
+            }
+        });
+        Arc::clone(&group.shutdown_handle)
+    }
+
+    /// Signal shutdown to the tasks in the given group only, and return a future that resolves
+    /// once they have all completed. The rest of the process keeps running. Handles requested for
+    /// the same label afterwards belong to a new group.
+    /// To prevent new connections from being accepted, drop the group's listening tasks first.
+    pub fn drain_group(&self, group: &str) -> impl Future<Output = ()> + Send + 'static {
+        let group = self.groups.lock().unwrap().remove(group);
+        async move {
+            if let Some(mut group) = group {
+                let _ = group.cancellation_tx.send(true);
+                drop(group.shutdown_handle);
+                let _ = group.shutdown_rx.recv().await;
+            }
+        }
+    }
+
+    /// Get an object that reports how long the handles created with `named_handle` took to drain.
+    pub fn drain_stats(&self) -> DrainStats {
+        DrainStats {
+            recorder: Arc::clone(&self.drain_recorder),
+        }
+    }
+
+    /// Send a message to all tasks that subscribed with `ShutdownHandle::messages`.
+    /// Returns the number of receivers.
+    pub fn broadcast<M: Any + Send + Sync>(&self, message: M) -> usize {
+        self.message_sender().broadcast(message)
+    }
+
+    /// Get a sender for broadcasting messages to all handles, which can still be used once
+    /// shutdown has started.
+    pub fn message_sender(&self) -> ShutdownMessageSender {
+        ShutdownMessageSender {
+            message_tx: self.message_tx.clone(),
+        }
+    }
+
     /// Initiate shutdown and wait for its successful completion.
     /// To prevent new connections from being accepted, drop any listening tasks first.
     pub async fn shutdown(mut self) {
+        self.drain_recorder.lock().unwrap().shutdown_requested = Some(Instant::now());
         let _ = self.cancellation_tx.send(true);
+        for (_, group) in self.groups.get_mut().unwrap().drain() {
+            let _ = group.cancellation_tx.send(true);
+        }
         drop(self.shutdown_handle);
         let _ = self.shutdown_rx.recv().await;
+        self.drain_recorder.lock().unwrap().finish();
     }
 
     /// Shutdown, waiting a maximum amount of time before returning.
     pub async fn shutdown_with_timeout(self, timeout: u64) {
-        let _ =
-            tokio::time::timeout(tokio::time::Duration::from_secs(timeout), self.shutdown()).await;
+        let _ = self.shutdown_within(Duration::from_secs(timeout)).await;
+    }
+
+    /// Shutdown, waiting at most `timeout` before returning. If the timeout expires, the error
+    /// lists the named handles that are still alive, so that the caller can decide whether it is
+    /// safe to abandon them.
+    pub async fn shutdown_within(self, timeout: Duration) -> Result<(), DrainTimeout> {
+        let recorder = Arc::clone(&self.drain_recorder);
+        if tokio::time::timeout(timeout, self.shutdown()).await.is_ok() {
+            return Ok(());
+        }
+        let mut recorder = recorder.lock().unwrap();
+        recorder.finish();
+        let mut still_running: Vec<_> = recorder.still_running(Instant::now()).collect();
+        still_running.sort_by_key(|h| std::cmp::Reverse(h.lifetime));
+        Err(DrainTimeout { still_running })
     }
 }
 
@@ -151,10 +755,262 @@ mod tests {
         assert!(shutdown_fut.now_or_never().is_some());
     }
 
+    #[tokio::test]
+    async fn test_spawn() {
+        let sc = ShutdownCoordinator::new();
+        let mut signal = ShutdownSignal::from(&*sc.handle());
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        let task = sc.spawn(async move {
+            signal.on_shutdown().await;
+            // Shutdown waits for the task, although it holds no handle of its own.
+            let _ = done_rx.await;
+            5
+        });
+
+        let shutdown_fut = sc.shutdown();
+        pin_mut!(shutdown_fut);
+        tokio::task::yield_now().await;
+        assert!(shutdown_fut.as_mut().now_or_never().is_none());
+        drop(done_tx);
+        shutdown_fut.await;
+        assert_eq!(task.await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_scope() {
+        let sc = ShutdownCoordinator::new();
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        let scope = sc.scope(|mut shutdown| async move {
+            shutdown.on_shutdown().await;
+            let _ = done_rx.await;
+            "drained"
+        });
+        let scope = tokio::spawn(scope);
+
+        let shutdown_fut = sc.shutdown();
+        pin_mut!(shutdown_fut);
+        tokio::task::yield_now().await;
+        assert!(shutdown_fut.as_mut().now_or_never().is_none());
+        drop(done_tx);
+        shutdown_fut.await;
+        assert_eq!(scope.await.unwrap(), "drained");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_policies() {
+        let sc = ShutdownCoordinator::new();
+        let handle = sc.handle();
+        let signal = |policy| ShutdownSignal::from(&*handle).with_policy(policy);
+        let mut immediate = signal(DrainPolicy::Immediate);
+        let mut finish = signal(DrainPolicy::FinishEvent);
+        let mut hold = signal(DrainPolicy::HoldUntilDisconnect(Duration::from_secs(30)));
+        assert!(immediate.on_drain().now_or_never().is_none());
+
+        let start = Instant::now();
+        let shutdown = tokio::spawn(sc.shutdown());
+        immediate.on_abort().await;
+        finish.on_drain().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        // Resolves again once shutdown has started.
+        immediate.on_drain().await;
+        assert!(finish.on_abort().now_or_never().is_none());
+
+        assert!(hold.on_drain().now_or_never().is_none());
+        hold.on_abort().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+
+        // A signal that was already signalled drains from when the policy is applied.
+        let mut signal = ShutdownSignal::from(&*handle);
+        signal.on_shutdown().await;
+        let mut signalled =
+            signal.with_policy(DrainPolicy::HoldUntilDisconnect(Duration::from_secs(5)));
+        signalled.on_drain().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(35));
+
+        drop(handle);
+        shutdown.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_default_policy_signal() {
+        let handle = ShutdownHandle::default();
+        for policy in [
+            DrainPolicy::Immediate,
+            DrainPolicy::FinishEvent,
+            DrainPolicy::HoldUntilDisconnect(Duration::from_secs(5)),
+        ] {
+            let mut signal = ShutdownSignal::from(&handle).with_policy(policy);
+            let drained = tokio::time::timeout(Duration::from_secs(3600), signal.on_drain());
+            assert!(drained.await.is_err());
+            let aborted = tokio::time::timeout(Duration::from_secs(3600), signal.on_abort());
+            assert!(aborted.await.is_err());
+        }
+        assert!(!BlockingShutdownSignal::from(&handle).is_shutdown());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_messages() {
+        #[derive(Debug, PartialEq)]
+        struct KeepaliveSecs(u32);
+
+        let sc = ShutdownCoordinator::new();
+        let mut messages = sc.handle().messages();
+        let sender = sc.message_sender();
+
+        assert_eq!(sc.broadcast("ignored"), 1);
+        let shutdown_fut = sc.shutdown();
+        pin_mut!(shutdown_fut);
+        assert!(shutdown_fut.as_mut().now_or_never().is_some());
+
+        assert_eq!(sender.broadcast(KeepaliveSecs(5)), 1);
+        assert_eq!(
+            messages.recv::<KeepaliveSecs>().await.as_deref(),
+            Some(&KeepaliveSecs(5))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_stats() {
+        let sc = ShutdownCoordinator::new();
+        let stats = sc.drain_stats();
+        drop(sc.named_handle("dropped before shutdown"));
+        let fast = sc.named_handle("fast");
+        let slow = sc.named_handle("slow");
+        assert_eq!(stats.report(), None);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let shutdown_fut = sc.shutdown();
+        pin_mut!(shutdown_fut);
+        assert!(shutdown_fut.as_mut().now_or_never().is_none());
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        drop(fast);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let report = stats.report().unwrap();
+        assert_eq!(report.handles, 2);
+        assert_eq!(report.still_running, 1);
+        assert_eq!(report.p50, Duration::from_secs(1));
+        assert_eq!(report.p95, Duration::from_secs(3));
+        assert_eq!(report.p99, Duration::from_secs(3));
+        assert_eq!(report.max, Duration::from_secs(3));
+        assert_eq!(
+            report.longest[0],
+            HandleDrainTime {
+                name: "slow".to_string(),
+                lifetime: Duration::from_secs(4),
+                drain_time: Duration::from_secs(3),
+                completed: false,
+            }
+        );
+        assert_eq!(report.longest[1].drain_time, Duration::from_secs(1));
+
+        drop(slow);
+        assert!(shutdown_fut.now_or_never().is_some());
+        assert_eq!(stats.report().unwrap().still_running, 0);
+
+        // The summary is kept as it was at the end of the drain.
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.count, 2);
+        assert_eq!(summary.still_running, 0);
+        assert_eq!(summary.max, Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_summary_after_timeout() {
+        let sc = ShutdownCoordinator::new();
+        let stats = sc.drain_stats();
+        let _stuck = sc.named_handle("stuck");
+        sc.shutdown_with_timeout(2).await;
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.still_running, 1);
+        assert_eq!(summary.max, Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_within() {
+        let sc = ShutdownCoordinator::new();
+        let _stuck = sc.named_handle("stuck");
+        let _unnamed = sc.handle();
+        let err = sc
+            .shutdown_within(Duration::from_secs(2))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.still_running,
+            [HandleDrainTime {
+                name: "stuck".to_string(),
+                lifetime: Duration::from_secs(2),
+                drain_time: Duration::from_secs(2),
+                completed: false,
+            }]
+        );
+
+        let sc = ShutdownCoordinator::new();
+        drop(sc.named_handle("done"));
+        assert_eq!(sc.shutdown_within(Duration::from_secs(2)).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_drain_group() {
+        let sc = ShutdownCoordinator::new();
+        let other = sc.handle();
+        let mut other_signal = ShutdownSignal::from(&*other);
+        let tenant = sc.group_handle("tenant-42");
+        let mut tenant_signal = ShutdownSignal::from(&*tenant);
+
+        let drain_fut = sc.drain_group("tenant-42");
+        pin_mut!(drain_fut);
+        assert!(drain_fut.as_mut().now_or_never().is_none());
+        assert!(tenant_signal.on_shutdown().now_or_never().is_some());
+        assert!(other_signal.on_shutdown().now_or_never().is_none());
+        drop(tenant);
+        assert!(drain_fut.now_or_never().is_some());
+
+        // A new group with the same label is signalled by the process shutdown.
+        let tenant = sc.group_handle("tenant-42");
+        let mut tenant_signal = ShutdownSignal::from(&*tenant);
+        drop(other);
+        let shutdown_fut = sc.shutdown();
+        pin_mut!(shutdown_fut);
+        assert!(shutdown_fut.as_mut().now_or_never().is_none());
+        assert!(tenant_signal.on_shutdown().now_or_never().is_some());
+        drop(tenant);
+        assert!(shutdown_fut.now_or_never().is_some());
+    }
+
     #[tokio::test]
     async fn test_default_shutdown_handle() {
         let handle = ShutdownHandle::default();
         let mut signal = ShutdownSignal::from(&handle);
         assert!(signal.on_shutdown().now_or_never().is_none());
+
+        let mut signal = BlockingShutdownSignal::from(&handle);
+        assert!(!signal.is_shutdown());
+        assert!(!signal.wait_timeout(Duration::from_millis(10)));
+    }
+
+    #[tokio::test]
+    async fn test_blocking_shutdown_signal() {
+        let sc = ShutdownCoordinator::new();
+        let handle = sc.handle();
+        let mut signal = BlockingShutdownSignal::from(&*handle);
+        let (waiting_tx, waiting_rx) = tokio::sync::oneshot::channel();
+        let worker = std::thread::spawn(move || {
+            assert!(!signal.wait_timeout(Duration::from_millis(10)));
+            waiting_tx.send(()).unwrap();
+            signal.wait();
+            drop(handle);
+            // It stays signalled.
+            assert!(signal.is_shutdown());
+            assert!(signal.wait_timeout(Duration::from_secs(60)));
+        });
+
+        waiting_rx.await.unwrap();
+        sc.shutdown().await;
+        worker.join().unwrap();
     }
 }
@@ -0,0 +1,292 @@
+//! Taking over from a Go process that uses [tableflip](https://github.com/cloudflare/tableflip),
+//! so that a service being rewritten in Rust can be deployed as an upgrade of the Go service
+//! without dropping connections.
+//!
+//! When the Go process upgrades, it spawns this process with tableflip's environment: the files it
+//! passes on, a pipe for their names and a pipe for signalling readiness. Listeners are handed to
+//! `listeners::listener_or_bind` or `listeners::listener_group` by name, so that from then on they
+//! are passed to the next process by shellflip.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use shellflip::tableflip::TableflipParent;
+//!
+//! let mut parent = TableflipParent::from_env()?;
+//! if let Some(parent) = &mut parent {
+//!     // The network and address the Go process passed to `Upgrader.Listen`.
+//!     parent.inherit_listener("http", "tcp", ":8080");
+//! }
+//! let listener = shellflip::listeners::listener_or_bind("http", "0.0.0.0:8080")?;
+//! // ... start serving ...
+//! if let Some(mut parent) = parent {
+//!     parent.ready()?;
+//!     parent.wait_for_exit().await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::pipes::set_cloexec;
+use crate::{diagnostics, listeners};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+
+/// Set by tableflip in the environment of the new process.
+const ENV_SENTINEL: &str = "TABLEFLIP_HAS_PARENT_7DIYDFyMJ7uq";
+/// Written to the readiness pipe once the new process is ready.
+const NOTIFY_READY: u8 = 42;
+const READY_FD: RawFd = 3;
+const NAMES_FD: RawFd = 4;
+/// The passed files follow the two pipes, in the order of their names.
+const FIRST_FILE_FD: RawFd = 5;
+const LISTENER_KIND: &str = "listener";
+const FILE_KIND: &str = "fd";
+/// Upper bound on the size of the names message, which is far larger than any real one.
+const MAX_NAMES_LEN: u64 = 1 << 20;
+
+/// The Go process this process was started by, and the files it passed on.
+#[derive(Debug)]
+pub struct TableflipParent {
+    ready: Option<File>,
+    names: File,
+    files: BTreeMap<[String; 3], OwnedFd>,
+}
+
+impl TableflipParent {
+    /// Returns the parent if this process was started by a tableflip upgrade. Only the first call
+    /// returns it, as it takes ownership of the inherited file descriptors.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        if env::var_os(ENV_SENTINEL).is_none() {
+            return Ok(None);
+        }
+        // Don't let the next process think that it was started by tableflip.
+        env::remove_var(ENV_SENTINEL);
+
+        let ready = File::from(unsafe { take_fd(READY_FD)? });
+        let mut names = File::from(unsafe { take_fd(NAMES_FD)? });
+        let mut files = BTreeMap::new();
+        for (i, parts) in read_names(&mut names)?.into_iter().enumerate() {
+            let fd = unsafe { take_fd(FIRST_FILE_FD + i as RawFd)? };
+            let mut name: [String; 3] = Default::default();
+            for (part, value) in name.iter_mut().zip(parts) {
+                *part = value;
+            }
+            files.insert(name, fd);
+        }
+        diagnostics::info!("Inherited {} files from tableflip parent", files.len());
+
+        Ok(Some(TableflipParent {
+            ready: Some(ready),
+            names,
+            files,
+        }))
+    }
+
+    /// Add the listener that the Go process created for `network` and `addr` to the inherited
+    /// listener group called `name`, or returns false if it passed no such listener. `network`
+    /// and `addr` must be exactly the strings the Go process listened with.
+    pub fn inherit_listener(&mut self, name: &str, network: &str, addr: &str) -> bool {
+        match self.files.remove(&file_name(LISTENER_KIND, network, addr)) {
+            Some(fd) => {
+                listeners::add_inherited(name, fd);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take the file that the Go process passed on with `Fds.AddFile(name, file)`.
+    pub fn take_file(&mut self, name: &str) -> Option<OwnedFd> {
+        self.files.remove(&file_name(FILE_KIND, name, ""))
+    }
+
+    /// Tell the Go process that this process is ready, after which it stops serving and exits.
+    /// Files that have not been taken are closed.
+    pub fn ready(&mut self) -> io::Result<()> {
+        self.files.clear();
+        if let Some(mut ready) = self.ready.take() {
+            ready.write_all(&[NOTIFY_READY])?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the Go process to exit. Call `ready` first, or the Go process waits for this
+    /// process instead.
+    pub async fn wait_for_exit(self) -> io::Result<()> {
+        let mut names = self.names;
+        // The parent keeps the names pipe open until it exits.
+        tokio::task::spawn_blocking(move || io::copy(&mut names, &mut io::sink())).await??;
+        Ok(())
+    }
+}
+
+fn file_name(kind: &str, network: &str, addr: &str) -> [String; 3] {
+    [kind.into(), network.into(), addr.into()]
+}
+
+/// Take ownership of a file descriptor inherited from the parent.
+unsafe fn take_fd(fd: RawFd) -> io::Result<OwnedFd> {
+    // Fails if the descriptor is not open.
+    set_cloexec(fd)?;
+    Ok(OwnedFd::from_raw_fd(fd))
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed file names from tableflip parent: {what}"),
+    )
+}
+
+/// Read the names of the passed files, which tableflip sends as a gob-encoded `[][]string`.
+/// Nothing past the value is read, as the pipe stays open.
+fn read_names(r: &mut impl Read) -> io::Result<Vec<Vec<String>>> {
+    loop {
+        let len = decode_uint(r)?;
+        if len > MAX_NAMES_LEN {
+            return Err(malformed("message too long"));
+        }
+        let mut message = vec![0; len as usize];
+        r.read_exact(&mut message)?;
+        let mut message = &message[..];
+
+        // Type definitions have negative IDs. There is only one value, so they need not be
+        // understood.
+        if decode_int(&mut message)? < 0 {
+            continue;
+        }
+        // Values other than structs are sent as the only field of a struct.
+        if decode_uint(&mut message)? != 0 {
+            return Err(malformed("unexpected field"));
+        }
+        return (0..decode_len(&mut message)?)
+            .map(|_| {
+                (0..decode_len(&mut message)?)
+                    .map(|_| decode_string(&mut message))
+                    .collect()
+            })
+            .collect();
+    }
+}
+
+fn decode_uint(r: &mut impl Read) -> io::Result<u64> {
+    let mut byte = [0];
+    r.read_exact(&mut byte)?;
+    if byte[0] < 0x80 {
+        return Ok(byte[0].into());
+    }
+    // Larger values are big-endian, preceded by their negated length.
+    let len = (byte[0] as i8).unsigned_abs() as usize;
+    if len > 8 {
+        return Err(malformed("integer too long"));
+    }
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes[8 - len..])?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn decode_int(r: &mut impl Read) -> io::Result<i64> {
+    let u = decode_uint(r)?;
+    // The sign is in the lowest bit, and negative values are complemented.
+    Ok(match u & 1 {
+        0 => (u >> 1) as i64,
+        _ => !(u >> 1) as i64,
+    })
+}
+
+/// Decode a length, which can't be longer than the rest of the message.
+fn decode_len(message: &mut &[u8]) -> io::Result<usize> {
+    match decode_uint(message)? {
+        len if len <= message.len() as u64 => Ok(len as usize),
+        _ => Err(malformed("length out of bounds")),
+    }
+}
+
+fn decode_string(message: &mut &[u8]) -> io::Result<String> {
+    let len = decode_len(message)?;
+    let (s, rest) = message.split_at(len);
+    *message = rest;
+    String::from_utf8(s.to_vec()).map_err(|_| malformed("invalid UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_uint(out: &mut Vec<u8>, u: u64) {
+        if u < 0x80 {
+            out.push(u as u8);
+        } else {
+            let bytes = u.to_be_bytes();
+            let skip = bytes.iter().take_while(|b| **b == 0).count();
+            out.push((-((8 - skip) as i8)) as u8);
+            out.extend_from_slice(&bytes[skip..]);
+        }
+    }
+
+    fn message(type_id: i64, body: &[u8]) -> Vec<u8> {
+        let mut contents = Vec::new();
+        let id = match type_id {
+            id if id < 0 => (!id as u64) << 1 | 1,
+            id => (id as u64) << 1,
+        };
+        encode_uint(&mut contents, id);
+        contents.extend_from_slice(body);
+        let mut out = Vec::new();
+        encode_uint(&mut out, contents.len() as u64);
+        out.extend(contents);
+        out
+    }
+
+    fn encode_names(names: &[&[&str]]) -> Vec<u8> {
+        let mut body = vec![0];
+        encode_uint(&mut body, names.len() as u64);
+        for name in names {
+            encode_uint(&mut body, name.len() as u64);
+            for part in *name {
+                encode_uint(&mut body, part.len() as u64);
+                body.extend_from_slice(part.as_bytes());
+            }
+        }
+        body
+    }
+
+    #[test]
+    fn test_read_names() {
+        let long_addr = "a".repeat(200);
+        let names: &[&[&str]] = &[
+            &["listener", "tcp", "127.0.0.1:8080"],
+            &["fd", "state", ""],
+            &["listener", "unix", &long_addr],
+        ];
+        // Type definitions for []string and [][]string precede the value.
+        let mut stream = message(-65, &[1, 2, 3]);
+        stream.extend(message(-66, &[4, 5]));
+        stream.extend(message(66, &encode_names(names)));
+        stream.extend_from_slice(b"trailing");
+
+        let mut r = &stream[..];
+        let decoded = read_names(&mut r).unwrap();
+        assert_eq!(decoded, names);
+        assert_eq!(r, b"trailing");
+
+        let mut r = &message(66, &encode_names(&[]))[..];
+        assert!(read_names(&mut r).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_names_malformed() {
+        let mut body = encode_names(&[&["listener", "tcp", ":80"]]);
+        body.truncate(body.len() - 1);
+        let mut r = &message(66, &body)[..];
+        assert_eq!(
+            read_names(&mut r).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut r = &[0x80u8, 0][..];
+        assert!(read_names(&mut r).is_err());
+    }
+}
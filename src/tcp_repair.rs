@@ -0,0 +1,418 @@
+//! Migrating established TCP connections with `TCP_REPAIR`, for when the new process can't simply
+//! inherit their fds, e.g. because it runs in another network namespace. Requires the `tcp-repair`
+//! feature and `CAP_NET_ADMIN`.
+//!
+//! `export` captures the state of a connection: its addresses, sequence numbers, negotiated
+//! options, window and the data in its queues. The old process sends the `TcpConnectionState`
+//! along with its other state, e.g. from `LifecycleHandler::send_to_new_process`, and the new
+//! process recreates the connection with `import`, without the peer noticing. This is what CRIU
+//! does, and the same caveats apply:
+//!
+//! - Packets that arrive after `export` are acknowledged by the old socket but not part of the
+//!   exported state, so they would be lost. Drop the connection's packets, e.g. with a firewall
+//!   rule, from before `export` until after `import`, and the peer retransmits them.
+//! - `export` leaves the socket in repair mode, in which closing it doesn't send a FIN or RST.
+//!   Don't use it afterwards, and close it before `import` if both processes share a network
+//!   namespace, as the connection's addresses are in use until then.
+//! - The new process must be reachable at the connection's local address, and the peer's packets
+//!   routed to it.
+//!
+//! This is an expert tool: passing the fds, as the `files` module does, is simpler and safer
+//! whenever the new process can inherit them.
+use crate::capabilities::Capability;
+use crate::files::{get_sockopt, set_sockopt};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+
+// From linux/tcp.h.
+const TCP_NO_QUEUE: libc::c_int = 0;
+const TCP_RECV_QUEUE: libc::c_int = 1;
+const TCP_SEND_QUEUE: libc::c_int = 2;
+const TCP_ESTABLISHED: u8 = 1;
+const TCPI_OPT_TIMESTAMPS: u8 = 1;
+const TCPI_OPT_SACK: u8 = 2;
+const TCPI_OPT_WSCALE: u8 = 4;
+const TCPOPT_MSS: u32 = 2;
+const TCPOPT_WINDOW: u32 = 3;
+const TCPOPT_SACK_PERM: u32 = 4;
+const TCPOPT_TIMESTAMP: u32 = 8;
+
+/// The state of an established TCP connection, captured by `export`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TcpConnectionState {
+    pub local: SocketAddr,
+    pub peer: SocketAddr,
+    /// The sequence number following the last byte in the send queue.
+    pub send_seq: u32,
+    /// The sequence number following the last byte in the receive queue.
+    pub recv_seq: u32,
+    /// Data that was sent but not acknowledged yet, followed by data that was not sent yet.
+    pub send_queue: Vec<u8>,
+    /// The length of the data at the end of `send_queue` that was not sent yet.
+    pub unsent_len: u32,
+    /// Data that was received but not read yet.
+    pub recv_queue: Vec<u8>,
+    pub mss: u32,
+    /// The send and receive window scale, if window scaling was negotiated.
+    pub window_scale: Option<(u8, u8)>,
+    pub sack: bool,
+    /// The current TCP timestamp, if timestamps were negotiated.
+    pub timestamp: Option<u32>,
+    /// The window state, which kernels before 4.8 don't report.
+    pub window: Option<TcpWindow>,
+}
+
+/// `struct tcp_repair_window`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TcpWindow {
+    pub snd_wl1: u32,
+    pub snd_wnd: u32,
+    pub max_window: u32,
+    pub rcv_wnd: u32,
+    pub rcv_wup: u32,
+}
+
+/// `struct tcp_repair_opt`.
+#[repr(C)]
+struct RepairOpt {
+    code: u32,
+    value: u32,
+}
+
+/// Captures the state of an established connection, leaving its socket in repair mode. See the
+/// module documentation for what must happen around this. If capturing fails, repair mode is
+/// turned off again, so the socket can still be used.
+pub fn export(stream: &TcpStream) -> io::Result<TcpConnectionState> {
+    check_capability()?;
+    let fd = stream.as_fd();
+    let (state, options, window_scale) = tcp_info(fd)?;
+    if state != TCP_ESTABLISHED {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("connection is in TCP state {state}, not established"),
+        ));
+    }
+    let repair = RepairMode::on(fd)?;
+
+    let send_len = ioctl_len(fd, libc::TIOCOUTQ as _)?;
+    let unsent_len = ioctl_len(fd, libc::SIOCOUTQNSD as _)?;
+    let recv_len = ioctl_len(fd, libc::FIONREAD as _)?;
+    let (send_seq, send_queue) = queue(fd, TCP_SEND_QUEUE, send_len)?;
+    let (recv_seq, recv_queue) = queue(fd, TCP_RECV_QUEUE, recv_len)?;
+    set_opt(fd, libc::TCP_REPAIR_QUEUE, TCP_NO_QUEUE)?;
+
+    let mut window = TcpWindow::default();
+    let window = match get_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_REPAIR_WINDOW, &mut window) {
+        Ok(()) => Some(window),
+        Err(e) if e.raw_os_error() == Some(libc::ENOPROTOOPT) => None,
+        Err(e) => return Err(e),
+    };
+    let state = TcpConnectionState {
+        local: stream.local_addr()?,
+        peer: stream.peer_addr()?,
+        send_seq,
+        recv_seq,
+        send_queue,
+        unsent_len: unsent_len as u32,
+        recv_queue,
+        mss: opt(fd, libc::TCP_MAXSEG)? as u32,
+        window_scale: (options & TCPI_OPT_WSCALE != 0).then_some(window_scale),
+        sack: options & TCPI_OPT_SACK != 0,
+        timestamp: match options & TCPI_OPT_TIMESTAMPS {
+            0 => None,
+            _ => Some(opt(fd, libc::TCP_TIMESTAMP)? as u32),
+        },
+        window,
+    };
+    repair.keep();
+    Ok(state)
+}
+
+/// Recreates a connection exported by `export`, which continues where the exported socket left
+/// off.
+pub fn import(state: &TcpConnectionState) -> io::Result<TcpStream> {
+    check_capability()?;
+    let family = match state.local {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+    let fd = owned.as_fd();
+    let repair = RepairMode::on(fd)?;
+    let unsent_len = (state.unsent_len as usize).min(state.send_queue.len());
+    // The sequence numbers before the queues, which restoring the queues advances.
+    let send_start = state.send_seq.wrapping_sub(state.send_queue.len() as u32);
+    let recv_start = state.recv_seq.wrapping_sub(state.recv_queue.len() as u32);
+    set_opt(fd, libc::TCP_REPAIR_QUEUE, TCP_SEND_QUEUE)?;
+    set_opt(fd, libc::TCP_QUEUE_SEQ, send_start as libc::c_int)?;
+    set_opt(fd, libc::TCP_REPAIR_QUEUE, TCP_RECV_QUEUE)?;
+    set_opt(fd, libc::TCP_QUEUE_SEQ, recv_start as libc::c_int)?;
+
+    // In repair mode, the local address can be bound even if it is in use, e.g. by a listener, and
+    // connecting moves the socket to the established state without a handshake.
+    with_sockaddr(&state.local, |addr, len| unsafe {
+        libc::bind(fd.as_raw_fd(), addr, len)
+    })?;
+    with_sockaddr(&state.peer, |addr, len| unsafe {
+        libc::connect(fd.as_raw_fd(), addr, len)
+    })?;
+
+    let mut options = vec![RepairOpt {
+        code: TCPOPT_MSS,
+        value: state.mss,
+    }];
+    if let Some((send, recv)) = state.window_scale {
+        options.push(RepairOpt {
+            code: TCPOPT_WINDOW,
+            value: u32::from(send) | u32::from(recv) << 16,
+        });
+    }
+    if state.sack {
+        options.push(RepairOpt {
+            code: TCPOPT_SACK_PERM,
+            value: 0,
+        });
+    }
+    if state.timestamp.is_some() {
+        options.push(RepairOpt {
+            code: TCPOPT_TIMESTAMP,
+            value: 0,
+        });
+    }
+    let res = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_REPAIR_OPTIONS,
+            options.as_ptr().cast(),
+            mem::size_of_val(options.as_slice()) as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if let Some(timestamp) = state.timestamp {
+        set_opt(fd, libc::TCP_TIMESTAMP, timestamp as libc::c_int)?;
+    }
+
+    set_opt(fd, libc::TCP_REPAIR_QUEUE, TCP_RECV_QUEUE)?;
+    send_all(fd, &state.recv_queue)?;
+    // Unacknowledged data is retransmitted as needed once repair mode is off.
+    let (unacked, unsent) = state
+        .send_queue
+        .split_at(state.send_queue.len() - unsent_len);
+    set_opt(fd, libc::TCP_REPAIR_QUEUE, TCP_SEND_QUEUE)?;
+    send_all(fd, unacked)?;
+    set_opt(fd, libc::TCP_REPAIR_QUEUE, TCP_NO_QUEUE)?;
+    if let Some(window) = &state.window {
+        set_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_REPAIR_WINDOW, window)?;
+    }
+    repair.off()?;
+
+    send_all(fd, unsent)?;
+    Ok(TcpStream::from(owned))
+}
+
+/// Repair mode on a socket, which is turned off again if the socket is not fully exported or
+/// imported, so that it isn't left unusable.
+struct RepairMode<'a> {
+    fd: Option<BorrowedFd<'a>>,
+}
+
+impl<'a> RepairMode<'a> {
+    fn on(fd: BorrowedFd<'a>) -> io::Result<Self> {
+        set_opt(fd, libc::TCP_REPAIR, 1)?;
+        Ok(RepairMode { fd: Some(fd) })
+    }
+
+    /// Leave the socket in repair mode.
+    fn keep(mut self) {
+        self.fd = None;
+    }
+
+    fn off(mut self) -> io::Result<()> {
+        match self.fd.take() {
+            Some(fd) => set_opt(fd, libc::TCP_REPAIR, 0),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RepairMode<'_> {
+    fn drop(&mut self) {
+        if let Some(fd) = self.fd {
+            let _ = set_opt(fd, libc::TCP_REPAIR_QUEUE, TCP_NO_QUEUE);
+            let _ = set_opt(fd, libc::TCP_REPAIR, 0);
+        }
+    }
+}
+
+fn check_capability() -> io::Result<()> {
+    match Capability::NET_ADMIN.is_effective()? {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "TCP_REPAIR needs CAP_NET_ADMIN",
+        )),
+    }
+}
+
+/// The TCP state, the `TCPI_OPT_*` options and the send and receive window scale.
+fn tcp_info(fd: BorrowedFd<'_>) -> io::Result<(u8, u8, (u8, u8))> {
+    // Only the leading bytes of `struct tcp_info` are needed, which the kernel copies as many of
+    // as asked for.
+    let mut info = [0u8; 8];
+    get_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_INFO, &mut info)?;
+    // `tcpi_snd_wscale : 4, tcpi_rcv_wscale : 4` bitfields.
+    let (send, recv) = match cfg!(target_endian = "little") {
+        true => (info[6] & 0xf, info[6] >> 4),
+        false => (info[6] >> 4, info[6] & 0xf),
+    };
+    Ok((info[0], info[5], (send, recv)))
+}
+
+/// The sequence number following the queue, and the data in it.
+fn queue(fd: BorrowedFd<'_>, queue: libc::c_int, len: usize) -> io::Result<(u32, Vec<u8>)> {
+    set_opt(fd, libc::TCP_REPAIR_QUEUE, queue)?;
+    let seq = opt(fd, libc::TCP_QUEUE_SEQ)? as u32;
+    let mut data = vec![0u8; len];
+    if len > 0 {
+        let n = unsafe {
+            libc::recv(
+                fd.as_raw_fd(),
+                data.as_mut_ptr().cast(),
+                len,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        data.truncate(n as usize);
+    }
+    Ok((seq, data))
+}
+
+fn ioctl_len(fd: BorrowedFd<'_>, request: libc::c_ulong) -> io::Result<usize> {
+    let mut len: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), request as _, &mut len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+fn send_all(fd: BorrowedFd<'_>, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        let n = unsafe { libc::send(fd.as_raw_fd(), data.as_ptr().cast(), data.len(), 0) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        data = &data[n as usize..];
+    }
+    Ok(())
+}
+
+fn opt(fd: BorrowedFd<'_>, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    get_sockopt(fd, libc::IPPROTO_TCP, name, &mut value)?;
+    Ok(value)
+}
+
+fn set_opt(fd: BorrowedFd<'_>, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    set_sockopt(fd, libc::IPPROTO_TCP, name, &value)
+}
+
+fn with_sockaddr(
+    addr: &SocketAddr,
+    f: impl FnOnce(*const libc::sockaddr, libc::socklen_t) -> libc::c_int,
+) -> io::Result<()> {
+    let res = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = sockaddr_in(addr);
+            f(
+                (&sin as *const libc::sockaddr_in).cast(),
+                mem::size_of_val(&sin) as libc::socklen_t,
+            )
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = sockaddr_in6(addr);
+            f(
+                (&sin6 as *const libc::sockaddr_in6).cast(),
+                mem::size_of_val(&sin6) as libc::socklen_t,
+            )
+        }
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn sockaddr_in(addr: &SocketAddrV4) -> libc::sockaddr_in {
+    let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+    sin.sin_port = addr.port().to_be();
+    sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+    sin
+}
+
+fn sockaddr_in6(addr: &SocketAddrV6) -> libc::sockaddr_in6 {
+    let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sin6.sin6_port = addr.port().to_be();
+    sin6.sin6_flowinfo = addr.flowinfo();
+    sin6.sin6_addr.s6_addr = addr.ip().octets();
+    sin6.sin6_scope_id = addr.scope_id();
+    sin6
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_migrate() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(b"unread").unwrap();
+        server.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).unwrap();
+        // Let the data arrive in the receive queue.
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let state = match export(&server) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return,
+            res => res.unwrap(),
+        };
+        assert_eq!(state.recv_queue, b"unread");
+        assert!(state.send_queue.is_empty());
+        let json = serde_json::to_string(&state).unwrap();
+        // Closing the socket in repair mode doesn't end the connection.
+        drop(server);
+
+        let mut server = import(&serde_json::from_str(&json).unwrap()).unwrap();
+        let mut buf = [0; 6];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"unread");
+        server.write_all(b"pong").unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+}
@@ -0,0 +1,576 @@
+//! Upgrading a process in place: fetch the next version of the binary, verify it, install it over
+//! the current one and restart into it. Requires the `self-update` feature.
+//!
+//! The new binary is staged in the directory of the one it replaces, so that it can be renamed
+//! into place atomically once verified. A process that is spawned from the old path at any point
+//! gets either the old or the new binary, never a partial one. The previous binary is kept next to
+//! it with a `.previous` suffix, and put back if the restart into the new one fails.
+//!
+//! Alternatively, the binary can be managed in two installation slots with `Slots`. The service is
+//! then started through a symlink to the current slot, and an update is installed into the other
+//! slot before the symlink is flipped to it. The replaced slot stays intact, so that the operator
+//! can restart back into it with `RestartConfig::request_rollback` if the upgrade turns out to be
+//! bad. This requires `RestartConfig::binary_slots` to be set in the running process.
+//!
+//! Updates must be verified, with a SHA-256 checksum, an implementation of `Verify` that e.g.
+//! checks a signature, or both. Local files are read directly. Fetching a URL is left to an
+//! implementation of `Fetch`, so that it can use the HTTP client and TLS settings of the
+//! application.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), shellflip::update::UpdateError> {
+//! use shellflip::update::{SelfUpdate, Sha256, UpdateSource};
+//!
+//! let update = SelfUpdate {
+//!     source: UpdateSource::Path("/var/cache/myservice/myservice-1.2.3".into()),
+//!     target: Some("/usr/local/bin/myservice".into()),
+//!     checksum: Some(Sha256::from_hex(
+//!         "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+//!     )?),
+//!     ..Default::default()
+//! };
+//! let outcome = update
+//!     .install_and_restart("/run/myservice/restart.sock", Default::default())
+//!     .await?;
+//! println!("running the new version as pid {}", outcome.pid);
+//! # Ok(())
+//! # }
+//! ```
+use crate::{diagnostics, Error, RestartConfig, RestartOptions, RestartOutcome};
+use async_trait::async_trait;
+use sha2::Digest;
+use std::env;
+use std::fmt;
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Where the next version of the binary comes from.
+#[derive(Clone, Debug)]
+pub enum UpdateSource {
+    Path(PathBuf),
+    /// Fetched with `SelfUpdate::fetcher`.
+    Url(String),
+}
+
+/// Downloads a new binary for `UpdateSource::Url`.
+#[async_trait]
+pub trait Fetch: Send + Sync {
+    /// Write the contents at `url` to `dest`.
+    async fn fetch(&self, url: &str, dest: &mut tokio::fs::File) -> io::Result<()>;
+}
+
+/// Checks a staged binary before it is installed, e.g. against a signature.
+pub trait Verify: Send + Sync {
+    /// Returns the reason if the binary at `path` must not be installed.
+    fn verify(&self, path: &Path) -> Result<(), String>;
+}
+
+/// How to update the current process.
+pub struct SelfUpdate {
+    pub source: UpdateSource,
+    /// The binary to replace. `install` defaults to the one the current process runs, which is
+    /// where the new process is started from, unless it was started through a symlink. Required
+    /// by `install_and_restart`, since the process it restarts need not run the same binary as
+    /// the current one. Ignored if `slots` is set.
+    pub target: Option<PathBuf>,
+    /// Install into the inactive slot and switch to it, rather than replacing `target`.
+    pub slots: Option<Slots>,
+    /// The expected SHA-256 checksum of the new binary.
+    pub checksum: Option<Sha256>,
+    /// Checks the new binary in addition to the checksum.
+    pub verifier: Option<Box<dyn Verify>>,
+    /// Required for `UpdateSource::Url`.
+    pub fetcher: Option<Box<dyn Fetch>>,
+}
+
+impl Default for SelfUpdate {
+    fn default() -> Self {
+        SelfUpdate {
+            source: UpdateSource::Path(PathBuf::new()),
+            target: None,
+            slots: None,
+            checksum: None,
+            verifier: None,
+            fetcher: None,
+        }
+    }
+}
+
+/// Why an update was not installed, or the restart into it failed.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum UpdateError {
+    /// Neither a checksum nor a verifier was given.
+    #[error("refusing to install an unverified update")]
+    Unverified,
+    /// `install_and_restart` was called without a `target` or `slots`.
+    #[error("no target binary given for the update")]
+    NoTarget,
+    /// The update is a URL, but no fetcher was given.
+    #[error("no fetcher configured for {0}")]
+    NoFetcher(String),
+    #[error("failed to fetch update from {source_name}: {error}")]
+    Fetch {
+        source_name: String,
+        error: io::Error,
+    },
+    #[error("update checksum mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch { expected: Sha256, actual: Sha256 },
+    #[error("update failed verification: {0}")]
+    Rejected(String),
+    #[error("failed to install update at {}: {error}", path.display())]
+    Install { path: PathBuf, error: io::Error },
+    /// The update was installed, but restarting into it failed. The previous binary was put back.
+    #[error("failed to restart into the update: {0}")]
+    Restart(#[from] Error),
+    #[error("invalid SHA-256 checksum {0:?}")]
+    InvalidChecksum(String),
+}
+
+/// An installed update.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Installed {
+    pub path: PathBuf,
+    /// The binary that was replaced, if there was one.
+    pub previous: Option<PathBuf>,
+    slots: Option<Slots>,
+}
+
+impl Installed {
+    /// Put the previous binary back.
+    pub fn roll_back(&self) -> io::Result<()> {
+        match (&self.slots, &self.previous) {
+            (Some(slots), _) => slots.roll_back().map(drop),
+            (None, Some(previous)) => fs::rename(previous, &self.path),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl SelfUpdate {
+    /// Fetch, verify and install the update. The running process is left alone.
+    pub async fn install(&self) -> Result<Installed, UpdateError> {
+        if self.checksum.is_none() && self.verifier.is_none() {
+            return Err(UpdateError::Unverified);
+        }
+        let target = match (&self.slots, &self.target) {
+            (Some(slots), _) => slots.inactive(),
+            (None, Some(target)) => Ok(target.clone()),
+            (None, None) => env::current_exe(),
+        };
+        let target = target.map_err(|error| UpdateError::Install {
+            path: PathBuf::new(),
+            error,
+        })?;
+        let staged = sibling(&target, &format!("{}.staged", process::id()));
+
+        let res = self.stage_and_verify(&staged).await;
+        let res = match res {
+            Ok(()) => install(&staged, &target, self.slots.is_none()),
+            Err(e) => Err(e),
+        };
+        if res.is_err() {
+            let _ = fs::remove_file(&staged);
+        }
+        let previous = res?;
+
+        let Some(slots) = &self.slots else {
+            return Ok(Installed {
+                path: target,
+                previous,
+                slots: None,
+            });
+        };
+        let previous = slots
+            .switch_to(&target)
+            .map_err(|error| UpdateError::Install {
+                path: slots.link.clone(),
+                error,
+            })?;
+        Ok(Installed {
+            path: slots.link.clone(),
+            previous,
+            slots: Some(slots.clone()),
+        })
+    }
+
+    /// Install the update, then restart the process that serves the restart coordination socket at
+    /// `socket` into it, which may be the current process. If the restart fails, the previous
+    /// binary is put back.
+    ///
+    /// Either `target` or `slots` must be set: the binary of the restarted process is not known
+    /// here, and guessing the current one would overwrite the updater itself.
+    pub async fn install_and_restart(
+        &self,
+        socket: impl Into<PathBuf>,
+        options: RestartOptions,
+    ) -> Result<RestartOutcome, UpdateError> {
+        if self.target.is_none() && self.slots.is_none() {
+            return Err(UpdateError::NoTarget);
+        }
+        let installed = self.install().await?;
+        let config = RestartConfig {
+            enabled: true,
+            coordination_socket_path: socket.into(),
+            ..Default::default()
+        };
+        match config.request_restart_with(options).await {
+            Ok(outcome) => Ok(outcome),
+            Err(e) => {
+                diagnostics::warn!(
+                    "Restart into the update failed, putting back the previous binary: {}",
+                    e
+                );
+                if let Err(error) = installed.roll_back() {
+                    diagnostics::error!("Failed to put back the previous binary: {}", error);
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn stage_and_verify(&self, staged: &Path) -> Result<(), UpdateError> {
+        let source_name = match &self.source {
+            UpdateSource::Path(path) => path.display().to_string(),
+            UpdateSource::Url(url) => url.clone(),
+        };
+        let fetch_error = |error| UpdateError::Fetch {
+            source_name: source_name.clone(),
+            error,
+        };
+
+        let mut file = tokio::fs::File::create(staged).await.map_err(fetch_error)?;
+        match &self.source {
+            UpdateSource::Path(path) => {
+                let mut source = tokio::fs::File::open(path).await.map_err(fetch_error)?;
+                tokio::io::copy(&mut source, &mut file)
+                    .await
+                    .map_err(fetch_error)?;
+            }
+            UpdateSource::Url(url) => {
+                let fetcher = self
+                    .fetcher
+                    .as_ref()
+                    .ok_or_else(|| UpdateError::NoFetcher(url.clone()))?;
+                fetcher.fetch(url, &mut file).await.map_err(fetch_error)?;
+            }
+        }
+        file.flush().await.map_err(fetch_error)?;
+        file.sync_all().await.map_err(fetch_error)?;
+        drop(file);
+
+        if let Some(expected) = self.checksum {
+            let actual = Sha256::of_file(staged).await.map_err(fetch_error)?;
+            if actual != expected {
+                return Err(UpdateError::ChecksumMismatch { expected, actual });
+            }
+        }
+        if let Some(verifier) = &self.verifier {
+            verifier.verify(staged).map_err(UpdateError::Rejected)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rename the verified binary over `target`. If `keep_previous` is set, a link to the binary it
+/// replaces is kept and returned.
+fn install(
+    staged: &Path,
+    target: &Path,
+    keep_previous: bool,
+) -> Result<Option<PathBuf>, UpdateError> {
+    let install_error = |error| UpdateError::Install {
+        path: target.to_path_buf(),
+        error,
+    };
+    fs::set_permissions(staged, Permissions::from_mode(0o755)).map_err(install_error)?;
+
+    let mut previous = None;
+    if keep_previous {
+        let path = sibling(target, "previous");
+        let _ = fs::remove_file(&path);
+        match fs::hard_link(target, &path) {
+            Ok(()) => previous = Some(path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(install_error(e)),
+        }
+    }
+    fs::rename(staged, target).map_err(install_error)?;
+    diagnostics::info!("Installed update at {}", target.display());
+    Ok(previous)
+}
+
+/// Two installation slots for a binary, `<link>.a` and `<link>.b`. The service is started through
+/// `link`, a symlink to the current slot, and `<link>.previous` points at the other slot once
+/// there has been an update.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Slots {
+    pub link: PathBuf,
+}
+
+impl Slots {
+    pub fn new(link: impl Into<PathBuf>) -> Self {
+        Slots { link: link.into() }
+    }
+
+    /// The slot the service runs from, or `None` if `link` is not a symlink (yet).
+    pub fn current(&self) -> io::Result<Option<PathBuf>> {
+        self.resolve(&self.link)
+    }
+
+    /// The slot the service ran from before the last switch, if any.
+    pub fn previous(&self) -> io::Result<Option<PathBuf>> {
+        self.resolve(&sibling(&self.link, "previous"))
+    }
+
+    /// The slot that updates are installed into.
+    pub fn inactive(&self) -> io::Result<PathBuf> {
+        let (a, b) = (sibling(&self.link, "a"), sibling(&self.link, "b"));
+        Ok(match self.current()? {
+            Some(current) if current == a => b,
+            _ => a,
+        })
+    }
+
+    /// Point `link` at `slot`, and the previous link at the slot it pointed at. Returns the slot
+    /// that was replaced. If `link` is a plain binary, it is kept in the other slot first.
+    pub fn switch_to(&self, slot: &Path) -> io::Result<Option<PathBuf>> {
+        let replaced = match fs::symlink_metadata(&self.link) {
+            Ok(meta) if !meta.file_type().is_symlink() => {
+                let other = match slot == sibling(&self.link, "a") {
+                    true => sibling(&self.link, "b"),
+                    false => sibling(&self.link, "a"),
+                };
+                let _ = fs::remove_file(&other);
+                fs::hard_link(&self.link, &other)?;
+                Some(other)
+            }
+            _ => self.current()?,
+        };
+        replace_symlink(&self.link, slot)?;
+        let previous = sibling(&self.link, "previous");
+        match &replaced {
+            Some(replaced) => replace_symlink(&previous, replaced)?,
+            None => match fs::remove_file(&previous) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            },
+        }
+        diagnostics::info!("Switched {} to {}", self.link.display(), slot.display());
+        Ok(replaced)
+    }
+
+    /// Switch back to the previous slot. Returns the slot that is now current.
+    pub fn roll_back(&self) -> io::Result<PathBuf> {
+        let previous = self
+            .previous()?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "there is no previous slot"))?;
+        self.switch_to(&previous)?;
+        Ok(previous)
+    }
+
+    /// Resolve a symlink to a slot. Returns `None` if `path` is not a symlink.
+    fn resolve(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        match fs::read_link(path) {
+            Ok(slot) => Ok(Some(path.with_file_name(slot))),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::InvalidInput
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Atomically point the symlink at `path` to the file `target` in the same directory.
+fn replace_symlink(path: &Path, target: &Path) -> io::Result<()> {
+    let tmp = sibling(path, &format!("{}.link", process::id()));
+    let _ = fs::remove_file(&tmp);
+    std::os::unix::fs::symlink(target.file_name().unwrap_or_default(), &tmp)?;
+    let res = fs::rename(&tmp, path);
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
+/// The path next to `path` with `.suffix` appended to the file name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// A SHA-256 digest.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Sha256(pub [u8; 32]);
+
+impl Sha256 {
+    /// Parses a checksum in hex, as printed by `sha256sum`.
+    pub fn from_hex(hex: &str) -> Result<Self, UpdateError> {
+        let invalid = || UpdateError::InvalidChecksum(hex.into());
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut digest = [0; 32];
+        for (i, byte) in digest.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Sha256(digest))
+    }
+
+    pub fn digest(data: &[u8]) -> Self {
+        Sha256(sha2::Sha256::digest(data).into())
+    }
+
+    async fn of_file(path: &Path) -> io::Result<Self> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut hasher = sha2::Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(Sha256(hasher.finalize().into()));
+            }
+            hasher.update(&buf[..n]);
+        }
+    }
+}
+
+impl fmt::Display for Sha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+impl fmt::Debug for Sha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sha256({self})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256() {
+        let hex = |data: &[u8]| Sha256::digest(data).to_string();
+        assert_eq!(
+            hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        let million = vec![b'a'; 1_000_000];
+        assert_eq!(
+            hex(&million),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+        assert_eq!(
+            Sha256::from_hex(&hex(b"abc")).unwrap(),
+            Sha256::digest(b"abc")
+        );
+        assert!(Sha256::from_hex("abc").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_install() {
+        let dir = env::temp_dir().join(format!("shellflip-update-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("service");
+        let source = dir.join("service-2");
+        fs::write(&target, b"old").unwrap();
+        fs::write(&source, b"new").unwrap();
+
+        let mut update = SelfUpdate {
+            source: UpdateSource::Path(source),
+            target: Some(target.clone()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            update.install().await,
+            Err(UpdateError::Unverified)
+        ));
+        update.checksum = Some(Sha256::digest(b"other"));
+        assert!(matches!(
+            update.install().await,
+            Err(UpdateError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+
+        update.checksum = Some(Sha256::digest(b"new"));
+        let installed = update.install().await.unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        installed.roll_back().unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+
+        update.target = None;
+        assert!(matches!(
+            update
+                .install_and_restart(dir.join("restart.sock"), Default::default())
+                .await,
+            Err(UpdateError::NoTarget)
+        ));
+        // Nothing is left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slots() {
+        let dir = env::temp_dir().join(format!("shellflip-slots-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let link = dir.join("service");
+        let source = dir.join("service-2");
+        fs::write(&link, b"v1").unwrap();
+        fs::write(&source, b"v2").unwrap();
+        let slots = Slots::new(&link);
+        let (a, b) = (dir.join("service.a"), dir.join("service.b"));
+
+        let mut update = SelfUpdate {
+            source: UpdateSource::Path(source.clone()),
+            slots: Some(slots.clone()),
+            checksum: Some(Sha256::digest(b"v2")),
+            ..Default::default()
+        };
+        // The plain binary is kept in the other slot.
+        let installed = update.install().await.unwrap();
+        assert_eq!(installed.previous, Some(b.clone()));
+        assert_eq!(slots.current().unwrap(), Some(a.clone()));
+        assert_eq!(fs::read(&link).unwrap(), b"v2");
+        assert_eq!(fs::read(&b).unwrap(), b"v1");
+
+        fs::write(&source, b"v3").unwrap();
+        update.checksum = Some(Sha256::digest(b"v3"));
+        update.install().await.unwrap();
+        assert_eq!(slots.current().unwrap(), Some(b.clone()));
+        assert_eq!(slots.previous().unwrap(), Some(a.clone()));
+        assert_eq!(fs::read(&link).unwrap(), b"v3");
+
+        assert_eq!(slots.roll_back().unwrap(), a);
+        assert_eq!(fs::read(&link).unwrap(), b"v2");
+        assert_eq!(slots.previous().unwrap(), Some(b));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,300 @@
+//! Serving the restart coordination socket over `AF_VSOCK`, for services running in a virtual
+//! machine, so that an orchestrator on the host can restart them without a shared filesystem.
+//!
+//! Add an `Endpoint` with `EndpointAddr::Vsock` to `RestartConfig::endpoints` to listen on a vsock
+//! port. Connections speak the same protocol as the restart coordination socket and are handled by
+//! the same code, and the listener is passed to the new process on restart. The host connects to
+//! the guest's CID and the port, e.g. with `connect`. With Firecracker, the host instead connects
+//! to the unix socket backing the guest's vsock device, sends `CONNECT <port>\n` and reads the `OK`
+//! line before speaking the protocol.
+//!
+//! vsock connections can't carry fds, so restart requests can't attach fds for the new process,
+//! and there are no peer credentials for the `audit` module to record or for the endpoint's policy
+//! to check. Unless the policy is read-only, anyone who can reach the port can restart the service,
+//! so only allow that if reaching it is limited to the host, e.g. by the hypervisor.
+use crate::diagnostics;
+use crate::files;
+use crate::restart_coordination_socket::RestartCoordinationSocket;
+use crate::{Error, RestartResult};
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use tokio::io::unix::AsyncFd;
+use tokio::net::UnixStream;
+use tokio::select;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// The number of connections that may wait for the restart task to pick them up.
+const CONNECTION_BACKLOG: usize = 16;
+
+/// Settings for serving the restart coordination socket over vsock.
+#[derive(Clone, Debug)]
+pub struct VsockConfig {
+    /// The port to listen on.
+    pub port: u32,
+    /// The CID to listen on. The default, `libc::VMADDR_CID_ANY`, accepts connections to any CID
+    /// of this machine.
+    pub cid: u32,
+}
+
+impl Default for VsockConfig {
+    fn default() -> Self {
+        VsockConfig {
+            port: 0,
+            cid: libc::VMADDR_CID_ANY,
+        }
+    }
+}
+
+/// Listen on the port, or take over the listener the old process passed under `name`, and serve it
+/// in a new task. Each connection is forwarded over a connection sent to the restart task through
+/// the returned channel. The listener stops when the channel closes.
+pub(crate) fn spawn(name: &str, config: VsockConfig) -> RestartResult<Receiver<UnixStream>> {
+    let listener = listen(name, &config).map_err(|e| {
+        Error::Io(io::Error::new(
+            e.kind(),
+            format!("failed to listen on vsock port {}: {}", config.port, e),
+        ))
+    })?;
+    files::register(name, &listener)?;
+    let flags = unsafe { libc::fcntl(listener.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0
+        || unsafe {
+            libc::fcntl(
+                listener.as_raw_fd(),
+                libc::F_SETFL,
+                flags | libc::O_NONBLOCK,
+            )
+        } < 0
+    {
+        return Err(io::Error::last_os_error().into());
+    }
+    let listener = AsyncFd::new(listener)?;
+    let (tx, rx) = channel(CONNECTION_BACKLOG);
+    tokio::spawn(serve(listener, tx));
+    Ok(rx)
+}
+
+/// Connect to the restart coordination socket served on vsock `port` of the machine with `cid`.
+pub async fn connect(cid: u32, port: u32) -> io::Result<RestartCoordinationSocket> {
+    let stream = tokio::task::spawn_blocking(move || {
+        let fd = socket()?;
+        let addr = sockaddr(cid, port);
+        let res = unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                (&addr as *const libc::sockaddr_vm).cast(),
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+        match res {
+            0 => Ok(fd),
+            _ => Err(io::Error::last_os_error()),
+        }
+    })
+    .await??;
+    let (ours, theirs) = UnixStream::pair()?;
+    tokio::spawn(forward(stream, ours));
+    Ok(RestartCoordinationSocket::new(theirs))
+}
+
+fn listen(name: &str, config: &VsockConfig) -> io::Result<OwnedFd> {
+    if let Some(fd) = files::take_inherited(name) {
+        match local_addr(fd.as_fd()) {
+            Ok((cid, port)) if cid == config.cid && port == config.port => {
+                diagnostics::info!("Using vsock listener on port {} of the old process", port);
+                return Ok(fd);
+            }
+            Ok((cid, port)) => diagnostics::info!(
+                "Closing inherited vsock listener on {}:{}, as it is not {}:{}",
+                cid,
+                port,
+                config.cid,
+                config.port
+            ),
+            Err(e) => diagnostics::info!("Closing inherited vsock listener: {}", e),
+        }
+    }
+    let fd = socket()?;
+    let addr = sockaddr(config.cid, config.port);
+    let res = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            (&addr as *const libc::sockaddr_vm).cast(),
+            mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if res < 0 || unsafe { libc::listen(fd.as_raw_fd(), libc::SOMAXCONN) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    diagnostics::info!("Listening on vsock port {}", config.port);
+    Ok(fd)
+}
+
+fn socket() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    match fd {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+fn sockaddr(cid: u32, port: u32) -> libc::sockaddr_vm {
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = cid;
+    addr.svm_port = port;
+    addr
+}
+
+/// The CID and port a vsock socket is bound to.
+fn local_addr(fd: BorrowedFd<'_>) -> io::Result<(u32, u32)> {
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockname(
+            fd.as_raw_fd(),
+            (&mut addr as *mut libc::sockaddr_vm).cast(),
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if addr.svm_family != libc::AF_VSOCK as libc::sa_family_t {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a vsock socket",
+        ));
+    }
+    Ok((addr.svm_cid, addr.svm_port))
+}
+
+async fn serve(listener: AsyncFd<OwnedFd>, connector: Sender<UnixStream>) {
+    loop {
+        let accepted = select! {
+            // The restart task completed, so the new process serves the listener from now on.
+            _ = connector.closed() => return,
+            accepted = accept(&listener) => accepted,
+        };
+        let stream = match accepted {
+            Ok((stream, cid)) => {
+                diagnostics::debug!("Accepted vsock connection from CID {}", cid);
+                stream
+            }
+            Err(e) => {
+                diagnostics::error!("vsock accept error: {}", e);
+                continue;
+            }
+        };
+        match UnixStream::pair() {
+            Ok((ours, theirs)) => {
+                if connector.send(theirs).await.is_ok() {
+                    tokio::spawn(forward(stream, ours));
+                }
+            }
+            Err(e) => diagnostics::error!("Failed to forward vsock connection: {}", e),
+        }
+    }
+}
+
+/// Accepts a connection, and returns it along with the CID of the peer.
+async fn accept(listener: &AsyncFd<OwnedFd>) -> io::Result<(OwnedFd, u32)> {
+    loop {
+        let mut guard = listener.readable().await?;
+        let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+        let fd = unsafe {
+            libc::accept4(
+                listener.as_raw_fd(),
+                (&mut addr as *mut libc::sockaddr_vm).cast(),
+                &mut len,
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        if fd >= 0 {
+            return Ok((unsafe { OwnedFd::from_raw_fd(fd) }, addr.svm_cid));
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::WouldBlock => guard.clear_ready(),
+            io::ErrorKind::Interrupted => {}
+            _ => return Err(err),
+        }
+    }
+}
+
+/// Copies bytes between a vsock connection and the unix socket it is forwarded to, until either
+/// side closes.
+async fn forward(vsock: OwnedFd, mut unix: UnixStream) {
+    // tokio has no vsock streams. A unix stream only reads and writes its fd, which works the same
+    // for any stream socket.
+    let vsock = StdUnixStream::from(vsock);
+    let res = match vsock.set_nonblocking(true) {
+        Ok(()) => match UnixStream::from_std(vsock) {
+            Ok(mut vsock) => tokio::io::copy_bidirectional(&mut vsock, &mut unix)
+                .await
+                .map(|_| ()),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        diagnostics::debug!("vsock connection closed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restart_coordination_socket::{RestartMessage, RestartRequest};
+
+    #[tokio::test]
+    async fn test_connect() {
+        // The loopback transport is not available everywhere.
+        let config = VsockConfig {
+            port: 0xf11b,
+            cid: libc::VMADDR_CID_LOCAL,
+        };
+        let mut connections = match spawn("shellflip-vsock", config) {
+            Ok(connections) => connections,
+            Err(Error::Io(e)) if e.kind() != io::ErrorKind::AddrInUse => return,
+            Err(e) => panic!("{e}"),
+        };
+        let client = tokio::spawn(async {
+            let mut client = connect(libc::VMADDR_CID_LOCAL, 0xf11b).await.unwrap();
+            client.query_status().await
+        });
+        let mut server = RestartCoordinationSocket::new(connections.recv().await.unwrap());
+        let message = server.receive_message().await.unwrap();
+        assert!(matches!(
+            message,
+            RestartMessage::Request(RestartRequest::Status)
+        ));
+        drop(server);
+        assert!(client.await.unwrap().is_err());
+        files::unregister("shellflip-vsock");
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        // Forwarding works the same for any stream socket.
+        let (client, remote) = StdUnixStream::pair().unwrap();
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        tokio::spawn(forward(OwnedFd::from(remote), ours));
+        client.set_nonblocking(true).unwrap();
+        let mut client = RestartCoordinationSocket::new(UnixStream::from_std(client).unwrap());
+        let mut server = RestartCoordinationSocket::new(theirs);
+        client
+            .send_message(RestartMessage::Request(RestartRequest::Status))
+            .await
+            .unwrap();
+        assert!(matches!(
+            server.receive_message().await.unwrap(),
+            RestartMessage::Request(RestartRequest::Status)
+        ));
+        drop(server);
+        assert!(client.receive_message().await.is_err());
+    }
+}